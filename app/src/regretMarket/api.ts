@@ -647,6 +647,7 @@ export function useRegretMarket() {
 			const [positionPda] = PublicKey.findProgramAddressSync(
 				[
 					Buffer.from("position"),
+					Buffer.from([Buffer.byteLength(pair)]),
 					Buffer.from(pair),
 					provider.wallet.publicKey.toBuffer(),
 					positionIdBN.toArrayLike(Buffer, 'le', 8)
@@ -0,0 +1,503 @@
+use anchor_lang::prelude::*;
+
+use crate::{instructions::UpdateConfigParams, state::FeedStatus};
+
+#[event]
+pub struct MarketParamsSnapshotted {
+    pub pair: String,
+    pub epoch: u64,
+    pub slot: u64,
+    pub snapshot_hash: [u8; 32],
+}
+
+#[event]
+pub struct CollateralDiscrepancy {
+    pub token_mint: String,
+    pub stored_trader_collateral: u64,
+    pub expected_trader_collateral: u64,
+    pub positions_checked: u64,
+    pub slot: u64,
+}
+
+#[event]
+pub struct PositionReducedByAdmin {
+    pub admin: Pubkey,
+    pub owner: Pubkey,
+    pub pair: String,
+    pub position_id: u64,
+    pub previous_size: u64,
+    pub remaining_size: u64,
+    pub price: u64,
+    pub closed_collateral: u64,
+    pub realized_pnl: i64,
+    pub closing_fee: u64,
+    pub amount_to_return: u64,
+    pub bad_debt: u64,
+    pub slot: u64,
+}
+
+#[event]
+pub struct PositionPartiallyClosed {
+    pub owner: Pubkey,
+    pub pair: String,
+    pub position_id: u64,
+    pub close_fraction_bps: u64,
+    pub previous_size: u64,
+    pub remaining_size: u64,
+    pub price: u64,
+    pub closed_collateral: u64,
+    pub realized_pnl: i64,
+    pub closing_fee: u64,
+    pub amount_to_return: u64,
+    pub bad_debt: u64,
+    pub slot: u64,
+}
+
+#[event]
+pub struct CollateralAdded {
+    pub owner: Pubkey,
+    pub pair: String,
+    pub position_id: u64,
+    pub amount: u64,
+    pub previous_collateral: u64,
+    pub collateral: u64,
+    pub leverage: u64,
+    pub slot: u64,
+}
+
+#[event]
+pub struct CollateralRemoved {
+    pub owner: Pubkey,
+    pub pair: String,
+    pub position_id: u64,
+    pub amount: u64,
+    pub previous_collateral: u64,
+    pub collateral: u64,
+    pub leverage: u64,
+    pub health_ratio_bps: u64,
+    pub slot: u64,
+}
+
+#[event]
+pub struct PositionSeizedByAdmin {
+    pub admin: Pubkey,
+    pub owner: Pubkey,
+    pub pair: String,
+    pub position_id: u64,
+    pub actual_size: u64,
+    pub seized_collateral: u64,
+    pub released_borrow: u64,
+    pub slot: u64,
+}
+
+#[event]
+pub struct PositionLiquidated {
+    pub keeper: Pubkey,
+    pub owner: Pubkey,
+    pub position_id: u64,
+    pub price: u64,
+    pub health_ratio: u64,
+    pub equity: i64,
+    pub liquidation_reward: u64,
+    pub bad_debt: u64,
+    pub trader_rebate: u64,
+    pub slot: u64,
+}
+
+#[event]
+pub struct PositionTriggersSet {
+    pub owner: Pubkey,
+    pub position_id: u64,
+    // 0 means unset
+    pub stop_loss_price: u64,
+    pub take_profit_price: u64,
+    pub slot: u64,
+}
+
+#[event]
+pub struct StopLossExecuted {
+    pub keeper: Pubkey,
+    pub owner: Pubkey,
+    pub position_id: u64,
+    pub price: u64,
+    pub stop_loss_price: u64,
+    pub final_pnl: i64,
+    pub keeper_reward: u64,
+    pub amount_to_return: u64,
+    pub slot: u64,
+}
+
+#[event]
+pub struct TakeProfitExecuted {
+    pub keeper: Pubkey,
+    pub owner: Pubkey,
+    pub position_id: u64,
+    pub price: u64,
+    pub take_profit_price: u64,
+    pub final_pnl: i64,
+    pub keeper_reward: u64,
+    pub amount_to_return: u64,
+    pub slot: u64,
+}
+
+#[event]
+pub struct PositionRebalanced {
+    pub owner: Pubkey,
+    pub position_id: u64,
+    pub price: u64,
+    pub previous_size: u64,
+    pub new_size: u64,
+    pub new_target_price: u64,
+    pub excess_to_insurance: u64,
+    pub profit_realized: u64,
+    pub size_capped: bool,
+    pub slot: u64,
+}
+
+#[event]
+pub struct StaleFundingSettled {
+    pub owner: Pubkey,
+    pub position_id: u64,
+    pub slots_elapsed: u64,
+    pub max_funding_staleness_slots: u64,
+    pub slot: u64,
+}
+
+#[event]
+pub struct BalanceWithdrawn {
+    pub owner: Pubkey,
+    pub token_mint: String,
+    pub amount: u64,
+    pub remaining_balance: u64,
+    pub slot: u64,
+}
+
+#[event]
+pub struct VirtualBalanceBurned {
+    pub owner: Pubkey,
+    pub token_mint: String,
+    pub amount: u64,
+    pub remaining_balance: u64,
+    pub slot: u64,
+}
+
+#[event]
+pub struct FundingApplied {
+    pub owner: Pubkey,
+    pub pair: String,
+    pub position_id: u64,
+    // basis points scaled by `PRECISION`
+    pub funding_rate_scaled: i64,
+    pub slots_elapsed: u64,
+    pub funding_amount: u64,
+    pub is_payment: bool,
+    // negative when the position has received more funding than it paid
+    pub cumulative_funding_paid: i64,
+    pub slot: u64,
+}
+
+#[event]
+pub struct MarketsPausedByFeed {
+    pub admin: Pubkey,
+    pub feed_id_prefix: String,
+    pub pairs: Vec<String>,
+    pub slot: u64,
+}
+
+#[event]
+pub struct MarketPauseToggled {
+    pub admin: Pubkey,
+    pub pair: String,
+    pub is_paused: bool,
+    pub slot: u64,
+}
+
+#[event]
+pub struct FundingRateSet {
+    pub admin: Pubkey,
+    pub pair: String,
+    pub previous_rate_bps: i64,
+    pub funding_rate_bps: i64,
+    pub slot: u64,
+}
+
+#[event]
+pub struct MarketFundingSettled {
+    pub pair: String,
+    pub positions_settled: u64,
+    pub total_funding_paid: u64,
+    pub total_funding_received: u64,
+    // long side rate in basis points scaled by `PRECISION`
+    pub funding_rate_scaled: i64,
+    pub price: u64,
+    pub slot: u64,
+}
+
+#[event]
+pub struct BadDebtAbsorbed {
+    pub token_mint: String,
+    pub bad_debt: u64,
+    pub from_insurance: u64,
+    pub socialized_to_lps: u64,
+    pub bad_debt_this_epoch: u64,
+    pub slot: u64,
+}
+
+#[event]
+pub struct PositionOpened {
+    // `Pubkey::default()` when the trader enabled privacy
+    pub owner: Pubkey,
+    pub pair: String,
+    pub position_id: u64,
+    pub is_long: bool,
+    pub desired_size: u64,
+    pub desired_entry_price: u64,
+    pub actual_size: u64,
+    pub actual_entered_price: u64,
+    pub collateral: u64,
+    pub leverage: u64,
+    pub position_value: u64,
+    pub opening_fee: u64,
+    pub slot: u64,
+}
+
+#[event]
+pub struct PositionClosed {
+    // `Pubkey::default()` when the trader enabled privacy
+    pub owner: Pubkey,
+    pub pair: String,
+    pub position_id: u64,
+    pub price: u64,
+    pub gross_price_pnl: i64,
+    // negative when funding was a net receipt
+    pub total_funding_paid: i64,
+    pub opening_fee_paid: u64,
+    pub closing_fee_paid: u64,
+    // not capped at the collateral, a larger loss shows in full with `amount_to_return`
+    // at 0 and the excess in `bad_debt`
+    pub net_pnl: i64,
+    pub amount_to_return: u64,
+    pub bad_debt: u64,
+    pub stale_close: bool,
+    pub entered_at_unix: i64,
+    pub closed_at_unix: i64,
+    pub slot: u64,
+}
+
+#[event]
+pub struct TraderMarginTierSet {
+    pub admin: Pubkey,
+    pub owner: Pubkey,
+    pub previous_tier: u8,
+    pub margin_tier: u8,
+    pub slot: u64,
+}
+
+#[event]
+pub struct MarketDecimalsWarning {
+    pub pair: String,
+    pub decimals: u8,
+    pub price: u64,
+    pub unit_too_coarse: bool,
+    pub max_size_overflows: bool,
+    pub slot: u64,
+}
+
+#[event]
+pub struct TraderAccountClosed {
+    pub owner: Pubkey,
+    pub token_mint: String,
+    // the balance was the last one, so the `Trader` went with it
+    pub trader_closed: bool,
+    pub slot: u64,
+}
+
+#[event]
+pub struct MarginWarningPenalty {
+    pub keeper: Pubkey,
+    pub owner: Pubkey,
+    pub position_id: u64,
+    pub price: u64,
+    pub health_ratio: u64,
+    pub penalty: u64,
+    pub remaining_collateral: u64,
+    pub slot: u64,
+}
+
+#[event]
+pub struct MarketRetired {
+    pub admin: Pubkey,
+    pub pair: String,
+    // 0 when positions settle at the last known feed price
+    pub settlement_price: u64,
+    pub total_active_positions: u64,
+    pub slot: u64,
+}
+
+#[event]
+pub struct PositionExpired {
+    pub keeper: Pubkey,
+    pub owner: Pubkey,
+    pub pair: String,
+    pub position_id: u64,
+    pub price: u64,
+    pub gross_price_pnl: i64,
+    pub total_funding_paid: i64,
+    pub net_pnl: i64,
+    pub amount_to_return: u64,
+    pub bad_debt: u64,
+    pub slot: u64,
+}
+
+#[event]
+pub struct StrandedPositionSettled {
+    pub admin: Pubkey,
+    pub owner: Pubkey,
+    pub pair: String,
+    pub position_id: u64,
+    pub feed_version_at_open: u32,
+    pub price: u64,
+    pub gross_price_pnl: i64,
+    pub total_funding_paid: i64,
+    pub net_pnl: i64,
+    pub amount_to_return: u64,
+    pub bad_debt: u64,
+    pub slot: u64,
+}
+
+#[event]
+pub struct FeedStatusChanged {
+    pub pair: String,
+    pub changed_by: Pubkey,
+    pub previous_status: FeedStatus,
+    pub feed_status: FeedStatus,
+    pub slot: u64,
+}
+
+#[event]
+pub struct PrivacyEnabled {
+    pub owner: Pubkey,
+    pub fee: u64,
+    pub slot: u64,
+}
+
+#[event]
+pub struct ConfigInitialized {
+    pub admin: Pubkey,
+    pub max_leverage: u64,
+    pub liquidation_fee: u64,
+    pub maintainance_margin: u16,
+    pub opening_fee: u16,
+    pub closing_fee: u16,
+    pub privacy_fee: u16,
+    pub protocol_fee_share: u16,
+    pub slot: u64,
+}
+
+#[event]
+pub struct ConfigUpdated {
+    pub admin: Pubkey,
+    // only the `Some` fields were applied
+    pub changes: UpdateConfigParams,
+    pub slot: u64,
+}
+
+#[event]
+pub struct MarketOpened {
+    pub opened_by: Pubkey,
+    pub pair: String,
+    pub decimals: u8,
+    pub feed_id: String,
+    pub target_band_bps: u64,
+    pub max_leverage: u64,
+    pub maintainance_margin: u16,
+    pub slot: u64,
+}
+
+#[event]
+pub struct MarketUpdated {
+    pub admin: Pubkey,
+    pub pair: String,
+    // `None` fields were left unchanged
+    pub feed_id: Option<String>,
+    pub open_window_start_slot: Option<u64>,
+    pub open_window_end_slot: Option<u64>,
+    pub reduce_only: Option<bool>,
+    pub max_leverage: Option<u64>,
+    pub maintainance_margin: Option<u16>,
+    pub feed_id_version: u32,
+    pub slot: u64,
+}
+
+#[event]
+pub struct DelegateSet {
+    pub owner: Pubkey,
+    pub previous_delegate: Option<Pubkey>,
+    pub delegate: Option<Pubkey>,
+    pub slot: u64,
+}
+
+#[event]
+pub struct AdminProposed {
+    pub admin: Pubkey,
+    // `None` when the proposal was cancelled
+    pub pending_admin: Option<Pubkey>,
+    pub slot: u64,
+}
+
+#[event]
+pub struct AdminTransferred {
+    pub previous_admin: Pubkey,
+    pub admin: Pubkey,
+    pub slot: u64,
+}
+
+#[event]
+pub struct LpFeesCompounded {
+    pub token_mint: String,
+    pub amount: u64,
+    pub lp_deposit: u64,
+    pub slot: u64,
+}
+
+#[event]
+pub struct PoolMintSet {
+    pub admin: Pubkey,
+    pub token_mint: String,
+    pub mint: Pubkey,
+    pub vault_token_account: Pubkey,
+    pub slot: u64,
+}
+
+#[event]
+pub struct BalanceDeposited {
+    pub owner: Pubkey,
+    pub token_mint: String,
+    pub amount: u64,
+    pub balance: u64,
+    pub slot: u64,
+}
+
+#[event]
+pub struct LiquidityAdded {
+    pub provider: Pubkey,
+    pub token_mint: String,
+    pub amount: u64,
+    pub shares: u64,
+    pub total_lp_shares: u64,
+    pub lp_deposit: u64,
+    pub lp_token_deposit: u64,
+    pub slot: u64,
+}
+
+#[event]
+pub struct LiquidityRemoved {
+    pub provider: Pubkey,
+    pub token_mint: String,
+    pub shares: u64,
+    pub amount: u64,
+    pub total_lp_shares: u64,
+    pub lp_deposit: u64,
+    pub lp_token_deposit: u64,
+    pub slot: u64,
+}
@@ -27,3 +27,37 @@ pub struct UpdateConfig<'info> {
     )]
     pub config: Account<'info, Config>,
 }
+
+/// New values for `update_config`, only the `Some` fields are applied
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Default)]
+pub struct UpdateConfigParams {
+    pub max_leverage: Option<u64>,
+    pub liquidation_fee: Option<u64>,
+    pub maintainance_margin: Option<u16>,
+    pub opening_fee: Option<u16>,
+    pub closing_fee: Option<u16>,
+    pub privacy_fee: Option<u16>,
+    pub protocol_fee_share: Option<u16>,
+    pub max_trader_exposure: Option<u64>,
+    pub min_rebalance_profit: Option<u64>,
+    pub tp_execution_reward: Option<u64>,
+    pub max_funding_staleness_slots: Option<u64>,
+    pub leverage_fee_surcharge: Option<u16>,
+    pub max_opening_fee: Option<u16>,
+    pub confidence_reference_leverage: Option<u64>,
+    pub liquidation_rebate: Option<u16>,
+    pub min_funding_interval_slots: Option<u64>,
+    pub max_rebalance_size_change_bps: Option<u64>,
+    pub bad_debt_epoch_slots: Option<u64>,
+    pub max_bad_debt_per_epoch: Option<u64>,
+    pub max_borrow_per_position: Option<u64>,
+    pub max_spot_ema_divergence_bps: Option<u64>,
+    pub max_global_positions: Option<u64>,
+    pub warning_health_bps: Option<u64>,
+    pub warning_penalty_bps: Option<u16>,
+    pub insurance_target_bps: Option<u64>,
+    pub insurance_fee_share_bps: Option<u16>,
+    pub liquidation_backoff_slots: Option<u64>,
+    pub min_effective_collateral: Option<u64>,
+    pub max_net_delta: Option<u64>,
+}
@@ -1,6 +1,9 @@
 use crate::{
-    state::{Config, Market, Position, Trader, TraderPoolDetail, Vault},
-    DISCRIMINATOR,
+    state::{
+        pair_seed_len, Config, InsuranceFund, Market, MarketParamSnapshot, Position, Trader,
+        TraderPoolDetail, Vault,
+    },
+    ErrorCode, DISCRIMINATOR,
 };
 use anchor_lang::prelude::*;
 use pyth_solana_receiver_sdk::price_update::PriceUpdateV2;
@@ -23,6 +26,8 @@ pub struct OpenMarket<'info> {
         bump,
     )]
     pub market: Account<'info, Market>,
+    // optional, only used to sanity check `decimals` against the feed price
+    pub price_update: Option<Account<'info, PriceUpdateV2>>,
     pub system_program: Program<'info, System>,
 }
 
@@ -44,10 +49,166 @@ pub struct UpdateMarket<'info> {
     pub market: Account<'info, Market>,
 }
 
+#[derive(Accounts)]
+#[instruction(pair: String)]
+pub struct FlagFeedSuspect<'info> {
+    #[account(
+        seeds = [b"config"],
+        bump = config.bump
+    )]
+    pub config: Account<'info, Config>,
+    pub signer: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [b"market", pair.as_bytes()],
+        bump = market.bump
+    )]
+    pub market: Account<'info, Market>,
+    pub price_update: Account<'info, PriceUpdateV2>,
+}
+
+#[derive(Accounts)]
+#[instruction(pair: String)]
+pub struct SetFeedStatus<'info> {
+    #[account(
+        seeds = [b"config"],
+        bump = config.bump
+    )]
+    pub config: Account<'info, Config>,
+    pub signer: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [b"market", pair.as_bytes()],
+        bump = market.bump
+    )]
+    pub market: Account<'info, Market>,
+}
+
+#[derive(Accounts)]
+#[instruction(pair: String)]
+pub struct RetireMarket<'info> {
+    #[account(
+        seeds = [b"config"],
+        bump = config.bump
+    )]
+    pub config: Account<'info, Config>,
+    pub signer: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [b"market", pair.as_bytes()],
+        bump = market.bump
+    )]
+    pub market: Account<'info, Market>,
+}
+
+#[derive(Accounts)]
+#[instruction(pair: String)]
+pub struct SetFundingRate<'info> {
+    #[account(
+        seeds = [b"config"],
+        bump = config.bump
+    )]
+    pub config: Account<'info, Config>,
+    pub signer: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [b"market", pair.as_bytes()],
+        bump = market.bump
+    )]
+    pub market: Account<'info, Market>,
+}
+
+#[derive(Accounts)]
+#[instruction(pair: String)]
+pub struct SettleMarketFunding<'info> {
+    #[account(
+        seeds = [b"config"],
+        bump = config.bump
+    )]
+    pub config: Account<'info, Config>,
+    #[account(
+        seeds = [b"market", pair.as_bytes()],
+        bump = market.bump
+    )]
+    pub market: Account<'info, Market>,
+    pub price_update: Account<'info, PriceUpdateV2>,
+}
+
+#[derive(Accounts)]
+pub struct PauseMarketsByFeed<'info> {
+    #[account(
+        seeds = [b"config"],
+        bump = config.bump
+    )]
+    pub config: Account<'info, Config>,
+    pub signer: Signer<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(pair: String)]
+pub struct PauseMarket<'info> {
+    #[account(
+        seeds = [b"config"],
+        bump = config.bump
+    )]
+    pub config: Account<'info, Config>,
+    pub signer: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [b"market", pair.as_bytes()],
+        bump = market.bump
+    )]
+    pub market: Account<'info, Market>,
+}
+
+#[derive(Accounts)]
+#[instruction(pair: String)]
+pub struct UnpauseMarket<'info> {
+    #[account(
+        seeds = [b"config"],
+        bump = config.bump
+    )]
+    pub config: Account<'info, Config>,
+    pub signer: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [b"market", pair.as_bytes()],
+        bump = market.bump
+    )]
+    pub market: Account<'info, Market>,
+}
+
+#[derive(Accounts)]
+#[instruction(pair: String, epoch: u64)]
+pub struct SnapshotMarketParams<'info> {
+    #[account(
+        seeds = [b"config"],
+        bump = config.bump
+    )]
+    pub config: Account<'info, Config>,
+    #[account(mut)]
+    pub signer: Signer<'info>,
+    #[account(
+        seeds = [b"market", pair.as_bytes()],
+        bump = market.bump
+    )]
+    pub market: Account<'info, Market>,
+    #[account(
+        init,
+        payer = signer,
+        space = DISCRIMINATOR + MarketParamSnapshot::INIT_SPACE,
+        seeds = [b"market_snapshot", pair.as_bytes(), epoch.to_le_bytes().as_ref()],
+        bump,
+    )]
+    pub snapshot: Account<'info, MarketParamSnapshot>,
+    pub system_program: Program<'info, System>,
+}
+
 #[derive(Accounts)]
 #[instruction(token_mint: String, pair: String, position_id: u64)]
 pub struct OpenPosition<'info> {
     #[account(
+        mut,
         seeds = [b"config"],
         bump = config.bump
     )]
@@ -72,6 +233,12 @@ pub struct OpenPosition<'info> {
         bump = pool.bump
     )]
     pub pool: Account<'info, Vault>,
+    #[account(
+        mut,
+        seeds = [b"insurance", token_mint.as_bytes()],
+        bump = insurance_fund.bump
+    )]
+    pub insurance_fund: Account<'info, InsuranceFund>,
     #[account(
         mut,
         seeds = [b"market", pair.as_bytes()],
@@ -82,7 +249,7 @@ pub struct OpenPosition<'info> {
         init,
         payer = signer,
         space = DISCRIMINATOR + Position::INIT_SPACE,
-        seeds = [b"position", pair.as_bytes(), signer.key().as_ref(), position_id.to_le_bytes().as_ref()],
+        seeds = [b"position", pair_seed_len(&pair).as_ref(), pair.as_bytes(), signer.key().as_ref(), position_id.to_le_bytes().as_ref()],
         bump
     )]
     pub position: Account<'info, Position>,
@@ -93,6 +260,11 @@ pub struct OpenPosition<'info> {
 #[derive(Accounts)]
 #[instruction(token_mint: String, pair: String, position_id: u64)]
 pub struct UpdatePosition<'info> {
+    #[account(
+        seeds = [b"config"],
+        bump = config.bump
+    )]
+    pub config: Account<'info, Config>,
     #[account(mut)]
     pub signer: Signer<'info>,
     #[account(
@@ -121,7 +293,7 @@ pub struct UpdatePosition<'info> {
     pub market: Account<'info, Market>,
     #[account(
         mut,
-        seeds = [b"position", pair.as_bytes(), signer.key().as_ref(), position_id.to_le_bytes().as_ref()],
+        seeds = [b"position", pair_seed_len(&pair).as_ref(), pair.as_bytes(), signer.key().as_ref(), position_id.to_le_bytes().as_ref()],
         bump
     )]
     pub position: Account<'info, Position>,
@@ -129,24 +301,29 @@ pub struct UpdatePosition<'info> {
 }
 
 #[derive(Accounts)]
-#[instruction(token_mint: String, pair: String, position_id: u64)]
+#[instruction(token_mint: String, pair: String, owner: Pubkey, position_id: u64)]
 pub struct ClosePosition<'info> {
     #[account(
+        mut,
         seeds = [b"config"],
         bump = config.bump
     )]
     pub config: Account<'info, Config>,
+    // the owner or their delegate, see `Trader::can_manage`
     #[account(mut)]
     pub signer: Signer<'info>,
+    // receives the position's rent
+    #[account(mut, address = owner @ ErrorCode::Unauthorized)]
+    pub owner_account: SystemAccount<'info>,
     #[account(
         mut,
-        seeds = [b"trader", signer.key().as_ref()],
+        seeds = [b"trader", owner.as_ref()],
         bump = trader.bump
     )]
     pub trader: Account<'info, Trader>,
     #[account(
         mut,
-        seeds = [b"trader_balance", signer.key().as_ref(), token_mint.as_bytes()],
+        seeds = [b"trader_balance", owner.as_ref(), token_mint.as_bytes()],
         bump = trader_balance.bump
     )]
     pub trader_balance: Account<'info, TraderPoolDetail>,
@@ -156,6 +333,12 @@ pub struct ClosePosition<'info> {
         bump = pool.bump
     )]
     pub pool: Account<'info, Vault>,
+    #[account(
+        mut,
+        seeds = [b"insurance", token_mint.as_bytes()],
+        bump = insurance_fund.bump
+    )]
+    pub insurance_fund: Account<'info, InsuranceFund>,
     #[account(
         mut,
         seeds = [b"market", pair.as_bytes()],
@@ -164,8 +347,8 @@ pub struct ClosePosition<'info> {
     pub market: Account<'info, Market>,
     #[account(
         mut,
-        close = signer,
-        seeds = [b"position", pair.as_bytes(), signer.key().as_ref(), position_id.to_le_bytes().as_ref()],
+        close = owner_account,
+        seeds = [b"position", pair_seed_len(&pair).as_ref(), pair.as_bytes(), owner.as_ref(), position_id.to_le_bytes().as_ref()],
         bump
     )]
     pub position: Account<'info, Position>,
@@ -174,8 +357,19 @@ pub struct ClosePosition<'info> {
 
 #[derive(Accounts)]
 #[instruction(token_mint: String, pair: String, owner: Pubkey, position_id: u64)]
-pub struct RebalanceOrLiquidatePosition<'info> {
+pub struct ForceExpirePosition<'info> {
+    #[account(
+        mut,
+        seeds = [b"config"],
+        bump = config.bump
+    )]
+    pub config: Account<'info, Config>,
+    // any keeper
+    #[account(mut)]
     pub signer: Signer<'info>,
+    // receives the position's rent
+    #[account(mut, address = owner @ ErrorCode::Unauthorized)]
+    pub owner_account: SystemAccount<'info>,
     #[account(
         mut,
         seeds = [b"trader", owner.as_ref()],
@@ -194,6 +388,12 @@ pub struct RebalanceOrLiquidatePosition<'info> {
         bump = pool.bump
     )]
     pub pool: Account<'info, Vault>,
+    #[account(
+        mut,
+        seeds = [b"insurance", token_mint.as_bytes()],
+        bump = insurance_fund.bump
+    )]
+    pub insurance_fund: Account<'info, InsuranceFund>,
     #[account(
         mut,
         seeds = [b"market", pair.as_bytes()],
@@ -202,10 +402,552 @@ pub struct RebalanceOrLiquidatePosition<'info> {
     pub market: Account<'info, Market>,
     #[account(
         mut,
-        close = signer,
-        seeds = [b"position", pair.as_bytes(), owner.as_ref(), position_id.to_le_bytes().as_ref()],
+        close = owner_account,
+        seeds = [b"position", pair_seed_len(&pair).as_ref(), pair.as_bytes(), owner.as_ref(), position_id.to_le_bytes().as_ref()],
+        bump
+    )]
+    pub position: Account<'info, Position>,
+}
+
+#[derive(Accounts)]
+#[instruction(token_mint: String, pair: String, owner: Pubkey, position_id: u64)]
+pub struct SettleStrandedPosition<'info> {
+    #[account(
+        mut,
+        seeds = [b"config"],
+        bump = config.bump
+    )]
+    pub config: Account<'info, Config>,
+    // admin
+    pub signer: Signer<'info>,
+    // receives the position's rent
+    #[account(mut, address = owner @ ErrorCode::Unauthorized)]
+    pub owner_account: SystemAccount<'info>,
+    #[account(
+        mut,
+        seeds = [b"trader", owner.as_ref()],
+        bump = trader.bump
+    )]
+    pub trader: Account<'info, Trader>,
+    #[account(
+        mut,
+        seeds = [b"trader_balance", owner.as_ref(), token_mint.as_bytes()],
+        bump = trader_balance.bump
+    )]
+    pub trader_balance: Account<'info, TraderPoolDetail>,
+    #[account(
+        mut,
+        seeds = [b"vault", token_mint.as_bytes()],
+        bump = pool.bump
+    )]
+    pub pool: Account<'info, Vault>,
+    #[account(
+        mut,
+        seeds = [b"insurance", token_mint.as_bytes()],
+        bump = insurance_fund.bump
+    )]
+    pub insurance_fund: Account<'info, InsuranceFund>,
+    #[account(
+        mut,
+        seeds = [b"market", pair.as_bytes()],
+        bump = market.bump
+    )]
+    pub market: Account<'info, Market>,
+    #[account(
+        mut,
+        close = owner_account,
+        seeds = [b"position", pair_seed_len(&pair).as_ref(), pair.as_bytes(), owner.as_ref(), position_id.to_le_bytes().as_ref()],
         bump
     )]
     pub position: Account<'info, Position>,
+}
+
+#[derive(Accounts)]
+#[instruction(token_mint: String, pair: String, position_id: u64)]
+pub struct PartialClosePosition<'info> {
+    #[account(
+        seeds = [b"config"],
+        bump = config.bump
+    )]
+    pub config: Account<'info, Config>,
+    pub signer: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [b"trader", signer.key().as_ref()],
+        bump = trader.bump
+    )]
+    pub trader: Account<'info, Trader>,
+    #[account(
+        mut,
+        seeds = [b"trader_balance", signer.key().as_ref(), token_mint.as_bytes()],
+        bump = trader_balance.bump
+    )]
+    pub trader_balance: Account<'info, TraderPoolDetail>,
+    #[account(
+        mut,
+        seeds = [b"vault", token_mint.as_bytes()],
+        bump = pool.bump
+    )]
+    pub pool: Account<'info, Vault>,
+    #[account(
+        mut,
+        seeds = [b"insurance", token_mint.as_bytes()],
+        bump = insurance_fund.bump
+    )]
+    pub insurance_fund: Account<'info, InsuranceFund>,
+    #[account(
+        mut,
+        seeds = [b"market", pair.as_bytes()],
+        bump = market.bump
+    )]
+    pub market: Account<'info, Market>,
+    #[account(
+        mut,
+        seeds = [b"position", pair_seed_len(&pair).as_ref(), pair.as_bytes(), signer.key().as_ref(), position_id.to_le_bytes().as_ref()],
+        bump = position.bump
+    )]
+    pub position: Account<'info, Position>,
+    pub price_update: Account<'info, PriceUpdateV2>,
+}
+
+#[derive(Accounts)]
+#[instruction(token_mint: String, pair: String, position_id: u64)]
+pub struct AddCollateral<'info> {
+    #[account(
+        seeds = [b"config"],
+        bump = config.bump
+    )]
+    pub config: Account<'info, Config>,
+    pub signer: Signer<'info>,
+    #[account(
+        seeds = [b"trader", signer.key().as_ref()],
+        bump = trader.bump
+    )]
+    pub trader: Account<'info, Trader>,
+    #[account(
+        mut,
+        seeds = [b"trader_balance", signer.key().as_ref(), token_mint.as_bytes()],
+        bump = trader_balance.bump
+    )]
+    pub trader_balance: Account<'info, TraderPoolDetail>,
+    #[account(
+        mut,
+        seeds = [b"vault", token_mint.as_bytes()],
+        bump = pool.bump
+    )]
+    pub pool: Account<'info, Vault>,
+    #[account(
+        mut,
+        seeds = [b"position", pair_seed_len(&pair).as_ref(), pair.as_bytes(), signer.key().as_ref(), position_id.to_le_bytes().as_ref()],
+        bump = position.bump,
+        constraint = position.token_mint == token_mint @ ErrorCode::InvalidMint
+    )]
+    pub position: Account<'info, Position>,
+}
+
+#[derive(Accounts)]
+#[instruction(token_mint: String, pair: String, position_id: u64)]
+pub struct RemoveCollateral<'info> {
+    #[account(
+        seeds = [b"config"],
+        bump = config.bump
+    )]
+    pub config: Account<'info, Config>,
+    pub signer: Signer<'info>,
+    #[account(
+        seeds = [b"trader", signer.key().as_ref()],
+        bump = trader.bump
+    )]
+    pub trader: Account<'info, Trader>,
+    #[account(
+        mut,
+        seeds = [b"trader_balance", signer.key().as_ref(), token_mint.as_bytes()],
+        bump = trader_balance.bump
+    )]
+    pub trader_balance: Account<'info, TraderPoolDetail>,
+    #[account(
+        mut,
+        seeds = [b"vault", token_mint.as_bytes()],
+        bump = pool.bump
+    )]
+    pub pool: Account<'info, Vault>,
+    #[account(
+        seeds = [b"market", pair.as_bytes()],
+        bump = market.bump
+    )]
+    pub market: Account<'info, Market>,
+    #[account(
+        mut,
+        seeds = [b"position", pair_seed_len(&pair).as_ref(), pair.as_bytes(), signer.key().as_ref(), position_id.to_le_bytes().as_ref()],
+        bump = position.bump,
+        constraint = position.token_mint == token_mint @ ErrorCode::InvalidMint
+    )]
+    pub position: Account<'info, Position>,
+    pub price_update: Account<'info, PriceUpdateV2>,
+}
+
+#[derive(Accounts)]
+#[instruction(pair: String, position_id: u64, new_position_id: u64)]
+pub struct SplitPosition<'info> {
+    #[account(
+        mut,
+        seeds = [b"config"],
+        bump = config.bump
+    )]
+    pub config: Account<'info, Config>,
+    #[account(mut)]
+    pub signer: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [b"trader", signer.key().as_ref()],
+        bump = trader.bump
+    )]
+    pub trader: Account<'info, Trader>,
+    #[account(
+        mut,
+        seeds = [b"market", pair.as_bytes()],
+        bump = market.bump
+    )]
+    pub market: Account<'info, Market>,
+    #[account(
+        mut,
+        seeds = [b"position", pair_seed_len(&pair).as_ref(), pair.as_bytes(), signer.key().as_ref(), position_id.to_le_bytes().as_ref()],
+        bump = position.bump
+    )]
+    pub position: Account<'info, Position>,
+    #[account(
+        init,
+        payer = signer,
+        space = DISCRIMINATOR + Position::INIT_SPACE,
+        seeds = [b"position", pair_seed_len(&pair).as_ref(), pair.as_bytes(), signer.key().as_ref(), new_position_id.to_le_bytes().as_ref()],
+        bump
+    )]
+    pub new_position: Account<'info, Position>,
+    pub price_update: Account<'info, PriceUpdateV2>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(pair: String, position_id: u64, other_position_id: u64)]
+pub struct MergePositions<'info> {
+    #[account(
+        mut,
+        seeds = [b"config"],
+        bump = config.bump
+    )]
+    pub config: Account<'info, Config>,
+    #[account(mut)]
+    pub signer: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [b"trader", signer.key().as_ref()],
+        bump = trader.bump
+    )]
+    pub trader: Account<'info, Trader>,
+    #[account(
+        mut,
+        seeds = [b"market", pair.as_bytes()],
+        bump = market.bump
+    )]
+    pub market: Account<'info, Market>,
+    #[account(
+        mut,
+        seeds = [b"position", pair_seed_len(&pair).as_ref(), pair.as_bytes(), signer.key().as_ref(), position_id.to_le_bytes().as_ref()],
+        bump = position.bump
+    )]
+    pub position: Account<'info, Position>,
+    #[account(
+        mut,
+        close = signer,
+        seeds = [b"position", pair_seed_len(&pair).as_ref(), pair.as_bytes(), signer.key().as_ref(), other_position_id.to_le_bytes().as_ref()],
+        bump = other_position.bump
+    )]
+    pub other_position: Account<'info, Position>,
+    pub price_update: Account<'info, PriceUpdateV2>,
+}
+
+#[derive(Accounts)]
+#[instruction(pair: String, owner: Pubkey, position_id: u64)]
+pub struct GetPositionStatus<'info> {
+    #[account(
+        seeds = [b"config"],
+        bump = config.bump
+    )]
+    pub config: Account<'info, Config>,
+    #[account(
+        seeds = [b"market", pair.as_bytes()],
+        bump = market.bump
+    )]
+    pub market: Account<'info, Market>,
+    #[account(
+        seeds = [b"trader", owner.as_ref()],
+        bump = trader.bump
+    )]
+    pub trader: Account<'info, Trader>,
+    #[account(
+        seeds = [b"position", pair_seed_len(&pair).as_ref(), pair.as_bytes(), owner.as_ref(), position_id.to_le_bytes().as_ref()],
+        bump = position.bump
+    )]
+    pub position: Account<'info, Position>,
+    pub price_update: Account<'info, PriceUpdateV2>,
+}
+
+#[derive(Accounts)]
+#[instruction(pair: String, owner: Pubkey, position_id: u64)]
+pub struct GetLiquidationContext<'info> {
+    #[account(
+        seeds = [b"config"],
+        bump = config.bump
+    )]
+    pub config: Account<'info, Config>,
+    #[account(
+        seeds = [b"market", pair.as_bytes()],
+        bump = market.bump
+    )]
+    pub market: Account<'info, Market>,
+    #[account(
+        seeds = [b"trader", owner.as_ref()],
+        bump = trader.bump
+    )]
+    pub trader: Account<'info, Trader>,
+    #[account(
+        seeds = [b"position", pair_seed_len(&pair).as_ref(), pair.as_bytes(), owner.as_ref(), position_id.to_le_bytes().as_ref()],
+        bump = position.bump
+    )]
+    pub position: Account<'info, Position>,
+    pub price_update: Account<'info, PriceUpdateV2>,
+}
+
+#[derive(Accounts)]
+#[instruction(pair: String)]
+pub struct GetFundingRate<'info> {
+    #[account(
+        seeds = [b"market", pair.as_bytes()],
+        bump = market.bump
+    )]
+    pub market: Account<'info, Market>,
+}
+
+#[derive(Accounts)]
+#[instruction(pair: String)]
+pub struct GetCollateralForLeverage<'info> {
+    #[account(
+        seeds = [b"config"],
+        bump = config.bump
+    )]
+    pub config: Account<'info, Config>,
+    #[account(
+        seeds = [b"market", pair.as_bytes()],
+        bump = market.bump
+    )]
+    pub market: Account<'info, Market>,
+    pub price_update: Account<'info, PriceUpdateV2>,
+}
+
+#[derive(Accounts)]
+#[instruction(token_mint: String, pair: String, owner: Pubkey)]
+pub struct GetMaxPosition<'info> {
+    #[account(
+        seeds = [b"config"],
+        bump = config.bump
+    )]
+    pub config: Account<'info, Config>,
+    #[account(
+        seeds = [b"trader", owner.as_ref()],
+        bump = trader.bump
+    )]
+    pub trader: Account<'info, Trader>,
+    #[account(
+        seeds = [b"trader_balance", owner.as_ref(), token_mint.as_bytes()],
+        bump = trader_balance.bump
+    )]
+    pub trader_balance: Account<'info, TraderPoolDetail>,
+    #[account(
+        seeds = [b"vault", token_mint.as_bytes()],
+        bump = pool.bump
+    )]
+    pub pool: Account<'info, Vault>,
+    #[account(
+        seeds = [b"market", pair.as_bytes()],
+        bump = market.bump
+    )]
+    pub market: Account<'info, Market>,
+    pub price_update: Account<'info, PriceUpdateV2>,
+}
+
+#[derive(Accounts)]
+#[instruction(pair: String, owner: Pubkey, position_id: u64)]
+pub struct SetPositionTriggers<'info> {
+    // the owner or their delegate, see `Trader::can_manage`
+    pub signer: Signer<'info>,
+    #[account(
+        seeds = [b"trader", owner.as_ref()],
+        bump = trader.bump
+    )]
+    pub trader: Account<'info, Trader>,
+    #[account(
+        mut,
+        seeds = [b"position", pair_seed_len(&pair).as_ref(), pair.as_bytes(), owner.as_ref(), position_id.to_le_bytes().as_ref()],
+        bump = position.bump
+    )]
+    pub position: Account<'info, Position>,
+}
+
+#[derive(Accounts)]
+#[instruction(token_mint: String, pair: String, owner: Pubkey, position_id: u64)]
+pub struct RebalanceOrLiquidatePosition<'info> {
+    #[account(
+        mut,
+        seeds = [b"config"],
+        bump = config.bump
+    )]
+    pub config: Account<'info, Config>,
+    #[account(mut)]
+    pub signer: Signer<'info>,
+    // receives the position's rent when it is liquidated or taken profit on
+    #[account(mut, address = owner @ ErrorCode::Unauthorized)]
+    pub owner_account: SystemAccount<'info>,
+    #[account(
+        mut,
+        seeds = [b"trader", owner.as_ref()],
+        bump = trader.bump
+    )]
+    pub trader: Account<'info, Trader>,
+    #[account(
+        mut,
+        seeds = [b"trader_balance", owner.as_ref(), token_mint.as_bytes()],
+        bump = trader_balance.bump
+    )]
+    pub trader_balance: Account<'info, TraderPoolDetail>,
+    #[account(
+        mut,
+        seeds = [b"vault", token_mint.as_bytes()],
+        bump = pool.bump
+    )]
+    pub pool: Account<'info, Vault>,
+    #[account(
+        mut,
+        seeds = [b"insurance", token_mint.as_bytes()],
+        bump = insurance_fund.bump
+    )]
+    pub insurance_fund: Account<'info, InsuranceFund>,
+    #[account(
+        mut,
+        seeds = [b"market", pair.as_bytes()],
+        bump = market.bump
+    )]
+    pub market: Account<'info, Market>,
+    /// CHECK: may already be closed by a competing keeper, loaded in the handler with
+    /// `Position::load_open` and closed manually when liquidated or taken profit on
+    #[account(
+        mut,
+        seeds = [b"position", pair_seed_len(&pair).as_ref(), pair.as_bytes(), owner.as_ref(), position_id.to_le_bytes().as_ref()],
+        bump
+    )]
+    pub position: UncheckedAccount<'info>,
+    pub price_update: Account<'info, PriceUpdateV2>,
+    // the signer's balance the keeper reward is credited to, omitted when the signer
+    // is the owner
+    #[account(
+        mut,
+        seeds = [b"trader_balance", signer.key().as_ref(), token_mint.as_bytes()],
+        bump = keeper_balance.bump
+    )]
+    pub keeper_balance: Option<Account<'info, TraderPoolDetail>>,
+}
+
+#[derive(Accounts)]
+#[instruction(token_mint: String, pair: String, owner: Pubkey, position_id: u64)]
+pub struct AdminSeizePosition<'info> {
+    #[account(
+        mut,
+        seeds = [b"config"],
+        bump = config.bump
+    )]
+    pub config: Account<'info, Config>,
+    pub signer: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [b"trader", owner.as_ref()],
+        bump = trader.bump
+    )]
+    pub trader: Account<'info, Trader>,
+    #[account(
+        mut,
+        seeds = [b"trader_balance", owner.as_ref(), token_mint.as_bytes()],
+        bump = trader_balance.bump
+    )]
+    pub trader_balance: Account<'info, TraderPoolDetail>,
+    #[account(
+        mut,
+        seeds = [b"vault", token_mint.as_bytes()],
+        bump = pool.bump
+    )]
+    pub pool: Account<'info, Vault>,
+    #[account(
+        mut,
+        seeds = [b"insurance", token_mint.as_bytes()],
+        bump = insurance_fund.bump
+    )]
+    pub insurance_fund: Account<'info, InsuranceFund>,
+    #[account(
+        mut,
+        seeds = [b"market", pair.as_bytes()],
+        bump = market.bump
+    )]
+    pub market: Account<'info, Market>,
+    #[account(
+        mut,
+        seeds = [b"position", pair_seed_len(&pair).as_ref(), pair.as_bytes(), owner.as_ref(), position_id.to_le_bytes().as_ref()],
+        bump = position.bump
+    )]
+    pub position: Account<'info, Position>,
+}
+
+#[derive(Accounts)]
+#[instruction(token_mint: String, pair: String, owner: Pubkey, position_id: u64)]
+pub struct AdminReducePosition<'info> {
+    #[account(
+        seeds = [b"config"],
+        bump = config.bump
+    )]
+    pub config: Account<'info, Config>,
+    pub signer: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [b"trader", owner.as_ref()],
+        bump = trader.bump
+    )]
+    pub trader: Account<'info, Trader>,
+    #[account(
+        mut,
+        seeds = [b"trader_balance", owner.as_ref(), token_mint.as_bytes()],
+        bump = trader_balance.bump
+    )]
+    pub trader_balance: Account<'info, TraderPoolDetail>,
+    #[account(
+        mut,
+        seeds = [b"vault", token_mint.as_bytes()],
+        bump = pool.bump
+    )]
+    pub pool: Account<'info, Vault>,
+    #[account(
+        mut,
+        seeds = [b"insurance", token_mint.as_bytes()],
+        bump = insurance_fund.bump
+    )]
+    pub insurance_fund: Account<'info, InsuranceFund>,
+    #[account(
+        mut,
+        seeds = [b"market", pair.as_bytes()],
+        bump = market.bump
+    )]
+    pub market: Account<'info, Market>,
+    #[account(
+        mut,
+        seeds = [b"position", pair_seed_len(&pair).as_ref(), pair.as_bytes(), owner.as_ref(), position_id.to_le_bytes().as_ref()],
+        bump = position.bump
+    )]
+    pub position: Account<'info, Position>,
     pub price_update: Account<'info, PriceUpdateV2>,
 }
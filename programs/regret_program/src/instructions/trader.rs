@@ -1,8 +1,9 @@
 use anchor_lang::prelude::*;
+use anchor_spl::token::{Mint, Token, TokenAccount};
 
 use crate::{
-    state::{Config, Trader, TraderPoolDetail, Vault},
-    DISCRIMINATOR,
+    state::{Config, InsuranceFund, Trader, TraderPoolDetail, Vault},
+    ErrorCode, DISCRIMINATOR,
 };
 
 #[derive(Accounts)]
@@ -39,6 +40,38 @@ pub struct Register<'info> {
     pub system_program: Program<'info, System>,
 }
 
+#[derive(Accounts)]
+#[instruction(token_mint: String)]
+pub struct OpenTraderBalance<'info> {
+    #[account(
+        seeds = [b"config"],
+        bump = config.bump,
+    )]
+    pub config: Account<'info, Config>,
+    #[account(mut)]
+    pub signer: Signer<'info>,
+    #[account(
+        seeds = [b"vault", token_mint.as_bytes()],
+        bump = pool.bump
+    )]
+    pub pool: Account<'info, Vault>,
+    #[account(
+        mut,
+        seeds = [b"trader", signer.key().as_ref()],
+        bump = trader.bump
+    )]
+    pub trader: Account<'info, Trader>,
+    #[account(
+        init,
+        payer = signer,
+        space = DISCRIMINATOR + TraderPoolDetail::INIT_SPACE,
+        seeds = [b"trader_balance", signer.key().as_ref(), token_mint.as_bytes()],
+        bump
+    )]
+    pub trader_balance: Account<'info, TraderPoolDetail>,
+    pub system_program: Program<'info, System>,
+}
+
 #[derive(Accounts)]
 #[instruction(token_mint: String)]
 pub struct ClaimVirtualBalance<'info> {
@@ -61,3 +94,203 @@ pub struct ClaimVirtualBalance<'info> {
     )]
     pub trader_balance: Account<'info, TraderPoolDetail>,
 }
+
+#[derive(Accounts)]
+#[instruction(token_mint: String)]
+pub struct BurnVirtualBalance<'info> {
+    #[account(
+        seeds = [b"config"],
+        bump = config.bump,
+    )]
+    pub config: Account<'info, Config>,
+    #[account(mut)]
+    pub signer: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [b"trader_balance", signer.key().as_ref(), token_mint.as_bytes()],
+        bump = trader_balance.bump
+    )]
+    pub trader_balance: Account<'info, TraderPoolDetail>,
+}
+
+#[derive(Accounts)]
+#[instruction(token_mint: String)]
+pub struct Deposit<'info> {
+    #[account(
+        seeds = [b"config"],
+        bump = config.bump,
+    )]
+    pub config: Account<'info, Config>,
+    pub signer: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [b"trader_balance", signer.key().as_ref(), token_mint.as_bytes()],
+        bump = trader_balance.bump
+    )]
+    pub trader_balance: Account<'info, TraderPoolDetail>,
+    #[account(
+        mut,
+        seeds = [b"vault", token_mint.as_bytes()],
+        bump = pool.bump,
+        constraint = pool.mint == mint.key() @ ErrorCode::InvalidMint
+    )]
+    pub pool: Account<'info, Vault>,
+    pub mint: Account<'info, Mint>,
+    #[account(
+        mut,
+        associated_token::mint = mint,
+        associated_token::authority = signer,
+    )]
+    pub trader_token_account: Account<'info, TokenAccount>,
+    #[account(
+        mut,
+        associated_token::mint = mint,
+        associated_token::authority = pool,
+    )]
+    pub vault_token_account: Account<'info, TokenAccount>,
+    pub token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+#[instruction(token_mint: String)]
+pub struct Withdraw<'info> {
+    #[account(
+        seeds = [b"config"],
+        bump = config.bump,
+    )]
+    pub config: Account<'info, Config>,
+    pub signer: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [b"trader_balance", signer.key().as_ref(), token_mint.as_bytes()],
+        bump = trader_balance.bump
+    )]
+    pub trader_balance: Account<'info, TraderPoolDetail>,
+    #[account(
+        mut,
+        seeds = [b"vault", token_mint.as_bytes()],
+        bump = pool.bump,
+        constraint = pool.mint == mint.key() @ ErrorCode::InvalidMint
+    )]
+    pub pool: Account<'info, Vault>,
+    pub mint: Account<'info, Mint>,
+    #[account(
+        mut,
+        associated_token::mint = mint,
+        associated_token::authority = signer,
+    )]
+    pub trader_token_account: Account<'info, TokenAccount>,
+    #[account(
+        mut,
+        associated_token::mint = mint,
+        associated_token::authority = pool,
+    )]
+    pub vault_token_account: Account<'info, TokenAccount>,
+    pub token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+#[instruction(token_mint: String, owner: Pubkey)]
+pub struct GetTraderInfo<'info> {
+    /// CHECK: may not exist yet, loaded in the handler only when initialized
+    #[account(
+        seeds = [b"trader", owner.as_ref()],
+        bump
+    )]
+    pub trader: UncheckedAccount<'info>,
+    /// CHECK: may not exist yet, loaded in the handler only when initialized
+    #[account(
+        seeds = [b"trader_balance", owner.as_ref(), token_mint.as_bytes()],
+        bump
+    )]
+    pub trader_balance: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(owner: Pubkey)]
+pub struct SetTraderMarginTier<'info> {
+    #[account(
+        seeds = [b"config"],
+        bump = config.bump,
+    )]
+    pub config: Account<'info, Config>,
+    pub signer: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [b"trader", owner.as_ref()],
+        bump = trader.bump
+    )]
+    pub trader: Account<'info, Trader>,
+}
+
+#[derive(Accounts)]
+pub struct SetDelegate<'info> {
+    pub signer: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [b"trader", signer.key().as_ref()],
+        bump = trader.bump
+    )]
+    pub trader: Account<'info, Trader>,
+}
+
+#[derive(Accounts)]
+#[instruction(token_mint: String)]
+pub struct EnablePrivacy<'info> {
+    #[account(
+        seeds = [b"config"],
+        bump = config.bump,
+    )]
+    pub config: Account<'info, Config>,
+    pub signer: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [b"trader", signer.key().as_ref()],
+        bump = trader.bump
+    )]
+    pub trader: Account<'info, Trader>,
+    #[account(
+        mut,
+        seeds = [b"trader_balance", signer.key().as_ref(), token_mint.as_bytes()],
+        bump = trader_balance.bump
+    )]
+    pub trader_balance: Account<'info, TraderPoolDetail>,
+    #[account(
+        mut,
+        seeds = [b"vault", token_mint.as_bytes()],
+        bump = pool.bump
+    )]
+    pub pool: Account<'info, Vault>,
+    #[account(
+        mut,
+        seeds = [b"insurance", token_mint.as_bytes()],
+        bump = insurance_fund.bump
+    )]
+    pub insurance_fund: Account<'info, InsuranceFund>,
+}
+
+#[derive(Accounts)]
+#[instruction(token_mint: String)]
+pub struct CloseTraderAccount<'info> {
+    #[account(
+        seeds = [b"config"],
+        bump = config.bump,
+    )]
+    pub config: Account<'info, Config>,
+    #[account(mut)]
+    pub signer: Signer<'info>,
+    // closed by the handler along with the last balance account
+    #[account(
+        mut,
+        seeds = [b"trader", signer.key().as_ref()],
+        bump = trader.bump
+    )]
+    pub trader: Account<'info, Trader>,
+    #[account(
+        mut,
+        close = signer,
+        seeds = [b"trader_balance", signer.key().as_ref(), token_mint.as_bytes()],
+        bump = trader_balance.bump
+    )]
+    pub trader_balance: Account<'info, TraderPoolDetail>,
+}
@@ -1,8 +1,12 @@
 use anchor_lang::prelude::*;
+use anchor_spl::{
+    associated_token::AssociatedToken,
+    token::{Mint, Token, TokenAccount},
+};
 
 use crate::{
-    state::{Config, Vault},
-    DISCRIMINATOR,
+    state::{Config, InsuranceFund, LpProvider, Vault},
+    ErrorCode, DISCRIMINATOR,
 };
 
 #[derive(Accounts)]
@@ -26,6 +30,33 @@ pub struct CreatePool<'info> {
     pub system_program: Program<'info, System>,
 }
 
+#[derive(Accounts)]
+#[instruction(token_mint: String)]
+pub struct CreateInsuranceFund<'info> {
+    #[account(
+        seeds = [b"config"],
+        bump = config.bump
+    )]
+    pub config: Account<'info, Config>,
+    #[account(mut)]
+    pub signer: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [b"vault", token_mint.as_bytes()],
+        bump = pool.bump
+    )]
+    pub pool: Account<'info, Vault>,
+    #[account(
+        init,
+        payer = signer,
+        space = DISCRIMINATOR + InsuranceFund::INIT_SPACE,
+        seeds = [b"insurance", token_mint.as_bytes()],
+        bump
+    )]
+    pub insurance_fund: Account<'info, InsuranceFund>,
+    pub system_program: Program<'info, System>,
+}
+
 #[derive(Accounts)]
 #[instruction(token_mint: String)]
 pub struct FundPool<'info> {
@@ -43,3 +74,139 @@ pub struct FundPool<'info> {
     )]
     pub pool: Account<'info, Vault>,
 }
+
+#[derive(Accounts)]
+#[instruction(token_mint: String)]
+pub struct SetPoolMint<'info> {
+    #[account(
+        seeds = [b"config"],
+        bump = config.bump
+    )]
+    pub config: Account<'info, Config>,
+    #[account(mut)]
+    pub signer: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [b"vault", token_mint.as_bytes()],
+        bump = pool.bump
+    )]
+    pub pool: Account<'info, Vault>,
+    pub mint: Account<'info, Mint>,
+    #[account(
+        init,
+        payer = signer,
+        associated_token::mint = mint,
+        associated_token::authority = pool,
+    )]
+    pub vault_token_account: Account<'info, TokenAccount>,
+    pub token_program: Program<'info, Token>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(token_mint: String)]
+pub struct AddLiquidity<'info> {
+    #[account(
+        seeds = [b"config"],
+        bump = config.bump
+    )]
+    pub config: Account<'info, Config>,
+    #[account(mut)]
+    pub signer: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [b"vault", token_mint.as_bytes()],
+        bump = pool.bump,
+        constraint = pool.mint == mint.key() @ ErrorCode::InvalidMint
+    )]
+    pub pool: Account<'info, Vault>,
+    #[account(
+        init_if_needed,
+        payer = signer,
+        space = DISCRIMINATOR + LpProvider::INIT_SPACE,
+        seeds = [b"lp", pool.key().as_ref(), signer.key().as_ref()],
+        bump
+    )]
+    pub lp_provider: Account<'info, LpProvider>,
+    pub mint: Account<'info, Mint>,
+    #[account(
+        mut,
+        associated_token::mint = mint,
+        associated_token::authority = signer,
+    )]
+    pub provider_token_account: Account<'info, TokenAccount>,
+    #[account(
+        mut,
+        associated_token::mint = mint,
+        associated_token::authority = pool,
+    )]
+    pub vault_token_account: Account<'info, TokenAccount>,
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(token_mint: String)]
+pub struct RemoveLiquidity<'info> {
+    #[account(
+        seeds = [b"config"],
+        bump = config.bump
+    )]
+    pub config: Account<'info, Config>,
+    pub signer: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [b"vault", token_mint.as_bytes()],
+        bump = pool.bump,
+        constraint = pool.mint == mint.key() @ ErrorCode::InvalidMint
+    )]
+    pub pool: Account<'info, Vault>,
+    #[account(
+        mut,
+        seeds = [b"lp", pool.key().as_ref(), signer.key().as_ref()],
+        bump = lp_provider.bump
+    )]
+    pub lp_provider: Account<'info, LpProvider>,
+    pub mint: Account<'info, Mint>,
+    #[account(
+        mut,
+        associated_token::mint = mint,
+        associated_token::authority = signer,
+    )]
+    pub provider_token_account: Account<'info, TokenAccount>,
+    #[account(
+        mut,
+        associated_token::mint = mint,
+        associated_token::authority = pool,
+    )]
+    pub vault_token_account: Account<'info, TokenAccount>,
+    pub token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+#[instruction(token_mint: String)]
+pub struct CompoundLpFees<'info> {
+    #[account(
+        seeds = [b"config"],
+        bump = config.bump
+    )]
+    pub config: Account<'info, Config>,
+    pub signer: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [b"vault", token_mint.as_bytes()],
+        bump = pool.bump
+    )]
+    pub pool: Account<'info, Vault>,
+}
+
+#[derive(Accounts)]
+#[instruction(token_mint: String)]
+pub struct VerifyCollateralInvariant<'info> {
+    #[account(
+        seeds = [b"vault", token_mint.as_bytes()],
+        bump = pool.bump
+    )]
+    pub pool: Account<'info, Vault>,
+}
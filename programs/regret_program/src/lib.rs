@@ -1,9 +1,20 @@
+// `#[program]` expands to crate-level items (the IDL account handlers) that call the
+// deprecated `AccountInfo::realloc`, an item-level allow doesn't reach them
+#![allow(deprecated)]
+
 use anchor_lang::prelude::*;
 
+mod events;
 mod instructions;
 mod price_update;
+mod signed_amount;
 mod state;
+#[cfg(test)]
+mod test_utils;
 
+use anchor_lang::solana_program::hash::hashv;
+use anchor_spl::token;
+use events::*;
 use instructions::*;
 use price_update::*;
 use state::*;
@@ -13,15 +24,36 @@ pub const PRECISION: u128 = 1_000_000;
 pub const BASIS_POINTS: u128 = 10_000; // 1 basis point = 0.01%
 pub const MIN_COLLATERAL: u64 = 10_000_000; // $10 minimum
 pub const MAX_COLLATERAL: u64 = 1_000_000_000_000; // $1000,000 maximum
+pub const DEFAULT_MIN_EFFECTIVE_COLLATERAL: u64 = MIN_COLLATERAL / 2; // $5 after fees
 pub const MIN_POSITION_VALUE: u64 = 10_000_000; // $10
 pub const MAX_POSITION_VALUE: u64 = 10_000_000_000_000; // $10,000,000
 
+// A zero maintenance margin makes positions unliquidatable
+pub const MIN_MAINTAINANCE_MARGIN: u16 = 50; // 0.5%
+pub const MAX_MARGIN_TIER: u8 = 3;
+pub const MARGIN_TIER_DISCOUNT_BPS: u64 = 2_500; // maintenance cut per tier, 25%
+
 // Price safety limit to prevent overflow in calculations
 pub const MAX_SAFE_PRICE: u64 = u64::MAX / 200;
+// Position math scales sizes by 10^decimals in u128, beyond 18 it overflows at realistic sizes
+pub const MAX_TOKEN_DECIMALS: u8 = 18;
 // Solana produces blocks at approximately 400ms per slot
 // This means: 2.5 slots per second, 150 slots per minute, 9000 slots per hour
 pub const SLOTS_PER_HOUR: u64 = 9000;
 pub const SLOTS_PER_8_HOURS: u64 = 72000; // Standard funding period
+pub const DEFAULT_FUNDING_RATE_BPS: i64 = 10; // initial `Market::funding_rate_bps`
+pub const MAX_FUNDING_RATE_BPS: i64 = 1000; // bound on `set_funding_rate`
+pub const DEFAULT_TARGET_BAND_BPS: u64 = 1000; // 10% target band
+pub const MIN_TARGET_BAND_BPS: u64 = 100; // bounds on `Market::target_band_bps`
+pub const MAX_TARGET_BAND_BPS: u64 = 5000;
+pub const CLIENT_ORDER_WINDOW_SLOTS: u64 = SLOTS_PER_HOUR; // client_order_id reuse window
+pub const RECENT_CLIENT_ORDERS: usize = 4; // client_order_ids remembered per trader
+pub const MAX_FUNDING_BATCH_SIZE: usize = 20; // positions per settle_market_funding call
+pub const MARGIN_WARNING_INTERVAL_SLOTS: u64 = SLOTS_PER_HOUR; // min gap between warning penalties
+pub const CLAIM_COOLDOWN_SLOTS: u64 = SLOTS_PER_HOUR; // min gap between virtual balance claims
+pub const MIN_HEALTH_AFTER_WITHDRAWAL_BPS: u64 = 15000; // 150% of maintenance, see `remove_collateral`
+pub const USD_DECIMALS: u8 = 6; // balances are USD with 6 decimals, deposit mints must match
+pub const MAX_LIQUIDATION_BACKOFF_SHIFT: u8 = 6; // backoff stops doubling at 64x the base
 
 declare_id!("5iYSGPQLrbvdxnTz39AcTGgisRjBBWhtUnh7hLm1DFXf");
 
@@ -53,12 +85,183 @@ pub enum ErrorCode {
     InsufficientCollateralForFees,
     InsufficientLiquidity,
     PositionAlreadyClosed,
+    LimitEntryNotMet,
+    InvalidEpoch,
+    TraderExposureExceeded,
+    ProgramNotPaused,
+    MarketClosed,
+    MaintainanceMarginTooLow,
+    NoActionRequired,
+    InvalidTriggerPrice,
+    FeedVersionMismatch,
+    LeverageBelowOne,
+    CollateralInvariantViolated,
+    DuplicateOrder,
+    BorrowCapExceeded,
+    InvalidDecimals,
+    OpenPositionsRemaining,
+    LockedBalanceRemaining,
+    MarketReduceOnly,
+    PriceDivergenceTooHigh,
+    GlobalPositionLimitReached,
+    FeedSuspect,
+    FeedHalted,
+    KeeperBalanceRequired,
+    LiquidationBackoff,
+    InvalidMint,
+    ClaimCooldown,
+    MarketNotRetired,
+    NetDeltaExceeded,
+    InvalidFundingRate,
+    BalanceInvariantViolated,
+    SlippageExceeded,
+    InvalidTargetBand,
+    SettlementPriceNotSet,
+    VirtualBalanceDisabled,
+    BalanceRemaining,
+}
+
+/// Pay a keeper reward to the signer and return what is added to the owner's payout
+///
+/// A third-party keeper is credited on its own balance for the pool's mint. A trader
+/// acting on their own position gets the reward folded into their payout instead, their
+/// balance can't be passed twice without one copy clobbering the other on exit.
+fn pay_keeper_reward(
+    signer: Pubkey,
+    owner: Pubkey,
+    keeper_balance: Option<&mut TraderPoolDetail>,
+    reward: u64,
+) -> Result<u64> {
+    if signer == owner {
+        require!(keeper_balance.is_none(), ErrorCode::InvalidInput);
+        return Ok(reward);
+    }
+
+    let keeper_balance = keeper_balance.ok_or(ErrorCode::KeeperBalanceRequired)?;
+    keeper_balance.balance = keeper_balance
+        .balance
+        .checked_add(reward)
+        .ok_or(ErrorCode::MathOverflow)?;
+    Ok(0)
+}
+
+/// Close an account loaded by the handler itself to `destination`, as `close = ...` would
+fn close_account<'info>(info: &AccountInfo<'info>, destination: &AccountInfo<'info>) -> Result<()> {
+    let lamports = info.lamports();
+    **destination.try_borrow_mut_lamports()? = destination
+        .lamports()
+        .checked_add(lamports)
+        .ok_or(ErrorCode::MathOverflow)?;
+    **info.try_borrow_mut_lamports()? = 0;
+    info.assign(&System::id());
+    info.realloc(0, false).map_err(Into::into)
+}
+
+/// Release a closed position from the pool, trader and market accounting
+///
+/// Fees are not handled here, the caller routes them before settling. The payout is
+/// checked against its parts first, see `ClosePayout::verify`.
+#[allow(clippy::too_many_arguments)]
+fn settle_closed_position(
+    pool: &mut Vault,
+    trader: &mut Trader,
+    trader_balance: &mut TraderPoolDetail,
+    market: &mut Market,
+    position: &mut Position,
+    config: &mut Config,
+    payout: &ClosePayout,
+    clock: &Clock,
+) -> Result<()> {
+    let collateral = position.collateral;
+    let credited = payout.verify(collateral)?;
+
+    // Update pool - release exactly what was booked for the position
+    pool.release_borrowed(position.booked_borrow);
+    pool.release_trader_collateral(collateral);
+
+    // Update trader balance - unlock collateral and replace it with the final amount
+    let available_before = trader_balance.available_balance();
+    let locked_before = trader_balance.locked_balance;
+    trader_balance.settle(collateral, credited)?;
+
+    // Exactly the verified payout becomes available and exactly the collateral unlocks
+    let expected_available = available_before
+        .checked_add(credited)
+        .ok_or(ErrorCode::MathOverflow)?;
+    let unlocked = locked_before
+        .checked_sub(trader_balance.locked_balance)
+        .ok_or(ErrorCode::BalanceInvariantViolated)?;
+    require!(
+        trader_balance.available_balance() == expected_available && unlocked == collateral,
+        ErrorCode::BalanceInvariantViolated
+    );
+    pool.forfeit_trader_deposit(trader_balance)?;
+
+    // Close position
+    position.closed_at = clock.slot;
+    position.closed_at_unix = clock.unix_timestamp;
+
+    // Update counters
+    trader.active_position = trader
+        .active_position
+        .checked_sub(1)
+        .ok_or(ErrorCode::MathOverflow)?;
+
+    trader.total_notional_exposure = trader
+        .total_notional_exposure
+        .checked_sub(position.position_value)
+        .ok_or(ErrorCode::MathOverflow)?;
+    market.shift_open_interest(position.is_long, position.position_value, 0)?;
+
+    market.total_active_positions = market
+        .total_active_positions
+        .checked_sub(1)
+        .ok_or(ErrorCode::MathOverflow)?;
+    config.track_position_closed()?;
+
+    Ok(())
+}
+
+/// Create the trader accounts with `initial_balance` of virtual balance
+///
+/// A virtual seed is only handed out on pools without a bound mint, see
+/// `TraderPoolDetail::deposited_balance`.
+fn register_trader(ctx: Context<Register>, token_mint: String, initial_balance: u64) -> Result<()> {
+    require!(!ctx.accounts.config.is_paused, ErrorCode::ProgramPaused);
+    require!(
+        initial_balance == 0 || ctx.accounts.pool.mint == Pubkey::default(),
+        ErrorCode::VirtualBalanceDisabled
+    );
+    ctx.accounts.trader.set_inner(Trader {
+        owner: ctx.accounts.signer.key(),
+        bump: ctx.bumps.trader,
+        privacy: false,
+        position_count: 0,
+        active_position: 0,
+        total_notional_exposure: 0,
+        recent_client_orders: Default::default(),
+        margin_tier: 0,
+        delegate: None,
+        balance_accounts: 1,
+        retained_positions: 0,
+    });
+    ctx.accounts.trader_balance.set_inner(TraderPoolDetail {
+        bump: ctx.bumps.trader_balance,
+        token_mint,
+        owner: ctx.accounts.signer.key(),
+        balance: initial_balance,
+        locked_balance: 0,
+        last_claim_slot: 0,
+        deposited_balance: 0,
+    });
+    Ok(())
 }
 
 #[program]
 pub mod regret_market {
     use super::*;
 
+    #[allow(clippy::too_many_arguments)]
     pub fn initialize(
         ctx: Context<Initialize>,
         max_leverage: u64,
@@ -71,6 +274,11 @@ pub mod regret_market {
     ) -> Result<()> {
         let config = &mut ctx.accounts.config;
         require!(config.bump == 0, ErrorCode::ProgramAlreadyStarted);
+        validate_maintainance_margin(maintainance_margin)?;
+        require!(
+            protocol_fee_share as u128 <= BASIS_POINTS,
+            ErrorCode::InvalidInput
+        );
         ctx.accounts.config.set_inner(Config {
             last_updated: Clock::get()?.slot,
             is_paused: false,
@@ -83,35 +291,390 @@ pub mod regret_market {
             closing_fee,
             privacy_fee,
             protocol_fee_share,
+            max_trader_exposure: 0,
+            min_rebalance_profit: 0,
+            tp_execution_reward: 0,
+            max_funding_staleness_slots: 0,
+            leverage_fee_surcharge: 0,
+            max_opening_fee: 0,
+            confidence_reference_leverage: 0,
+            liquidation_rebate: 0,
+            min_funding_interval_slots: 0,
+            max_rebalance_size_change_bps: 0,
+            bad_debt_epoch_slots: 0,
+            max_bad_debt_per_epoch: 0,
+            max_borrow_per_position: 0,
+            max_spot_ema_divergence_bps: 0,
+            max_global_positions: 0,
+            total_open_positions: 0,
+            warning_health_bps: 0,
+            warning_penalty_bps: 0,
+            insurance_target_bps: 0,
+            insurance_fee_share_bps: 0,
+            liquidation_backoff_slots: 0,
+            pending_admin: None,
+            min_effective_collateral: DEFAULT_MIN_EFFECTIVE_COLLATERAL,
+            max_net_delta: 0,
+        });
+
+        emit!(ConfigInitialized {
+            admin: ctx.accounts.signer.key(),
+            max_leverage,
+            liquidation_fee,
+            maintainance_margin,
+            opening_fee,
+            closing_fee,
+            privacy_fee,
+            protocol_fee_share,
+            slot: ctx.accounts.config.last_updated,
+        });
+        Ok(())
+    }
+
+    pub fn update_config(ctx: Context<UpdateConfig>, params: UpdateConfigParams) -> Result<()> {
+        require!(
+            ctx.accounts.signer.key() == ctx.accounts.config.admin,
+            ErrorCode::Unauthorized
+        );
+        let changes = params.clone();
+        let config = &mut ctx.accounts.config;
+        if let Some(max_leverage) = params.max_leverage {
+            require!(max_leverage >= BASIS_POINTS as u64, ErrorCode::InvalidInput);
+            config.max_leverage = max_leverage;
+        }
+        if let Some(liquidation_fee) = params.liquidation_fee {
+            require!(
+                liquidation_fee as u128 <= BASIS_POINTS,
+                ErrorCode::InvalidInput
+            );
+            config.liquidation_fee = liquidation_fee;
+        }
+        if let Some(maintainance_margin) = params.maintainance_margin {
+            validate_maintainance_margin(maintainance_margin)?;
+            config.maintainance_margin = maintainance_margin;
+        }
+        // A typo'd fee above 100% would take more than the whole amount it's charged on
+        if let Some(opening_fee) = params.opening_fee {
+            require!(opening_fee as u128 <= BASIS_POINTS, ErrorCode::InvalidInput);
+            config.opening_fee = opening_fee;
+        }
+        if let Some(closing_fee) = params.closing_fee {
+            require!(closing_fee as u128 <= BASIS_POINTS, ErrorCode::InvalidInput);
+            config.closing_fee = closing_fee;
+        }
+        if let Some(privacy_fee) = params.privacy_fee {
+            config.privacy_fee = privacy_fee;
+        }
+        if let Some(protocol_fee_share) = params.protocol_fee_share {
+            require!(
+                protocol_fee_share as u128 <= BASIS_POINTS,
+                ErrorCode::InvalidInput
+            );
+            config.protocol_fee_share = protocol_fee_share;
+        }
+        if let Some(max_trader_exposure) = params.max_trader_exposure {
+            config.max_trader_exposure = max_trader_exposure;
+        }
+        if let Some(min_rebalance_profit) = params.min_rebalance_profit {
+            config.min_rebalance_profit = min_rebalance_profit;
+        }
+        if let Some(tp_execution_reward) = params.tp_execution_reward {
+            config.tp_execution_reward = tp_execution_reward;
+        }
+        if let Some(max_funding_staleness_slots) = params.max_funding_staleness_slots {
+            config.max_funding_staleness_slots = max_funding_staleness_slots;
+        }
+        if let Some(leverage_fee_surcharge) = params.leverage_fee_surcharge {
+            config.leverage_fee_surcharge = leverage_fee_surcharge;
+        }
+        if let Some(max_opening_fee) = params.max_opening_fee {
+            require!(
+                max_opening_fee as u128 <= BASIS_POINTS,
+                ErrorCode::InvalidInput
+            );
+            config.max_opening_fee = max_opening_fee;
+        }
+        if let Some(confidence_reference_leverage) = params.confidence_reference_leverage {
+            config.confidence_reference_leverage = confidence_reference_leverage;
+        }
+        if let Some(liquidation_rebate) = params.liquidation_rebate {
+            require!(
+                liquidation_rebate as u128 <= BASIS_POINTS,
+                ErrorCode::InvalidInput
+            );
+            config.liquidation_rebate = liquidation_rebate;
+        }
+        if let Some(min_funding_interval_slots) = params.min_funding_interval_slots {
+            config.min_funding_interval_slots = min_funding_interval_slots;
+        }
+        if let Some(max_rebalance_size_change_bps) = params.max_rebalance_size_change_bps {
+            config.max_rebalance_size_change_bps = max_rebalance_size_change_bps;
+        }
+        if let Some(bad_debt_epoch_slots) = params.bad_debt_epoch_slots {
+            config.bad_debt_epoch_slots = bad_debt_epoch_slots;
+        }
+        if let Some(max_bad_debt_per_epoch) = params.max_bad_debt_per_epoch {
+            config.max_bad_debt_per_epoch = max_bad_debt_per_epoch;
+        }
+        if let Some(max_borrow_per_position) = params.max_borrow_per_position {
+            config.max_borrow_per_position = max_borrow_per_position;
+        }
+        if let Some(max_global_positions) = params.max_global_positions {
+            config.max_global_positions = max_global_positions;
+        }
+        if let Some(warning_health_bps) = params.warning_health_bps {
+            // Below 100% the position is liquidated before the band is ever checked
+            require!(
+                warning_health_bps == 0 || warning_health_bps > BASIS_POINTS as u64,
+                ErrorCode::InvalidInput
+            );
+            config.warning_health_bps = warning_health_bps;
+        }
+        if let Some(warning_penalty_bps) = params.warning_penalty_bps {
+            require!(
+                warning_penalty_bps as u128 <= BASIS_POINTS,
+                ErrorCode::InvalidInput
+            );
+            config.warning_penalty_bps = warning_penalty_bps;
+        }
+        if let Some(max_spot_ema_divergence_bps) = params.max_spot_ema_divergence_bps {
+            require!(
+                max_spot_ema_divergence_bps <= BASIS_POINTS as u64,
+                ErrorCode::InvalidInput
+            );
+            config.max_spot_ema_divergence_bps = max_spot_ema_divergence_bps;
+        }
+        if let Some(insurance_target_bps) = params.insurance_target_bps {
+            config.insurance_target_bps = insurance_target_bps;
+        }
+        if let Some(insurance_fee_share_bps) = params.insurance_fee_share_bps {
+            require!(
+                insurance_fee_share_bps as u128 <= BASIS_POINTS,
+                ErrorCode::InvalidInput
+            );
+            config.insurance_fee_share_bps = insurance_fee_share_bps;
+        }
+        if let Some(liquidation_backoff_slots) = params.liquidation_backoff_slots {
+            config.liquidation_backoff_slots = liquidation_backoff_slots;
+        }
+        if let Some(min_effective_collateral) = params.min_effective_collateral {
+            config.min_effective_collateral = min_effective_collateral;
+        }
+        if let Some(max_net_delta) = params.max_net_delta {
+            config.max_net_delta = max_net_delta;
+        }
+        config.last_updated = Clock::get()?.slot;
+
+        emit!(ConfigUpdated {
+            admin: ctx.accounts.signer.key(),
+            changes,
+            slot: config.last_updated,
+        });
+        Ok(())
+    }
+
+    /// Propose `new_admin` as the next admin, replacing any earlier proposal
+    ///
+    /// Nothing changes until `new_admin` signs `accept_admin`, so a mistyped key can't
+    /// lock the protocol out. Proposing the current admin cancels a pending transfer.
+    pub fn propose_admin(ctx: Context<UpdateConfig>, new_admin: Pubkey) -> Result<()> {
+        require!(
+            ctx.accounts.signer.key() == ctx.accounts.config.admin,
+            ErrorCode::Unauthorized
+        );
+        require!(new_admin != Pubkey::default(), ErrorCode::InvalidInput);
+
+        let config = &mut ctx.accounts.config;
+        config.pending_admin = (new_admin != config.admin).then_some(new_admin);
+        config.last_updated = Clock::get()?.slot;
+
+        emit!(AdminProposed {
+            admin: config.admin,
+            pending_admin: config.pending_admin,
+            slot: config.last_updated,
+        });
+        Ok(())
+    }
+
+    /// Complete an admin transfer, signed by the proposed admin
+    pub fn accept_admin(ctx: Context<UpdateConfig>) -> Result<()> {
+        let config = &mut ctx.accounts.config;
+        require!(
+            config.pending_admin == Some(ctx.accounts.signer.key()),
+            ErrorCode::Unauthorized
+        );
+
+        let previous_admin = config.admin;
+        config.admin = ctx.accounts.signer.key();
+        config.pending_admin = None;
+        config.last_updated = Clock::get()?.slot;
+
+        emit!(AdminTransferred {
+            previous_admin,
+            admin: config.admin,
+            slot: config.last_updated,
         });
         Ok(())
     }
 
+    /// Create the signer's trader accounts with a $100k virtual balance
+    ///
+    /// Only on pools without a bound mint, use `register_empty` and `deposit` otherwise.
     pub fn register(ctx: Context<Register>, token_mint: String) -> Result<()> {
-        require!(!ctx.accounts.config.is_paused, ErrorCode::ProgramPaused);
-        ctx.accounts.trader.set_inner(Trader {
-            owner: ctx.accounts.signer.key(),
-            bump: ctx.bumps.trader,
-            privacy: false,
-            position_count: 0,
-            active_position: 0,
+        register_trader(ctx, token_mint, 100_000_000_000) // 100,000$
+    }
+
+    /// Same as `register`, but the balance starts at zero for accounts funded later
+    pub fn register_empty(ctx: Context<Register>, token_mint: String) -> Result<()> {
+        register_trader(ctx, token_mint, 0)
+    }
+
+    /// Read whether `owner` is registered and their balance for `token_mint`
+    ///
+    /// Unregistered owners get `is_registered: false` and zeroes instead of an error.
+    pub fn get_trader_info(
+        ctx: Context<GetTraderInfo>,
+        _token_mint: String,
+        _owner: Pubkey,
+    ) -> Result<TraderInfo> {
+        let trader_info = &ctx.accounts.trader;
+        if trader_info.data_is_empty() {
+            return Ok(TraderInfo::default());
+        }
+        require_keys_eq!(*trader_info.owner, crate::ID, ErrorCode::InvalidInput);
+        let trader = Trader::try_deserialize(&mut &trader_info.try_borrow_data()?[..])?;
+
+        let mut info = TraderInfo {
+            is_registered: true,
+            position_count: trader.position_count,
+            active_position: trader.active_position,
+            privacy: trader.privacy,
+            ..TraderInfo::default()
+        };
+
+        let balance_info = &ctx.accounts.trader_balance;
+        if !balance_info.data_is_empty() {
+            require_keys_eq!(*balance_info.owner, crate::ID, ErrorCode::InvalidInput);
+            let trader_balance =
+                TraderPoolDetail::try_deserialize(&mut &balance_info.try_borrow_data()?[..])?;
+            info.balance = trader_balance.balance;
+            info.locked_balance = trader_balance.locked_balance;
+            info.available_balance = trader_balance.available_balance();
+            info.withdrawable_balance = trader_balance.withdrawable_balance();
+        }
+        Ok(info)
+    }
+
+    /// Grant `owner` a reduced maintenance margin
+    ///
+    /// Applies to every health check on the trader's positions from now on, including
+    /// already open ones. See `maintenance_margin_for_tier` for the tiered margin.
+    ///
+    /// # Arguments
+    /// * `owner` - Trader to update
+    /// * `margin_tier` - 0 (standard) up to `MAX_MARGIN_TIER`
+    pub fn set_trader_margin_tier(
+        ctx: Context<SetTraderMarginTier>,
+        owner: Pubkey,
+        margin_tier: u8,
+    ) -> Result<()> {
+        require!(
+            ctx.accounts.signer.key() == ctx.accounts.config.admin,
+            ErrorCode::Unauthorized
+        );
+        require!(margin_tier <= MAX_MARGIN_TIER, ErrorCode::InvalidInput);
+
+        let trader = &mut ctx.accounts.trader;
+        let previous_tier = trader.margin_tier;
+        trader.margin_tier = margin_tier;
+
+        emit!(TraderMarginTierSet {
+            admin: ctx.accounts.signer.key(),
+            owner,
+            previous_tier,
+            margin_tier,
+            slot: Clock::get()?.slot,
         });
-        ctx.accounts.trader_balance.set_inner(TraderPoolDetail {
-            bump: ctx.bumps.trader_balance,
-            token_mint,
-            owner: ctx.accounts.signer.key(),
-            balance: 100_000_000_000, // 100,000$
-            locked_balance: 0,
+        Ok(())
+    }
+
+    /// Authorize `delegate` to manage the signer's positions, or revoke with `None`
+    ///
+    /// A delegate can set and clear triggers and close positions, the payout still goes
+    /// to the owner's balance. Withdrawals stay owner-only.
+    pub fn set_delegate(ctx: Context<SetDelegate>, delegate: Option<Pubkey>) -> Result<()> {
+        let trader = &mut ctx.accounts.trader;
+        require!(delegate != Some(trader.owner), ErrorCode::InvalidInput);
+
+        let previous_delegate = trader.delegate;
+        trader.delegate = delegate;
+
+        emit!(DelegateSet {
+            owner: trader.owner,
+            previous_delegate,
+            delegate,
+            slot: Clock::get()?.slot,
         });
         Ok(())
     }
 
+    /// Hide the signer as owner in position events, for a one-off `privacy_fee`
+    ///
+    /// The fee is a flat `privacy_fee` in whole USD, paid from the available balance in
+    /// `token_mint`'s pool and routed like any other fee. Privacy blanks the owner in
+    /// every position event and log, the trader and position accounts are still derived
+    /// from and owned by the owner's key, so the link stays visible on-chain.
+    pub fn enable_privacy(ctx: Context<EnablePrivacy>, _token_mint: String) -> Result<()> {
+        let config = &ctx.accounts.config;
+        require!(!config.is_paused, ErrorCode::ProgramPaused);
+        let trader = &mut ctx.accounts.trader;
+        let trader_balance = &mut ctx.accounts.trader_balance;
+        require!(!trader.privacy, ErrorCode::InvalidInput);
+
+        let fee = (config.privacy_fee as u64)
+            .checked_mul(10u64.pow(USD_DECIMALS as u32))
+            .ok_or(ErrorCode::MathOverflow)?;
+        require!(
+            trader_balance.available_balance() >= fee,
+            ErrorCode::NotEnoughBalance
+        );
+        trader_balance.balance = trader_balance
+            .balance
+            .checked_sub(fee)
+            .ok_or(ErrorCode::MathOverflow)?;
+        ctx.accounts.pool.forfeit_trader_deposit(trader_balance)?;
+        ctx.accounts
+            .pool
+            .route_fee(fee, config, &mut ctx.accounts.insurance_fund)?;
+        trader.privacy = true;
+
+        emit!(PrivacyEnabled {
+            owner: trader.owner,
+            fee,
+            slot: Clock::get()?.slot,
+        });
+        Ok(())
+    }
+
+    /// Top up the signer's balance with $10k of virtual balance, once per `CLAIM_COOLDOWN_SLOTS`
+    ///
+    /// Disabled once `set_pool_mint` has bound the pool to a real mint.
     pub fn claim_virtual_balance(
         ctx: Context<ClaimVirtualBalance>,
         _token_mint: String,
     ) -> Result<()> {
         require!(!ctx.accounts.config.is_paused, ErrorCode::ProgramPaused);
+        require!(
+            ctx.accounts.pool.mint == Pubkey::default(),
+            ErrorCode::VirtualBalanceDisabled
+        );
         let balance = &mut ctx.accounts.trader_balance;
+        let slot = Clock::get()?.slot;
+        require!(
+            slot.saturating_sub(balance.last_claim_slot) >= CLAIM_COOLDOWN_SLOTS,
+            ErrorCode::ClaimCooldown
+        );
+        balance.last_claim_slot = slot;
         balance.balance = balance
             .balance
             .checked_add(10_000_000_000)
@@ -119,489 +682,5475 @@ pub mod regret_market {
         Ok(())
     }
 
-    pub fn create_pool(ctx: Context<CreatePool>, token_mint: String) -> Result<()> {
+    /// Burn unlocked virtual balance, no tokens move
+    ///
+    /// Real deposits are left alone, they only leave through `withdraw`. Collateral
+    /// locked in open positions can't be burned either.
+    ///
+    /// # Arguments
+    /// * `token_mint` - Token of the balance to burn from
+    /// * `amount` - Amount to burn, at most `TraderPoolDetail::virtual_balance`
+    pub fn burn_virtual_balance(
+        ctx: Context<BurnVirtualBalance>,
+        token_mint: String,
+        amount: u64,
+    ) -> Result<()> {
         require!(!ctx.accounts.config.is_paused, ErrorCode::ProgramPaused);
-        ctx.accounts.pool.set_inner(Vault {
-            bump: ctx.bumps.pool,
-            is_paused: false,
+        require!(amount > 0, ErrorCode::InvalidInput);
+
+        let trader_balance = &mut ctx.accounts.trader_balance;
+        require!(
+            amount <= trader_balance.virtual_balance(),
+            ErrorCode::NotEnoughBalance
+        );
+
+        trader_balance.balance = trader_balance
+            .balance
+            .checked_sub(amount)
+            .ok_or(ErrorCode::MathOverflow)?;
+
+        emit!(VirtualBalanceBurned {
+            owner: ctx.accounts.signer.key(),
             token_mint,
-            lp_deposit: 100_000_000_000_000,
-            total_lp_shares: 0,
-            accumulated_lp_fees: 0,
-            trader_deposit: 0,
-            trader_collateral: 0,
-            total_borrowed: 0,
-            accumulated_fees: 0,
-            accumulated_liquidation_rewards: 0,
+            amount,
+            remaining_balance: trader_balance.balance,
+            slot: Clock::get()?.slot,
         });
         Ok(())
     }
 
-    pub fn fund_pool(ctx: Context<FundPool>, _token_mint: String) -> Result<()> {
+    /// Deposit `amount` of the pool's SPL mint into its vault and credit the signer's balance
+    ///
+    /// Tokens move from the signer's associated token account to the vault's, which is
+    /// owned by the pool PDA. Only available once `set_pool_mint` has bound a mint.
+    ///
+    /// # Arguments
+    /// * `token_mint` - Pool to deposit into
+    /// * `amount` - Token amount, USD with 6 decimals
+    pub fn deposit(ctx: Context<Deposit>, token_mint: String, amount: u64) -> Result<()> {
         require!(!ctx.accounts.config.is_paused, ErrorCode::ProgramPaused);
+        require!(!ctx.accounts.pool.is_paused, ErrorCode::ProgramPaused);
+        require!(amount > 0, ErrorCode::InvalidInput);
+
+        token::transfer(
+            CpiContext::new(
+                ctx.accounts.token_program.to_account_info(),
+                token::Transfer {
+                    from: ctx.accounts.trader_token_account.to_account_info(),
+                    to: ctx.accounts.vault_token_account.to_account_info(),
+                    authority: ctx.accounts.signer.to_account_info(),
+                },
+            ),
+            amount,
+        )?;
+
+        let trader_balance = &mut ctx.accounts.trader_balance;
+        trader_balance.balance = trader_balance
+            .balance
+            .checked_add(amount)
+            .ok_or(ErrorCode::MathOverflow)?;
+        trader_balance.deposited_balance = trader_balance
+            .deposited_balance
+            .checked_add(amount)
+            .ok_or(ErrorCode::MathOverflow)?;
         let pool = &mut ctx.accounts.pool;
-        pool.lp_deposit = pool
-            .lp_deposit
-            .checked_add(1_000_000_000_000)
+        pool.trader_deposit = pool
+            .trader_deposit
+            .checked_add(amount)
             .ok_or(ErrorCode::MathOverflow)?;
+
+        emit!(BalanceDeposited {
+            owner: ctx.accounts.signer.key(),
+            token_mint,
+            amount,
+            balance: trader_balance.balance,
+            slot: Clock::get()?.slot,
+        });
         Ok(())
     }
 
-    pub fn open_market(
-        ctx: Context<OpenMarket>,
-        pair: String,
-        decimals: u8,
-        feed_id: String,
-    ) -> Result<()> {
+    /// Withdraw `amount` of the pool's SPL mint from its vault to the signer
+    ///
+    /// Counterpart of `deposit`, the vault account is signed for by the pool PDA. Only
+    /// real deposits can leave, at most `TraderPoolDetail::withdrawable_balance`:
+    /// virtual balance, virtual PnL and collateral locked in open positions stay put.
+    ///
+    /// # Arguments
+    /// * `token_mint` - Pool to withdraw from
+    /// * `amount` - Token amount, USD with 6 decimals
+    pub fn withdraw(ctx: Context<Withdraw>, token_mint: String, amount: u64) -> Result<()> {
         require!(!ctx.accounts.config.is_paused, ErrorCode::ProgramPaused);
-        ctx.accounts.market.set_inner(Market {
-            bump: ctx.bumps.market,
-            pair,
-            decimals,
-            feed_id,
-            total_active_positions: 0,
-            is_paused: false,
+        require!(!ctx.accounts.pool.is_paused, ErrorCode::ProgramPaused);
+        require!(amount > 0, ErrorCode::InvalidInput);
+
+        let trader_balance = &mut ctx.accounts.trader_balance;
+        require!(
+            amount <= trader_balance.withdrawable_balance(),
+            ErrorCode::NotEnoughBalance
+        );
+        trader_balance.balance = trader_balance
+            .balance
+            .checked_sub(amount)
+            .ok_or(ErrorCode::MathOverflow)?;
+        trader_balance.deposited_balance = trader_balance
+            .deposited_balance
+            .checked_sub(amount)
+            .ok_or(ErrorCode::MathOverflow)?;
+        let pool = &mut ctx.accounts.pool;
+        pool.trader_deposit = pool.trader_deposit.saturating_sub(amount);
+
+        let bump = [pool.bump];
+        let signer_seeds: &[&[&[u8]]] = &[&[b"vault", token_mint.as_bytes(), &bump]];
+        token::transfer(
+            CpiContext::new_with_signer(
+                ctx.accounts.token_program.to_account_info(),
+                token::Transfer {
+                    from: ctx.accounts.vault_token_account.to_account_info(),
+                    to: ctx.accounts.trader_token_account.to_account_info(),
+                    authority: pool.to_account_info(),
+                },
+                signer_seeds,
+            ),
+            amount,
+        )?;
+
+        emit!(BalanceWithdrawn {
+            owner: ctx.accounts.signer.key(),
+            token_mint,
+            amount,
+            remaining_balance: trader_balance.balance,
+            slot: Clock::get()?.slot,
         });
         Ok(())
     }
 
-    pub fn update_market(
-        ctx: Context<UpdateMarket>,
-        _pair: String,
-        feed_id: Option<String>,
-    ) -> Result<()> {
+    /// Open a balance account for another `token_mint` under an existing `Trader`
+    ///
+    /// The balance starts at zero, see `deposit` and `claim_virtual_balance`.
+    ///
+    /// # Arguments
+    /// * `token_mint` - Token of the new balance account
+    pub fn open_trader_balance(ctx: Context<OpenTraderBalance>, token_mint: String) -> Result<()> {
         require!(!ctx.accounts.config.is_paused, ErrorCode::ProgramPaused);
-        require!(!ctx.accounts.market.is_paused, ErrorCode::ProgramPaused);
-        require!(
-            ctx.accounts.signer.key() == ctx.accounts.config.admin,
-            ErrorCode::Unauthorized
-        );
-        let market = &mut ctx.accounts.market;
-        feed_id.map(|id| {
-            market.feed_id = id;
+        let trader = &mut ctx.accounts.trader;
+        trader.balance_accounts = trader
+            .balance_accounts
+            .checked_add(1)
+            .ok_or(ErrorCode::MathOverflow)?;
+
+        ctx.accounts.trader_balance.set_inner(TraderPoolDetail {
+            bump: ctx.bumps.trader_balance,
+            token_mint,
+            owner: ctx.accounts.signer.key(),
+            balance: 0,
+            locked_balance: 0,
+            last_claim_slot: 0,
+            deposited_balance: 0,
         });
         Ok(())
     }
 
-    pub fn open_position(
-        ctx: Context<OpenPosition>,
+    /// Close the signer's `token_mint` balance account, and the `Trader` with the last one
+    ///
+    /// The balance has to be withdrawn or burned first and no collateral may be locked.
+    /// The `Trader` is only closed once no position account is left: none open and none
+    /// retained by `admin_seize_position`. `position_count` restarts at 0 after
+    /// re-registering, so every id it hands out again is free.
+    ///
+    /// # Arguments
+    /// * `token_mint` - Token of the balance account to close
+    pub fn close_trader_account(
+        ctx: Context<CloseTraderAccount>,
         token_mint: String,
-        pair: String,
-        position_id: u64,
-        desired_size: u64,
-        desired_entry_price: u64,
-        collateral: u64,
-        is_long: bool,
     ) -> Result<()> {
         require!(!ctx.accounts.config.is_paused, ErrorCode::ProgramPaused);
-        require!(!ctx.accounts.market.is_paused, ErrorCode::ProgramPaused);
-        require!(!ctx.accounts.pool.is_paused, ErrorCode::ProgramPaused);
-
-        require_eq!(
-            position_id,
-            ctx.accounts.trader.position_count,
-            ErrorCode::InvalidPositionId
+        require!(
+            ctx.accounts.trader_balance.locked_balance == 0,
+            ErrorCode::LockedBalanceRemaining
+        );
+        require!(
+            ctx.accounts.trader_balance.balance == 0,
+            ErrorCode::BalanceRemaining
         );
 
-        // Validate inputs
-        validate_collateral(collateral)?;
-        validate_position_size(desired_size)?;
-        validate_price(desired_entry_price)?;
+        let trader = &mut ctx.accounts.trader;
+        trader.balance_accounts = trader
+            .balance_accounts
+            .checked_sub(1)
+            .ok_or(ErrorCode::MathOverflow)?;
+        let trader_closed = trader.balance_accounts == 0;
+        if trader_closed {
+            require!(
+                trader.active_position == 0 && trader.retained_positions == 0,
+                ErrorCode::OpenPositionsRemaining
+            );
+        }
 
-        let config = &ctx.accounts.config;
+        emit!(TraderAccountClosed {
+            owner: ctx.accounts.signer.key(),
+            token_mint,
+            trader_closed,
+            slot: Clock::get()?.slot,
+        });
+
+        if trader_closed {
+            return ctx
+                .accounts
+                .trader
+                .close(ctx.accounts.signer.to_account_info());
+        }
+        Ok(())
+    }
+
+    pub fn create_pool(ctx: Context<CreatePool>, token_mint: String) -> Result<()> {
+        require!(!ctx.accounts.config.is_paused, ErrorCode::ProgramPaused);
+        require!(
+            ctx.accounts.signer.key() == ctx.accounts.config.admin,
+            ErrorCode::Unauthorized
+        );
+        ctx.accounts.pool.set_inner(Vault {
+            bump: ctx.bumps.pool,
+            is_paused: false,
+            token_mint,
+            mint: Pubkey::default(),
+            lp_deposit: 100_000_000_000_000,
+            total_lp_shares: 0,
+            lp_token_deposit: 0,
+            accumulated_lp_fees: 0,
+            trader_deposit: 0,
+            forfeited_trader_deposit: 0,
+            trader_collateral: 0,
+            total_borrowed: 0,
+            accumulated_fees: 0,
+            lifetime_keeper_rewards: 0,
+            accumulated_bad_debt: 0,
+            bad_debt_this_epoch: 0,
+            bad_debt_epoch_start_slot: 0,
+            lifetime_protocol_fees: 0,
+            lifetime_lp_fees: 0,
+            accumulated_insurance: 0,
+        });
+        Ok(())
+    }
+
+    /// Create the insurance fund backing `token_mint`'s pool, admin only
+    ///
+    /// Every instruction that opens, closes or liquidates on the pool takes the fund, so
+    /// a pool created before it can't trade until this is called. The insurance the pool
+    /// accumulated itself carries over as the opening balance.
+    pub fn create_insurance_fund(
+        ctx: Context<CreateInsuranceFund>,
+        token_mint: String,
+    ) -> Result<()> {
+        require!(!ctx.accounts.config.is_paused, ErrorCode::ProgramPaused);
+        require!(
+            ctx.accounts.signer.key() == ctx.accounts.config.admin,
+            ErrorCode::Unauthorized
+        );
+        let pool = &mut ctx.accounts.pool;
+        ctx.accounts.insurance_fund.set_inner(InsuranceFund {
+            bump: ctx.bumps.insurance_fund,
+            token_mint,
+            balance: pool.accumulated_insurance,
+        });
+        pool.accumulated_insurance = 0;
+        Ok(())
+    }
+
+    pub fn fund_pool(ctx: Context<FundPool>, _token_mint: String) -> Result<()> {
+        require!(!ctx.accounts.config.is_paused, ErrorCode::ProgramPaused);
+        require!(
+            ctx.accounts.signer.key() == ctx.accounts.config.admin,
+            ErrorCode::Unauthorized
+        );
+        let pool = &mut ctx.accounts.pool;
+        pool.lp_deposit = pool
+            .lp_deposit
+            .checked_add(1_000_000_000_000)
+            .ok_or(ErrorCode::MathOverflow)?;
+        Ok(())
+    }
+
+    /// Bind the pool to an SPL mint and create its vault token account, admin only
+    ///
+    /// Can only be set once, deposits already held in the vault would otherwise be
+    /// stranded. The mint has to use `USD_DECIMALS` since balances are credited 1:1.
+    pub fn set_pool_mint(ctx: Context<SetPoolMint>, token_mint: String) -> Result<()> {
+        require!(
+            ctx.accounts.signer.key() == ctx.accounts.config.admin,
+            ErrorCode::Unauthorized
+        );
+        require_eq!(
+            ctx.accounts.mint.decimals,
+            USD_DECIMALS,
+            ErrorCode::InvalidDecimals
+        );
+        let pool = &mut ctx.accounts.pool;
+        require!(pool.mint == Pubkey::default(), ErrorCode::InvalidMint);
+        pool.mint = ctx.accounts.mint.key();
+
+        emit!(PoolMintSet {
+            admin: ctx.accounts.signer.key(),
+            token_mint,
+            mint: pool.mint,
+            vault_token_account: ctx.accounts.vault_token_account.key(),
+            slot: Clock::get()?.slot,
+        });
+        Ok(())
+    }
+
+    /// Provide `amount` of the pool's SPL mint as liquidity in exchange for LP shares
+    ///
+    /// The tokens move into the pool's vault account and back the shares, see
+    /// `Vault::add_liquidity` for how they are priced.
+    ///
+    /// # Arguments
+    /// * `token_mint` - Pool to provide liquidity to
+    /// * `amount` - Token amount, USD with 6 decimals
+    pub fn add_liquidity(
+        ctx: Context<AddLiquidity>,
+        token_mint: String,
+        amount: u64,
+    ) -> Result<()> {
+        require!(!ctx.accounts.config.is_paused, ErrorCode::ProgramPaused);
+        require!(!ctx.accounts.pool.is_paused, ErrorCode::ProgramPaused);
+        require!(amount > 0, ErrorCode::InvalidInput);
+
+        token::transfer(
+            CpiContext::new(
+                ctx.accounts.token_program.to_account_info(),
+                token::Transfer {
+                    from: ctx.accounts.provider_token_account.to_account_info(),
+                    to: ctx.accounts.vault_token_account.to_account_info(),
+                    authority: ctx.accounts.signer.to_account_info(),
+                },
+            ),
+            amount,
+        )?;
+
+        let shares = ctx.accounts.pool.add_liquidity(amount)?;
+
+        let lp_provider = &mut ctx.accounts.lp_provider;
+        if lp_provider.provider == Pubkey::default() {
+            lp_provider.bump = ctx.bumps.lp_provider;
+            lp_provider.pool = ctx.accounts.pool.key();
+            lp_provider.provider = ctx.accounts.signer.key();
+        }
+        lp_provider.shares = lp_provider
+            .shares
+            .checked_add(shares)
+            .ok_or(ErrorCode::MathOverflow)?;
+
+        emit!(LiquidityAdded {
+            provider: ctx.accounts.signer.key(),
+            token_mint,
+            amount,
+            shares,
+            total_lp_shares: ctx.accounts.pool.total_lp_shares,
+            lp_deposit: ctx.accounts.pool.lp_deposit,
+            lp_token_deposit: ctx.accounts.pool.lp_token_deposit,
+            slot: Clock::get()?.slot,
+        });
+        Ok(())
+    }
+
+    /// Burn `shares` of the signer's LP position and pay out their value in the pool's mint
+    ///
+    /// Pays `shares / total_lp_shares` of the real LP deposits. Fails with
+    /// `InsufficientLiquidity` when that is lent out to open positions.
+    ///
+    /// # Arguments
+    /// * `token_mint` - Pool to withdraw liquidity from
+    /// * `shares` - LP shares to redeem, at most the signer's
+    pub fn remove_liquidity(
+        ctx: Context<RemoveLiquidity>,
+        token_mint: String,
+        shares: u64,
+    ) -> Result<()> {
+        require!(!ctx.accounts.config.is_paused, ErrorCode::ProgramPaused);
+        require!(!ctx.accounts.pool.is_paused, ErrorCode::ProgramPaused);
+
+        let lp_provider = &mut ctx.accounts.lp_provider;
+        lp_provider.shares = lp_provider
+            .shares
+            .checked_sub(shares)
+            .ok_or(ErrorCode::NotEnoughBalance)?;
+
+        let pool = &mut ctx.accounts.pool;
+        let amount = pool.remove_liquidity(shares)?;
+
+        let bump = [pool.bump];
+        let signer_seeds: &[&[&[u8]]] = &[&[b"vault", token_mint.as_bytes(), &bump]];
+        token::transfer(
+            CpiContext::new_with_signer(
+                ctx.accounts.token_program.to_account_info(),
+                token::Transfer {
+                    from: ctx.accounts.vault_token_account.to_account_info(),
+                    to: ctx.accounts.provider_token_account.to_account_info(),
+                    authority: pool.to_account_info(),
+                },
+                signer_seeds,
+            ),
+            amount,
+        )?;
+
+        emit!(LiquidityRemoved {
+            provider: ctx.accounts.signer.key(),
+            token_mint,
+            shares,
+            amount,
+            total_lp_shares: pool.total_lp_shares,
+            lp_deposit: pool.lp_deposit,
+            lp_token_deposit: pool.lp_token_deposit,
+            slot: Clock::get()?.slot,
+        });
+        Ok(())
+    }
+
+    /// Move the accrued LP fees into `lp_deposit`, open to any keeper
+    ///
+    /// The fees become lendable liquidity. The part paid out of real trader deposits
+    /// also raises what LP shares redeem for, fees on virtual balances don't, see
+    /// `Vault::lp_value`. The accumulator is zeroed in the same step so a fee is
+    /// compounded exactly once.
+    pub fn compound_lp_fees(ctx: Context<CompoundLpFees>, token_mint: String) -> Result<()> {
+        require!(!ctx.accounts.config.is_paused, ErrorCode::ProgramPaused);
         let pool = &mut ctx.accounts.pool;
+        require!(!pool.is_paused, ErrorCode::ProgramPaused);
+
+        let amount = pool.compound_lp_fees()?;
+        require!(amount > 0, ErrorCode::NoActionRequired);
+
+        emit!(LpFeesCompounded {
+            token_mint,
+            amount,
+            lp_deposit: pool.lp_deposit,
+            slot: Clock::get()?.slot,
+        });
+        Ok(())
+    }
+
+    /// Create a market for `pair` priced by `feed_id`
+    ///
+    /// `decimals` is the token's decimals and can't be changed later. Every size, notional
+    /// and PnL is scaled by it, so a wrong value silently misprices all positions, e.g. 6
+    /// for an 18-decimal asset values each unit 10^12 times too high.
+    ///
+    /// When `price_update` is passed the decimals are sanity checked against the feed
+    /// price and `MarketDecimalsWarning` is emitted if they look off. It doesn't fail,
+    /// the heuristic can't tell a cheap token with few decimals from a misconfiguration.
+    ///
+    /// # Arguments
+    /// * `pair` - Market pair, also the PDA seed
+    /// * `decimals` - Token decimals, at most `MAX_TOKEN_DECIMALS`
+    /// * `feed_id` - Pyth feed id (hex)
+    /// * `expected_decimals` - Optional second input that has to match `decimals`
+    /// * `target_band_bps` - Target distance from the price, `DEFAULT_TARGET_BAND_BPS` if `None`
+    /// * `max_leverage` - Market leverage cap, the config's cap applies if `None` or lower.
+    ///   Admin only
+    /// * `maintainance_margin` - Market maintenance margin, the config's applies if `None`.
+    ///   Admin only
+    #[allow(clippy::too_many_arguments)]
+    pub fn open_market(
+        ctx: Context<OpenMarket>,
+        pair: String,
+        decimals: u8,
+        feed_id: String,
+        expected_decimals: Option<u8>,
+        target_band_bps: Option<u64>,
+        max_leverage: Option<u64>,
+        maintainance_margin: Option<u16>,
+    ) -> Result<()> {
+        require!(!ctx.accounts.config.is_paused, ErrorCode::ProgramPaused);
+        require!(decimals <= MAX_TOKEN_DECIMALS, ErrorCode::InvalidDecimals);
+        if let Some(expected_decimals) = expected_decimals {
+            require_eq!(decimals, expected_decimals, ErrorCode::InvalidDecimals);
+        }
+        let target_band_bps = target_band_bps.unwrap_or(DEFAULT_TARGET_BAND_BPS);
+        require!(
+            (MIN_TARGET_BAND_BPS..=MAX_TARGET_BAND_BPS).contains(&target_band_bps),
+            ErrorCode::InvalidTargetBand
+        );
+        // Anyone can open a market, but only the admin may override its risk limits
+        if max_leverage.is_some() || maintainance_margin.is_some() {
+            require!(
+                ctx.accounts.signer.key() == ctx.accounts.config.admin,
+                ErrorCode::Unauthorized
+            );
+        }
+        let max_leverage = max_leverage.unwrap_or(0);
+        require!(
+            max_leverage == 0 || max_leverage >= BASIS_POINTS as u64,
+            ErrorCode::InvalidInput
+        );
+        let maintainance_margin = maintainance_margin.unwrap_or(0);
+        if maintainance_margin != 0 {
+            validate_maintainance_margin(maintainance_margin)?;
+        }
+
+        if let Some(price_update) = &ctx.accounts.price_update {
+            let clock = Clock::get()?;
+            let price = get_normalized_price(price_update, &feed_id, &clock)?;
+            validate_price(price)?;
+            let check = check_market_decimals(price, decimals)?;
+            if check.unit_too_coarse || check.max_size_overflows {
+                msg!(
+                    "Warning: decimals {} look inconsistent with the feed price",
+                    decimals
+                );
+                emit!(MarketDecimalsWarning {
+                    pair: pair.clone(),
+                    decimals,
+                    price,
+                    unit_too_coarse: check.unit_too_coarse,
+                    max_size_overflows: check.max_size_overflows,
+                    slot: clock.slot,
+                });
+            }
+        }
+
+        emit!(MarketOpened {
+            opened_by: ctx.accounts.signer.key(),
+            pair: pair.clone(),
+            decimals,
+            feed_id: feed_id.clone(),
+            target_band_bps,
+            max_leverage,
+            maintainance_margin,
+            slot: Clock::get()?.slot,
+        });
+
+        ctx.accounts.market.set_inner(Market {
+            bump: ctx.bumps.market,
+            pair,
+            decimals,
+            feed_id,
+            total_active_positions: 0,
+            is_paused: false,
+            open_window_start_slot: 0,
+            open_window_end_slot: 0,
+            cumulative_trader_pnl: 0,
+            feed_id_version: 0,
+            reduce_only: false,
+            feed_status: FeedStatus::Healthy,
+            retired: false,
+            settlement_price: 0,
+            net_delta: 0,
+            funding_rate_bps: DEFAULT_FUNDING_RATE_BPS,
+            long_open_interest: 0,
+            short_open_interest: 0,
+            target_band_bps,
+            max_leverage,
+            maintainance_margin,
+        });
+        Ok(())
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn update_market(
+        ctx: Context<UpdateMarket>,
+        pair: String,
+        feed_id: Option<String>,
+        open_window_start_slot: Option<u64>,
+        open_window_end_slot: Option<u64>,
+        reduce_only: Option<bool>,
+        max_leverage: Option<u64>,
+        maintainance_margin: Option<u16>,
+    ) -> Result<()> {
+        require!(!ctx.accounts.config.is_paused, ErrorCode::ProgramPaused);
+        require!(!ctx.accounts.market.is_paused, ErrorCode::ProgramPaused);
+        require!(
+            ctx.accounts.signer.key() == ctx.accounts.config.admin,
+            ErrorCode::Unauthorized
+        );
         let market = &mut ctx.accounts.market;
-        let trader = &mut ctx.accounts.trader;
-        let trader_balance = &mut ctx.accounts.trader_balance;
-        let position = &mut ctx.accounts.position;
+        if let Some(id) = feed_id.clone() {
+            // Open positions can only leave through `settle_stranded_position` after this
+            if id != market.feed_id {
+                market.feed_id_version = market
+                    .feed_id_version
+                    .checked_add(1)
+                    .ok_or(ErrorCode::MathOverflow)?;
+            }
+            market.feed_id = id;
+        }
+        if let Some(start) = open_window_start_slot {
+            market.open_window_start_slot = start;
+        }
+        if let Some(end) = open_window_end_slot {
+            market.open_window_end_slot = end;
+        }
+        if let Some(reduce_only) = reduce_only {
+            // A retired market stays reduce-only
+            require!(reduce_only || !market.retired, ErrorCode::InvalidInput);
+            market.reduce_only = reduce_only;
+        }
+        if let Some(max_leverage) = max_leverage {
+            // 0 clears the override
+            require!(
+                max_leverage == 0 || max_leverage >= BASIS_POINTS as u64,
+                ErrorCode::InvalidInput
+            );
+            market.max_leverage = max_leverage;
+        }
+        if let Some(maintainance_margin) = maintainance_margin {
+            // 0 clears the override
+            if maintainance_margin != 0 {
+                validate_maintainance_margin(maintainance_margin)?;
+            }
+            market.maintainance_margin = maintainance_margin;
+        }
+        require!(
+            market.open_window_end_slot == 0
+                || market.open_window_start_slot < market.open_window_end_slot,
+            ErrorCode::InvalidInput
+        );
 
-        // Check balance
-        if trader_balance.available_balance() < collateral {
-            return err!(ErrorCode::NotEnoughBalance);
+        emit!(MarketUpdated {
+            admin: ctx.accounts.signer.key(),
+            pair,
+            feed_id,
+            open_window_start_slot,
+            open_window_end_slot,
+            reduce_only,
+            max_leverage,
+            maintainance_margin,
+            feed_id_version: market.feed_id_version,
+            slot: Clock::get()?.slot,
+        });
+        Ok(())
+    }
+
+    /// Flag `pair`'s feed `Suspect`, blocking new opens
+    ///
+    /// Open to any keeper, but only with evidence from a fresh price update, see
+    /// `is_feed_suspect`. Fails with `NoActionRequired` otherwise.
+    pub fn flag_feed_suspect(ctx: Context<FlagFeedSuspect>, pair: String) -> Result<()> {
+        let market = &mut ctx.accounts.market;
+        let clock = Clock::get()?;
+        require!(
+            market.feed_status == FeedStatus::Healthy,
+            ErrorCode::NoActionRequired
+        );
+        require!(
+            is_feed_suspect(
+                &ctx.accounts.price_update,
+                &market.feed_id,
+                &clock,
+                ctx.accounts.config.max_spot_ema_divergence_bps,
+            )?,
+            ErrorCode::NoActionRequired
+        );
+
+        market.feed_status = FeedStatus::Suspect;
+        emit!(FeedStatusChanged {
+            pair,
+            changed_by: ctx.accounts.signer.key(),
+            previous_status: FeedStatus::Healthy,
+            feed_status: FeedStatus::Suspect,
+            slot: clock.slot,
+        });
+        Ok(())
+    }
+
+    /// Flag `pair` for retirement so its remaining positions can be force-expired
+    ///
+    /// The market becomes reduce-only for good. Can be called again to adjust the
+    /// settlement price, force-expiry only starts once one is set.
+    ///
+    /// # Arguments
+    /// * `settlement_price` - USD per whole token with 6 decimals, 0 to retire without one yet
+    pub fn retire_market(
+        ctx: Context<RetireMarket>,
+        pair: String,
+        settlement_price: u64,
+    ) -> Result<()> {
+        require!(
+            ctx.accounts.signer.key() == ctx.accounts.config.admin,
+            ErrorCode::Unauthorized
+        );
+        if settlement_price != 0 {
+            validate_price(settlement_price)?;
         }
+        let market = &mut ctx.accounts.market;
+        market.retired = true;
+        market.reduce_only = true;
+        market.settlement_price = settlement_price;
 
-        // Get current price from oracle
-        let current_price =
-            get_normalized_price(&ctx.accounts.price_update, &market.feed_id, &Clock::get()?)?;
+        emit!(MarketRetired {
+            admin: ctx.accounts.signer.key(),
+            pair,
+            settlement_price,
+            total_active_positions: market.total_active_positions,
+            slot: Clock::get()?.slot,
+        });
+        Ok(())
+    }
 
-        // Validate current price
-        validate_price(current_price)?;
+    /// Set the funding rate of a fully one-sided `pair`, admin only
+    ///
+    /// This is the unsigned maximum, not the rate positions pay: that floats with the
+    /// long/short skew up to this and takes its sign from the dominant side, see
+    /// `Market::funding_rate_for`. Slots not yet settled accrue at the new rate, run
+    /// `settle_market_funding` first to close them out at the old one.
+    ///
+    /// # Arguments
+    /// * `funding_rate_bps` - Per `SLOTS_PER_8_HOURS`, from 0 to `MAX_FUNDING_RATE_BPS`
+    pub fn set_funding_rate(
+        ctx: Context<SetFundingRate>,
+        pair: String,
+        funding_rate_bps: i64,
+    ) -> Result<()> {
+        require!(
+            ctx.accounts.signer.key() == ctx.accounts.config.admin,
+            ErrorCode::Unauthorized
+        );
+        require!(
+            (0..=MAX_FUNDING_RATE_BPS).contains(&funding_rate_bps),
+            ErrorCode::InvalidFundingRate
+        );
+        let market = &mut ctx.accounts.market;
+        let previous_rate_bps = market.funding_rate_bps;
+        market.funding_rate_bps = funding_rate_bps;
+
+        emit!(FundingRateSet {
+            admin: ctx.accounts.signer.key(),
+            pair,
+            previous_rate_bps,
+            funding_rate_bps,
+            slot: Clock::get()?.slot,
+        });
+        Ok(())
+    }
+
+    /// Admin override of `pair`'s feed status, e.g. confirming `Halted` or restoring `Healthy`
+    pub fn set_feed_status(
+        ctx: Context<SetFeedStatus>,
+        pair: String,
+        feed_status: FeedStatus,
+    ) -> Result<()> {
+        require!(
+            ctx.accounts.signer.key() == ctx.accounts.config.admin,
+            ErrorCode::Unauthorized
+        );
+        let market = &mut ctx.accounts.market;
+        let previous_status = market.feed_status;
+        market.feed_status = feed_status;
+
+        emit!(FeedStatusChanged {
+            pair,
+            changed_by: ctx.accounts.signer.key(),
+            previous_status,
+            feed_status,
+            slot: Clock::get()?.slot,
+        });
+        Ok(())
+    }
+
+    pub fn snapshot_market_params(
+        ctx: Context<SnapshotMarketParams>,
+        pair: String,
+        epoch: u64,
+    ) -> Result<()> {
+        require!(
+            ctx.accounts.signer.key() == ctx.accounts.config.admin,
+            ErrorCode::Unauthorized
+        );
+        let clock = Clock::get()?;
+        // One snapshot per market per epoch, the PDA can't be re-initialized
+        require_eq!(epoch, clock.epoch, ErrorCode::InvalidEpoch);
+
+        let config = &ctx.accounts.config;
+        let market = &ctx.accounts.market;
+        let snapshot = MarketParamSnapshot {
+            bump: ctx.bumps.snapshot,
+            pair: pair.clone(),
+            epoch,
+            slot: clock.slot,
+            feed_id: market.feed_id.clone(),
+            decimals: market.decimals,
+            max_leverage: market.effective_max_leverage(config.max_leverage),
+            liquidation_fee: config.liquidation_fee,
+            maintainance_margin: market.effective_maintainance_margin(config.maintainance_margin),
+            opening_fee: config.opening_fee,
+            closing_fee: config.closing_fee,
+            privacy_fee: config.privacy_fee,
+            protocol_fee_share: config.protocol_fee_share,
+        };
+
+        let snapshot_hash = hashv(&[&snapshot.try_to_vec()?]).to_bytes();
+        ctx.accounts.snapshot.set_inner(snapshot);
+
+        emit!(MarketParamsSnapshotted {
+            pair,
+            epoch,
+            slot: clock.slot,
+            snapshot_hash,
+        });
+        Ok(())
+    }
+
+    /// Settle funding on the market's open positions passed in `remaining_accounts`
+    ///
+    /// Lets a keeper keep funding current market-wide instead of waiting for each
+    /// position to be touched. Funding accrues on `cumulative_funding_paid` as usual and
+    /// is realized through PnL when the position closes. Closed positions are skipped,
+    /// and positions settled less than `min_funding_interval_slots` ago don't accrue.
+    pub fn settle_market_funding<'info>(
+        ctx: Context<'_, '_, 'info, 'info, SettleMarketFunding<'info>>,
+        pair: String,
+    ) -> Result<()> {
+        let market = &ctx.accounts.market;
+        let clock = Clock::get()?;
+        require!(!ctx.accounts.config.is_paused, ErrorCode::ProgramPaused);
+        require!(!market.is_paused, ErrorCode::ProgramPaused);
+        require!(
+            ctx.remaining_accounts.len() <= MAX_FUNDING_BATCH_SIZE,
+            ErrorCode::InvalidInput
+        );
+
+        let current_price =
+            get_normalized_price(&ctx.accounts.price_update, &market.feed_id, &clock)?;
+        validate_price(current_price)?;
+
+        let mut seen: Vec<Pubkey> = Vec::with_capacity(ctx.remaining_accounts.len());
+        let mut positions_settled: u64 = 0;
+        let mut total_funding_paid: u64 = 0;
+        let mut total_funding_received: u64 = 0;
+
+        for account_info in ctx.remaining_accounts.iter() {
+            require!(!seen.contains(account_info.key), ErrorCode::InvalidInput);
+            seen.push(*account_info.key);
+
+            let mut position = Account::<Position>::try_from(account_info)?;
+            require!(position.pair == pair, ErrorCode::InvalidInput);
+            if position.closed_at != 0 {
+                continue;
+            }
+            require_eq!(
+                position.feed_version_at_open,
+                market.feed_id_version,
+                ErrorCode::FeedVersionMismatch
+            );
+            require!(account_info.is_writable, ErrorCode::InvalidInput);
+
+            let funding_rate_scaled = market.funding_rate_for(position.is_long)?;
+            let funding = position.update_funding_throttled(
+                clock.slot,
+                current_price,
+                funding_rate_scaled,
+                market.decimals,
+                ctx.accounts.config.min_funding_interval_slots,
+            )?;
+            position.exit(&crate::ID)?;
+
+            if let Some(funding) = funding {
+                // The batch doesn't load the `Trader`s, so it can't tell who opted into
+                // privacy and reports no owner at all
+                emit!(position.funding_applied(&funding, Pubkey::default())?);
+                total_funding_paid = total_funding_paid
+                    .checked_add(funding.payment.positive_part()?)
+                    .ok_or(ErrorCode::MathOverflow)?;
+                total_funding_received = total_funding_received
+                    .checked_add(funding.payment.negative_part()?)
+                    .ok_or(ErrorCode::MathOverflow)?;
+            }
+            positions_settled = positions_settled
+                .checked_add(1)
+                .ok_or(ErrorCode::MathOverflow)?;
+        }
+
+        emit!(MarketFundingSettled {
+            pair,
+            positions_settled,
+            total_funding_paid,
+            total_funding_received,
+            funding_rate_scaled: market.funding_rate_for(true)?,
+            price: current_price,
+            slot: clock.slot,
+        });
+        Ok(())
+    }
+
+    /// Pause every market in `remaining_accounts` whose feed id starts with `feed_id_prefix`
+    ///
+    /// Emergency switch for an incident at a price provider. Markets that don't match
+    /// are left untouched, matching ones have to be passed writable.
+    pub fn pause_markets_by_feed<'info>(
+        ctx: Context<'_, '_, 'info, 'info, PauseMarketsByFeed<'info>>,
+        feed_id_prefix: String,
+    ) -> Result<()> {
+        require!(
+            ctx.accounts.signer.key() == ctx.accounts.config.admin,
+            ErrorCode::Unauthorized
+        );
+        require!(!feed_id_prefix.is_empty(), ErrorCode::InvalidInput);
+
+        let mut pairs: Vec<String> = Vec::new();
+        for account_info in ctx.remaining_accounts.iter() {
+            let mut market = Account::<Market>::try_from(account_info)?;
+            if !market.feed_id.starts_with(&feed_id_prefix) || market.is_paused {
+                continue;
+            }
+            require!(account_info.is_writable, ErrorCode::InvalidInput);
+
+            market.is_paused = true;
+            market.exit(&crate::ID)?;
+            pairs.push(market.pair.clone());
+        }
+
+        msg!("Paused {} markets", pairs.len());
+        emit!(MarketsPausedByFeed {
+            admin: ctx.accounts.signer.key(),
+            feed_id_prefix,
+            pairs,
+            slot: Clock::get()?.slot,
+        });
+        Ok(())
+    }
+
+    /// Halt trading on `pair` without pausing the whole protocol, e.g. during an oracle outage
+    ///
+    /// # Arguments
+    /// * `pair` - Market pair
+    pub fn pause_market(ctx: Context<PauseMarket>, pair: String) -> Result<()> {
+        require!(
+            ctx.accounts.signer.key() == ctx.accounts.config.admin,
+            ErrorCode::Unauthorized
+        );
+        let market = &mut ctx.accounts.market;
+        require!(!market.is_paused, ErrorCode::InvalidInput);
+        market.is_paused = true;
+
+        emit!(MarketPauseToggled {
+            admin: ctx.accounts.signer.key(),
+            pair,
+            is_paused: true,
+            slot: Clock::get()?.slot,
+        });
+        Ok(())
+    }
+
+    /// Resume trading on `pair` after `pause_market` or `pause_markets_by_feed`
+    ///
+    /// # Arguments
+    /// * `pair` - Market pair
+    pub fn unpause_market(ctx: Context<UnpauseMarket>, pair: String) -> Result<()> {
+        require!(
+            ctx.accounts.signer.key() == ctx.accounts.config.admin,
+            ErrorCode::Unauthorized
+        );
+        let market = &mut ctx.accounts.market;
+        require!(market.is_paused, ErrorCode::InvalidInput);
+        market.is_paused = false;
+
+        emit!(MarketPauseToggled {
+            admin: ctx.accounts.signer.key(),
+            pair,
+            is_paused: false,
+            slot: Clock::get()?.slot,
+        });
+        Ok(())
+    }
+
+    /// Recompute `pool.trader_collateral` from the open positions passed in
+    /// `remaining_accounts` and emit `CollateralDiscrepancy` if it doesn't match
+    ///
+    /// The check is only meaningful when every open position of the pool is passed.
+    pub fn verify_collateral_invariant<'info>(
+        ctx: Context<'_, '_, 'info, 'info, VerifyCollateralInvariant<'info>>,
+        token_mint: String,
+    ) -> Result<()> {
+        let pool = &ctx.accounts.pool;
+        let mut seen: Vec<Pubkey> = Vec::with_capacity(ctx.remaining_accounts.len());
+        let mut expected_trader_collateral: u64 = 0;
+        let mut positions_checked: u64 = 0;
+
+        for account_info in ctx.remaining_accounts.iter() {
+            // Don't let a duplicated position inflate the sum
+            require!(!seen.contains(account_info.key), ErrorCode::InvalidInput);
+            seen.push(*account_info.key);
+
+            let position = Account::<Position>::try_from(account_info)?;
+            if position.closed_at != 0 || position.token_mint != token_mint {
+                continue;
+            }
+
+            expected_trader_collateral = expected_trader_collateral
+                .checked_add(position.collateral)
+                .ok_or(ErrorCode::MathOverflow)?;
+            positions_checked = positions_checked
+                .checked_add(1)
+                .ok_or(ErrorCode::MathOverflow)?;
+        }
+
+        if expected_trader_collateral != pool.trader_collateral {
+            msg!(
+                "Collateral discrepancy: stored {}, expected {}",
+                pool.trader_collateral,
+                expected_trader_collateral
+            );
+            emit!(CollateralDiscrepancy {
+                token_mint,
+                stored_trader_collateral: pool.trader_collateral,
+                expected_trader_collateral,
+                positions_checked,
+                slot: Clock::get()?.slot,
+            });
+        }
+        Ok(())
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn open_position(
+        ctx: Context<OpenPosition>,
+        token_mint: String,
+        pair: String,
+        position_id: u64,
+        desired_size: u64,
+        desired_entry_price: u64,
+        collateral: u64,
+        is_long: bool,
+        limit_entry: bool,
+        client_order_id: u64,
+        max_slippage_bps: u64,
+    ) -> Result<OpenResult> {
+        require!(!ctx.accounts.config.is_paused, ErrorCode::ProgramPaused);
+        require!(!ctx.accounts.market.is_paused, ErrorCode::ProgramPaused);
+        require!(!ctx.accounts.pool.is_paused, ErrorCode::ProgramPaused);
+        // Closes stay allowed outside the window, only opens are gated
+        require!(
+            ctx.accounts.market.is_open_at(Clock::get()?.slot),
+            ErrorCode::MarketClosed
+        );
+        require!(
+            !ctx.accounts.market.reduce_only,
+            ErrorCode::MarketReduceOnly
+        );
+        ctx.accounts.market.require_feed_healthy()?;
+
+        // A retried open with the same client order id is reported before the id check
+        require!(
+            !ctx.accounts
+                .trader
+                .is_duplicate_order(client_order_id, Clock::get()?.slot),
+            ErrorCode::DuplicateOrder
+        );
+
+        require_eq!(
+            position_id,
+            ctx.accounts.trader.position_count,
+            ErrorCode::InvalidPositionId
+        );
+
+        // Validate inputs
+        validate_collateral(collateral)?;
+        validate_position_size(desired_size)?;
+        validate_price(desired_entry_price)?;
+
+        let config = &mut ctx.accounts.config;
+        let pool = &mut ctx.accounts.pool;
+        let insurance_fund = &mut ctx.accounts.insurance_fund;
+        let market = &mut ctx.accounts.market;
+        let trader = &mut ctx.accounts.trader;
+        let trader_balance = &mut ctx.accounts.trader_balance;
+        let position = &mut ctx.accounts.position;
+
+        // Check balance
+        if trader_balance.available_balance() < collateral {
+            return err!(ErrorCode::NotEnoughBalance);
+        }
+
+        // Get current price from oracle
+        let (current_price, confidence_bps) = get_normalized_price_with_confidence(
+            &ctx.accounts.price_update,
+            &market.feed_id,
+            &Clock::get()?,
+        )?;
+        // Opening adds risk, so it waits out a spot price running away from the EMA
+        check_spot_ema_divergence(
+            &ctx.accounts.price_update,
+            config.max_spot_ema_divergence_bps,
+        )?;
+
+        // Validate current price
+        validate_price(current_price)?;
+        check_slippage(
+            current_price,
+            desired_entry_price,
+            max_slippage_bps,
+            is_long,
+        )?;
+
+        // Rejected upfront, before any state mutation, if the fee eats the collateral
+        let (opening_fee, effective_collateral) = split_opening_collateral(
+            collateral,
+            config.opening_fee,
+            config.min_effective_collateral,
+        )?;
+
+        // Calculate target price, `target_band_bps` above for long and below for short
+        let target_price = calculate_target_price(current_price, market.target_band_bps, is_long)?;
+
+        // Calculate position parameters with effective collateral.
+        // A limit entry only fills at or better than `desired_entry_price` and is opened
+        // without amplification, see `calculate_limit_entry_position`.
+        let PositionParams {
+            actual_size,
+            leverage_bps: _,
+            position_value,
+            target_price: current_target_price,
+        } = if limit_entry {
+            calculate_limit_entry_position(
+                desired_entry_price,
+                desired_size,
+                current_price,
+                target_price,
+                effective_collateral,
+                market.decimals,
+                is_long,
+            )?
+        } else if is_long {
+            calculate_long_position(
+                desired_entry_price,
+                desired_size,
+                current_price,
+                target_price,
+                effective_collateral,
+                market.decimals,
+            )?
+        } else {
+            calculate_short_position(
+                desired_entry_price,
+                desired_size,
+                current_price,
+                target_price,
+                effective_collateral,
+                market.decimals,
+            )?
+        };
+
+        // Validate position parameters
+        validate_position_value(position_value)?;
+        validate_position_size(actual_size)?;
+        // A position must not be rebalance-eligible the moment it opens
+        validate_target_band(
+            current_price,
+            current_target_price,
+            market.target_band_bps,
+            is_long,
+        )?;
+
+        // Re-derive leverage from the rounded value the borrow is computed from
+        let leverage_bps = calculate_leverage_bps(position_value, effective_collateral)?;
+
+        // Validate leverage is within limits
+        require!(
+            leverage_bps <= market.effective_max_leverage(config.max_leverage),
+            ErrorCode::ExcessiveLeverage
+        );
+
+        // Higher leverage demands a tighter oracle confidence
+        require!(
+            confidence_bps
+                <= max_confidence_bps_for_leverage(
+                    leverage_bps,
+                    config.confidence_reference_leverage
+                ),
+            ErrorCode::PriceConfidenceTooHigh
+        );
+
+        // Charge the leverage surcharge on top of the base fee. It is taken from the
+        // free balance so the effective collateral, and with it the leverage, is unchanged.
+        let opening_fee_bps = calculate_opening_fee_bps(
+            config.opening_fee,
+            config.leverage_fee_surcharge,
+            config.max_opening_fee,
+            leverage_bps,
+        )?;
+        let leverage_fee = calculate_fee(collateral, opening_fee_bps)?.saturating_sub(opening_fee);
+        let total_opening_fee = opening_fee
+            .checked_add(leverage_fee)
+            .ok_or(ErrorCode::MathOverflow)?;
+        let required_balance = collateral
+            .checked_add(leverage_fee)
+            .ok_or(ErrorCode::MathOverflow)?;
+        if trader_balance.available_balance() < required_balance {
+            return err!(ErrorCode::NotEnoughBalance);
+        }
+
+        // Reject positions that a single funding period at the full-skew rate, the worst
+        // case, would push below maintenance
+        let funding = calculate_funding_payment(
+            actual_size,
+            current_price,
+            market.max_funding_rate_scaled()?,
+            SLOTS_PER_8_HOURS,
+            market.decimals,
+        )?;
+        let funding_cost = funding.payment.positive_part()?;
+        let maintainance_margin = maintenance_margin_for_tier(
+            market.effective_maintainance_margin(config.maintainance_margin),
+            trader.margin_tier,
+        )?;
+        let maintenance_requirement = proportional(
+            position_value,
+            maintainance_margin as u64,
+            BASIS_POINTS as u64,
+        )?;
+        require!(
+            effective_collateral.saturating_sub(funding_cost) > maintenance_requirement,
+            ErrorCode::EffectiveCollateralTooLow
+        );
+
+        // Check trader's total exposure across all positions
+        let total_notional_exposure =
+            trader.exposure_after_open(position_value, config.max_trader_exposure)?;
+
+        // Cap the market's directional imbalance, see `Market::net_delta_within_cap`
+        let net_delta = market.net_delta_after(is_long, 0, position_value)?;
+        require!(
+            market.net_delta_within_cap(net_delta, config.max_net_delta),
+            ErrorCode::NetDeltaExceeded
+        );
+
+        // Calculate borrowing amount
+        let borrowing_amount = position_value
+            .checked_sub(effective_collateral)
+            .ok_or(ErrorCode::MathOverflow)?;
+
+        // Check pool has sufficient liquidity
+        if pool.available_liquidity() < borrowing_amount {
+            return err!(ErrorCode::InsufficientLiquidity);
+        }
+
+        config.check_borrow(borrowing_amount)?;
+
+        // Split opening fee between insurance, protocol and LPs
+        pool.route_fee(total_opening_fee, config, insurance_fund)?;
+
+        // Deduct opening fee from trader's balance
+        trader_balance.balance = trader_balance
+            .balance
+            .checked_sub(total_opening_fee)
+            .ok_or(ErrorCode::MathOverflow)?;
+        pool.forfeit_trader_deposit(trader_balance)?;
+
+        // Lock effective collateral
+        trader_balance.lock(effective_collateral)?;
+
+        // Update pool accounting
+        pool.lock_trader_collateral(effective_collateral)?;
+        pool.check_covers_collateral(effective_collateral)?;
+
+        pool.total_borrowed = pool
+            .total_borrowed
+            .checked_add(borrowing_amount)
+            .ok_or(ErrorCode::MathOverflow)?;
+
+        // Initialize position
+        position.set_inner(Position {
+            owner: ctx.accounts.signer.key(),
+            bump: ctx.bumps.position,
+            entered_at: Clock::get()?.slot,
+            closed_at: 0,
+            entered_at_unix: Clock::get()?.unix_timestamp,
+            closed_at_unix: 0,
+            last_funding_slot: Clock::get()?.slot,
+            cumulative_funding_paid: 0,
+            position_id,
+            is_long,
+            token_mint,
+            pair,
+            current_target_price,
+            desired_entry_price,
+            desired_size,
+            collateral: effective_collateral,
+            actual_size,
+            actual_entered_price: current_price,
+            current_price,
+            position_value,
+            leverage: leverage_bps,
+            last_updated: Clock::get()?.slot,
+            take_profit_price: 0,
+            stop_loss_price: 0,
+            last_funding_rate_scaled: 0,
+            feed_version_at_open: market.feed_id_version,
+            client_order_id,
+            last_health_ratio_bps: 0,
+            last_health_slot: 0,
+            opening_fee_paid: total_opening_fee,
+            last_warning_slot: 0,
+            booked_borrow: borrowing_amount,
+            liquidation_attempts: 0,
+            last_liquidation_attempt_slot: 0,
+        });
+        position.cache_health(
+            current_price,
+            market.effective_maintainance_margin(config.maintainance_margin),
+            trader.margin_tier,
+            market.decimals,
+            Clock::get()?.slot,
+        )?;
+
+        trader.position_count = trader
+            .position_count
+            .checked_add(1)
+            .ok_or(ErrorCode::MathOverflow)?;
+
+        trader.active_position = trader
+            .active_position
+            .checked_add(1)
+            .ok_or(ErrorCode::MathOverflow)?;
+
+        trader.total_notional_exposure = total_notional_exposure;
+        market.shift_open_interest(is_long, 0, position_value)?;
+
+        trader.record_client_order(client_order_id, Clock::get()?.slot);
+
+        market.total_active_positions = market
+            .total_active_positions
+            .checked_add(1)
+            .ok_or(ErrorCode::MathOverflow)?;
+        config.track_position_opened()?;
+
+        // Built from the stored position so the return always matches the account
+        let liquidation_price =
+            calculate_liquidation_price(position, maintainance_margin, market.decimals)?;
+
+        emit!(PositionOpened {
+            owner: trader.event_owner(),
+            pair: position.pair.clone(),
+            position_id: position.position_id,
+            is_long,
+            desired_size,
+            desired_entry_price,
+            actual_size: position.actual_size,
+            actual_entered_price: position.actual_entered_price,
+            collateral: position.collateral,
+            leverage: position.leverage,
+            position_value: position.position_value,
+            opening_fee: total_opening_fee,
+            slot: position.entered_at,
+        });
+
+        Ok(OpenResult {
+            position_id: position.position_id,
+            actual_size: position.actual_size,
+            actual_entered_price: position.actual_entered_price,
+            leverage: position.leverage,
+            position_value: position.position_value,
+            liquidation_price,
+            opening_fee: total_opening_fee,
+        })
+    }
+
+    /// Same as `open_position`, but the size is given as a USD notional at
+    /// `desired_entry_price` instead of token units
+    #[allow(clippy::too_many_arguments)]
+    pub fn open_position_by_notional(
+        ctx: Context<OpenPosition>,
+        token_mint: String,
+        pair: String,
+        position_id: u64,
+        desired_notional_usd: u64,
+        desired_entry_price: u64,
+        collateral: u64,
+        is_long: bool,
+        limit_entry: bool,
+        client_order_id: u64,
+        max_slippage_bps: u64,
+    ) -> Result<OpenResult> {
+        validate_price(desired_entry_price)?;
+
+        let desired_size = notional_to_size(
+            desired_notional_usd,
+            desired_entry_price,
+            ctx.accounts.market.decimals,
+        )?;
+        validate_position_size(desired_size)?;
+
+        open_position(
+            ctx,
+            token_mint,
+            pair,
+            position_id,
+            desired_size,
+            desired_entry_price,
+            collateral,
+            is_long,
+            limit_entry,
+            client_order_id,
+            max_slippage_bps,
+        )
+    }
+
+    /// Refresh a position's funding and mark it at the current price
+    ///
+    /// Settles funding up to now and re-marks `current_price`, `position_value` and
+    /// `leverage`, so clients can read a live state without simulating a close. The
+    /// trader's exposure follows the re-marked value, the pool borrow is untouched.
+    pub fn update_position(
+        ctx: Context<UpdatePosition>,
+        _token_mint: String,
+        _pair: String,
+        _position_id: u64,
+    ) -> Result<()> {
+        let config = &ctx.accounts.config;
+        let market = &mut ctx.accounts.market;
+        let trader = &mut ctx.accounts.trader;
+        let position = &mut ctx.accounts.position;
+        let clock = Clock::get()?;
+
+        require!(position.closed_at == 0, ErrorCode::PositionAlreadyClosed);
+        require!(!config.is_paused, ErrorCode::ProgramPaused);
+        require!(!market.is_paused, ErrorCode::ProgramPaused);
+        require!(!ctx.accounts.pool.is_paused, ErrorCode::ProgramPaused);
+        // Marking against a swapped feed would corrupt the position, as in `close_position`
+        require_eq!(
+            position.feed_version_at_open,
+            market.feed_id_version,
+            ErrorCode::FeedVersionMismatch
+        );
+        market.require_feed_not_halted()?;
+
+        let current_price =
+            get_normalized_price(&ctx.accounts.price_update, &market.feed_id, &clock)?;
+        validate_price(current_price)?;
+
+        let funding_rate_scaled = market.funding_rate_for(position.is_long)?;
+        let funding = position.update_funding(
+            clock.slot,
+            current_price,
+            funding_rate_scaled,
+            market.decimals,
+        )?;
+        emit!(position.funding_applied(&funding, trader.event_owner())?);
+
+        let position_value = notional_value(position.actual_size, current_price, market.decimals)?;
+        trader.total_notional_exposure = trader
+            .total_notional_exposure
+            .checked_sub(position.position_value)
+            .ok_or(ErrorCode::MathOverflow)?
+            .checked_add(position_value)
+            .ok_or(ErrorCode::MathOverflow)?;
+        market.shift_open_interest(position.is_long, position.position_value, position_value)?;
+
+        position.current_price = current_price;
+        position.position_value = position_value;
+        // A mark can take the notional below the collateral, so no `LeverageBelowOne` here
+        require!(position.collateral > 0, ErrorCode::CollateralTooLow);
+        position.leverage = proportional(position_value, BASIS_POINTS as u64, position.collateral)?;
+        position.last_updated = clock.slot;
+        position.cache_health(
+            current_price,
+            market.effective_maintainance_margin(config.maintainance_margin),
+            trader.margin_tier,
+            market.decimals,
+            clock.slot,
+        )?;
+        Ok(())
+    }
+
+    /// Close a position at the current price, by its owner or their delegate
+    pub fn close_position(
+        ctx: Context<ClosePosition>,
+        _token_mint: String,
+        _pair: String,
+        _owner: Pubkey,
+        _position_id: u64,
+        allow_stale_close: bool,
+    ) -> Result<()> {
+        require!(
+            ctx.accounts.trader.can_manage(ctx.accounts.signer.key()),
+            ErrorCode::Unauthorized
+        );
+
+        let position = &mut ctx.accounts.position;
+        let pool = &mut ctx.accounts.pool;
+        let insurance_fund = &mut ctx.accounts.insurance_fund;
+        let trader_balance = &mut ctx.accounts.trader_balance;
+        let trader = &mut ctx.accounts.trader;
+        let market = &mut ctx.accounts.market;
+        let config = &mut ctx.accounts.config;
+        let clock = Clock::get()?;
+
+        // Validate
+        require!(position.closed_at == 0, ErrorCode::PositionAlreadyClosed);
+        require!(!config.is_paused, ErrorCode::ProgramPaused);
+        require!(!market.is_paused, ErrorCode::ProgramPaused);
+        require!(!pool.is_paused, ErrorCode::ProgramPaused);
+        // The feed was swapped since the open, pricing against it would corrupt PnL
+        require_eq!(
+            position.feed_version_at_open,
+            market.feed_id_version,
+            ErrorCode::FeedVersionMismatch
+        );
+        market.require_feed_not_halted()?;
+
+        // Stale oracle fail-safe: when explicitly requested, a position can be closed at
+        // its `actual_entered_price` so the collateral isn't trapped until the feed
+        // recovers. The trader gives up any profit and no final funding is settled, only
+        // already settled funding and the closing fee apply. If the last known price
+        // shows a loss, the loss is still realized so a stale feed can't be used to
+        // escape a losing position.
+        let stale_close = allow_stale_close && is_price_stale(&ctx.accounts.price_update, &clock);
+
+        let current_price = if stale_close {
+            let last_known_price =
+                get_last_known_price(&ctx.accounts.price_update, &market.feed_id)?;
+            msg!("Stale price, closing at entry price");
+            stale_close_price(position, last_known_price)
+        } else {
+            // Get current price
+            let current_price = get_exit_price(
+                &ctx.accounts.price_update,
+                &market.feed_id,
+                &clock,
+                config.max_spot_ema_divergence_bps,
+                market.feed_status == FeedStatus::Suspect,
+                position.is_long,
+            )?;
+
+            // Validate price
+            validate_price(current_price)?;
+
+            // The whole gap is still settled below, this only flags it
+            let funding_slots_elapsed = clock.slot.saturating_sub(position.last_funding_slot);
+            if config.max_funding_staleness_slots != 0
+                && funding_slots_elapsed > config.max_funding_staleness_slots
+            {
+                msg!(
+                    "Warning: settling {} slots of funding at close",
+                    funding_slots_elapsed
+                );
+                emit!(StaleFundingSettled {
+                    owner: trader.event_owner(),
+                    position_id: position.position_id,
+                    slots_elapsed: funding_slots_elapsed,
+                    max_funding_staleness_slots: config.max_funding_staleness_slots,
+                    slot: clock.slot,
+                });
+            }
+
+            // Final funding update
+            let funding_rate_scaled = market.funding_rate_for(position.is_long)?;
+            let funding = position.update_funding(
+                clock.slot,
+                current_price,
+                funding_rate_scaled,
+                market.decimals,
+            )?;
+            emit!(position.funding_applied(&funding, trader.event_owner())?);
+
+            current_price
+        };
+
+        // Calculate PnL, closing fee and final amount to return to trader
+        let close = calculate_close(position, current_price, config.closing_fee, market.decimals)?;
+
+        // Split fee between insurance, protocol and LPs
+        pool.route_fee(close.closing_fee, config, insurance_fund)?;
+        pool.absorb_bad_debt(close.bad_debt, clock.slot, config, insurance_fund)?;
+
+        settle_closed_position(
+            pool,
+            trader,
+            trader_balance,
+            market,
+            position,
+            config,
+            &close.payout(0),
+            &clock,
+        )?;
+
+        // Convert PnL to signed integer and record it against the market
+        let final_pnl = close.net_pnl.to_i64()?;
+        market.cumulative_trader_pnl = market
+            .cumulative_trader_pnl
+            .checked_add(final_pnl)
+            .ok_or(ErrorCode::MathOverflow)?;
+
+        // gross_price_pnl - total_funding_paid = net_pnl, the opening fee was already
+        // taken from the balance at open and the closing fee comes out of the payout
+        emit!(PositionClosed {
+            owner: trader.event_owner(),
+            pair: position.pair.clone(),
+            position_id: position.position_id,
+            price: current_price,
+            gross_price_pnl: close.gross_price_pnl,
+            total_funding_paid: position.cumulative_funding_paid,
+            opening_fee_paid: position.opening_fee_paid,
+            closing_fee_paid: close.closing_fee,
+            net_pnl: final_pnl,
+            amount_to_return: close.amount_to_return,
+            bad_debt: close.bad_debt,
+            stale_close,
+            entered_at_unix: position.entered_at_unix,
+            closed_at_unix: position.closed_at_unix,
+            slot: clock.slot,
+        });
+        Ok(())
+    }
+
+    /// Close a position on a retired market on the owner's behalf
+    ///
+    /// Any keeper can call this once the market is flagged with `retire_market` and the
+    /// admin has set a `settlement_price`. The position settles at that price with no
+    /// closing fee, a fallback to the feed would let keepers race the admin's price.
+    /// Collateral plus PnL is credited to the owner's balance and the position rent goes
+    /// back to the owner.
+    pub fn force_expire_position(
+        ctx: Context<ForceExpirePosition>,
+        _token_mint: String,
+        _pair: String,
+        _owner: Pubkey,
+        _position_id: u64,
+    ) -> Result<()> {
+        let position = &mut ctx.accounts.position;
+        let pool = &mut ctx.accounts.pool;
+        let insurance_fund = &mut ctx.accounts.insurance_fund;
+        let trader_balance = &mut ctx.accounts.trader_balance;
+        let trader = &mut ctx.accounts.trader;
+        let market = &mut ctx.accounts.market;
+        let config = &mut ctx.accounts.config;
+        let clock = Clock::get()?;
+
+        // Validate
+        require!(market.retired, ErrorCode::MarketNotRetired);
+        require!(position.closed_at == 0, ErrorCode::PositionAlreadyClosed);
+        require!(!config.is_paused, ErrorCode::ProgramPaused);
+        require!(!pool.is_paused, ErrorCode::ProgramPaused);
+
+        require!(
+            market.settlement_price != 0,
+            ErrorCode::SettlementPriceNotSet
+        );
+        let settlement_price = market.settlement_price;
+        validate_price(settlement_price)?;
+
+        // Final funding update
+        let funding_rate_scaled = market.funding_rate_for(position.is_long)?;
+        let funding = position.update_funding(
+            clock.slot,
+            settlement_price,
+            funding_rate_scaled,
+            market.decimals,
+        )?;
+        emit!(position.funding_applied(&funding, trader.event_owner())?);
+
+        // Forced by the protocol, so no closing fee
+        let close = calculate_close(position, settlement_price, 0, market.decimals)?;
+        pool.absorb_bad_debt(close.bad_debt, clock.slot, config, insurance_fund)?;
+
+        settle_closed_position(
+            pool,
+            trader,
+            trader_balance,
+            market,
+            position,
+            config,
+            &close.payout(0),
+            &clock,
+        )?;
+
+        let final_pnl = close.net_pnl.to_i64()?;
+        market.cumulative_trader_pnl = market
+            .cumulative_trader_pnl
+            .checked_add(final_pnl)
+            .ok_or(ErrorCode::MathOverflow)?;
+
+        emit!(PositionExpired {
+            keeper: ctx.accounts.signer.key(),
+            owner: trader.event_owner(),
+            pair: position.pair.clone(),
+            position_id: position.position_id,
+            price: settlement_price,
+            gross_price_pnl: close.gross_price_pnl,
+            total_funding_paid: position.cumulative_funding_paid,
+            net_pnl: final_pnl,
+            amount_to_return: close.amount_to_return,
+            bad_debt: close.bad_debt,
+            slot: clock.slot,
+        });
+        Ok(())
+    }
+
+    /// Close a position whose market swapped feeds since the open, at an admin price
+    ///
+    /// Every pricing path rejects such a position with `FeedVersionMismatch`, so this is
+    /// the only way out of it. `settlement_price` is the price on the feed the position
+    /// was opened against. It settles like `force_expire_position`, with no closing fee,
+    /// and the position rent goes back to the owner.
+    ///
+    /// # Arguments
+    /// * `settlement_price` - USD per whole token with 6 decimals, on the old feed
+    pub fn settle_stranded_position(
+        ctx: Context<SettleStrandedPosition>,
+        _token_mint: String,
+        _pair: String,
+        _owner: Pubkey,
+        _position_id: u64,
+        settlement_price: u64,
+    ) -> Result<()> {
+        let position = &mut ctx.accounts.position;
+        let pool = &mut ctx.accounts.pool;
+        let insurance_fund = &mut ctx.accounts.insurance_fund;
+        let trader_balance = &mut ctx.accounts.trader_balance;
+        let trader = &mut ctx.accounts.trader;
+        let market = &mut ctx.accounts.market;
+        let config = &mut ctx.accounts.config;
+        let clock = Clock::get()?;
+
+        require!(
+            ctx.accounts.signer.key() == config.admin,
+            ErrorCode::Unauthorized
+        );
+        require!(position.closed_at == 0, ErrorCode::PositionAlreadyClosed);
+        // Positions on the current feed close through the usual paths
+        require!(
+            position.feed_version_at_open != market.feed_id_version,
+            ErrorCode::InvalidInput
+        );
+        validate_price(settlement_price)?;
+
+        let funding_rate_scaled = market.funding_rate_for(position.is_long)?;
+        let funding = position.update_funding(
+            clock.slot,
+            settlement_price,
+            funding_rate_scaled,
+            market.decimals,
+        )?;
+        emit!(position.funding_applied(&funding, trader.event_owner())?);
+
+        // Stranded by the protocol, so no closing fee
+        let close = calculate_close(position, settlement_price, 0, market.decimals)?;
+        pool.absorb_bad_debt(close.bad_debt, clock.slot, config, insurance_fund)?;
+
+        settle_closed_position(
+            pool,
+            trader,
+            trader_balance,
+            market,
+            position,
+            config,
+            &close.payout(0),
+            &clock,
+        )?;
+
+        let final_pnl = close.net_pnl.to_i64()?;
+        market.cumulative_trader_pnl = market
+            .cumulative_trader_pnl
+            .checked_add(final_pnl)
+            .ok_or(ErrorCode::MathOverflow)?;
+
+        emit!(StrandedPositionSettled {
+            admin: ctx.accounts.signer.key(),
+            owner: trader.event_owner(),
+            pair: position.pair.clone(),
+            position_id: position.position_id,
+            feed_version_at_open: position.feed_version_at_open,
+            price: settlement_price,
+            gross_price_pnl: close.gross_price_pnl,
+            total_funding_paid: position.cumulative_funding_paid,
+            net_pnl: final_pnl,
+            amount_to_return: close.amount_to_return,
+            bad_debt: close.bad_debt,
+            slot: clock.slot,
+        });
+        Ok(())
+    }
+
+    /// Close `close_fraction_bps` of a position and keep the rest open
+    ///
+    /// The closed part realizes its share of the PnL and settled funding, the closing
+    /// fee is charged on the closed notional only. Use `close_position` to close it all.
+    pub fn partial_close_position(
+        ctx: Context<PartialClosePosition>,
+        _token_mint: String,
+        _pair: String,
+        _position_id: u64,
+        close_fraction_bps: u64,
+    ) -> Result<()> {
+        let config = &ctx.accounts.config;
+        let market = &mut ctx.accounts.market;
+        let pool = &mut ctx.accounts.pool;
+        let insurance_fund = &mut ctx.accounts.insurance_fund;
+        let trader = &mut ctx.accounts.trader;
+        let trader_balance = &mut ctx.accounts.trader_balance;
+        let position = &mut ctx.accounts.position;
+        let clock = Clock::get()?;
+
+        require!(!config.is_paused, ErrorCode::ProgramPaused);
+        require!(!market.is_paused, ErrorCode::ProgramPaused);
+        require!(!pool.is_paused, ErrorCode::ProgramPaused);
+        require!(position.closed_at == 0, ErrorCode::PositionAlreadyClosed);
+        require!(
+            close_fraction_bps > 0 && close_fraction_bps < BASIS_POINTS as u64,
+            ErrorCode::InvalidInput
+        );
+        // Pricing against a swapped feed would corrupt PnL, as in `close_position`
+        require_eq!(
+            position.feed_version_at_open,
+            market.feed_id_version,
+            ErrorCode::FeedVersionMismatch
+        );
+        market.require_feed_not_halted()?;
+
+        let current_price = get_exit_price(
+            &ctx.accounts.price_update,
+            &market.feed_id,
+            &clock,
+            config.max_spot_ema_divergence_bps,
+            market.feed_status == FeedStatus::Suspect,
+            position.is_long,
+        )?;
+        validate_price(current_price)?;
+
+        let funding_rate_scaled = market.funding_rate_for(position.is_long)?;
+        let funding = position.update_funding(
+            clock.slot,
+            current_price,
+            funding_rate_scaled,
+            market.decimals,
+        )?;
+        emit!(position.funding_applied(&funding, trader.event_owner())?);
+
+        let previous_size = position.actual_size;
+        let closed_size = proportional(previous_size, close_fraction_bps, BASIS_POINTS as u64)?;
+        let remaining_size = previous_size
+            .checked_sub(closed_size)
+            .ok_or(ErrorCode::MathOverflow)?;
+        let reduction = calculate_reduction(
+            position,
+            current_price,
+            remaining_size,
+            config.closing_fee,
+            market.decimals,
+        )?;
+
+        position.apply_reduction(&reduction)?;
+        // What stays open has to be a valid position on its own
+        validate_position_value(position.position_value)?;
+        position.current_price = current_price;
+        position.last_updated = clock.slot;
+
+        // Route the fee before the open interest shrinks, like a full close does
+        pool.route_fee(reduction.closing_fee, config, insurance_fund)?;
+        pool.absorb_bad_debt(reduction.bad_debt, clock.slot, config, insurance_fund)?;
+
+        pool.release_borrowed(reduction.closed_borrowed);
+        pool.release_trader_collateral(reduction.closed_collateral);
+        pool.check_covers_collateral(position.collateral)?;
+
+        trader_balance.settle(reduction.closed_collateral, reduction.amount_to_return)?;
+        pool.forfeit_trader_deposit(trader_balance)?;
+
+        trader.total_notional_exposure = trader
+            .total_notional_exposure
+            .checked_sub(reduction.closed_value)
+            .ok_or(ErrorCode::MathOverflow)?;
+        market.shift_open_interest(position.is_long, reduction.closed_value, 0)?;
+
+        let realized_pnl = reduction.realized_pnl.to_i64()?;
+        market.cumulative_trader_pnl = market
+            .cumulative_trader_pnl
+            .checked_add(realized_pnl)
+            .ok_or(ErrorCode::MathOverflow)?;
+
+        emit!(PositionPartiallyClosed {
+            owner: trader.event_owner(),
+            pair: position.pair.clone(),
+            position_id: position.position_id,
+            close_fraction_bps,
+            previous_size,
+            remaining_size,
+            price: current_price,
+            closed_collateral: reduction.closed_collateral,
+            realized_pnl,
+            closing_fee: reduction.closing_fee,
+            amount_to_return: reduction.amount_to_return,
+            bad_debt: reduction.bad_debt,
+            slot: clock.slot,
+        });
+        Ok(())
+    }
+
+    /// Top up a position's margin from the trader's available balance
+    ///
+    /// The notional is unchanged, so the top-up replaces the same amount of the pool
+    /// borrow and the leverage drops. A top-up that would take the collateral past the
+    /// notional fails with `LeverageBelowOne`.
+    pub fn add_collateral(
+        ctx: Context<AddCollateral>,
+        _token_mint: String,
+        _pair: String,
+        _position_id: u64,
+        amount: u64,
+    ) -> Result<()> {
+        let pool = &mut ctx.accounts.pool;
+        let trader_balance = &mut ctx.accounts.trader_balance;
+        let position = &mut ctx.accounts.position;
+
+        // Topping up only lowers risk, so a paused market doesn't block it
+        require!(!ctx.accounts.config.is_paused, ErrorCode::ProgramPaused);
+        require!(!pool.is_paused, ErrorCode::ProgramPaused);
+        require!(position.closed_at == 0, ErrorCode::PositionAlreadyClosed);
+        require!(amount > 0, ErrorCode::InvalidInput);
+        require!(
+            trader_balance.available_balance() >= amount,
+            ErrorCode::NotEnoughBalance
+        );
+
+        let previous_collateral = position.collateral;
+        position.collateral = position
+            .collateral
+            .checked_add(amount)
+            .ok_or(ErrorCode::MathOverflow)?;
+        validate_collateral(position.collateral)?;
+        position.refresh_leverage()?;
+        position.last_updated = Clock::get()?.slot;
+
+        // The trader's margin now funds what the pool lent
+        position.booked_borrow = position
+            .booked_borrow
+            .checked_sub(amount)
+            .ok_or(ErrorCode::LeverageBelowOne)?;
+        pool.release_borrowed(amount);
+
+        trader_balance.lock(amount)?;
+        pool.lock_trader_collateral(amount)?;
+        pool.check_covers_collateral(position.collateral)?;
+
+        emit!(CollateralAdded {
+            owner: ctx.accounts.trader.event_owner(),
+            pair: position.pair.clone(),
+            position_id: position.position_id,
+            amount,
+            previous_collateral,
+            collateral: position.collateral,
+            leverage: position.leverage,
+            slot: position.last_updated,
+        });
+        Ok(())
+    }
+
+    /// Withdraw excess margin from a healthy position back to the available balance
+    ///
+    /// Funding is settled first. What's left has to keep the position at
+    /// `MIN_HEALTH_AFTER_WITHDRAWAL_BPS` health and within `max_leverage`. The notional is
+    /// unchanged, so the pool lends what the collateral loses.
+    pub fn remove_collateral(
+        ctx: Context<RemoveCollateral>,
+        _token_mint: String,
+        _pair: String,
+        _position_id: u64,
+        amount: u64,
+    ) -> Result<()> {
+        let config = &ctx.accounts.config;
+        let market = &ctx.accounts.market;
+        let pool = &mut ctx.accounts.pool;
+        let trader_balance = &mut ctx.accounts.trader_balance;
+        let position = &mut ctx.accounts.position;
+        let clock = Clock::get()?;
+
+        require!(!config.is_paused, ErrorCode::ProgramPaused);
+        require!(!market.is_paused, ErrorCode::ProgramPaused);
+        require!(!pool.is_paused, ErrorCode::ProgramPaused);
+        require!(position.closed_at == 0, ErrorCode::PositionAlreadyClosed);
+        require!(amount > 0, ErrorCode::InvalidInput);
+        require_eq!(
+            position.feed_version_at_open,
+            market.feed_id_version,
+            ErrorCode::FeedVersionMismatch
+        );
+        // Margin only leaves against a price that can be trusted
+        market.require_feed_healthy()?;
+
+        let current_price =
+            get_normalized_price(&ctx.accounts.price_update, &market.feed_id, &clock)?;
+        check_spot_ema_divergence(
+            &ctx.accounts.price_update,
+            config.max_spot_ema_divergence_bps,
+        )?;
+        validate_price(current_price)?;
+
+        let funding_rate_scaled = market.funding_rate_for(position.is_long)?;
+        let funding = position.update_funding(
+            clock.slot,
+            current_price,
+            funding_rate_scaled,
+            market.decimals,
+        )?;
+        emit!(position.funding_applied(&funding, ctx.accounts.trader.event_owner())?);
+
+        let previous_collateral = position.collateral;
+        position.collateral = position
+            .collateral
+            .checked_sub(amount)
+            .ok_or(ErrorCode::CollateralTooLow)?;
+        validate_collateral(position.collateral)?;
+        position.refresh_leverage()?;
+        require!(
+            position.leverage <= market.effective_max_leverage(config.max_leverage),
+            ErrorCode::ExcessiveLeverage
+        );
+
+        let health_ratio_bps = calculate_health_ratio(
+            position,
+            current_price,
+            market.effective_maintainance_margin(config.maintainance_margin),
+            ctx.accounts.trader.margin_tier,
+            market.decimals,
+        )?;
+        require!(
+            health_ratio_bps >= MIN_HEALTH_AFTER_WITHDRAWAL_BPS,
+            ErrorCode::EffectiveCollateralTooLow
+        );
+        position.current_price = current_price;
+        position.last_updated = clock.slot;
+        position.cache_health(
+            current_price,
+            market.effective_maintainance_margin(config.maintainance_margin),
+            ctx.accounts.trader.margin_tier,
+            market.decimals,
+            clock.slot,
+        )?;
+
+        position.booked_borrow = position
+            .booked_borrow
+            .checked_add(amount)
+            .ok_or(ErrorCode::MathOverflow)?;
+        config.check_borrow(position.booked_borrow)?;
+        if pool.available_liquidity() < amount {
+            return err!(ErrorCode::InsufficientLiquidity);
+        }
+        pool.total_borrowed = pool
+            .total_borrowed
+            .checked_add(amount)
+            .ok_or(ErrorCode::MathOverflow)?;
+
+        trader_balance.unlock(amount)?;
+        pool.release_trader_collateral(amount);
+        pool.check_covers_collateral(position.collateral)?;
+
+        emit!(CollateralRemoved {
+            owner: ctx.accounts.trader.event_owner(),
+            pair: position.pair.clone(),
+            position_id: position.position_id,
+            amount,
+            previous_collateral,
+            collateral: position.collateral,
+            leverage: position.leverage,
+            health_ratio_bps,
+            slot: clock.slot,
+        });
+        Ok(())
+    }
+
+    /// Split `fraction_bps` of a position into a new position at `new_position_id`
+    ///
+    /// Funding is settled first so both parts carry their share of it. Pool borrow and
+    /// trader exposure are unchanged since the two parts sum to the original.
+    pub fn split_position(
+        ctx: Context<SplitPosition>,
+        _pair: String,
+        _position_id: u64,
+        new_position_id: u64,
+        fraction_bps: u64,
+    ) -> Result<()> {
+        let config = &mut ctx.accounts.config;
+        let market = &mut ctx.accounts.market;
+        let trader = &mut ctx.accounts.trader;
+        let position = &mut ctx.accounts.position;
+        let clock = Clock::get()?;
+
+        require!(!config.is_paused, ErrorCode::ProgramPaused);
+        require!(!market.is_paused, ErrorCode::ProgramPaused);
+        require!(position.closed_at == 0, ErrorCode::PositionAlreadyClosed);
+        require_eq!(
+            position.feed_version_at_open,
+            market.feed_id_version,
+            ErrorCode::FeedVersionMismatch
+        );
+        require_eq!(
+            new_position_id,
+            trader.position_count,
+            ErrorCode::InvalidPositionId
+        );
+
+        let current_price =
+            get_normalized_price(&ctx.accounts.price_update, &market.feed_id, &clock)?;
+        validate_price(current_price)?;
+
+        let funding_rate_scaled = market.funding_rate_for(position.is_long)?;
+        let funding = position.update_funding(
+            clock.slot,
+            current_price,
+            funding_rate_scaled,
+            market.decimals,
+        )?;
+        emit!(position.funding_applied(&funding, trader.event_owner())?);
+
+        let mut new_position = position.split_off(fraction_bps)?;
+        new_position.bump = ctx.bumps.new_position;
+        new_position.position_id = new_position_id;
+        new_position.last_updated = clock.slot;
+        position.last_updated = clock.slot;
+
+        // Both parts have to stand on their own
+        for part in [&**position, &new_position] {
+            validate_position_value(part.position_value)?;
+            validate_position_size(part.actual_size)?;
+            require!(
+                part.leverage <= market.effective_max_leverage(config.max_leverage),
+                ErrorCode::ExcessiveLeverage
+            );
+        }
+
+        ctx.accounts.new_position.set_inner(new_position);
+
+        trader.position_count = trader
+            .position_count
+            .checked_add(1)
+            .ok_or(ErrorCode::MathOverflow)?;
+
+        trader.active_position = trader
+            .active_position
+            .checked_add(1)
+            .ok_or(ErrorCode::MathOverflow)?;
+
+        market.total_active_positions = market
+            .total_active_positions
+            .checked_add(1)
+            .ok_or(ErrorCode::MathOverflow)?;
+        config.track_position_opened()?;
+        Ok(())
+    }
+
+    /// Merge `other_position_id` into `position_id` and close its account
+    ///
+    /// Both positions need the same direction. Funding is settled on both first and the
+    /// merged position has to pass the same value, leverage and borrow checks as an open.
+    /// Pool borrow and trader exposure are unchanged since the merge keeps the sums.
+    pub fn merge_positions(
+        ctx: Context<MergePositions>,
+        _pair: String,
+        position_id: u64,
+        other_position_id: u64,
+    ) -> Result<()> {
+        let config = &mut ctx.accounts.config;
+        let market = &mut ctx.accounts.market;
+        let trader = &mut ctx.accounts.trader;
+        let position = &mut ctx.accounts.position;
+        let other_position = &mut ctx.accounts.other_position;
+        let clock = Clock::get()?;
+
+        require!(!config.is_paused, ErrorCode::ProgramPaused);
+        require!(!market.is_paused, ErrorCode::ProgramPaused);
+        require!(
+            position_id != other_position_id,
+            ErrorCode::InvalidPositionId
+        );
+        require!(
+            position.closed_at == 0 && other_position.closed_at == 0,
+            ErrorCode::PositionAlreadyClosed
+        );
+        require!(
+            position.feed_version_at_open == market.feed_id_version
+                && other_position.feed_version_at_open == market.feed_id_version,
+            ErrorCode::FeedVersionMismatch
+        );
+
+        let current_price =
+            get_normalized_price(&ctx.accounts.price_update, &market.feed_id, &clock)?;
+        validate_price(current_price)?;
+
+        let funding_rate_scaled = market.funding_rate_for(position.is_long)?;
+        let funding = position.update_funding(
+            clock.slot,
+            current_price,
+            funding_rate_scaled,
+            market.decimals,
+        )?;
+        emit!(position.funding_applied(&funding, trader.event_owner())?);
+        let funding_rate_scaled = market.funding_rate_for(other_position.is_long)?;
+        let funding = other_position.update_funding(
+            clock.slot,
+            current_price,
+            funding_rate_scaled,
+            market.decimals,
+        )?;
+        emit!(other_position.funding_applied(&funding, trader.event_owner())?);
+
+        position.merge(other_position)?;
+        position.last_updated = clock.slot;
+
+        validate_position_value(position.position_value)?;
+        validate_position_size(position.actual_size)?;
+        require!(
+            position.leverage <= market.effective_max_leverage(config.max_leverage),
+            ErrorCode::ExcessiveLeverage
+        );
+        config.check_borrow(position.booked_borrow)?;
+
+        other_position.closed_at = clock.slot;
+        config.track_position_closed()?;
+        other_position.closed_at_unix = clock.unix_timestamp;
+
+        trader.active_position = trader
+            .active_position
+            .checked_sub(1)
+            .ok_or(ErrorCode::MathOverflow)?;
+
+        market.total_active_positions = market
+            .total_active_positions
+            .checked_sub(1)
+            .ok_or(ErrorCode::MathOverflow)?;
+        Ok(())
+    }
+
+    /// Read the funding rate `update_funding` currently applies to positions on `pair`
+    pub fn get_funding_rate(
+        ctx: Context<GetFundingRate>,
+        _pair: String,
+    ) -> Result<FundingRateInfo> {
+        let market = &ctx.accounts.market;
+        Ok(FundingRateInfo {
+            funding_rate_scaled: market.funding_rate_for(true)?,
+            max_funding_rate_bps: market.funding_rate_bps,
+            long_open_interest: market.long_open_interest,
+            short_open_interest: market.short_open_interest,
+            funding_period_slots: SLOTS_PER_8_HOURS,
+            total_active_positions: market.total_active_positions,
+            slot: Clock::get()?.slot,
+        })
+    }
+
+    /// Read-only liquidation check for keeper bots
+    ///
+    /// Settles funding on a copy of the position and runs the same computation as the
+    /// liquidation branch of `rebalance_or_liquidate_position`.
+    pub fn get_liquidation_context(
+        ctx: Context<GetLiquidationContext>,
+        _pair: String,
+        _owner: Pubkey,
+        _position_id: u64,
+    ) -> Result<LiquidationContext> {
+        let config = &ctx.accounts.config;
+        let market = &ctx.accounts.market;
+        let clock = Clock::get()?;
+        require!(
+            ctx.accounts.position.closed_at == 0,
+            ErrorCode::PositionAlreadyClosed
+        );
+        require_eq!(
+            ctx.accounts.position.feed_version_at_open,
+            market.feed_id_version,
+            ErrorCode::FeedVersionMismatch
+        );
+
+        let current_price =
+            get_normalized_price(&ctx.accounts.price_update, &market.feed_id, &clock)?;
+        validate_price(current_price)?;
+
+        let mut position = (*ctx.accounts.position).clone();
+        let funding_rate_scaled = market.funding_rate_for(position.is_long)?;
+        position.update_funding(
+            clock.slot,
+            current_price,
+            funding_rate_scaled,
+            market.decimals,
+        )?;
+
+        let liquidation = calculate_liquidation(
+            &position,
+            current_price,
+            config,
+            market.effective_maintainance_margin(config.maintainance_margin),
+            ctx.accounts.trader.margin_tier,
+            market.decimals,
+        )?;
+
+        Ok(LiquidationContext {
+            health_ratio_bps: liquidation.health_ratio,
+            equity: liquidation.equity,
+            is_liquidatable: liquidation.is_liquidatable,
+            expected_liquidator_reward: liquidation.liquidation_reward,
+            estimated_bad_debt: liquidation.bad_debt,
+            price: current_price,
+        })
+    }
+
+    /// Read a position's PnL and health at the current price
+    ///
+    /// Settles funding on a copy of the position first, like `get_liquidation_context`,
+    /// so every field comes from the same `calculate_pnl` result.
+    pub fn get_position_status(
+        ctx: Context<GetPositionStatus>,
+        _pair: String,
+        _owner: Pubkey,
+        _position_id: u64,
+    ) -> Result<PositionStatus> {
+        let config = &ctx.accounts.config;
+        let market = &ctx.accounts.market;
+        let clock = Clock::get()?;
+        require!(
+            ctx.accounts.position.closed_at == 0,
+            ErrorCode::PositionAlreadyClosed
+        );
+        require_eq!(
+            ctx.accounts.position.feed_version_at_open,
+            market.feed_id_version,
+            ErrorCode::FeedVersionMismatch
+        );
+
+        let current_price =
+            get_normalized_price(&ctx.accounts.price_update, &market.feed_id, &clock)?;
+        validate_price(current_price)?;
+
+        let mut position = (*ctx.accounts.position).clone();
+        let funding_rate_scaled = market.funding_rate_for(position.is_long)?;
+        position.update_funding(
+            clock.slot,
+            current_price,
+            funding_rate_scaled,
+            market.decimals,
+        )?;
+
+        let pnl = calculate_pnl(&position, current_price, market.decimals)?;
+        let health_ratio_bps = calculate_health_ratio(
+            &position,
+            current_price,
+            market.effective_maintainance_margin(config.maintainance_margin),
+            ctx.accounts.trader.margin_tier,
+            market.decimals,
+        )?;
+
+        Ok(PositionStatus {
+            price: current_price,
+            position_value: notional_value(position.actual_size, current_price, market.decimals)?,
+            current_target_price: position.current_target_price,
+            gross_pnl: pnl.gross_pnl.to_i64()?,
+            net_pnl: pnl.net_pnl.to_i64()?,
+            pnl_pct_of_collateral_bps: calculate_pnl_bps_of_collateral(
+                pnl.net_pnl,
+                position.collateral,
+            )?,
+            health_ratio_bps,
+        })
+    }
+
+    /// Read the collateral to post for a regret position to open at a target leverage
+    ///
+    /// Sizes the position at the current price like `open_position` and grosses the
+    /// collateral up for the base opening fee, which is taken from the collateral. The
+    /// leverage surcharge is paid from the free balance and doesn't change the result.
+    ///
+    /// # Arguments
+    /// * `desired_size` - Token amount in smallest units
+    /// * `desired_entry_price` - USD per token with 6 decimals
+    /// * `target_leverage_bps` - Leverage in basis points (10000 = 1x)
+    pub fn get_collateral_for_leverage(
+        ctx: Context<GetCollateralForLeverage>,
+        _pair: String,
+        desired_size: u64,
+        desired_entry_price: u64,
+        target_leverage_bps: u64,
+    ) -> Result<u64> {
+        let config = &ctx.accounts.config;
+        let market = &ctx.accounts.market;
+        let clock = Clock::get()?;
+
+        require!(
+            target_leverage_bps <= market.effective_max_leverage(config.max_leverage),
+            ErrorCode::ExcessiveLeverage
+        );
+
+        let current_price =
+            get_normalized_price(&ctx.accounts.price_update, &market.feed_id, &clock)?;
+        validate_price(current_price)?;
+
+        let is_long = current_price > desired_entry_price;
+        let target_price = calculate_target_price(current_price, market.target_band_bps, is_long)?;
+        let effective_collateral = collateral_for_leverage(
+            desired_size,
+            desired_entry_price,
+            current_price,
+            target_price,
+            target_leverage_bps,
+            market.decimals,
+        )?;
+
+        proportional(
+            effective_collateral,
+            BASIS_POINTS as u64,
+            (BASIS_POINTS as u64)
+                .checked_sub(config.opening_fee as u64)
+                .ok_or(ErrorCode::MathOverflow)?,
+        )
+    }
+
+    /// Read the largest `desired_size` `open_position` would currently accept
+    ///
+    /// Uses the trader's whole available balance as collateral, holding back enough to
+    /// pay the leverage surcharge at max leverage, and reports which check binds.
+    ///
+    /// # Arguments
+    /// * `owner` - Trader to compute the maximum for
+    /// * `is_long` - Direction of the position
+    /// * `desired_entry_price` - USD per token with 6 decimals
+    pub fn get_max_position(
+        ctx: Context<GetMaxPosition>,
+        _token_mint: String,
+        _pair: String,
+        _owner: Pubkey,
+        is_long: bool,
+        desired_entry_price: u64,
+    ) -> Result<MaxOpenResult> {
+        let config = &ctx.accounts.config;
+        let market = &ctx.accounts.market;
+        let clock = Clock::get()?;
+
+        let current_price =
+            get_normalized_price(&ctx.accounts.price_update, &market.feed_id, &clock)?;
+        validate_price(current_price)?;
+
+        let max_fee_bps = calculate_opening_fee_bps(
+            config.opening_fee,
+            config.leverage_fee_surcharge,
+            config.max_opening_fee,
+            config.max_leverage,
+        )?;
+        let surcharge_bps = max_fee_bps.saturating_sub(config.opening_fee) as u64;
+        let collateral = proportional(
+            ctx.accounts.trader_balance.available_balance(),
+            BASIS_POINTS as u64,
+            (BASIS_POINTS as u64)
+                .checked_add(surcharge_bps)
+                .ok_or(ErrorCode::MathOverflow)?,
+        )?
+        .min(MAX_COLLATERAL);
+        validate_collateral(collateral)?;
+
+        let opening_fee = calculate_fee(collateral, config.opening_fee)?;
+        let effective_collateral = collateral
+            .checked_sub(opening_fee)
+            .ok_or(ErrorCode::InsufficientCollateralForFees)?;
+
+        let (max_position_value, binding_constraint) = calculate_max_position_value(
+            effective_collateral,
+            config,
+            market.effective_max_leverage(config.max_leverage),
+            market.effective_maintainance_margin(config.maintainance_margin),
+            ctx.accounts.trader.margin_tier,
+            ctx.accounts.pool.available_liquidity(),
+            ctx.accounts.trader.total_notional_exposure,
+            market.funding_rate_bps,
+        )?;
+
+        // Position value is linear in the desired size, so size one token and scale it
+        let unit_size = 10_u64
+            .checked_pow(market.decimals as u32)
+            .ok_or(ErrorCode::MathOverflow)?;
+        let target_price = calculate_target_price(current_price, market.target_band_bps, is_long)?;
+        let unit = if is_long {
+            size_long_position(
+                desired_entry_price,
+                unit_size,
+                current_price,
+                target_price,
+                market.decimals,
+            )?
+        } else {
+            size_short_position(
+                desired_entry_price,
+                unit_size,
+                current_price,
+                target_price,
+                market.decimals,
+            )?
+        };
+        // A price small enough for one token to round to no value can't be scaled
+        require!(unit.position_value > 0, ErrorCode::InvalidInput);
+        let max_desired_size = proportional(max_position_value, unit_size, unit.position_value)?;
+
+        Ok(MaxOpenResult {
+            max_desired_size,
+            max_position_value,
+            collateral,
+            binding_constraint,
+        })
+    }
+
+    /// Set (or clear with 0) the stop-loss and take-profit a keeper closes the position at
+    ///
+    /// Both are checked against the entry at set time, longs need
+    /// `stop_loss < entry < take_profit` and shorts the reverse. Execution goes through
+    /// `rebalance_or_liquidate_position`.
+    pub fn set_position_triggers(
+        ctx: Context<SetPositionTriggers>,
+        _pair: String,
+        _owner: Pubkey,
+        _position_id: u64,
+        stop_loss_price: u64,
+        take_profit_price: u64,
+    ) -> Result<()> {
+        require!(
+            ctx.accounts.trader.can_manage(ctx.accounts.signer.key()),
+            ErrorCode::Unauthorized
+        );
+        let position = &mut ctx.accounts.position;
+        require!(position.closed_at == 0, ErrorCode::PositionAlreadyClosed);
+        position.validate_triggers(stop_loss_price, take_profit_price)?;
+
+        position.stop_loss_price = stop_loss_price;
+        position.take_profit_price = take_profit_price;
+
+        emit!(PositionTriggersSet {
+            owner: ctx.accounts.trader.event_owner(),
+            position_id: position.position_id,
+            stop_loss_price,
+            take_profit_price,
+            slot: Clock::get()?.slot,
+        });
+        Ok(())
+    }
+
+    /// Clear both triggers, same as `set_position_triggers` with zeros
+    pub fn clear_position_triggers(
+        ctx: Context<SetPositionTriggers>,
+        pair: String,
+        owner: Pubkey,
+        position_id: u64,
+    ) -> Result<()> {
+        set_position_triggers(ctx, pair, owner, position_id, 0, 0)
+    }
+
+    /// Admin de-risking lever: partially close a position down to `remaining_size`
+    ///
+    /// Only available while the protocol or the market is paused. The trader realizes
+    /// the PnL of the closed part as with a regular close.
+    pub fn admin_reduce_position(
+        ctx: Context<AdminReducePosition>,
+        _token_mint: String,
+        pair: String,
+        _owner: Pubkey,
+        position_id: u64,
+        remaining_size: u64,
+    ) -> Result<()> {
+        let config = &ctx.accounts.config;
+        let market = &mut ctx.accounts.market;
+        let pool = &mut ctx.accounts.pool;
+        let insurance_fund = &mut ctx.accounts.insurance_fund;
+        let trader = &mut ctx.accounts.trader;
+        let trader_balance = &mut ctx.accounts.trader_balance;
+        let position = &mut ctx.accounts.position;
+        let clock = Clock::get()?;
+
+        require!(
+            ctx.accounts.signer.key() == config.admin,
+            ErrorCode::Unauthorized
+        );
+        require!(
+            config.is_paused || market.is_paused,
+            ErrorCode::ProgramNotPaused
+        );
+        require!(position.closed_at == 0, ErrorCode::PositionAlreadyClosed);
+        // Stranded by a feed swap, see `settle_stranded_position`
+        require_eq!(
+            position.feed_version_at_open,
+            market.feed_id_version,
+            ErrorCode::FeedVersionMismatch
+        );
+
+        let current_price = get_exit_price(
+            &ctx.accounts.price_update,
+            &market.feed_id,
+            &clock,
+            config.max_spot_ema_divergence_bps,
+            market.feed_status == FeedStatus::Suspect,
+            position.is_long,
+        )?;
+        validate_price(current_price)?;
+
+        let funding_rate_scaled = market.funding_rate_for(position.is_long)?;
+        let funding = position.update_funding(
+            clock.slot,
+            current_price,
+            funding_rate_scaled,
+            market.decimals,
+        )?;
+        emit!(position.funding_applied(&funding, trader.event_owner())?);
+
+        let previous_size = position.actual_size;
+        let reduction = calculate_reduction(
+            position,
+            current_price,
+            remaining_size,
+            config.closing_fee,
+            market.decimals,
+        )?;
+
+        position.apply_reduction(&reduction)?;
+        validate_position_value(position.position_value)?;
+        position.current_price = current_price;
+        position.last_updated = clock.slot;
+
+        // Route the fee before the open interest shrinks, like a full close does
+        pool.route_fee(reduction.closing_fee, config, insurance_fund)?;
+        pool.absorb_bad_debt(reduction.bad_debt, clock.slot, config, insurance_fund)?;
+
+        // Restore pool headroom
+        pool.release_borrowed(reduction.closed_borrowed);
+        pool.release_trader_collateral(reduction.closed_collateral);
+        pool.check_covers_collateral(position.collateral)?;
+
+        trader_balance.settle(reduction.closed_collateral, reduction.amount_to_return)?;
+        pool.forfeit_trader_deposit(trader_balance)?;
+
+        trader.total_notional_exposure = trader
+            .total_notional_exposure
+            .checked_sub(reduction.closed_value)
+            .ok_or(ErrorCode::MathOverflow)?;
+        market.shift_open_interest(position.is_long, reduction.closed_value, 0)?;
+
+        let realized_pnl = reduction.realized_pnl.to_i64()?;
+
+        msg!(
+            "Admin reduced position {} of {} from {} to {}",
+            position_id,
+            trader.event_owner(),
+            previous_size,
+            remaining_size
+        );
+        emit!(PositionReducedByAdmin {
+            admin: ctx.accounts.signer.key(),
+            owner: trader.event_owner(),
+            pair,
+            position_id,
+            previous_size,
+            remaining_size,
+            price: current_price,
+            closed_collateral: reduction.closed_collateral,
+            realized_pnl,
+            closing_fee: reduction.closing_fee,
+            amount_to_return: reduction.amount_to_return,
+            bad_debt: reduction.bad_debt,
+            slot: clock.slot,
+        });
+        Ok(())
+    }
+
+    /// Admin emergency lever: close a position and send its whole collateral to insurance
+    ///
+    /// Meant for positions tied to a compromised or sanctioned account. The trader gets
+    /// nothing back and no PnL is realized, so this is a governance power that can
+    /// confiscate funds: it's only available while the protocol or the market is paused,
+    /// and every use is logged and emits `PositionSeizedByAdmin`. The position account
+    /// is kept, marked closed, as a record of the seizure.
+    pub fn admin_seize_position(
+        ctx: Context<AdminSeizePosition>,
+        _token_mint: String,
+        pair: String,
+        _owner: Pubkey,
+        position_id: u64,
+    ) -> Result<()> {
+        let config = &mut ctx.accounts.config;
+        let market = &mut ctx.accounts.market;
+        let pool = &mut ctx.accounts.pool;
+        let insurance_fund = &mut ctx.accounts.insurance_fund;
+        let trader = &mut ctx.accounts.trader;
+        let trader_balance = &mut ctx.accounts.trader_balance;
+        let position = &mut ctx.accounts.position;
+        let clock = Clock::get()?;
+
+        require!(
+            ctx.accounts.signer.key() == config.admin,
+            ErrorCode::Unauthorized
+        );
+        require!(
+            config.is_paused || market.is_paused,
+            ErrorCode::ProgramNotPaused
+        );
+        require!(position.closed_at == 0, ErrorCode::PositionAlreadyClosed);
+        // Stranded by a feed swap, see `settle_stranded_position`
+        require_eq!(
+            position.feed_version_at_open,
+            market.feed_id_version,
+            ErrorCode::FeedVersionMismatch
+        );
+
+        let seized_collateral = position.collateral;
+        let released_borrow = position.booked_borrow;
+
+        settle_closed_position(
+            pool,
+            trader,
+            trader_balance,
+            market,
+            position,
+            config,
+            &ClosePayout::seizure(seized_collateral),
+            &clock,
+        )?;
+
+        insurance_fund.credit(seized_collateral)?;
+        // The kept account holds on to its id, see `close_trader_account`
+        trader.retained_positions = trader
+            .retained_positions
+            .checked_add(1)
+            .ok_or(ErrorCode::MathOverflow)?;
+
+        msg!(
+            "ADMIN SEIZURE: position {} of {} on {}, collateral {} sent to insurance",
+            position_id,
+            trader.event_owner(),
+            pair,
+            seized_collateral
+        );
+        emit!(PositionSeizedByAdmin {
+            admin: ctx.accounts.signer.key(),
+            owner: trader.event_owner(),
+            pair,
+            position_id,
+            actual_size: position.actual_size,
+            seized_collateral,
+            released_borrow,
+            slot: clock.slot,
+        });
+        Ok(())
+    }
+
+    /// Keeper entry point for a position
+    ///
+    /// When several conditions hold in the same call, they're handled in this order:
+    /// 1. liquidation, an unhealthy position is always liquidated first
+    /// 2. take-profit or stop-loss, the position is realized and closed at market
+    /// 3. margin warning, below `warning_health_bps` a penalty is seized from the
+    ///    collateral while size stays, at most once per `MARGIN_WARNING_INTERVAL_SLOTS`.
+    ///    The pool lends the penalty, if that would break `max_borrow_per_position` or
+    ///    exceed its liquidity the warning is skipped
+    /// 4. rebalance, the target is rolled forward and the position stays open
+    ///
+    /// Fails with `NoActionRequired` when none applies, unless `liquidation_backoff_slots`
+    /// is set: then the miss is recorded and further rebalance checks fail with
+    /// `LiquidationBackoff` until the backoff has passed. Liquidation and the triggers are
+    /// evaluated on every call regardless. Liquidation and take-profit rewards are
+    /// credited to the signer, see `pay_keeper_reward`, the closed position's rent goes
+    /// back to the owner.
+    pub fn rebalance_or_liquidate_position(
+        ctx: Context<RebalanceOrLiquidatePosition>,
+        _token_mint: String,
+        _pair: String,
+        _owner: Pubkey,
+        position_id: u64,
+    ) -> Result<()> {
+        // Loaded first so a keeper losing a same-slot race gets a cheap, explicit failure
+        // instead of the account failing to deserialize
+        let position_info = ctx.accounts.position.to_account_info();
+        let mut position = Position::load_open(&position_info)?;
+
+        let config = &mut ctx.accounts.config;
+        let pool = &mut ctx.accounts.pool;
+        let insurance_fund = &mut ctx.accounts.insurance_fund;
+        let market = &mut ctx.accounts.market;
+        let trader = &mut ctx.accounts.trader;
+        let trader_balance = &mut ctx.accounts.trader_balance;
+        let position = &mut position;
+        let clock = Clock::get()?;
+
+        require!(!config.is_paused, ErrorCode::ProgramPaused);
+        require!(!market.is_paused, ErrorCode::ProgramPaused);
+        require!(!pool.is_paused, ErrorCode::ProgramPaused);
+        // Stranded by a feed swap, see `settle_stranded_position`
+        require_eq!(
+            position.feed_version_at_open,
+            market.feed_id_version,
+            ErrorCode::FeedVersionMismatch
+        );
+
+        market.require_feed_not_halted()?;
+
+        let current_price = get_exit_price(
+            &ctx.accounts.price_update,
+            &market.feed_id,
+            &clock,
+            config.max_spot_ema_divergence_bps,
+            market.feed_status == FeedStatus::Suspect,
+            position.is_long,
+        )?;
+        validate_price(current_price)?;
+
+        let funding_rate_scaled = market.funding_rate_for(position.is_long)?;
+        let funding = position.update_funding(
+            clock.slot,
+            current_price,
+            funding_rate_scaled,
+            market.decimals,
+        )?;
+        emit!(position.funding_applied(&funding, trader.event_owner())?);
+
+        // 1. Liquidation
+        let liquidation = calculate_liquidation(
+            position,
+            current_price,
+            config,
+            market.effective_maintainance_margin(config.maintainance_margin),
+            trader.margin_tier,
+            market.decimals,
+        )?;
+        let action = position.keeper_action(
+            current_price,
+            &liquidation,
+            config.warning_health_bps,
+            clock.slot,
+        );
+        if action == KeeperAction::Liquidate {
+            pool.lifetime_keeper_rewards = pool
+                .lifetime_keeper_rewards
+                .checked_add(liquidation.liquidation_reward)
+                .ok_or(ErrorCode::MathOverflow)?;
+            pool.absorb_bad_debt(liquidation.bad_debt, clock.slot, config, insurance_fund)?;
+
+            let owner_reward = pay_keeper_reward(
+                ctx.accounts.signer.key(),
+                trader.owner,
+                ctx.accounts.keeper_balance.as_deref_mut(),
+                liquidation.liquidation_reward,
+            )?;
+
+            settle_closed_position(
+                pool,
+                trader,
+                trader_balance,
+                market,
+                position,
+                config,
+                &liquidation.payout(owner_reward),
+                &clock,
+            )?;
+
+            emit!(PositionLiquidated {
+                keeper: ctx.accounts.signer.key(),
+                owner: trader.event_owner(),
+                position_id,
+                price: current_price,
+                health_ratio: liquidation.health_ratio,
+                equity: liquidation.equity,
+                liquidation_reward: liquidation.liquidation_reward,
+                bad_debt: liquidation.bad_debt,
+                trader_rebate: liquidation.trader_rebate,
+                slot: clock.slot,
+            });
+
+            return close_account(&position_info, &ctx.accounts.owner_account);
+        }
+
+        // 2. Take-profit / stop-loss
+        let stop_loss_hit = action == KeeperAction::StopLoss;
+        if stop_loss_hit || action == KeeperAction::TakeProfit {
+            let close =
+                calculate_close(position, current_price, config.closing_fee, market.decimals)?;
+
+            // The keeper is paid out of the closing fee, the rest is split as usual
+            let keeper_reward = config.tp_execution_reward.min(close.closing_fee);
+            pool.route_fee(close.closing_fee - keeper_reward, config, insurance_fund)?;
+            pool.lifetime_keeper_rewards = pool
+                .lifetime_keeper_rewards
+                .checked_add(keeper_reward)
+                .ok_or(ErrorCode::MathOverflow)?;
+            pool.absorb_bad_debt(close.bad_debt, clock.slot, config, insurance_fund)?;
+
+            let owner_reward = pay_keeper_reward(
+                ctx.accounts.signer.key(),
+                trader.owner,
+                ctx.accounts.keeper_balance.as_deref_mut(),
+                keeper_reward,
+            )?;
+
+            settle_closed_position(
+                pool,
+                trader,
+                trader_balance,
+                market,
+                position,
+                config,
+                &close.payout(owner_reward),
+                &clock,
+            )?;
+
+            let final_pnl = close.net_pnl.to_i64()?;
+
+            if stop_loss_hit {
+                emit!(StopLossExecuted {
+                    keeper: ctx.accounts.signer.key(),
+                    owner: trader.event_owner(),
+                    position_id,
+                    price: current_price,
+                    stop_loss_price: position.stop_loss_price,
+                    final_pnl,
+                    keeper_reward,
+                    amount_to_return: close.amount_to_return,
+                    slot: clock.slot,
+                });
+            } else {
+                emit!(TakeProfitExecuted {
+                    keeper: ctx.accounts.signer.key(),
+                    owner: trader.event_owner(),
+                    position_id,
+                    price: current_price,
+                    take_profit_price: position.take_profit_price,
+                    final_pnl,
+                    keeper_reward,
+                    amount_to_return: close.amount_to_return,
+                    slot: clock.slot,
+                });
+            }
+
+            return close_account(&position_info, &ctx.accounts.owner_account);
+        }
+
+        // 3. Margin warning
+        // Equity shrinks instead of size, the trader tops up or is liquidated next
+        if action == KeeperAction::MarginWarning {
+            let penalty = calculate_fee(position.collateral, config.warning_penalty_bps)?;
+            // The notional is unchanged, so the pool lends what the collateral lost
+            let warned_borrow = position
+                .booked_borrow
+                .checked_add(penalty)
+                .ok_or(ErrorCode::MathOverflow)?;
+            if config.check_borrow(warned_borrow).is_ok() && pool.available_liquidity() >= penalty {
+                position.collateral = position
+                    .collateral
+                    .checked_sub(penalty)
+                    .ok_or(ErrorCode::MathOverflow)?;
+                trader_balance.settle(penalty, 0)?;
+                pool.forfeit_trader_deposit(trader_balance)?;
+                pool.release_trader_collateral(penalty);
+
+                pool.total_borrowed = pool
+                    .total_borrowed
+                    .checked_add(penalty)
+                    .ok_or(ErrorCode::MathOverflow)?;
+                position.booked_borrow = warned_borrow;
+                insurance_fund.credit(penalty)?;
+
+                position.refresh_leverage()?;
+                position.last_warning_slot = clock.slot;
+                position.liquidation_attempts = 0;
+                position.last_updated = clock.slot;
+                position.cache_health(
+                    current_price,
+                    market.effective_maintainance_margin(config.maintainance_margin),
+                    trader.margin_tier,
+                    market.decimals,
+                    clock.slot,
+                )?;
+
+                msg!(
+                    "Margin warning: position {} of {} at health {}bps, {} seized",
+                    position_id,
+                    trader.event_owner(),
+                    liquidation.health_ratio,
+                    penalty
+                );
+                emit!(MarginWarningPenalty {
+                    keeper: ctx.accounts.signer.key(),
+                    owner: trader.event_owner(),
+                    position_id,
+                    price: current_price,
+                    health_ratio: liquidation.health_ratio,
+                    penalty,
+                    remaining_collateral: position.collateral,
+                    slot: clock.slot,
+                });
+                return position.store(&position_info);
+            }
+            msg!(
+                "Margin warning skipped for position {}, the pool can't lend the {} penalty",
+                position_id,
+                penalty
+            );
+        }
+
+        // 4. Rebalance
+        // Only the rebalance check backs off, a position that turned liquidatable or hit a
+        // trigger is always acted on above, so fruitless calls can't delay that
+        if clock
+            .slot
+            .saturating_sub(position.last_liquidation_attempt_slot)
+            < position.liquidation_backoff(config.liquidation_backoff_slots)
+        {
+            msg!(
+                "Position {} checked at slot {}, backing off",
+                position_id,
+                position.last_liquidation_attempt_slot
+            );
+            return err!(ErrorCode::LiquidationBackoff);
+        }
+
+        let mut rebalance = calculate_rebalance_with_new_target(
+            position.clone(),
+            current_price,
+            market.target_band_bps,
+            market.decimals,
+            config.min_rebalance_profit,
+        )?;
+        if !rebalance.should_rebalance {
+            if config.liquidation_backoff_slots == 0 {
+                return err!(ErrorCode::NoActionRequired);
+            }
+            // Record the miss instead of failing, a reverted transaction couldn't
+            position.liquidation_attempts = position.liquidation_attempts.saturating_add(1);
+            position.last_liquidation_attempt_slot = clock.slot;
+            msg!(
+                "No action required for position {}, next check in {} slots",
+                position_id,
+                position.liquidation_backoff(config.liquidation_backoff_slots)
+            );
+            return position.store(&position_info);
+        }
+
+        // Don't swing the pool's borrow and the trader's risk too far in one step
+        let size_capped = rebalance.cap_size_change(
+            position.actual_size,
+            config.max_rebalance_size_change_bps,
+            current_price,
+            market.decimals,
+        )?;
+        rebalance.new_leverage_bps =
+            calculate_leverage_bps(rebalance.new_position_value, position.collateral)?;
+        require!(
+            rebalance.new_leverage_bps <= market.effective_max_leverage(config.max_leverage),
+            ErrorCode::ExcessiveLeverage
+        );
+
+        // Swap the old borrow for the new one
+        let previous_borrowed = position.booked_borrow;
+        let new_borrowed = rebalance
+            .new_position_value
+            .checked_sub(position.collateral)
+            .ok_or(ErrorCode::MathOverflow)?;
+        config.check_borrow(new_borrowed)?;
+
+        pool.release_borrowed(previous_borrowed);
+        if pool.available_liquidity() < new_borrowed {
+            return err!(ErrorCode::InsufficientLiquidity);
+        }
+        pool.total_borrowed = pool
+            .total_borrowed
+            .checked_add(new_borrowed)
+            .ok_or(ErrorCode::MathOverflow)?;
+        // The collateral stays put, only the borrow is re-struck
+        pool.check_covers_collateral(position.collateral)?;
+
+        insurance_fund.credit(rebalance.excess_to_insurance)?;
+
+        trader.total_notional_exposure = trader
+            .total_notional_exposure
+            .checked_sub(position.position_value)
+            .ok_or(ErrorCode::MathOverflow)?
+            .checked_add(rebalance.new_position_value)
+            .ok_or(ErrorCode::MathOverflow)?;
+        market.shift_open_interest(
+            position.is_long,
+            position.position_value,
+            rebalance.new_position_value,
+        )?;
+
+        let previous_size = position.actual_size;
+
+        // Re-strike the position at the current price for the next cycle
+        position.actual_size = rebalance.new_actual_size;
+        position.current_target_price = rebalance.new_target_price;
+        position.leverage = rebalance.new_leverage_bps;
+        position.position_value = rebalance.new_position_value;
+        position.booked_borrow = new_borrowed;
+        position.liquidation_attempts = 0;
+        position.actual_entered_price = current_price;
+        position.current_price = current_price;
+        position.last_updated = clock.slot;
+        position.cache_health(
+            current_price,
+            market.effective_maintainance_margin(config.maintainance_margin),
+            trader.margin_tier,
+            market.decimals,
+            clock.slot,
+        )?;
+
+        emit!(PositionRebalanced {
+            owner: trader.event_owner(),
+            position_id,
+            price: current_price,
+            previous_size,
+            new_size: rebalance.new_actual_size,
+            new_target_price: rebalance.new_target_price,
+            excess_to_insurance: rebalance.excess_to_insurance,
+            profit_realized: rebalance.profit_realized,
+            size_capped,
+            slot: clock.slot,
+        });
+        position.store(&position_info)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+    use pyth_solana_receiver_sdk::price_update::PriceUpdateV2;
+
+    /// One pool's books: a trader, a third-party keeper and the pool as counterparty
+    ///
+    /// Each step mirrors the accounting of its handler through the same functions, and
+    /// `total` has to stay put across all of them. Value only moves between balances,
+    /// fees, insurance and LPs, what the pool pays or keeps as the traders'
+    /// counterparty is tracked in `counterparty_paid`.
+    struct Ledger {
+        config: Config,
+        pool: Vault,
+        insurance: InsuranceFund,
+        market: Market,
+        trader: Trader,
+        trader_balance: TraderPoolDetail,
+        keeper_balance: TraderPoolDetail,
+        counterparty_paid: i128,
+        clock: Clock,
+    }
+
+    impl Ledger {
+        fn new() -> Self {
+            let mut config = config();
+            config.protocol_fee_share = 5_000;
+            config.insurance_target_bps = 1_000;
+            config.insurance_fee_share_bps = 2_000;
+            config.liquidation_rebate = 5_000;
+            Ledger {
+                config,
+                pool: pool(usd(1_000_000)),
+                insurance: insurance_fund(usd(50)),
+                market: market(0),
+                trader: trader(),
+                trader_balance: trader_balance(usd(10_000)),
+                keeper_balance: trader_balance(0),
+                counterparty_paid: 0,
+                clock: Clock::default(),
+            }
+        }
+
+        fn total(&self) -> i128 {
+            self.trader_balance.balance as i128
+                + self.keeper_balance.balance as i128
+                + self.pool.accumulated_fees as i128
+                + self.pool.accumulated_lp_fees as i128
+                + self.insurance.balance as i128
+                + self.pool.lp_deposit as i128
+                - self.counterparty_paid
+        }
+
+        /// Open on `collateral` as `open_position` does, the opening fee comes out of it
+        fn open(&mut self, is_long: bool, price: u64, tokens: u64, collateral: u64) -> Position {
+            let (fee, effective_collateral) = split_opening_collateral(
+                collateral,
+                self.config.opening_fee,
+                self.config.min_effective_collateral,
+            )
+            .unwrap();
+            let mut position = position(is_long, price, tokens, effective_collateral);
+            position.opening_fee_paid = fee;
+
+            self.pool
+                .route_fee(fee, &self.config, &mut self.insurance)
+                .unwrap();
+            self.trader_balance.balance -= fee;
+            self.trader_balance.lock(effective_collateral).unwrap();
+            self.pool
+                .lock_trader_collateral(effective_collateral)
+                .unwrap();
+            self.pool.total_borrowed += position.booked_borrow;
+
+            self.trader.active_position += 1;
+            self.trader.total_notional_exposure += position.position_value;
+            self.market
+                .shift_open_interest(is_long, 0, position.position_value)
+                .unwrap();
+            self.market.total_active_positions += 1;
+            self.config.track_position_opened().unwrap();
+            position
+        }
+
+        /// Settle a funding period, then close as `close_position` does
+        fn close(&mut self, position: &mut Position, price: u64) {
+            let rate = self.market.funding_rate_for(position.is_long).unwrap();
+            self.clock.slot = position.last_funding_slot + SLOTS_PER_8_HOURS;
+            position
+                .update_funding(self.clock.slot, price, rate, DECIMALS)
+                .unwrap();
+
+            let close =
+                calculate_close(position, price, self.config.closing_fee, DECIMALS).unwrap();
+            self.pool
+                .route_fee(close.closing_fee, &self.config, &mut self.insurance)
+                .unwrap();
+            self.pool
+                .absorb_bad_debt(close.bad_debt, 0, &self.config, &mut self.insurance)
+                .unwrap();
+            self.settle(position, &close.payout(0));
+            self.counterparty_paid += close.net_pnl.0 - close.forfeited_pnl as i128;
+        }
+
+        /// Liquidate as `rebalance_or_liquidate_position` does, for a third-party keeper
+        fn liquidate(&mut self, position: &mut Position, price: u64) {
+            let margin = self.config.maintainance_margin;
+            let liquidation =
+                calculate_liquidation(position, price, &self.config, margin, 0, DECIMALS).unwrap();
+            assert!(liquidation.is_liquidatable);
+
+            self.pool
+                .absorb_bad_debt(liquidation.bad_debt, 0, &self.config, &mut self.insurance)
+                .unwrap();
+            let owner_reward = pay_keeper_reward(
+                Pubkey::new_unique(),
+                position.owner,
+                Some(&mut self.keeper_balance),
+                liquidation.liquidation_reward,
+            )
+            .unwrap();
+            self.settle(position, &liquidation.payout(owner_reward));
+            // the pool keeps the equity that isn't rebated
+            self.counterparty_paid += liquidation.net_pnl.0 - liquidation.retained_equity as i128;
+        }
+
+        /// Re-strike at `price` as `rebalance_or_liquidate_position` does
+        fn rebalance(&mut self, position: &mut Position, price: u64) {
+            let rebalance = calculate_rebalance_with_new_target(
+                position.clone(),
+                price,
+                self.market.target_band_bps,
+                DECIMALS,
+                0,
+            )
+            .unwrap();
+            assert!(rebalance.should_rebalance);
+
+            let new_borrowed = rebalance.new_position_value - position.collateral;
+            self.pool.release_borrowed(position.booked_borrow);
+            self.pool.total_borrowed += new_borrowed;
+            self.insurance
+                .credit(rebalance.excess_to_insurance)
+                .unwrap();
+            self.counterparty_paid += rebalance.excess_to_insurance as i128;
+
+            self.trader.total_notional_exposure = self.trader.total_notional_exposure
+                - position.position_value
+                + rebalance.new_position_value;
+            self.market
+                .shift_open_interest(
+                    position.is_long,
+                    position.position_value,
+                    rebalance.new_position_value,
+                )
+                .unwrap();
+            position.actual_size = rebalance.new_actual_size;
+            position.current_target_price = rebalance.new_target_price;
+            position.position_value = rebalance.new_position_value;
+            position.booked_borrow = new_borrowed;
+            position.actual_entered_price = price;
+        }
+
+        fn settle(&mut self, position: &mut Position, payout: &ClosePayout) {
+            settle_closed_position(
+                &mut self.pool,
+                &mut self.trader,
+                &mut self.trader_balance,
+                &mut self.market,
+                position,
+                &mut self.config,
+                payout,
+                &self.clock,
+            )
+            .unwrap();
+        }
+
+        /// Nothing left open and the books back where they started
+        fn assert_flat(&self) {
+            assert_eq!(self.trader_balance.locked_balance, 0);
+            assert_eq!(self.pool.trader_collateral, 0);
+            assert_eq!(self.pool.total_borrowed, 0);
+            assert_eq!(self.trader.total_notional_exposure, 0);
+        }
+    }
+
+    #[test]
+    fn long_and_short_closes_conserve_value() {
+        let mut ledger = Ledger::new();
+        let total = ledger.total();
+
+        // long in profit
+        let mut long = ledger.open(true, usd(100), 50, usd(1_000));
+        assert_eq!(ledger.total(), total);
+        ledger.close(&mut long, usd(110));
+        assert_eq!(ledger.total(), total);
+
+        // short at a loss, and the long side pays it funding while open alone
+        let mut short = ledger.open(false, usd(100), 50, usd(1_000));
+        ledger.close(&mut short, usd(104));
+        assert_eq!(ledger.total(), total);
+
+        ledger.assert_flat();
+        assert!(ledger.pool.accumulated_fees > 0 && ledger.insurance.balance > usd(50));
+    }
+
+    #[test]
+    fn loss_beyond_collateral_conserves_value() {
+        let mut ledger = Ledger::new();
+        let total = ledger.total();
+
+        let mut long = ledger.open(true, usd(100), 100, usd(1_000));
+        ledger.close(&mut long, usd(85));
+        assert_eq!(ledger.total(), total);
+        assert!(ledger.pool.accumulated_bad_debt > 0);
+        ledger.assert_flat();
+    }
+
+    #[test]
+    fn liquidation_conserves_value() {
+        let mut ledger = Ledger::new();
+        let total = ledger.total();
+
+        // still some equity left, the keeper is paid and the owner rebated
+        let mut short = ledger.open(false, usd(100), 50, usd(1_000));
+        ledger.liquidate(&mut short, usd(118));
+        assert_eq!(ledger.total(), total);
+        assert!(ledger.keeper_balance.balance > 0);
+
+        // underwater, the loss beyond the collateral is absorbed as bad debt
+        let mut long = ledger.open(true, usd(100), 50, usd(1_000));
+        ledger.liquidate(&mut long, usd(75));
+        assert_eq!(ledger.total(), total);
+        assert!(ledger.pool.accumulated_bad_debt > 0);
+        ledger.assert_flat();
+    }
+
+    #[test]
+    fn rebalance_then_close_conserves_value() {
+        let mut ledger = Ledger::new();
+        let total = ledger.total();
+
+        let mut long = ledger.open(true, usd(100), 50, usd(1_000));
+        long.current_target_price = usd(110);
+        ledger.rebalance(&mut long, usd(112));
+        assert_eq!(ledger.total(), total);
+
+        ledger.close(&mut long, usd(115));
+        assert_eq!(ledger.total(), total);
+        ledger.assert_flat();
+    }
+
+    #[test]
+    fn random_open_close_cycles_never_underflow() {
+        // xorshift, deterministic so a failure reproduces
+        let mut seed = 0x2545_f491_4f6c_dd1d_u64;
+        let mut next = move |range: u64| {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            seed % range
+        };
+
+        let mut ledger = Ledger::new();
+        ledger.trader_balance.balance = usd(1_000_000_000);
+        let total = ledger.total();
+        let mut open = Vec::new();
+        for _ in 0..5_000 {
+            if open.len() < 4 && (open.is_empty() || next(2) == 0) {
+                // odd amounts so every fee and notional truncates
+                let price = usd(50) + next(usd(100));
+                let tokens = 1 + next(20);
+                let notional = notional_value(tokens * ONE_TOKEN, price, DECIMALS).unwrap();
+                let collateral = (notional / (1 + next(8)) + next(1_000)).min(notional);
+                open.push(ledger.open(next(2) == 0, price, tokens, collateral));
+            } else {
+                let mut position = open.swap_remove(next(open.len() as u64) as usize);
+                let entry = position.actual_entered_price;
+                let price = entry - entry / 10 + next(entry / 5);
+                ledger.close(&mut position, price);
+            }
+            assert_eq!(ledger.total(), total);
+        }
+        while let Some(mut position) = open.pop() {
+            let price = position.actual_entered_price;
+            ledger.close(&mut position, price);
+        }
+        ledger.assert_flat();
+        assert_eq!(ledger.total(), total);
+    }
+
+    /// Run `open_position` for the ledger's trader as a limit entry at the price of
+    /// `price_update`, so `tokens` open 1:1. The ledger takes over the accounts only when
+    /// the open succeeds
+    fn open_position(
+        ledger: &mut Ledger,
+        price_update: &PriceUpdateV2,
+        is_long: bool,
+        tokens: u64,
+        collateral: u64,
+    ) -> (Result<OpenResult>, Position) {
+        let key = Pubkey::new_unique;
+        let mut config = TestAccount::program(key(), &ledger.config);
+        let mut signer = TestAccount::wallet(ledger.trader.owner);
+        let mut trader = TestAccount::program(key(), &ledger.trader);
+        let mut trader_balance = TestAccount::program(key(), &ledger.trader_balance);
+        let mut pool = TestAccount::program(key(), &ledger.pool);
+        let mut insurance_fund = TestAccount::program(key(), &ledger.insurance);
+        let mut market = TestAccount::program(key(), &ledger.market);
+        let mut position = TestAccount::program(key(), &crate::test_utils::position(true, 1, 1, 1));
+        let mut oracle = TestAccount::oracle(price_update);
+        let mut system_program = TestAccount::system_program();
+        let infos = [
+            config.info(false),
+            signer.info(true),
+            trader.info(false),
+            trader_balance.info(false),
+            pool.info(false),
+            insurance_fund.info(false),
+            market.info(false),
+            position.info(false),
+            oracle.info(false),
+            system_program.info(false),
+        ];
+        let mut accounts = OpenPosition {
+            config: Account::try_from(&infos[0]).unwrap(),
+            signer: Signer::try_from(&infos[1]).unwrap(),
+            trader: Account::try_from(&infos[2]).unwrap(),
+            trader_balance: Account::try_from(&infos[3]).unwrap(),
+            pool: Account::try_from(&infos[4]).unwrap(),
+            insurance_fund: Account::try_from(&infos[5]).unwrap(),
+            market: Account::try_from(&infos[6]).unwrap(),
+            position: Account::try_from(&infos[7]).unwrap(),
+            price_update: Account::try_from(&infos[8]).unwrap(),
+            system_program: Program::try_from(&infos[9]).unwrap(),
+        };
+        let price = price_update.price_message.price as u64;
+        let result = regret_market::open_position(
+            Context::new(&crate::ID, &mut accounts, &[], Default::default()),
+            "USDC".to_string(),
+            "SOL/USD".to_string(),
+            ledger.trader.position_count,
+            tokens * ONE_TOKEN,
+            price,
+            collateral,
+            is_long,
+            true,
+            0,
+            0,
+        );
+        if result.is_ok() {
+            ledger.config = (*accounts.config).clone();
+            ledger.trader = (*accounts.trader).clone();
+            ledger.trader_balance = (*accounts.trader_balance).clone();
+            ledger.pool = (*accounts.pool).clone();
+            ledger.insurance = (*accounts.insurance_fund).clone();
+            ledger.market = (*accounts.market).clone();
+        }
+        (result, (*accounts.position).clone())
+    }
+
+    #[test]
+    fn open_result_matches_the_stored_position() {
+        reset_syscalls();
+        let mut ledger = Ledger::new();
+        ledger.trader.position_count = 3;
+        let (result, position) =
+            open_position(&mut ledger, &price_update(usd(100)), false, 40, usd(1_000));
+        let margin = maintenance_margin_for_tier(
+            ledger
+                .market
+                .effective_maintainance_margin(ledger.config.maintainance_margin),
+            ledger.trader.margin_tier,
+        )
+        .unwrap();
+        assert_eq!(
+            result.unwrap(),
+            OpenResult {
+                position_id: position.position_id,
+                actual_size: position.actual_size,
+                actual_entered_price: position.actual_entered_price,
+                leverage: position.leverage,
+                position_value: position.position_value,
+                liquidation_price: calculate_liquidation_price(&position, margin, DECIMALS)
+                    .unwrap(),
+                opening_fee: position.opening_fee_paid,
+            }
+        );
+        assert_eq!(position.position_id, 3);
+        assert!(position.actual_size > 0 && position.opening_fee_paid > 0);
+    }
+
+    #[test]
+    fn opens_outside_the_window_are_rejected_but_closes_go_through() {
+        reset_syscalls();
+        let price_update = price_update(usd(100));
+        let mut ledger = Ledger::new();
+        let position = ledger.open(true, usd(100), 10, usd(200));
+
+        for (start, end) in [
+            (TEST_SLOT - 100, TEST_SLOT),
+            (TEST_SLOT + 1, TEST_SLOT + 100),
+        ] {
+            ledger.market.open_window_start_slot = start;
+            ledger.market.open_window_end_slot = end;
+            let (result, _) = open_position(&mut ledger, &price_update, true, 10, usd(200));
+            assert_eq!(result.unwrap_err(), ErrorCode::MarketClosed.into());
+            let (result, _, _, _) =
+                close_position(&ledger, &position, ledger.trader.owner, usd(100));
+            result.unwrap();
+        }
+
+        ledger.market.open_window_start_slot = TEST_SLOT;
+        ledger.market.open_window_end_slot = TEST_SLOT + 1;
+        let (result, _) = open_position(&mut ledger, &price_update, true, 10, usd(200));
+        result.unwrap();
+    }
+
+    #[test]
+    fn reduce_only_blocks_opens_but_not_closes() {
+        reset_syscalls();
+        let price_update = price_update(usd(100));
+        let mut ledger = Ledger::new();
+        let position = ledger.open(true, usd(100), 10, usd(200));
+        ledger.market.reduce_only = true;
+
+        let (result, _) = open_position(&mut ledger, &price_update, true, 10, usd(200));
+        assert_eq!(result.unwrap_err(), ErrorCode::MarketReduceOnly.into());
+        // existing positions can still be managed and wound down
+        let (result, _, _, _) = add_collateral(&ledger, &position, usd(50));
+        result.unwrap();
+        let (result, _, _, _) = close_position(&ledger, &position, ledger.trader.owner, usd(100));
+        result.unwrap();
+
+        ledger.market.reduce_only = false;
+        let (result, _) = open_position(&mut ledger, &price_update, true, 10, usd(200));
+        result.unwrap();
+    }
+
+    #[test]
+    fn global_position_cap_spans_markets() {
+        reset_syscalls();
+        let price_update = price_update(usd(100));
+        let mut ledger = Ledger::new();
+        ledger.config.max_global_positions = 3;
+        let mut btc = market(0);
+        btc.pair = "BTC/USD".to_string();
+        let mut markets = [ledger.market.clone(), btc];
+
+        for i in [0, 1, 0] {
+            std::mem::swap(&mut ledger.market, &mut markets[i]);
+            let (result, _) = open_position(&mut ledger, &price_update, true, 10, usd(200));
+            result.unwrap();
+            std::mem::swap(&mut ledger.market, &mut markets[i]);
+        }
+        assert_eq!(ledger.config.total_open_positions, 3);
+        assert_eq!(markets[0].total_active_positions, 2);
+        assert_eq!(markets[1].total_active_positions, 1);
+
+        // neither market is near a limit of its own
+        ledger.market = markets[1].clone();
+        let (result, _) = open_position(&mut ledger, &price_update, true, 10, usd(200));
+        assert_eq!(
+            result.unwrap_err(),
+            ErrorCode::GlobalPositionLimitReached.into()
+        );
+        assert_eq!(ledger.config.total_open_positions, 3);
+    }
+
+    #[test]
+    fn configured_collateral_floor_replaces_the_fixed_fraction() {
+        reset_syscalls();
+        let price_update = price_update(usd(100));
+        let mut ledger = Ledger::new();
+        ledger.config.min_effective_collateral = usd(50);
+
+        // $49.95 after the fee clears `MIN_COLLATERAL / 2` by far but not the configured floor
+        let (result, _) = open_position(&mut ledger, &price_update, true, 5, usd(50));
+        assert_eq!(
+            result.unwrap_err(),
+            ErrorCode::EffectiveCollateralTooLow.into()
+        );
+        let (result, position) = open_position(&mut ledger, &price_update, true, 5, usd(60));
+        result.unwrap();
+        assert!(position.collateral >= usd(50));
+    }
+
+    #[test]
+    fn one_funding_period_must_not_liquidate_a_new_position() {
+        reset_syscalls();
+        let price_update = price_update(usd(100));
+        let mut ledger = Ledger::new();
+        // 7% of the notional per period against 5% maintenance
+        ledger.market.funding_rate_bps = 700;
+
+        // about 9x leaves 11% of the notional, 4% after a period
+        let (result, _) = open_position(&mut ledger, &price_update, true, 90, usd(1_000));
+        assert_eq!(
+            result.unwrap_err(),
+            ErrorCode::EffectiveCollateralTooLow.into()
+        );
+        // about 3x still has 26% left
+        let (result, _) = open_position(&mut ledger, &price_update, true, 30, usd(1_000));
+        result.unwrap();
+
+        // without the funding 9x clears maintenance
+        ledger.market.funding_rate_bps = 0;
+        let (result, _) = open_position(&mut ledger, &price_update, true, 90, usd(1_000));
+        result.unwrap();
+    }
+
+    #[test]
+    fn high_leverage_opens_demand_a_tighter_confidence() {
+        reset_syscalls();
+        let mut ledger = Ledger::new();
+        ledger.config.confidence_reference_leverage = 20_000;
+        // a 0.4% band, inside the 1% a 2x open accepts but not the 0.25% at 8x
+        let mut price_update = price_update(usd(100));
+        price_update.price_message.conf = 400_000;
+
+        let (result, _) = open_position(&mut ledger, &price_update, true, 80, usd(1_000));
+        assert_eq!(
+            result.unwrap_err(),
+            ErrorCode::PriceConfidenceTooHigh.into()
+        );
+        let (result, low) = open_position(&mut ledger, &price_update, true, 20, usd(1_000));
+        result.unwrap();
+        assert!(low.leverage <= 25_000);
+
+        // with the scaling off 8x gets the full 1% again
+        ledger.config.confidence_reference_leverage = 0;
+        let (result, _) = open_position(&mut ledger, &price_update, true, 80, usd(1_000));
+        result.unwrap();
+    }
+
+    #[test]
+    fn open_position_holds_the_tighter_leverage_cap() {
+        reset_syscalls();
+        let price_update = price_update(usd(100));
+        let mut ledger = Ledger::new();
+        // 10x config cap, a $1,000 collateral opens $8,000 at 8x and $12,000 at 12x
+        assert_eq!(ledger.config.max_leverage, 100_000);
+
+        // a tighter market cap binds
+        ledger.market.max_leverage = 50_000;
+        let (result, _) = open_position(&mut ledger, &price_update, true, 80, usd(1_000));
+        assert_eq!(result.unwrap_err(), ErrorCode::ExcessiveLeverage.into());
+
+        // a looser one doesn't lift the config's
+        ledger.market.max_leverage = 200_000;
+        let (result, _) = open_position(&mut ledger, &price_update, true, 120, usd(1_000));
+        assert_eq!(result.unwrap_err(), ErrorCode::ExcessiveLeverage.into());
+        let (result, position) = open_position(&mut ledger, &price_update, true, 80, usd(1_000));
+        result.unwrap();
+        assert!(position.leverage > 80_000 && position.leverage <= 100_000);
+    }
+
+    #[test]
+    fn opening_fee_rises_with_leverage() {
+        reset_syscalls();
+        let price_update = price_update(usd(100));
+        let mut ledger = Ledger::new();
+        ledger.config.leverage_fee_surcharge = 5;
+        ledger.config.max_opening_fee = 40;
+
+        // about 2x pays 10 bps plus about 5 bps of the $1,000
+        let (result, low) = open_position(&mut ledger, &price_update, true, 20, usd(1_000));
+        let low_fee = result.unwrap().opening_fee;
+        assert!(low.leverage >= 20_000 && low.leverage < 30_000);
+        assert!(low_fee > usd(1) && low_fee < usd(4));
+        // about 8x is past the 40 bps cap
+        let (result, high) = open_position(&mut ledger, &price_update, true, 80, usd(1_000));
+        let high_fee = result.unwrap().opening_fee;
+        assert!(high.leverage >= 80_000);
+        assert_eq!(high_fee, usd(4));
+        assert_eq!(high.opening_fee_paid, high_fee);
+    }
+
+    /// Config administered by `admin`, and the market `pair` with its key
+    fn admin_accounts(admin: Pubkey) -> (TestAccount, TestAccount) {
+        let mut config = config();
+        config.admin = admin;
+        let market = market(0);
+        let (market_key, _) =
+            Pubkey::find_program_address(&[b"market", market.pair.as_bytes()], &crate::ID);
+        let (config_key, _) = Pubkey::find_program_address(&[b"config"], &crate::ID);
+        (
+            TestAccount::program(config_key, &config),
+            TestAccount::program(market_key, &market),
+        )
+    }
+
+    #[test]
+    fn update_config_emits_the_applied_changes() {
+        reset_syscalls();
+        let admin = Pubkey::new_unique();
+        let (mut config, _) = admin_accounts(admin);
+        let mut signer = TestAccount::wallet(admin);
+        let (config_info, signer_info) = (config.info(false), signer.info(true));
+        let mut accounts = UpdateConfig {
+            signer: Signer::try_from(&signer_info).unwrap(),
+            config: Account::try_from(&config_info).unwrap(),
+        };
+        let params = UpdateConfigParams {
+            opening_fee: Some(20),
+            ..Default::default()
+        };
+        regret_market::update_config(
+            Context::new(&crate::ID, &mut accounts, &[], Default::default()),
+            params,
+        )
+        .unwrap();
+
+        let events = emitted::<ConfigUpdated>();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].admin, admin);
+        assert_eq!(events[0].changes.opening_fee, Some(20));
+        assert_eq!(events[0].changes.closing_fee, None);
+        assert_eq!(events[0].slot, TEST_SLOT);
+    }
+
+    #[test]
+    fn update_market_emits_the_changed_fields() {
+        reset_syscalls();
+        let admin = Pubkey::new_unique();
+        let (mut config, mut market) = admin_accounts(admin);
+        let mut signer = TestAccount::wallet(admin);
+        let (config_info, market_info, signer_info) =
+            (config.info(false), market.info(false), signer.info(true));
+        let mut accounts = UpdateMarket {
+            config: Account::try_from(&config_info).unwrap(),
+            signer: Signer::try_from(&signer_info).unwrap(),
+            market: Account::try_from(&market_info).unwrap(),
+        };
+        regret_market::update_market(
+            Context::new(&crate::ID, &mut accounts, &[], Default::default()),
+            "SOL/USD".to_string(),
+            Some("new-feed".to_string()),
+            None,
+            None,
+            Some(true),
+            None,
+            None,
+        )
+        .unwrap();
+
+        let events = emitted::<MarketUpdated>();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].admin, admin);
+        assert_eq!(events[0].feed_id.as_deref(), Some("new-feed"));
+        assert_eq!(events[0].reduce_only, Some(true));
+        assert_eq!(events[0].max_leverage, None);
+        assert_eq!(events[0].feed_id_version, 1);
+        assert_eq!(events[0].slot, TEST_SLOT);
+    }
+
+    #[test]
+    fn pausing_a_market_emits_the_toggle() {
+        reset_syscalls();
+        let admin = Pubkey::new_unique();
+        let (mut config, mut market) = admin_accounts(admin);
+        let mut signer = TestAccount::wallet(admin);
+        let (config_info, market_info, signer_info) =
+            (config.info(false), market.info(false), signer.info(true));
+        let mut accounts = PauseMarket {
+            config: Account::try_from(&config_info).unwrap(),
+            signer: Signer::try_from(&signer_info).unwrap(),
+            market: Account::try_from(&market_info).unwrap(),
+        };
+        regret_market::pause_market(
+            Context::new(&crate::ID, &mut accounts, &[], Default::default()),
+            "SOL/USD".to_string(),
+        )
+        .unwrap();
+
+        let events = emitted::<MarketPauseToggled>();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].admin, admin);
+        assert!(events[0].is_paused);
+    }
+
+    #[test]
+    fn rejected_mutation_emits_nothing() {
+        reset_syscalls();
+        let (mut config, _) = admin_accounts(Pubkey::new_unique());
+        let mut signer = TestAccount::wallet(Pubkey::new_unique());
+        let (config_info, signer_info) = (config.info(false), signer.info(true));
+        let mut accounts = UpdateConfig {
+            signer: Signer::try_from(&signer_info).unwrap(),
+            config: Account::try_from(&config_info).unwrap(),
+        };
+        assert_eq!(
+            regret_market::update_config(
+                Context::new(&crate::ID, &mut accounts, &[], Default::default()),
+                UpdateConfigParams::default(),
+            )
+            .unwrap_err(),
+            ErrorCode::Unauthorized.into()
+        );
+        assert!(emitted::<ConfigUpdated>().is_empty());
+    }
+
+    /// Run `open_market` for `signer` on a config administered by `admin`, returning the
+    /// market as the handler left it
+    fn open_market(
+        admin: Pubkey,
+        signer: Pubkey,
+        max_leverage: Option<u64>,
+        maintainance_margin: Option<u16>,
+    ) -> (Result<()>, Market) {
+        let (mut config, mut market) = admin_accounts(admin);
+        let mut signer = TestAccount::wallet(signer);
+        let mut system_program = TestAccount::system_program();
+        let infos = [
+            config.info(false),
+            signer.info(true),
+            market.info(false),
+            system_program.info(false),
+        ];
+        let mut accounts = OpenMarket {
+            config: Account::try_from(&infos[0]).unwrap(),
+            signer: Signer::try_from(&infos[1]).unwrap(),
+            market: Account::try_from(&infos[2]).unwrap(),
+            price_update: None,
+            system_program: Program::try_from(&infos[3]).unwrap(),
+        };
+        let result = regret_market::open_market(
+            Context::new(&crate::ID, &mut accounts, &[], Default::default()),
+            "SOL/USD".to_string(),
+            DECIMALS,
+            FEED_ID.to_string(),
+            None,
+            None,
+            max_leverage,
+            maintainance_margin,
+        );
+        (result, (*accounts.market).clone())
+    }
+
+    #[test]
+    fn only_the_admin_overrides_a_new_markets_margin() {
+        reset_syscalls();
+        let admin = Pubkey::new_unique();
+        let anyone = Pubkey::new_unique();
+
+        let (result, _) = open_market(admin, anyone, None, Some(MIN_MAINTAINANCE_MARGIN));
+        assert_eq!(result.unwrap_err(), ErrorCode::Unauthorized.into());
+        assert!(emitted::<MarketOpened>().is_empty());
+
+        // without the override anyone can open it on the config's margin
+        let (result, market) = open_market(admin, anyone, None, None);
+        result.unwrap();
+        assert_eq!(market.maintainance_margin, 0);
+
+        let (result, market) = open_market(admin, admin, None, Some(MIN_MAINTAINANCE_MARGIN));
+        result.unwrap();
+        assert_eq!(market.maintainance_margin, MIN_MAINTAINANCE_MARGIN);
+    }
+
+    #[test]
+    fn only_the_admin_caps_a_new_markets_leverage() {
+        reset_syscalls();
+        let admin = Pubkey::new_unique();
+
+        let (result, _) = open_market(admin, Pubkey::new_unique(), Some(200_000), None);
+        assert_eq!(result.unwrap_err(), ErrorCode::Unauthorized.into());
+
+        let (result, market) = open_market(admin, admin, Some(200_000), None);
+        result.unwrap();
+        assert_eq!(market.max_leverage, 200_000);
+    }
+
+    /// Run `create_pool` then `fund_pool` for `signer`, returning both results and the
+    /// pool as left
+    fn create_and_fund_pool(admin: Pubkey, signer: Pubkey) -> (Result<()>, Result<()>, Vault) {
+        let (mut config, _) = admin_accounts(admin);
+        let mut signer = TestAccount::wallet(signer);
+        let mut pool = TestAccount::program(Pubkey::new_unique(), &pool(0));
+        let mut system_program = TestAccount::system_program();
+        let infos = [
+            config.info(false),
+            signer.info(true),
+            pool.info(false),
+            system_program.info(false),
+        ];
+        let mut accounts = CreatePool {
+            config: Account::try_from(&infos[0]).unwrap(),
+            signer: Signer::try_from(&infos[1]).unwrap(),
+            pool: Account::try_from(&infos[2]).unwrap(),
+            system_program: Program::try_from(&infos[3]).unwrap(),
+        };
+        let created = regret_market::create_pool(
+            Context::new(&crate::ID, &mut accounts, &[], Default::default()),
+            "USDC".to_string(),
+        );
+        let mut accounts = FundPool {
+            config: accounts.config,
+            signer: accounts.signer,
+            pool: accounts.pool,
+        };
+        let funded = regret_market::fund_pool(
+            Context::new(&crate::ID, &mut accounts, &[], Default::default()),
+            "USDC".to_string(),
+        );
+        (created, funded, (*accounts.pool).clone())
+    }
+
+    #[test]
+    fn only_the_admin_creates_and_funds_pools() {
+        reset_syscalls();
+        let admin = Pubkey::new_unique();
+
+        let (created, funded, pool) = create_and_fund_pool(admin, Pubkey::new_unique());
+        assert_eq!(created.unwrap_err(), ErrorCode::Unauthorized.into());
+        assert_eq!(funded.unwrap_err(), ErrorCode::Unauthorized.into());
+        assert_eq!(pool.lp_deposit, 0);
+
+        let (created, funded, pool) = create_and_fund_pool(admin, admin);
+        created.unwrap();
+        funded.unwrap();
+        assert_eq!(pool.lp_deposit, 101_000_000_000_000);
+    }
+
+    /// Run `force_expire_position` for `keeper` against the ledger's accounts, returning
+    /// the owner's balance account as the handler left it
+    fn force_expire(
+        ledger: &Ledger,
+        position: &Position,
+        keeper: Pubkey,
+    ) -> (Result<()>, TraderPoolDetail) {
+        let key = Pubkey::new_unique;
+        let mut config = TestAccount::program(key(), &ledger.config);
+        let mut signer = TestAccount::wallet(keeper);
+        let mut owner = TestAccount::wallet(position.owner);
+        let mut trader = TestAccount::program(key(), &ledger.trader);
+        let mut trader_balance = TestAccount::program(key(), &ledger.trader_balance);
+        let mut pool = TestAccount::program(key(), &ledger.pool);
+        let mut insurance_fund = TestAccount::program(key(), &ledger.insurance);
+        let mut market = TestAccount::program(key(), &ledger.market);
+        let mut position = TestAccount::program(key(), position);
+        let infos = [
+            config.info(false),
+            signer.info(true),
+            owner.info(false),
+            trader.info(false),
+            trader_balance.info(false),
+            pool.info(false),
+            insurance_fund.info(false),
+            market.info(false),
+            position.info(false),
+        ];
+        let mut accounts = ForceExpirePosition {
+            config: Account::try_from(&infos[0]).unwrap(),
+            signer: Signer::try_from(&infos[1]).unwrap(),
+            owner_account: SystemAccount::try_from(&infos[2]).unwrap(),
+            trader: Account::try_from(&infos[3]).unwrap(),
+            trader_balance: Account::try_from(&infos[4]).unwrap(),
+            pool: Account::try_from(&infos[5]).unwrap(),
+            insurance_fund: Account::try_from(&infos[6]).unwrap(),
+            market: Account::try_from(&infos[7]).unwrap(),
+            position: Account::try_from(&infos[8]).unwrap(),
+        };
+        let result = regret_market::force_expire_position(
+            Context::new(&crate::ID, &mut accounts, &[], Default::default()),
+            "USDC".to_string(),
+            "SOL/USD".to_string(),
+            Pubkey::default(),
+            0,
+        );
+        (result, (*accounts.trader_balance).clone())
+    }
+
+    #[test]
+    fn force_expiry_is_restricted_to_retired_markets() {
+        reset_syscalls();
+        let keeper = Pubkey::new_unique();
+        let mut ledger = Ledger::new();
+        let position = ledger.open(true, usd(100), 10, usd(200));
+
+        // still trading
+        let (result, _) = force_expire(&ledger, &position, keeper);
+        assert_eq!(result.unwrap_err(), ErrorCode::MarketNotRetired.into());
+
+        // retired, but without a settlement price to close at
+        ledger.market.retired = true;
+        ledger.market.reduce_only = true;
+        let (result, _) = force_expire(&ledger, &position, keeper);
+        assert_eq!(result.unwrap_err(), ErrorCode::SettlementPriceNotSet.into());
+        assert!(emitted::<PositionExpired>().is_empty());
+
+        // settled at $110, the collateral unlocks and the profit less funding is credited
+        ledger.market.settlement_price = usd(110);
+        let before = ledger.trader_balance.balance;
+        let (result, trader_balance) = force_expire(&ledger, &position, keeper);
+        result.unwrap();
+        assert_eq!(trader_balance.locked_balance, 0);
+
+        let events = emitted::<PositionExpired>();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].keeper, keeper);
+        assert_eq!(events[0].price, usd(110));
+        assert_eq!(events[0].gross_price_pnl, usd(100) as i64);
+        assert_eq!(trader_balance.balance, before + events[0].net_pnl as u64);
+        assert!(events[0].net_pnl <= usd(100) as i64);
+    }
+
+    /// Run `add_collateral` against the ledger's accounts, returning the balance, pool and
+    /// position as the handler left them
+    fn add_collateral(
+        ledger: &Ledger,
+        position: &Position,
+        amount: u64,
+    ) -> (Result<()>, TraderPoolDetail, Vault, Position) {
+        let key = Pubkey::new_unique;
+        let mut config = TestAccount::program(key(), &ledger.config);
+        let mut signer = TestAccount::wallet(position.owner);
+        let mut trader = TestAccount::program(key(), &ledger.trader);
+        let mut trader_balance = TestAccount::program(key(), &ledger.trader_balance);
+        let mut pool = TestAccount::program(key(), &ledger.pool);
+        let mut position = TestAccount::program(key(), position);
+        let infos = [
+            config.info(false),
+            signer.info(true),
+            trader.info(false),
+            trader_balance.info(false),
+            pool.info(false),
+            position.info(false),
+        ];
+        let mut accounts = AddCollateral {
+            config: Account::try_from(&infos[0]).unwrap(),
+            signer: Signer::try_from(&infos[1]).unwrap(),
+            trader: Account::try_from(&infos[2]).unwrap(),
+            trader_balance: Account::try_from(&infos[3]).unwrap(),
+            pool: Account::try_from(&infos[4]).unwrap(),
+            position: Account::try_from(&infos[5]).unwrap(),
+        };
+        let result = regret_market::add_collateral(
+            Context::new(&crate::ID, &mut accounts, &[], Default::default()),
+            "USDC".to_string(),
+            "SOL/USD".to_string(),
+            0,
+            amount,
+        );
+        (
+            result,
+            (*accounts.trader_balance).clone(),
+            (*accounts.pool).clone(),
+            (*accounts.position).clone(),
+        )
+    }
+
+    #[test]
+    fn added_collateral_replaces_pool_borrow() {
+        reset_syscalls();
+        let mut ledger = Ledger::new();
+        let position = ledger.open(true, usd(100), 10, usd(200));
+        let borrowed = ledger.pool.total_borrowed;
+        let collateral = position.collateral + usd(300);
+        let position_borrowed = position.booked_borrow - usd(300);
+        let leverage = calculate_leverage_bps(position.position_value, collateral).unwrap();
+
+        let (result, _, pool, added) = add_collateral(&ledger, &position, usd(300));
+        result.unwrap();
+        assert_eq!(pool.total_borrowed, borrowed - usd(300));
+        assert_eq!(added.booked_borrow, position_borrowed);
+        assert_eq!(added.collateral, collateral);
+        assert_eq!(added.leverage, leverage);
+        assert_eq!(pool.trader_collateral, collateral);
+    }
+
+    #[test]
+    fn close_after_a_deposit_restores_total_borrowed() {
+        reset_syscalls();
+        let mut ledger = Ledger::new();
+        let borrowed = ledger.pool.total_borrowed;
+        let position = ledger.open(true, usd(100), 10, usd(200));
+        assert!(ledger.pool.total_borrowed > borrowed);
+
+        let (result, trader_balance, pool, added) = add_collateral(&ledger, &position, usd(300));
+        result.unwrap();
+        ledger.trader_balance = trader_balance;
+        ledger.pool = pool;
+
+        // the entry value less the grown collateral would release $300 too little
+        let (result, _, pool, _) = close_position(&ledger, &added, added.owner, usd(100));
+        result.unwrap();
+        assert_eq!(pool.total_borrowed, borrowed);
+        assert_eq!(pool.trader_collateral, 0);
+    }
+
+    /// Run `remove_collateral` against the ledger's accounts, returning the pool and
+    /// position as the handler left them
+    fn remove_collateral(
+        ledger: &Ledger,
+        position: &Position,
+        price_update: &PriceUpdateV2,
+        amount: u64,
+    ) -> (Result<()>, Vault, Position) {
+        let key = Pubkey::new_unique;
+        let mut config = TestAccount::program(key(), &ledger.config);
+        let mut signer = TestAccount::wallet(position.owner);
+        let mut trader = TestAccount::program(key(), &ledger.trader);
+        let mut trader_balance = TestAccount::program(key(), &ledger.trader_balance);
+        let mut pool = TestAccount::program(key(), &ledger.pool);
+        let mut market = TestAccount::program(key(), &ledger.market);
+        let mut position = TestAccount::program(key(), position);
+        let mut price_update = TestAccount::oracle(price_update);
+        let infos = [
+            config.info(false),
+            signer.info(true),
+            trader.info(false),
+            trader_balance.info(false),
+            pool.info(false),
+            market.info(false),
+            position.info(false),
+            price_update.info(false),
+        ];
+        let mut accounts = RemoveCollateral {
+            config: Account::try_from(&infos[0]).unwrap(),
+            signer: Signer::try_from(&infos[1]).unwrap(),
+            trader: Account::try_from(&infos[2]).unwrap(),
+            trader_balance: Account::try_from(&infos[3]).unwrap(),
+            pool: Account::try_from(&infos[4]).unwrap(),
+            market: Account::try_from(&infos[5]).unwrap(),
+            position: Account::try_from(&infos[6]).unwrap(),
+            price_update: Account::try_from(&infos[7]).unwrap(),
+        };
+        let result = regret_market::remove_collateral(
+            Context::new(&crate::ID, &mut accounts, &[], Default::default()),
+            "USDC".to_string(),
+            "SOL/USD".to_string(),
+            0,
+            amount,
+        );
+        (
+            result,
+            (*accounts.pool).clone(),
+            (*accounts.position).clone(),
+        )
+    }
+
+    #[test]
+    fn removed_collateral_is_lent_by_the_pool() {
+        reset_syscalls();
+        let mut ledger = Ledger::new();
+        let position = ledger.open(true, usd(100), 10, usd(500));
+        let borrowed = ledger.pool.total_borrowed;
+
+        let (result, pool, removed) =
+            remove_collateral(&ledger, &position, &price_update(usd(100)), usd(200));
+        result.unwrap();
+        assert_eq!(pool.total_borrowed, borrowed + usd(200));
+        assert_eq!(removed.booked_borrow, position.booked_borrow + usd(200));
+        assert_eq!(removed.collateral, position.collateral - usd(200));
+
+        // nor past the per-position borrow cap it opened under
+        ledger.config.max_borrow_per_position = position.booked_borrow + usd(150);
+        let (result, _, _) =
+            remove_collateral(&ledger, &position, &price_update(usd(100)), usd(200));
+        assert_eq!(result.unwrap_err(), ErrorCode::BorrowCapExceeded.into());
+        let (result, _, removed) =
+            remove_collateral(&ledger, &position, &price_update(usd(100)), usd(150));
+        result.unwrap();
+        assert_eq!(removed.booked_borrow, ledger.config.max_borrow_per_position);
+        ledger.config.max_borrow_per_position = 0;
+
+        // a pool without the headroom to lend it keeps the margin in place
+        ledger.pool.lp_deposit = ledger.pool.total_borrowed + usd(100);
+        let (result, _, _) =
+            remove_collateral(&ledger, &position, &price_update(usd(100)), usd(200));
+        assert_eq!(result.unwrap_err(), ErrorCode::InsufficientLiquidity.into());
+    }
+
+    #[test]
+    fn diverging_feed_blocks_collateral_removal() {
+        reset_syscalls();
+        let mut ledger = Ledger::new();
+        ledger.config.max_spot_ema_divergence_bps = 200;
+        let position = ledger.open(true, usd(100), 10, usd(500));
+
+        // spot 5% over the EMA
+        let mut diverging = price_update(usd(105));
+        diverging.price_message.ema_price = usd(100) as i64;
+        let (result, _, _) = remove_collateral(&ledger, &position, &diverging, usd(200));
+        assert_eq!(
+            result.unwrap_err(),
+            ErrorCode::PriceDivergenceTooHigh.into()
+        );
+    }
+
+    /// Run `close_trader_account` for the owner of `trader`, returning the `Trader` as
+    /// the handler left it
+    fn close_trader_account(
+        trader: &Trader,
+        trader_balance: &TraderPoolDetail,
+    ) -> (Result<()>, Trader) {
+        let key = Pubkey::new_unique;
+        let mut config = TestAccount::program(key(), &config());
+        let mut signer = TestAccount::wallet(trader.owner);
+        let mut trader = TestAccount::program(key(), trader);
+        let mut trader_balance = TestAccount::program(key(), trader_balance);
+        let infos = [
+            config.info(false),
+            signer.info(true),
+            trader.info(false),
+            trader_balance.info(false),
+        ];
+        let mut accounts = CloseTraderAccount {
+            config: Account::try_from(&infos[0]).unwrap(),
+            signer: Signer::try_from(&infos[1]).unwrap(),
+            trader: Account::try_from(&infos[2]).unwrap(),
+            trader_balance: Account::try_from(&infos[3]).unwrap(),
+        };
+        let result = regret_market::close_trader_account(
+            Context::new(&crate::ID, &mut accounts, &[], Default::default()),
+            "USDC".to_string(),
+        );
+        (result, (*accounts.trader).clone())
+    }
+
+    #[test]
+    fn trader_outlives_its_other_balances_and_retained_positions() {
+        reset_syscalls();
+        let mut ledger = Ledger::new();
+        ledger.trader.balance_accounts = 2;
+
+        // funds left behind would be forfeited
+        let (result, _) = close_trader_account(&ledger.trader, &ledger.trader_balance);
+        assert_eq!(result.unwrap_err(), ErrorCode::BalanceRemaining.into());
+
+        // another mint's balance keeps the trader open
+        ledger.trader_balance.balance = 0;
+        let (result, trader) = close_trader_account(&ledger.trader, &ledger.trader_balance);
+        result.unwrap();
+        assert_eq!(trader.balance_accounts, 1);
+        assert!(!emitted::<TraderAccountClosed>()[0].trader_closed);
+
+        // a seized position record still holds its id
+        ledger.trader.balance_accounts = 1;
+        ledger.trader.retained_positions = 1;
+        let (result, _) = close_trader_account(&ledger.trader, &ledger.trader_balance);
+        assert_eq!(
+            result.unwrap_err(),
+            ErrorCode::OpenPositionsRemaining.into()
+        );
+    }
+
+    /// Run `settle_stranded_position` as `admin` against the ledger's accounts, returning
+    /// the owner's balance account as the handler left it
+    fn settle_stranded(
+        ledger: &Ledger,
+        position: &Position,
+        admin: Pubkey,
+        settlement_price: u64,
+    ) -> (Result<()>, TraderPoolDetail) {
+        let key = Pubkey::new_unique;
+        let mut config = TestAccount::program(key(), &ledger.config);
+        let mut signer = TestAccount::wallet(admin);
+        let mut owner = TestAccount::wallet(position.owner);
+        let mut trader = TestAccount::program(key(), &ledger.trader);
+        let mut trader_balance = TestAccount::program(key(), &ledger.trader_balance);
+        let mut pool = TestAccount::program(key(), &ledger.pool);
+        let mut insurance_fund = TestAccount::program(key(), &ledger.insurance);
+        let mut market = TestAccount::program(key(), &ledger.market);
+        let mut position = TestAccount::program(key(), position);
+        let infos = [
+            config.info(false),
+            signer.info(true),
+            owner.info(false),
+            trader.info(false),
+            trader_balance.info(false),
+            pool.info(false),
+            insurance_fund.info(false),
+            market.info(false),
+            position.info(false),
+        ];
+        let mut accounts = SettleStrandedPosition {
+            config: Account::try_from(&infos[0]).unwrap(),
+            signer: Signer::try_from(&infos[1]).unwrap(),
+            owner_account: SystemAccount::try_from(&infos[2]).unwrap(),
+            trader: Account::try_from(&infos[3]).unwrap(),
+            trader_balance: Account::try_from(&infos[4]).unwrap(),
+            pool: Account::try_from(&infos[5]).unwrap(),
+            insurance_fund: Account::try_from(&infos[6]).unwrap(),
+            market: Account::try_from(&infos[7]).unwrap(),
+            position: Account::try_from(&infos[8]).unwrap(),
+        };
+        let result = regret_market::settle_stranded_position(
+            Context::new(&crate::ID, &mut accounts, &[], Default::default()),
+            "USDC".to_string(),
+            "SOL/USD".to_string(),
+            Pubkey::default(),
+            0,
+            settlement_price,
+        );
+        (result, (*accounts.trader_balance).clone())
+    }
+
+    #[test]
+    fn feed_swap_strands_positions_until_the_admin_settles() {
+        reset_syscalls();
+        let admin = Pubkey::new_unique();
+        let mut ledger = Ledger::new();
+        ledger.config.admin = admin;
+        let position = ledger.open(true, usd(100), 10, usd(500));
+
+        // on the current feed the usual paths apply
+        let (result, _) = settle_stranded(&ledger, &position, admin, usd(110));
+        assert_eq!(result.unwrap_err(), ErrorCode::InvalidInput.into());
+
+        ledger.market.feed_id_version += 1;
+        let (result, _, _) =
+            remove_collateral(&ledger, &position, &price_update(usd(100)), usd(100));
+        assert_eq!(result.unwrap_err(), ErrorCode::FeedVersionMismatch.into());
+
+        let (result, _) = settle_stranded(&ledger, &position, Pubkey::new_unique(), usd(110));
+        assert_eq!(result.unwrap_err(), ErrorCode::Unauthorized.into());
+
+        let before = ledger.trader_balance.balance;
+        let (result, trader_balance) = settle_stranded(&ledger, &position, admin, usd(110));
+        result.unwrap();
+        assert_eq!(trader_balance.locked_balance, 0);
+
+        let events = emitted::<StrandedPositionSettled>();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].feed_version_at_open, 0);
+        assert_eq!(events[0].price, usd(110));
+        assert_eq!(events[0].gross_price_pnl, usd(100) as i64);
+        assert_eq!(trader_balance.balance, before + events[0].net_pnl as u64);
+    }
+
+    /// Run `enable_privacy` against the ledger's accounts, returning the balance
+    /// account, pool and insurance fund as the handler left them
+    fn enable_privacy(ledger: &Ledger) -> (Result<()>, TraderPoolDetail, Vault, InsuranceFund) {
+        let key = Pubkey::new_unique;
+        let mut config = TestAccount::program(key(), &ledger.config);
+        let mut signer = TestAccount::wallet(ledger.trader.owner);
+        let mut trader = TestAccount::program(key(), &ledger.trader);
+        let mut trader_balance = TestAccount::program(key(), &ledger.trader_balance);
+        let mut pool = TestAccount::program(key(), &ledger.pool);
+        let mut insurance_fund = TestAccount::program(key(), &ledger.insurance);
+        let infos = [
+            config.info(false),
+            signer.info(true),
+            trader.info(false),
+            trader_balance.info(false),
+            pool.info(false),
+            insurance_fund.info(false),
+        ];
+        let mut accounts = EnablePrivacy {
+            config: Account::try_from(&infos[0]).unwrap(),
+            signer: Signer::try_from(&infos[1]).unwrap(),
+            trader: Account::try_from(&infos[2]).unwrap(),
+            trader_balance: Account::try_from(&infos[3]).unwrap(),
+            pool: Account::try_from(&infos[4]).unwrap(),
+            insurance_fund: Account::try_from(&infos[5]).unwrap(),
+        };
+        let result = regret_market::enable_privacy(
+            Context::new(&crate::ID, &mut accounts, &[], Default::default()),
+            "USDC".to_string(),
+        );
+        (
+            result,
+            (*accounts.trader_balance).clone(),
+            (*accounts.pool).clone(),
+            (*accounts.insurance_fund).clone(),
+        )
+    }
+
+    #[test]
+    fn privacy_costs_a_flat_fee_whatever_the_balance() {
+        reset_syscalls();
+        let mut ledger = Ledger::new();
+        ledger.config.privacy_fee = 50;
+        ledger.config.insurance_target_bps = BASIS_POINTS as u64;
+        ledger.config.insurance_fee_share_bps = 2_000;
+        let position = ledger.open(true, usd(100), 10, usd(200));
 
-        // Calculate opening fee with precision
-        let opening_fee_scaled = (collateral as u128)
-            .checked_mul(config.opening_fee as u128)
-            .ok_or(ErrorCode::MathOverflow)?
-            .checked_mul(PRECISION)
-            .ok_or(ErrorCode::MathOverflow)?
-            .checked_div(BASIS_POINTS)
-            .ok_or(ErrorCode::MathOverflow)?;
+        // a balance too small for the fee doesn't get privacy for less
+        let balance = ledger.trader_balance.balance;
+        ledger.trader_balance.balance = ledger.trader_balance.locked_balance + usd(49);
+        let (result, _, _, _) = enable_privacy(&ledger);
+        assert_eq!(result.unwrap_err(), ErrorCode::NotEnoughBalance.into());
+        ledger.trader_balance.balance = balance;
 
-        let opening_fee = opening_fee_scaled
-            .checked_div(PRECISION)
-            .ok_or(ErrorCode::MathOverflow)?;
+        // $50 whatever the balance, a fifth of it to insurance below the target
+        let (result, trader_balance, pool, insurance) = enable_privacy(&ledger);
+        result.unwrap();
+        assert_eq!(
+            trader_balance.balance,
+            ledger.trader_balance.balance - usd(50)
+        );
+        assert_eq!(insurance.balance, ledger.insurance.balance + usd(10));
+        assert_eq!(
+            pool.accumulated_fees + pool.accumulated_lp_fees,
+            ledger.pool.accumulated_fees + ledger.pool.accumulated_lp_fees + usd(40)
+        );
+        assert_eq!(emitted::<PrivacyEnabled>()[0].fee, usd(50));
 
-        let opening_fee = u64::try_from(opening_fee).map_err(|_| ErrorCode::MathOverflow)?;
+        // position events no longer name the owner
+        ledger.trader.privacy = true;
+        let (result, _, _) =
+            remove_collateral(&ledger, &position, &price_update(usd(100)), usd(10));
+        result.unwrap();
+        assert_eq!(emitted::<CollateralRemoved>()[0].owner, Pubkey::default());
+    }
 
-        // Ensure minimum fee is collected
-        require!(opening_fee > 0, ErrorCode::FeeTooLow);
+    /// Run `rebalance_or_liquidate_position` for `signer` at `price` against the ledger's
+    /// accounts, with `keeper_balance` as the signer's balance, returning the owner's
+    /// balance account, pool and position as the handler left them, `None` once closed
+    fn rebalance_or_liquidate(
+        ledger: &Ledger,
+        position: &Position,
+        signer: Pubkey,
+        keeper_balance: Option<&TraderPoolDetail>,
+        price: u64,
+    ) -> (Result<()>, TraderPoolDetail, Vault, Option<Position>) {
+        let mut owner = TestAccount::wallet(position.owner);
+        let mut signer = TestAccount::wallet(signer);
+        let mut position = TestAccount::program(Pubkey::new_unique(), position);
+        keeper_call(
+            ledger,
+            &mut position,
+            &mut owner,
+            &mut signer,
+            keeper_balance,
+            price,
+        )
+    }
 
-        // Calculate effective collateral after fee
-        let effective_collateral = collateral
-            .checked_sub(opening_fee)
-            .ok_or(ErrorCode::InsufficientCollateralForFees)?;
+    /// `rebalance_or_liquidate` on the given position, owner and signer accounts, so their
+    /// lamports can be checked and calls can follow each other on the same position
+    fn keeper_call(
+        ledger: &Ledger,
+        position: &mut TestAccount,
+        owner: &mut TestAccount,
+        signer: &mut TestAccount,
+        keeper_balance: Option<&TraderPoolDetail>,
+        price: u64,
+    ) -> (Result<()>, TraderPoolDetail, Vault, Option<Position>) {
+        let key = Pubkey::new_unique;
+        let mut config = TestAccount::program(key(), &ledger.config);
+        let mut trader = TestAccount::program(key(), &ledger.trader);
+        let mut trader_balance = TestAccount::program(key(), &ledger.trader_balance);
+        let mut pool = TestAccount::program(key(), &ledger.pool);
+        let mut insurance_fund = TestAccount::program(key(), &ledger.insurance);
+        let mut market = TestAccount::program(key(), &ledger.market);
+        let mut price_update = TestAccount::oracle(&price_update(price));
+        let mut keeper_balance = keeper_balance.map(|balance| TestAccount::program(key(), balance));
+        let infos = [
+            config.info(false),
+            signer.info(true),
+            owner.info(false),
+            trader.info(false),
+            trader_balance.info(false),
+            pool.info(false),
+            insurance_fund.info(false),
+            market.info(false),
+            position.info(false),
+            price_update.info(false),
+        ];
+        let keeper_info = keeper_balance.as_mut().map(|balance| balance.info(false));
+        let mut accounts = RebalanceOrLiquidatePosition {
+            config: Account::try_from(&infos[0]).unwrap(),
+            signer: Signer::try_from(&infos[1]).unwrap(),
+            owner_account: SystemAccount::try_from(&infos[2]).unwrap(),
+            trader: Account::try_from(&infos[3]).unwrap(),
+            trader_balance: Account::try_from(&infos[4]).unwrap(),
+            pool: Account::try_from(&infos[5]).unwrap(),
+            insurance_fund: Account::try_from(&infos[6]).unwrap(),
+            market: Account::try_from(&infos[7]).unwrap(),
+            position: UncheckedAccount::try_from(&infos[8]),
+            price_update: Account::try_from(&infos[9]).unwrap(),
+            keeper_balance: keeper_info
+                .as_ref()
+                .map(|info| Account::try_from(info).unwrap()),
+        };
+        let result = regret_market::rebalance_or_liquidate_position(
+            Context::new(&crate::ID, &mut accounts, &[], Default::default()),
+            "USDC".to_string(),
+            "SOL/USD".to_string(),
+            Pubkey::default(),
+            0,
+        );
+        let position =
+            Position::try_deserialize(&mut &infos[8].try_borrow_data().unwrap()[..]).ok();
+        (
+            result,
+            (*accounts.trader_balance).clone(),
+            (*accounts.pool).clone(),
+            position,
+        )
+    }
 
-        // Ensure effective collateral is still reasonable (at least 50% of minimum)
-        require!(
-            effective_collateral >= MIN_COLLATERAL / 2,
-            ErrorCode::EffectiveCollateralTooLow
+    #[test]
+    fn private_owner_acts_as_keeper_on_their_own_position() {
+        reset_syscalls();
+        let owner = Pubkey::new_unique();
+        let mut ledger = Ledger::new();
+        ledger.config.tp_execution_reward = usd(1);
+        ledger.trader.owner = owner;
+        ledger.trader.privacy = true;
+        let mut position = ledger.open(true, usd(100), 10, usd(200));
+        position.owner = owner;
+        let before = ledger.trader_balance.balance - position.collateral;
+
+        // liquidated at $82, the reward is folded into the payout without a keeper balance
+        let (result, trader_balance, pool, _) =
+            rebalance_or_liquidate(&ledger, &position, owner, None, usd(82));
+        result.unwrap();
+        assert_eq!(trader_balance.locked_balance, 0);
+        assert_eq!(pool.trader_collateral, 0);
+        let liquidated = &emitted::<PositionLiquidated>()[0];
+        assert_eq!(liquidated.owner, Pubkey::default());
+        assert!(liquidated.liquidation_reward > 0);
+        assert_eq!(
+            trader_balance.balance,
+            before + liquidated.trader_rebate + liquidated.liquidation_reward
         );
 
-        // Calculate target price with precision (10% above for long, 10% below for short)
-        let target_price = if is_long {
-            let target_scaled = (current_price as u128)
-                .checked_mul(110)
-                .ok_or(ErrorCode::MathOverflow)?
-                .checked_mul(PRECISION)
-                .ok_or(ErrorCode::MathOverflow)?
-                .checked_div(100)
-                .ok_or(ErrorCode::MathOverflow)?;
+        // so is the take-profit execution reward
+        reset_syscalls();
+        position.take_profit_price = usd(110);
+        let (result, trader_balance, _, _) =
+            rebalance_or_liquidate(&ledger, &position, owner, None, usd(111));
+        result.unwrap();
+        assert_eq!(trader_balance.locked_balance, 0);
+        let executed = &emitted::<TakeProfitExecuted>()[0];
+        assert_eq!(executed.owner, Pubkey::default());
+        assert_eq!(executed.keeper_reward, usd(1));
+        assert_eq!(
+            trader_balance.balance,
+            before + executed.amount_to_return + executed.keeper_reward
+        );
 
-            let target = target_scaled
-                .checked_div(PRECISION)
-                .ok_or(ErrorCode::MathOverflow)?;
+        // a third-party keeper still needs its own balance for the reward
+        let (result, _, _, _) =
+            rebalance_or_liquidate(&ledger, &position, Pubkey::new_unique(), None, usd(111));
+        assert_eq!(result.unwrap_err(), ErrorCode::KeeperBalanceRequired.into());
+    }
 
-            u64::try_from(target).map_err(|_| ErrorCode::MathOverflow)?
-        } else {
-            let target_scaled = (current_price as u128)
-                .checked_mul(90)
-                .ok_or(ErrorCode::MathOverflow)?
-                .checked_mul(PRECISION)
-                .ok_or(ErrorCode::MathOverflow)?
-                .checked_div(100)
-                .ok_or(ErrorCode::MathOverflow)?;
+    #[test]
+    fn closing_keeper_actions_return_the_rent_to_the_owner() {
+        reset_syscalls();
+        let keeper = Pubkey::new_unique();
+        let mut ledger = Ledger::new();
+        ledger.config.tp_execution_reward = usd(1);
+        let keeper_balance = trader_balance(0);
+        let mut position = ledger.open(true, usd(100), 10, usd(200));
 
-            let target = target_scaled
-                .checked_div(PRECISION)
-                .ok_or(ErrorCode::MathOverflow)?;
+        // liquidated at $82, then taken profit on at $111
+        for (price, take_profit_price) in [(usd(82), 0), (usd(111), usd(110))] {
+            position.take_profit_price = take_profit_price;
+            let mut owner = TestAccount::wallet(position.owner);
+            let mut signer = TestAccount::wallet(keeper);
+            let mut account = TestAccount::program(Pubkey::new_unique(), &position);
+            let (owner_before, signer_before) = (owner.lamports(), signer.lamports());
+            let rent = account.lamports();
 
-            u64::try_from(target).map_err(|_| ErrorCode::MathOverflow)?
-        };
+            let (result, _, pool, _) = keeper_call(
+                &ledger,
+                &mut account,
+                &mut owner,
+                &mut signer,
+                Some(&keeper_balance),
+                price,
+            );
+            result.unwrap();
+            assert!(pool.lifetime_keeper_rewards > 0);
+            assert_eq!(account.lamports(), 0);
+            assert_eq!(owner.lamports(), owner_before + rent);
+            assert_eq!(signer.lamports(), signer_before);
+        }
+        assert_eq!(emitted::<PositionLiquidated>().len(), 1);
+        assert_eq!(emitted::<TakeProfitExecuted>().len(), 1);
+    }
 
-        // Calculate position parameters with effective collateral
-        let PositionParams {
-            actual_size,
-            leverage_bps,
-            position_value,
-            target_price: current_target_price,
-        } = if is_long {
-            calculate_long_position(
-                desired_entry_price,
-                desired_size,
-                current_price,
-                target_price,
-                effective_collateral,
-                market.decimals,
-            )?
-        } else {
-            calculate_short_position(
-                desired_entry_price,
-                desired_size,
-                current_price,
-                target_price,
-                effective_collateral,
-                market.decimals,
-            )?
-        };
+    #[test]
+    fn losing_keeper_finds_the_position_already_closed() {
+        reset_syscalls();
+        let (first, second) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let mut ledger = Ledger::new();
+        let keeper_balance = trader_balance(0);
+        let position = ledger.open(true, usd(100), 10, usd(200));
+        let mut account = TestAccount::program(Pubkey::new_unique(), &position);
+        let mut owner = TestAccount::wallet(position.owner);
 
-        // Validate position parameters
-        validate_position_value(position_value)?;
-        validate_position_size(actual_size)?;
+        // both keepers race to liquidate at $82, the first one wins
+        let (result, _, _, left) = keeper_call(
+            &ledger,
+            &mut account,
+            &mut owner,
+            &mut TestAccount::wallet(first),
+            Some(&keeper_balance),
+            usd(82),
+        );
+        result.unwrap();
+        assert!(left.is_none());
 
-        // Validate leverage is within limits
-        require!(
-            leverage_bps <= config.max_leverage,
-            ErrorCode::ExcessiveLeverage
+        // the second one hits the emptied account
+        let (result, _, _, _) = keeper_call(
+            &ledger,
+            &mut account,
+            &mut owner,
+            &mut TestAccount::wallet(second),
+            Some(&keeper_balance),
+            usd(82),
         );
+        assert_eq!(result.unwrap_err(), ErrorCode::PositionAlreadyClosed.into());
+        assert_eq!(emitted::<PositionLiquidated>().len(), 1);
 
-        // Calculate borrowing amount
-        let borrowing_amount = position_value
-            .checked_sub(effective_collateral)
-            .ok_or(ErrorCode::MathOverflow)?;
+        // so does a keeper calling on a position that's closed but still on chain
+        let mut closed = position.clone();
+        closed.closed_at = 1;
+        let (result, _, _, _) =
+            rebalance_or_liquidate(&ledger, &closed, second, Some(&keeper_balance), usd(82));
+        assert_eq!(result.unwrap_err(), ErrorCode::PositionAlreadyClosed.into());
+    }
 
-        // Check pool has sufficient liquidity
-        if pool.available_liquidity() < borrowing_amount {
-            return err!(ErrorCode::InsufficientLiquidity);
+    #[test]
+    fn a_too_soon_retry_backs_off() {
+        reset_syscalls();
+        let keeper = Pubkey::new_unique();
+        let mut ledger = Ledger::new();
+        ledger.config.liquidation_backoff_slots = 10;
+        let keeper_balance = trader_balance(0);
+        let mut position = ledger.open(true, usd(100), 10, usd(200));
+        position.current_target_price = usd(110);
+
+        // nothing to do at $100, the miss is recorded instead of failing
+        let (result, _, _, checked) =
+            rebalance_or_liquidate(&ledger, &position, keeper, Some(&keeper_balance), usd(100));
+        result.unwrap();
+        let mut checked = checked.unwrap();
+        assert_eq!(checked.liquidation_attempts, 1);
+        assert_eq!(checked.last_liquidation_attempt_slot, TEST_SLOT);
+
+        // retrying in the same slot, or one short of the backoff, is rejected
+        for since in [0, 9] {
+            checked.last_liquidation_attempt_slot = TEST_SLOT - since;
+            let (result, _, _, _) =
+                rebalance_or_liquidate(&ledger, &checked, keeper, Some(&keeper_balance), usd(100));
+            assert_eq!(result.unwrap_err(), ErrorCode::LiquidationBackoff.into());
         }
 
-        // Split opening fee between protocol and LPs with precision
-        let protocol_fee_scaled = (opening_fee as u128)
-            .checked_mul(config.protocol_fee_share as u128)
-            .ok_or(ErrorCode::MathOverflow)?
-            .checked_mul(PRECISION)
-            .ok_or(ErrorCode::MathOverflow)?
-            .checked_div(BASIS_POINTS)
-            .ok_or(ErrorCode::MathOverflow)?;
+        // once it has passed the next miss doubles the wait
+        checked.last_liquidation_attempt_slot = TEST_SLOT - 10;
+        let (result, _, _, rechecked) =
+            rebalance_or_liquidate(&ledger, &checked, keeper, Some(&keeper_balance), usd(100));
+        result.unwrap();
+        let rechecked = rechecked.unwrap();
+        assert_eq!(rechecked.liquidation_attempts, 2);
+        assert_eq!(rechecked.liquidation_backoff(10), 20);
 
-        let protocol_fee = protocol_fee_scaled
-            .checked_div(PRECISION)
-            .ok_or(ErrorCode::MathOverflow)?;
+        // a liquidatable position isn't held back
+        let (result, trader_balance, _, left) =
+            rebalance_or_liquidate(&ledger, &rechecked, keeper, Some(&keeper_balance), usd(82));
+        result.unwrap();
+        assert!(left.is_none());
+        assert_eq!(trader_balance.locked_balance, 0);
+    }
 
-        let protocol_fee = u64::try_from(protocol_fee).map_err(|_| ErrorCode::MathOverflow)?;
+    #[test]
+    fn rebalance_holds_the_borrow_cap() {
+        reset_syscalls();
+        let keeper = Pubkey::new_unique();
+        let mut ledger = Ledger::new();
+        let mut position = ledger.open(true, usd(100), 10, usd(500));
+        position.current_target_price = usd(110);
+        let keeper_balance = trader_balance(0);
 
-        let lp_fee = opening_fee
-            .checked_sub(protocol_fee)
-            .ok_or(ErrorCode::MathOverflow)?;
+        let (result, _, _, rebalanced) =
+            rebalance_or_liquidate(&ledger, &position, keeper, Some(&keeper_balance), usd(112));
+        result.unwrap();
+        let rebalanced = rebalanced.unwrap();
+        assert!(rebalanced.booked_borrow > position.booked_borrow);
 
-        // Update pool fee accumulators
-        pool.accumulated_fees = pool
-            .accumulated_fees
-            .checked_add(protocol_fee)
-            .ok_or(ErrorCode::MathOverflow)?;
+        // opened under the cap, but re-struck past it
+        ledger.config.max_borrow_per_position = rebalanced.booked_borrow - 1;
+        let (result, _, _, _) =
+            rebalance_or_liquidate(&ledger, &position, keeper, Some(&keeper_balance), usd(112));
+        assert_eq!(result.unwrap_err(), ErrorCode::BorrowCapExceeded.into());
+    }
 
-        pool.accumulated_lp_fees = pool
-            .accumulated_lp_fees
-            .checked_add(lp_fee)
-            .ok_or(ErrorCode::MathOverflow)?;
+    /// Run `merge_positions` for the owner against the ledger's accounts, returning the
+    /// merged position as the handler left it
+    fn merge_positions(
+        ledger: &Ledger,
+        position: &Position,
+        other_position: &Position,
+    ) -> (Result<()>, Position) {
+        let key = Pubkey::new_unique;
+        let mut config = TestAccount::program(key(), &ledger.config);
+        let mut signer = TestAccount::wallet(position.owner);
+        let mut trader = TestAccount::program(key(), &ledger.trader);
+        let mut market = TestAccount::program(key(), &ledger.market);
+        let mut position = TestAccount::program(key(), position);
+        let mut other_position = TestAccount::program(key(), other_position);
+        let mut price_update = TestAccount::oracle(&price_update(usd(100)));
+        let infos = [
+            config.info(false),
+            signer.info(true),
+            trader.info(false),
+            market.info(false),
+            position.info(false),
+            other_position.info(false),
+            price_update.info(false),
+        ];
+        let mut accounts = MergePositions {
+            config: Account::try_from(&infos[0]).unwrap(),
+            signer: Signer::try_from(&infos[1]).unwrap(),
+            trader: Account::try_from(&infos[2]).unwrap(),
+            market: Account::try_from(&infos[3]).unwrap(),
+            position: Account::try_from(&infos[4]).unwrap(),
+            other_position: Account::try_from(&infos[5]).unwrap(),
+            price_update: Account::try_from(&infos[6]).unwrap(),
+        };
+        let result = regret_market::merge_positions(
+            Context::new(&crate::ID, &mut accounts, &[], Default::default()),
+            "SOL/USD".to_string(),
+            0,
+            1,
+        );
+        (result, (*accounts.position).clone())
+    }
 
-        // Deduct opening fee from trader's balance
-        trader_balance.balance = trader_balance
-            .balance
-            .checked_sub(opening_fee)
-            .ok_or(ErrorCode::MathOverflow)?;
+    #[test]
+    fn merged_positions_hold_the_borrow_cap() {
+        reset_syscalls();
+        let mut ledger = Ledger::new();
+        // about $800 borrowed each
+        let position = ledger.open(true, usd(100), 10, usd(200));
+        let mut other_position = ledger.open(true, usd(100), 10, usd(200));
+        other_position.position_id = 1;
+        let merged_borrow = position.booked_borrow * 2;
+        ledger.config.max_borrow_per_position = usd(1_000);
 
-        // Lock effective collateral
-        trader_balance.locked_balance = trader_balance
-            .locked_balance
-            .checked_add(effective_collateral)
-            .ok_or(ErrorCode::MathOverflow)?;
+        let (result, _) = merge_positions(&ledger, &position, &other_position);
+        assert_eq!(result.unwrap_err(), ErrorCode::BorrowCapExceeded.into());
 
-        // Update pool accounting
-        pool.trader_collateral = pool
-            .trader_collateral
-            .checked_add(effective_collateral)
-            .ok_or(ErrorCode::MathOverflow)?;
+        ledger.config.max_borrow_per_position = merged_borrow;
+        let (result, merged) = merge_positions(&ledger, &position, &other_position);
+        result.unwrap();
+        assert_eq!(merged.booked_borrow, merged_borrow);
+    }
 
-        pool.total_borrowed = pool
-            .total_borrowed
-            .checked_add(borrowing_amount)
-            .ok_or(ErrorCode::MathOverflow)?;
+    #[test]
+    fn margin_warning_is_skipped_when_the_pool_cannot_lend_the_penalty() {
+        reset_syscalls();
+        let keeper = Pubkey::new_unique();
+        let keeper_balance = trader_balance(0);
+        let mut ledger = Ledger::new();
+        ledger.config.warning_health_bps = u64::MAX;
+        ledger.config.warning_penalty_bps = 1_000;
+        // about $800 borrowed, out of reach of a rebalance
+        let mut position = ledger.open(true, usd(100), 10, usd(200));
+        position.current_target_price = usd(110);
+        let penalty = position.collateral / 10;
 
-        // Initialize position
-        position.set_inner(Position {
-            owner: ctx.accounts.signer.key(),
-            bump: ctx.bumps.position,
-            entered_at: Clock::get()?.slot,
-            closed_at: 0,
-            last_funding_slot: Clock::get()?.slot,
-            cumulative_funding_paid: 0,
-            position_id,
-            is_long,
-            token_mint,
-            pair,
-            current_target_price,
-            desired_entry_price,
-            desired_size,
-            collateral: effective_collateral,
-            actual_size,
-            actual_entered_price: current_price,
-            current_price,
-            position_value,
-            leverage: leverage_bps,
-            last_updated: Clock::get()?.slot,
-        });
+        // a tenth of the collateral is seized and lent by the pool
+        let (result, _, pool, warned) =
+            rebalance_or_liquidate(&ledger, &position, keeper, Some(&keeper_balance), usd(90));
+        result.unwrap();
+        let warned = warned.unwrap();
+        assert_eq!(warned.collateral, position.collateral - penalty);
+        assert_eq!(warned.booked_borrow, position.booked_borrow + penalty);
+        assert_eq!(pool.total_borrowed, ledger.pool.total_borrowed + penalty);
+        assert_eq!(emitted::<MarginWarningPenalty>()[0].penalty, penalty);
 
-        trader.position_count = trader
-            .position_count
-            .checked_add(1)
-            .ok_or(ErrorCode::MathOverflow)?;
+        // not past the borrow cap
+        reset_syscalls();
+        ledger.config.max_borrow_per_position = usd(810);
+        let (result, _, _, _) =
+            rebalance_or_liquidate(&ledger, &position, keeper, Some(&keeper_balance), usd(90));
+        assert_eq!(result.unwrap_err(), ErrorCode::NoActionRequired.into());
+        assert!(emitted::<MarginWarningPenalty>().is_empty());
+        ledger.config.max_borrow_per_position = 0;
 
-        trader.active_position = trader
-            .active_position
-            .checked_add(1)
-            .ok_or(ErrorCode::MathOverflow)?;
+        // nor beyond the pool's liquidity
+        ledger.pool.lp_deposit = ledger.pool.total_borrowed + usd(10);
+        let (result, _, _, _) =
+            rebalance_or_liquidate(&ledger, &position, keeper, Some(&keeper_balance), usd(90));
+        assert_eq!(result.unwrap_err(), ErrorCode::NoActionRequired.into());
+        assert!(emitted::<MarginWarningPenalty>().is_empty());
 
-        market.total_active_positions = market
-            .total_active_positions
-            .checked_add(1)
-            .ok_or(ErrorCode::MathOverflow)?;
-        Ok(())
+        // and the position can still be liquidated
+        let (result, trader_balance, _, _) =
+            rebalance_or_liquidate(&ledger, &position, keeper, Some(&keeper_balance), usd(82));
+        result.unwrap();
+        assert_eq!(trader_balance.locked_balance, 0);
     }
 
-    pub fn update_position(
-        ctx: Context<UpdatePosition>,
-        token_mint: String,
-        pair: String,
-        position_id: u64,
-    ) -> Result<()> {
-        todo!()
+    /// Run `set_position_triggers`, or `clear_position_triggers` without triggers, for
+    /// `signer` against the ledger's accounts, returning the position as left
+    fn position_triggers(
+        ledger: &Ledger,
+        position: &Position,
+        signer: Pubkey,
+        triggers: Option<(u64, u64)>,
+    ) -> (Result<()>, Position) {
+        let key = Pubkey::new_unique;
+        let mut signer = TestAccount::wallet(signer);
+        let mut trader = TestAccount::program(key(), &ledger.trader);
+        let mut position = TestAccount::program(key(), position);
+        let infos = [signer.info(true), trader.info(false), position.info(false)];
+        let mut accounts = SetPositionTriggers {
+            signer: Signer::try_from(&infos[0]).unwrap(),
+            trader: Account::try_from(&infos[1]).unwrap(),
+            position: Account::try_from(&infos[2]).unwrap(),
+        };
+        let ctx = Context::new(&crate::ID, &mut accounts, &[], Default::default());
+        let (pair, owner) = ("SOL/USD".to_string(), ledger.trader.owner);
+        let result = match triggers {
+            Some((stop_loss_price, take_profit_price)) => regret_market::set_position_triggers(
+                ctx,
+                pair,
+                owner,
+                0,
+                stop_loss_price,
+                take_profit_price,
+            ),
+            None => regret_market::clear_position_triggers(ctx, pair, owner, 0),
+        };
+        (result, (*accounts.position).clone())
     }
 
-    pub fn close_position(
-        ctx: Context<ClosePosition>,
-        _token_mint: String,
-        _pair: String,
-        _position_id: u64,
-    ) -> Result<()> {
-        let position = &mut ctx.accounts.position;
-        let pool = &mut ctx.accounts.pool;
-        let trader_balance = &mut ctx.accounts.trader_balance;
-        let trader = &mut ctx.accounts.trader;
-        let market = &mut ctx.accounts.market;
-        let config = &ctx.accounts.config;
-        let clock = Clock::get()?;
+    /// Run `close_position` for `signer` at `price` against the ledger's accounts,
+    /// returning the owner's balance account, the pool and the market as the handler left
+    /// them
+    fn close_position(
+        ledger: &Ledger,
+        position: &Position,
+        signer: Pubkey,
+        price: u64,
+    ) -> (Result<()>, TraderPoolDetail, Vault, Market) {
+        let key = Pubkey::new_unique;
+        let mut config = TestAccount::program(key(), &ledger.config);
+        let mut signer = TestAccount::wallet(signer);
+        let mut owner = TestAccount::wallet(position.owner);
+        let mut trader = TestAccount::program(key(), &ledger.trader);
+        let mut trader_balance = TestAccount::program(key(), &ledger.trader_balance);
+        let mut pool = TestAccount::program(key(), &ledger.pool);
+        let mut insurance_fund = TestAccount::program(key(), &ledger.insurance);
+        let mut market = TestAccount::program(key(), &ledger.market);
+        let mut position = TestAccount::program(key(), position);
+        let mut price_update = TestAccount::oracle(&price_update(price));
+        let infos = [
+            config.info(false),
+            signer.info(true),
+            owner.info(false),
+            trader.info(false),
+            trader_balance.info(false),
+            pool.info(false),
+            insurance_fund.info(false),
+            market.info(false),
+            position.info(false),
+            price_update.info(false),
+        ];
+        let mut accounts = ClosePosition {
+            config: Account::try_from(&infos[0]).unwrap(),
+            signer: Signer::try_from(&infos[1]).unwrap(),
+            owner_account: SystemAccount::try_from(&infos[2]).unwrap(),
+            trader: Account::try_from(&infos[3]).unwrap(),
+            trader_balance: Account::try_from(&infos[4]).unwrap(),
+            pool: Account::try_from(&infos[5]).unwrap(),
+            insurance_fund: Account::try_from(&infos[6]).unwrap(),
+            market: Account::try_from(&infos[7]).unwrap(),
+            position: Account::try_from(&infos[8]).unwrap(),
+            price_update: Account::try_from(&infos[9]).unwrap(),
+        };
+        let result = regret_market::close_position(
+            Context::new(&crate::ID, &mut accounts, &[], Default::default()),
+            "USDC".to_string(),
+            "SOL/USD".to_string(),
+            Pubkey::default(),
+            0,
+            false,
+        );
+        (
+            result,
+            (*accounts.trader_balance).clone(),
+            (*accounts.pool).clone(),
+            (*accounts.market).clone(),
+        )
+    }
 
-        // Validate
-        require!(position.closed_at == 0, ErrorCode::PositionAlreadyClosed);
-        require!(!config.is_paused, ErrorCode::ProgramPaused);
-        require!(!market.is_paused, ErrorCode::ProgramPaused);
-        require!(!pool.is_paused, ErrorCode::ProgramPaused);
+    #[test]
+    fn a_win_then_a_loss_net_in_the_market_pnl() {
+        reset_syscalls();
+        let mut ledger = Ledger::new();
+        let owner = ledger.trader.owner;
 
-        // Get current price
-        let current_price =
-            get_normalized_price(&ctx.accounts.price_update, &market.feed_id, &clock)?;
+        let win = ledger.open(true, usd(100), 10, usd(200));
+        let (result, trader_balance, pool, market) = close_position(&ledger, &win, owner, usd(110));
+        result.unwrap();
+        let won = emitted::<PositionClosed>()[0].net_pnl;
+        assert!(won > 0);
+        assert_eq!(market.cumulative_trader_pnl, won);
+        (ledger.trader_balance, ledger.pool, ledger.market) = (trader_balance, pool, market);
 
-        // Validate price
-        validate_price(current_price)?;
+        reset_syscalls();
+        let loss = ledger.open(true, usd(100), 20, usd(400));
+        let (result, _, _, market) = close_position(&ledger, &loss, owner, usd(95));
+        result.unwrap();
+        let lost = emitted::<PositionClosed>()[0].net_pnl;
+        assert!(lost < -won);
+        // traders are net losing to the pool
+        assert_eq!(market.cumulative_trader_pnl, won + lost);
+        assert!(market.cumulative_trader_pnl < 0);
+    }
 
-        // Final funding update
-        let funding_rate_bps = 10i64; // TODO: Get from oracle
-        position.update_funding(clock.slot, current_price, funding_rate_bps, market.decimals)?;
+    /// Validate the `withdraw` accounts of the ledger's trader with `signer` signing, the
+    /// balance account is the trader's own PDA
+    fn withdraw_accounts(ledger: &Ledger, signer: Pubkey) -> Result<()> {
+        use anchor_spl::associated_token::get_associated_token_address;
+        use anchor_spl::token::spl_token::state::{Account as SplAccount, AccountState, Mint};
 
-        // Calculate PnL
-        let pnl_result = calculate_pnl(position, current_price, market.decimals)?;
+        let pda = |seeds: &[&[u8]]| Pubkey::find_program_address(seeds, &crate::ID);
+        let owner = ledger.trader.owner;
+        let mint_key = Pubkey::new_unique();
+        let (config_key, config_bump) = pda(&[b"config"]);
+        let (balance_key, balance_bump) = pda(&[b"trader_balance", owner.as_ref(), b"USDC"]);
+        let (pool_key, pool_bump) = pda(&[b"vault", b"USDC"]);
+        let token_account = |authority: Pubkey| {
+            let account = SplAccount {
+                mint: mint_key,
+                owner: authority,
+                state: AccountState::Initialized,
+                ..Default::default()
+            };
+            TestAccount::spl(get_associated_token_address(&authority, &mint_key), account)
+        };
 
-        // Calculate closing fee with precision
-        let closing_fee_scaled = (position.position_value as u128)
-            .checked_mul(config.closing_fee as u128)
-            .ok_or(ErrorCode::MathOverflow)?
-            .checked_mul(PRECISION)
-            .ok_or(ErrorCode::MathOverflow)?
-            .checked_div(BASIS_POINTS)
-            .ok_or(ErrorCode::MathOverflow)?;
+        let mut config = TestAccount::program(
+            config_key,
+            &Config {
+                bump: config_bump,
+                ..ledger.config.clone()
+            },
+        );
+        let mut signer_account = TestAccount::wallet(signer);
+        let mut trader_balance = TestAccount::program(
+            balance_key,
+            &TraderPoolDetail {
+                bump: balance_bump,
+                ..ledger.trader_balance.clone()
+            },
+        );
+        let mut pool = TestAccount::program(
+            pool_key,
+            &Vault {
+                bump: pool_bump,
+                mint: mint_key,
+                ..ledger.pool.clone()
+            },
+        );
+        let mint = Mint {
+            decimals: 6,
+            is_initialized: true,
+            ..Default::default()
+        };
+        let mut mint = TestAccount::spl(mint_key, mint);
+        let mut trader_token_account = token_account(signer);
+        let mut vault_token_account = token_account(pool_key);
+        let mut token_program = TestAccount::token_program();
+        let infos = [
+            config.info(false),
+            signer_account.info(true),
+            trader_balance.info(false),
+            pool.info(false),
+            mint.info(false),
+            trader_token_account.info(false),
+            vault_token_account.info(false),
+            token_program.info(false),
+        ];
+        let args = ("USDC".to_string(), usd(1)).try_to_vec().unwrap();
+        Withdraw::try_accounts(
+            &crate::ID,
+            &mut &infos[..],
+            &args,
+            &mut Default::default(),
+            &mut Default::default(),
+        )
+        .map(|_| ())
+    }
 
-        let closing_fee = closing_fee_scaled
-            .checked_div(PRECISION)
-            .ok_or(ErrorCode::MathOverflow)?;
+    #[test]
+    fn a_delegate_manages_positions_but_cannot_withdraw() {
+        reset_syscalls();
+        let bot = Pubkey::new_unique();
+        let mut ledger = Ledger::new();
+        ledger.trader.delegate = Some(bot);
+        let position = ledger.open(true, usd(100), 10, usd(200));
 
-        let closing_fee = u64::try_from(closing_fee).map_err(|_| ErrorCode::MathOverflow)?;
+        let (result, set) = position_triggers(&ledger, &position, bot, Some((usd(90), usd(110))));
+        result.unwrap();
+        assert_eq!(
+            (set.stop_loss_price, set.take_profit_price),
+            (usd(90), usd(110))
+        );
+        let (result, cleared) = position_triggers(&ledger, &set, bot, None);
+        result.unwrap();
+        assert_eq!((cleared.stop_loss_price, cleared.take_profit_price), (0, 0));
+        let events = emitted::<PositionTriggersSet>();
+        assert_eq!(events.len(), 2);
+        assert_eq!(
+            (events[1].stop_loss_price, events[1].take_profit_price),
+            (0, 0)
+        );
 
-        // Split fee between protocol and LPs with precision
-        let protocol_fee_scaled = (closing_fee as u128)
-            .checked_mul(config.protocol_fee_share as u128)
-            .ok_or(ErrorCode::MathOverflow)?
-            .checked_mul(PRECISION)
-            .ok_or(ErrorCode::MathOverflow)?
-            .checked_div(BASIS_POINTS)
-            .ok_or(ErrorCode::MathOverflow)?;
+        // closing pays the owner's balance, not the delegate's
+        let (result, trader_balance, _, _) = close_position(&ledger, &position, bot, usd(110));
+        result.unwrap();
+        assert_eq!(trader_balance.locked_balance, 0);
+        assert!(trader_balance.balance > ledger.trader_balance.balance);
 
-        let protocol_fee = protocol_fee_scaled
-            .checked_div(PRECISION)
-            .ok_or(ErrorCode::MathOverflow)?;
+        // the balance account is derived from the signer, so only the owner's matches
+        withdraw_accounts(&ledger, ledger.trader.owner).unwrap();
+        let Err(Error::AnchorError(error)) = withdraw_accounts(&ledger, bot) else {
+            panic!("a delegate passed the withdraw accounts");
+        };
+        assert_eq!(
+            error.error_code_number,
+            anchor_lang::error::ErrorCode::ConstraintSeeds as u32
+        );
+    }
 
-        let protocol_fee = u64::try_from(protocol_fee).map_err(|_| ErrorCode::MathOverflow)?;
+    #[test]
+    fn only_the_owner_or_delegate_manages_positions() {
+        reset_syscalls();
+        let (bot, stranger) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let mut ledger = Ledger::new();
+        let position = ledger.open(true, usd(100), 10, usd(200));
 
-        let lp_fee = closing_fee
-            .checked_sub(protocol_fee)
-            .ok_or(ErrorCode::MathOverflow)?;
+        // not a delegate yet, then revoked in favour of another
+        for delegate in [None, Some(stranger)] {
+            ledger.trader.delegate = delegate;
+            let (result, _) = position_triggers(&ledger, &position, bot, Some((usd(90), usd(110))));
+            assert_eq!(result.unwrap_err(), ErrorCode::Unauthorized.into());
+            let (result, _) = position_triggers(&ledger, &position, bot, None);
+            assert_eq!(result.unwrap_err(), ErrorCode::Unauthorized.into());
+            let (result, _, _, _) = close_position(&ledger, &position, bot, usd(110));
+            assert_eq!(result.unwrap_err(), ErrorCode::Unauthorized.into());
+        }
+        assert!(emitted::<PositionTriggersSet>().is_empty());
 
-        // Calculate amounts
-        let collateral = position.collateral;
-        let borrowed_amount = position
-            .position_value
-            .checked_sub(collateral)
-            .ok_or(ErrorCode::MathOverflow)?;
+        let (result, _, _, _) = close_position(&ledger, &position, ledger.trader.owner, usd(110));
+        result.unwrap();
+    }
 
-        // Calculate final amount to return to trader
-        let amount_to_return = if pnl_result.is_profit {
-            // Profit: collateral + profit - fees
-            let profit_after_fees = pnl_result.net_pnl.saturating_sub(closing_fee);
-            collateral
-                .checked_add(profit_after_fees)
-                .ok_or(ErrorCode::MathOverflow)?
-        } else {
-            // Loss: collateral - loss - fees
-            let total_deduction = pnl_result
-                .net_pnl
-                .checked_add(closing_fee)
-                .ok_or(ErrorCode::MathOverflow)?;
+    /// Run `settle_market_funding` at `price` over the given position accounts
+    fn settle_market_funding(
+        ledger: &Ledger,
+        positions: &mut [TestAccount],
+        price: u64,
+    ) -> Result<()> {
+        let key = Pubkey::new_unique;
+        let mut config = TestAccount::program(key(), &ledger.config);
+        let mut market = TestAccount::program(key(), &ledger.market);
+        let mut price_update = TestAccount::oracle(&price_update(price));
+        let infos = [
+            config.info(false),
+            market.info(false),
+            price_update.info(false),
+        ];
+        let remaining: Vec<_> = positions
+            .iter_mut()
+            .map(|position| position.info(false))
+            .collect();
+        let mut accounts = SettleMarketFunding {
+            config: Account::try_from(&infos[0]).unwrap(),
+            market: Account::try_from(&infos[1]).unwrap(),
+            price_update: Account::try_from(&infos[2]).unwrap(),
+        };
+        regret_market::settle_market_funding(
+            Context::new(&crate::ID, &mut accounts, &remaining, Default::default()),
+            "SOL/USD".to_string(),
+        )
+    }
 
-            if total_deduction >= collateral {
-                // Total loss exceeds collateral - trader gets nothing
-                0
-            } else {
-                collateral
-                    .checked_sub(total_deduction)
-                    .ok_or(ErrorCode::MathOverflow)?
-            }
+    #[test]
+    fn market_funding_settles_several_positions() {
+        reset_syscalls();
+        let mut ledger = Ledger::new();
+        ledger.config.min_funding_interval_slots = 100;
+        // longs dominate 2:1, so they pay and the short receives
+        let long = ledger.open(true, usd(100), 10, usd(200));
+        let short = ledger.open(false, usd(100), 5, usd(100));
+        let mut recent = ledger.open(true, usd(100), 10, usd(200));
+        recent.last_funding_slot = TEST_SLOT - 10;
+        let mut closed = ledger.open(true, usd(100), 10, usd(200));
+        closed.closed_at = 1;
+
+        let mut accounts: Vec<_> = [&long, &short, &recent, &closed]
+            .into_iter()
+            .map(|position| TestAccount::program(Pubkey::new_unique(), position))
+            .collect();
+        settle_market_funding(&ledger, &mut accounts, usd(100)).unwrap();
+        let settled: Vec<Position> = accounts
+            .iter_mut()
+            .map(|account| {
+                Position::try_deserialize(&mut &account.info(false).data.borrow()[..]).unwrap()
+            })
+            .collect();
+
+        // each open one accrues as its own `update_funding` would
+        for (before, after) in [(&long, &settled[0]), (&short, &settled[1])] {
+            let rate = ledger.market.funding_rate_for(before.is_long).unwrap();
+            let mut expected = before.clone();
+            expected
+                .update_funding(TEST_SLOT, usd(100), rate, DECIMALS)
+                .unwrap();
+            assert_eq!(
+                after.cumulative_funding_paid,
+                expected.cumulative_funding_paid
+            );
+            assert_eq!(after.last_funding_slot, TEST_SLOT);
+        }
+        assert!(settled[0].cumulative_funding_paid > 0 && settled[1].cumulative_funding_paid < 0);
+        // the one settled inside the interval and the closed one are left alone
+        assert_eq!(settled[2].cumulative_funding_paid, 0);
+        assert_eq!(settled[2].last_funding_slot, TEST_SLOT - 10);
+        assert_eq!(settled[3].last_funding_slot, closed.last_funding_slot);
+
+        assert_eq!(emitted::<FundingApplied>().len(), 2);
+        let summary = &emitted::<MarketFundingSettled>()[0];
+        assert_eq!(summary.positions_settled, 3);
+        assert_eq!(
+            summary.total_funding_paid,
+            settled[0].cumulative_funding_paid as u64
+        );
+        assert_eq!(
+            summary.total_funding_received,
+            settled[1].cumulative_funding_paid.unsigned_abs()
+        );
+
+        // a position passed twice is rejected
+        let mut twice = vec![TestAccount::program(Pubkey::default(), &long)];
+        twice.push(TestAccount::program(Pubkey::default(), &long));
+        assert_eq!(
+            settle_market_funding(&ledger, &mut twice, usd(100)).unwrap_err(),
+            ErrorCode::InvalidInput.into()
+        );
+    }
+
+    /// Run `burn_virtual_balance` on `trader_balance`, returning it as the handler left it
+    fn burn_virtual_balance(
+        trader_balance: &TraderPoolDetail,
+        amount: u64,
+    ) -> (Result<()>, TraderPoolDetail) {
+        let mut config = TestAccount::program(Pubkey::new_unique(), &config());
+        let mut signer = TestAccount::wallet(trader_balance.owner);
+        let mut trader_balance = TestAccount::program(Pubkey::new_unique(), trader_balance);
+        let infos = [
+            config.info(false),
+            signer.info(true),
+            trader_balance.info(false),
+        ];
+        let mut accounts = BurnVirtualBalance {
+            config: Account::try_from(&infos[0]).unwrap(),
+            signer: Signer::try_from(&infos[1]).unwrap(),
+            trader_balance: Account::try_from(&infos[2]).unwrap(),
         };
+        let result = regret_market::burn_virtual_balance(
+            Context::new(&crate::ID, &mut accounts, &[], Default::default()),
+            "USDC".to_string(),
+            amount,
+        );
+        (result, (*accounts.trader_balance).clone())
+    }
 
-        // Update pool - release borrowed amount
-        pool.total_borrowed = pool
-            .total_borrowed
-            .checked_sub(borrowed_amount)
-            .ok_or(ErrorCode::MathOverflow)?;
+    #[test]
+    fn only_unlocked_virtual_balance_is_burned() {
+        reset_syscalls();
+        // $1000 of which $300 is locked as collateral and $200 was deposited
+        let mut balance = trader_balance(usd(1_000));
+        balance.lock(usd(300)).unwrap();
+        balance.deposited_balance = usd(200);
+        assert_eq!(balance.virtual_balance(), usd(500));
 
-        pool.trader_collateral = pool
-            .trader_collateral
-            .checked_sub(collateral)
-            .ok_or(ErrorCode::MathOverflow)?;
+        // reaching into the deposits or the locked collateral is rejected
+        for amount in [usd(501), usd(800), usd(1_000)] {
+            let (result, _) = burn_virtual_balance(&balance, amount);
+            assert_eq!(result.unwrap_err(), ErrorCode::NotEnoughBalance.into());
+        }
+        let (result, _) = burn_virtual_balance(&balance, 0);
+        assert_eq!(result.unwrap_err(), ErrorCode::InvalidInput.into());
+        assert!(emitted::<VirtualBalanceBurned>().is_empty());
 
-        // Distribute fees
-        pool.accumulated_fees = pool
-            .accumulated_fees
-            .checked_add(protocol_fee)
-            .ok_or(ErrorCode::MathOverflow)?;
+        let (result, burned) = burn_virtual_balance(&balance, usd(500));
+        result.unwrap();
+        assert_eq!(burned.balance, usd(500));
+        assert_eq!(burned.locked_balance, usd(300));
+        assert_eq!(burned.withdrawable_balance(), usd(200));
+        assert_eq!(burned.virtual_balance(), 0);
+        let events = emitted::<VirtualBalanceBurned>();
+        assert_eq!(
+            (events[0].amount, events[0].remaining_balance),
+            (usd(500), usd(500))
+        );
 
-        pool.accumulated_lp_fees = pool
-            .accumulated_lp_fees
-            .checked_add(lp_fee)
-            .ok_or(ErrorCode::MathOverflow)?;
+        // the collateral can't be burned even once it's all that's left
+        let (result, _) = burn_virtual_balance(&burned, 1);
+        assert_eq!(result.unwrap_err(), ErrorCode::NotEnoughBalance.into());
+    }
 
-        // Update trader balance - unlock collateral and add final amount
-        trader_balance.locked_balance = trader_balance
-            .locked_balance
-            .checked_sub(collateral)
-            .ok_or(ErrorCode::MathOverflow)?;
+    #[test]
+    fn feed_status_gates_opens_but_not_closes() {
+        let (owner, keeper) = (Ledger::new().trader.owner, Pubkey::new_unique());
+        for (feed_status, open_error, close_error) in [
+            (FeedStatus::Healthy, None, None),
+            (FeedStatus::Suspect, Some(ErrorCode::FeedSuspect), None),
+            (
+                FeedStatus::Halted,
+                Some(ErrorCode::FeedHalted),
+                Some(ErrorCode::FeedHalted),
+            ),
+        ] {
+            reset_syscalls();
+            let mut ledger = Ledger::new();
+            let position = ledger.open(true, usd(100), 10, usd(200));
+            ledger.market.feed_status = feed_status;
 
-        trader_balance.balance = trader_balance
-            .balance
-            .checked_add(amount_to_return)
-            .ok_or(ErrorCode::MathOverflow)?;
+            let (result, _) =
+                open_position(&mut ledger, &price_update(usd(100)), true, 10, usd(200));
+            assert_eq!(result.err(), open_error.map(Into::into), "{feed_status:?}");
+            let (result, _, _, _) = close_position(&ledger, &position, owner, usd(110));
+            assert_eq!(result.err(), close_error.map(Into::into), "{feed_status:?}");
+            // liquidations follow the closes
+            let (result, _, _, _) = rebalance_or_liquidate(
+                &ledger,
+                &position,
+                keeper,
+                Some(&trader_balance(0)),
+                usd(82),
+            );
+            assert_eq!(result.err(), close_error.map(Into::into), "{feed_status:?}");
+        }
+    }
 
-        // Close position
-        position.closed_at = clock.slot;
+    /// Run `pause_markets_by_feed` for `signer` over `markets`, returning them as left
+    fn pause_markets_by_feed(
+        admin: Pubkey,
+        signer: Pubkey,
+        markets: &[Market],
+        feed_id_prefix: &str,
+    ) -> (Result<()>, Vec<Market>) {
+        let (mut config, _) = admin_accounts(admin);
+        let mut signer = TestAccount::wallet(signer);
+        let mut markets: Vec<_> = markets
+            .iter()
+            .map(|market| TestAccount::program(Pubkey::new_unique(), market))
+            .collect();
+        let infos = [config.info(false), signer.info(true)];
+        let remaining: Vec<_> = markets
+            .iter_mut()
+            .map(|market| market.info(false))
+            .collect();
+        let mut accounts = PauseMarketsByFeed {
+            config: Account::try_from(&infos[0]).unwrap(),
+            signer: Signer::try_from(&infos[1]).unwrap(),
+        };
+        let result = regret_market::pause_markets_by_feed(
+            Context::new(&crate::ID, &mut accounts, &remaining, Default::default()),
+            feed_id_prefix.to_string(),
+        );
+        let markets = remaining
+            .iter()
+            .map(|info| Market::try_deserialize(&mut &info.data.borrow()[..]).unwrap())
+            .collect();
+        (result, markets)
+    }
 
-        // Update counters
-        trader.active_position = trader
-            .active_position
-            .checked_sub(1)
-            .ok_or(ErrorCode::MathOverflow)?;
+    #[test]
+    fn pausing_by_feed_only_touches_matching_markets() {
+        reset_syscalls();
+        let admin = Pubkey::new_unique();
+        let with_feed = |pair: &str, feed_id: &str, is_paused: bool| Market {
+            pair: pair.to_string(),
+            feed_id: feed_id.to_string(),
+            is_paused,
+            ..market(0)
+        };
+        let markets = [
+            with_feed("SOL/USD", FEED_ID, false),
+            with_feed("BTC/USD", "e62df6c8b4a85fe1a67db44dc12de5db", false),
+            with_feed("JITOSOL/USD", "ef0d5b6f43c67b6b4cbb3c4b7a2c1e8d", false),
+            with_feed("MSOL/USD", "ef0d7a1c9b3e4f5a6b7c8d9e0f1a2b3c", true),
+        ];
 
-        market.total_active_positions = market
-            .total_active_positions
-            .checked_sub(1)
-            .ok_or(ErrorCode::MathOverflow)?;
+        let (result, _) = pause_markets_by_feed(admin, Pubkey::new_unique(), &markets, "ef0d");
+        assert_eq!(result.unwrap_err(), ErrorCode::Unauthorized.into());
+        let (result, _) = pause_markets_by_feed(admin, admin, &markets, "");
+        assert_eq!(result.unwrap_err(), ErrorCode::InvalidInput.into());
 
-        // Convert PnL to signed integer for event
-        let final_pnl = if pnl_result.is_profit {
-            pnl_result.net_pnl as i64
+        let (result, paused) = pause_markets_by_feed(admin, admin, &markets, "ef0d");
+        result.unwrap();
+        let is_paused: Vec<_> = paused.iter().map(|market| market.is_paused).collect();
+        assert_eq!(is_paused, [true, false, true, true]);
+        // the one that was already paused isn't reported again
+        let events = emitted::<MarketsPausedByFeed>();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].pairs, ["SOL/USD", "JITOSOL/USD"]);
+    }
+
+    /// Run `register`, or `register_empty` when `empty`, for a new signer on `pool`,
+    /// returning the balance account as the handler left it
+    fn register(pool: &Vault, empty: bool) -> (Result<()>, TraderPoolDetail) {
+        let key = Pubkey::new_unique;
+        let mut config = TestAccount::program(key(), &config());
+        let mut signer = TestAccount::wallet(key());
+        let mut pool = TestAccount::program(key(), pool);
+        let mut trader = TestAccount::program(key(), &trader());
+        let mut trader_balance = TestAccount::program(key(), &trader_balance(0));
+        let mut system_program = TestAccount::system_program();
+        let infos = [
+            config.info(false),
+            signer.info(true),
+            pool.info(false),
+            trader.info(false),
+            trader_balance.info(false),
+            system_program.info(false),
+        ];
+        let mut accounts = Register {
+            config: Account::try_from(&infos[0]).unwrap(),
+            signer: Signer::try_from(&infos[1]).unwrap(),
+            pool: Account::try_from(&infos[2]).unwrap(),
+            trader: Account::try_from(&infos[3]).unwrap(),
+            trader_balance: Account::try_from(&infos[4]).unwrap(),
+            system_program: Program::try_from(&infos[5]).unwrap(),
+        };
+        let ctx = Context::new(&crate::ID, &mut accounts, &[], Default::default());
+        let result = if empty {
+            regret_market::register_empty(ctx, "USDC".to_string())
         } else {
-            -(pnl_result.net_pnl as i64)
+            regret_market::register(ctx, "USDC".to_string())
         };
-        Ok(())
+        (result, (*accounts.trader_balance).clone())
     }
 
-    pub fn rebalance_or_liquidate_position(
-        ctx: Context<RebalanceOrLiquidatePosition>,
-        token_mint: String,
-        pair: String,
-        owner: Pubkey,
-        position_id: u64,
-    ) -> Result<()> {
-        todo!()
+    #[test]
+    fn register_empty_starts_at_zero() {
+        reset_syscalls();
+        let mut pool = pool(0);
+        let (result, empty) = register(&pool, true);
+        result.unwrap();
+        assert_eq!((empty.balance, empty.deposited_balance), (0, 0));
+        let (result, granted) = register(&pool, false);
+        result.unwrap();
+        assert_eq!(granted.balance, usd(100_000));
+
+        // once the pool takes real tokens only the empty variant is left
+        pool.mint = Pubkey::new_unique();
+        let (result, _) = register(&pool, false);
+        assert_eq!(
+            result.unwrap_err(),
+            ErrorCode::VirtualBalanceDisabled.into()
+        );
+        let (result, empty) = register(&pool, true);
+        result.unwrap();
+        assert_eq!(empty.balance, 0);
+    }
+
+    /// Run `admin_seize_position` for `signer` against the ledger's accounts, returning
+    /// the balance, pool, insurance fund and position as the handler left them
+    fn admin_seize(
+        ledger: &Ledger,
+        position: &Position,
+        signer: Pubkey,
+    ) -> (Result<()>, TraderPoolDetail, Vault, InsuranceFund, Position) {
+        let key = Pubkey::new_unique;
+        let mut config = TestAccount::program(key(), &ledger.config);
+        let mut signer = TestAccount::wallet(signer);
+        let mut trader = TestAccount::program(key(), &ledger.trader);
+        let mut trader_balance = TestAccount::program(key(), &ledger.trader_balance);
+        let mut pool = TestAccount::program(key(), &ledger.pool);
+        let mut insurance_fund = TestAccount::program(key(), &ledger.insurance);
+        let mut market = TestAccount::program(key(), &ledger.market);
+        let mut position = TestAccount::program(key(), position);
+        let infos = [
+            config.info(false),
+            signer.info(true),
+            trader.info(false),
+            trader_balance.info(false),
+            pool.info(false),
+            insurance_fund.info(false),
+            market.info(false),
+            position.info(false),
+        ];
+        let mut accounts = AdminSeizePosition {
+            config: Account::try_from(&infos[0]).unwrap(),
+            signer: Signer::try_from(&infos[1]).unwrap(),
+            trader: Account::try_from(&infos[2]).unwrap(),
+            trader_balance: Account::try_from(&infos[3]).unwrap(),
+            pool: Account::try_from(&infos[4]).unwrap(),
+            insurance_fund: Account::try_from(&infos[5]).unwrap(),
+            market: Account::try_from(&infos[6]).unwrap(),
+            position: Account::try_from(&infos[7]).unwrap(),
+        };
+        let result = regret_market::admin_seize_position(
+            Context::new(&crate::ID, &mut accounts, &[], Default::default()),
+            "USDC".to_string(),
+            "SOL/USD".to_string(),
+            Pubkey::default(),
+            0,
+        );
+        (
+            result,
+            (*accounts.trader_balance).clone(),
+            (*accounts.pool).clone(),
+            (*accounts.insurance_fund).clone(),
+            (*accounts.position).clone(),
+        )
     }
 
-    /*
-        pub fn update_config(){}
-    */
+    #[test]
+    fn admin_seizure_sends_the_collateral_to_insurance() {
+        reset_syscalls();
+        let admin = Pubkey::new_unique();
+        let mut ledger = Ledger::new();
+        ledger.config.admin = admin;
+        let position = ledger.open(true, usd(100), 10, usd(200));
+
+        let (result, _, _, _, _) = admin_seize(&ledger, &position, Pubkey::new_unique());
+        assert_eq!(result.unwrap_err(), ErrorCode::Unauthorized.into());
+        // only while trading is paused
+        let (result, _, _, _, _) = admin_seize(&ledger, &position, admin);
+        assert_eq!(result.unwrap_err(), ErrorCode::ProgramNotPaused.into());
+        assert!(emitted::<PositionSeizedByAdmin>().is_empty());
+
+        ledger.market.is_paused = true;
+        let (result, trader_balance, pool, insurance, seized) =
+            admin_seize(&ledger, &position, admin);
+        result.unwrap();
+        assert_eq!(
+            insurance.balance,
+            ledger.insurance.balance + position.collateral
+        );
+        assert_eq!(
+            trader_balance.balance,
+            ledger.trader_balance.balance - position.collateral
+        );
+        assert_eq!(trader_balance.locked_balance, 0);
+        assert_eq!(
+            pool.total_borrowed,
+            ledger.pool.total_borrowed - position.booked_borrow
+        );
+        assert_eq!(pool.trader_collateral, 0);
+        assert!(seized.closed_at != 0);
+        let events = emitted::<PositionSeizedByAdmin>();
+        assert_eq!(events[0].seized_collateral, position.collateral);
+        assert_eq!(events[0].released_borrow, position.booked_borrow);
+
+        // the kept record can't be seized twice
+        let (result, _, _, _, _) = admin_seize(&ledger, &seized, admin);
+        assert_eq!(result.unwrap_err(), ErrorCode::PositionAlreadyClosed.into());
+    }
 }
@@ -25,7 +25,7 @@ pub const MAX_PRICE_AGE_SECONDS: u64 = 60;
 /// # Arguments:
 /// * `price_update` - The Pyth price update account
 /// * `feed_id` - The price feed ID
-/// * `max_age` - Maximum acceptable price age in seconds
+/// * `clock` - Current clock, prices older than `MAX_PRICE_AGE_SECONDS` are rejected
 ///
 /// # Returns:
 /// Normalized price in your protocol's precision (6 decimals)
@@ -34,6 +34,18 @@ pub fn get_normalized_price(
     feed_id: &str,
     clock: &Clock,
 ) -> Result<u64> {
+    get_normalized_price_with_confidence(price_update, feed_id, clock).map(|(price, _)| price)
+}
+
+/// Same as `get_normalized_price`, also returning the confidence in basis points of the price
+///
+/// For callers that apply a tighter confidence bound than `MAX_CONFIDENCE_BPS`,
+/// see `max_confidence_bps_for_leverage`.
+pub fn get_normalized_price_with_confidence(
+    price_update: &PriceUpdateV2,
+    feed_id: &str,
+    clock: &Clock,
+) -> Result<(u64, u64)> {
     // Get the price with staleness check
     let feed_id = get_feed_id_from_hex(feed_id)?;
     let price_data = price_update
@@ -45,9 +57,98 @@ pub fn get_normalized_price(
     require!(price_data.price > 0, ErrorCode::InvalidPrice);
 
     // Check confidence interval
-    validate_confidence(&price_data)?;
+    let confidence_bps = validate_confidence(&price_data)?;
 
     // Normalize price to your protocol's decimals
+    let price = normalize_price_to_protocol_precision(&price_data)?;
+
+    Ok((price, confidence_bps))
+}
+
+/// Reject a price update whose spot is more than `max_divergence_bps` off its EMA
+///
+/// Spot running away from the EMA hints at a feed being manipulated. Only paths that
+/// add risk check it, opens and collateral removal, exits take `get_exit_price`
+/// instead so a diverging feed can't trap positions.
+pub fn check_spot_ema_divergence(
+    price_update: &PriceUpdateV2,
+    max_divergence_bps: u64,
+) -> Result<()> {
+    validate_ema_divergence(
+        price_update.price_message.price,
+        price_update.price_message.ema_price,
+        max_divergence_bps,
+    )
+}
+
+/// Price to close, reduce or liquidate a position at
+///
+/// Never rejected for spot/EMA divergence. While the feed is `Suspect` or the spot
+/// diverges beyond `max_divergence_bps`, the price is shaded against the position
+/// with `conservative_price`.
+///
+/// # Arguments
+/// * `price_update` - The Pyth price update account
+/// * `feed_id` - The price feed ID
+/// * `clock` - Current clock, prices older than `MAX_PRICE_AGE_SECONDS` are rejected
+/// * `max_divergence_bps` - Spot/EMA gap beyond which the price is shaded, 0 disables
+/// * `feed_suspect` - Whether the market's feed is flagged `Suspect`
+/// * `is_long` - Direction of the position
+pub fn get_exit_price(
+    price_update: &PriceUpdateV2,
+    feed_id: &str,
+    clock: &Clock,
+    max_divergence_bps: u64,
+    feed_suspect: bool,
+    is_long: bool,
+) -> Result<u64> {
+    let (price, confidence_bps) =
+        get_normalized_price_with_confidence(price_update, feed_id, clock)?;
+    if feed_suspect || check_spot_ema_divergence(price_update, max_divergence_bps).is_err() {
+        conservative_price(price, confidence_bps, is_long)
+    } else {
+        Ok(price)
+    }
+}
+
+/// Maximum confidence in basis points accepted for a position at `leverage_bps`
+///
+/// Up to `reference_leverage_bps` the full `MAX_CONFIDENCE_BPS` applies, above it the
+/// band shrinks inversely with leverage, e.g. with a 2x reference a 20x position only
+/// accepts a tenth of it. A reference of 0 disables the scaling.
+pub fn max_confidence_bps_for_leverage(leverage_bps: u64, reference_leverage_bps: u64) -> u64 {
+    if reference_leverage_bps == 0 || leverage_bps <= reference_leverage_bps {
+        return MAX_CONFIDENCE_BPS;
+    }
+
+    ((MAX_CONFIDENCE_BPS as u128) * (reference_leverage_bps as u128) / (leverage_bps as u128))
+        as u64
+}
+
+/// Check whether the price update is older than `MAX_PRICE_AGE_SECONDS`
+///
+/// Used by paths that have an explicit fallback for a stale oracle instead of
+/// failing with `StalePrice`.
+pub fn is_price_stale(price_update: &PriceUpdateV2, clock: &Clock) -> bool {
+    price_update
+        .price_message
+        .publish_time
+        .saturating_add(MAX_PRICE_AGE_SECONDS as i64)
+        < clock.unix_timestamp
+}
+
+/// Last known normalized price for the feed, without the staleness check
+///
+/// Only meant for fail-safe paths where the caller can't profit from an old price,
+/// e.g. the stale close which never settles above the entry price.
+pub fn get_last_known_price(price_update: &PriceUpdateV2, feed_id: &str) -> Result<u64> {
+    let feed_id = get_feed_id_from_hex(feed_id)?;
+    let price_data = price_update
+        .get_price_unchecked(&feed_id)
+        .map_err(|_| ErrorCode::InvalidPrice)?;
+
+    require!(price_data.price > 0, ErrorCode::InvalidPrice);
+
     normalize_price_to_protocol_precision(&price_data)
 }
 
@@ -55,16 +156,11 @@ pub fn get_normalized_price(
 ///
 /// Confidence should be a small percentage of the price.
 /// If conf is too high, the price is too uncertain to use safely.
-fn validate_confidence(price_data: &pyth_solana_receiver_sdk::price_update::Price) -> Result<()> {
-    let price_abs = price_data.price.unsigned_abs();
-
-    // Calculate confidence as percentage of price (in basis points)
-    // confidence_bps = (conf / price) × 10000
-    let confidence_bps = (price_data.conf as u128)
-        .checked_mul(10_000)
-        .ok_or(ErrorCode::MathOverflow)?
-        .checked_div(price_abs as u128)
-        .ok_or(ErrorCode::MathOverflow)?;
+///
+/// # Returns:
+/// The confidence in basis points of the price
+fn validate_confidence(price_data: &pyth_solana_receiver_sdk::price_update::Price) -> Result<u64> {
+    let confidence_bps = calculate_confidence_bps(price_data)?;
 
     require!(
         confidence_bps <= MAX_CONFIDENCE_BPS as u128,
@@ -77,6 +173,91 @@ fn validate_confidence(price_data: &pyth_solana_receiver_sdk::price_update::Pric
         confidence_bps
     );
 
+    Ok(confidence_bps as u64)
+}
+
+/// Confidence as basis points of the price, `(conf / price) × 10000`
+fn calculate_confidence_bps(
+    price_data: &pyth_solana_receiver_sdk::price_update::Price,
+) -> Result<u128> {
+    let price_abs = price_data.price.unsigned_abs();
+
+    (price_data.conf as u128)
+        .checked_mul(10_000)
+        .ok_or(ErrorCode::MathOverflow)?
+        .checked_div(price_abs as u128)
+        .ok_or(ErrorCode::MathOverflow.into())
+}
+
+/// Whether a fresh price update shows the feed misbehaving
+///
+/// Evidence a keeper can flag a market `Suspect` with: confidence above
+/// `MAX_CONFIDENCE_BPS`, or spot/EMA divergence above `max_divergence_bps` when it's
+/// set. A stale update isn't evidence, any old update would do.
+pub fn is_feed_suspect(
+    price_update: &PriceUpdateV2,
+    feed_id: &str,
+    clock: &Clock,
+    max_divergence_bps: u64,
+) -> Result<bool> {
+    let feed_id = get_feed_id_from_hex(feed_id)?;
+    let price_data = price_update
+        .get_price_no_older_than(clock, MAX_PRICE_AGE_SECONDS, &feed_id)
+        .map_err(|_| ErrorCode::StalePrice)?;
+    require!(price_data.price > 0, ErrorCode::InvalidPrice);
+
+    if calculate_confidence_bps(&price_data)? > MAX_CONFIDENCE_BPS as u128 {
+        return Ok(true);
+    }
+    Ok(validate_ema_divergence(
+        price_data.price,
+        price_update.price_message.ema_price,
+        max_divergence_bps,
+    )
+    .is_err())
+}
+
+/// Shade `price` by its confidence against the position
+///
+/// Used by `get_exit_price` while a feed is `Suspect` or diverging from its EMA: longs
+/// are valued at `price - conf`, shorts at `price + conf`, so an odd print can't be
+/// used to exit at a favorable price.
+pub fn conservative_price(price: u64, confidence_bps: u64, is_long: bool) -> Result<u64> {
+    let conf = (price as u128)
+        .checked_mul(confidence_bps as u128)
+        .ok_or(ErrorCode::MathOverflow)?
+        / 10_000;
+    let shaded = if is_long {
+        (price as u128).saturating_sub(conf)
+    } else {
+        (price as u128)
+            .checked_add(conf)
+            .ok_or(ErrorCode::MathOverflow)?
+    };
+    u64::try_from(shaded).map_err(|_| ErrorCode::MathOverflow.into())
+}
+
+/// Validate that the spot price is within `max_divergence_bps` of the EMA price
+///
+/// Both prices come from the same message and share its exponent, so the raw values
+/// are compared directly. A `max_divergence_bps` of 0 disables the check.
+fn validate_ema_divergence(spot_price: i64, ema_price: i64, max_divergence_bps: u64) -> Result<()> {
+    if max_divergence_bps == 0 {
+        return Ok(());
+    }
+    require!(ema_price > 0, ErrorCode::InvalidPrice);
+
+    // divergence_bps = |spot - ema| / ema × 10000
+    let divergence_bps = (spot_price.abs_diff(ema_price) as u128)
+        .checked_mul(10_000)
+        .ok_or(ErrorCode::MathOverflow)?
+        .checked_div(ema_price as u128)
+        .ok_or(ErrorCode::MathOverflow)?;
+
+    require!(
+        divergence_bps <= max_divergence_bps as u128,
+        ErrorCode::PriceDivergenceTooHigh
+    );
     Ok(())
 }
 
@@ -130,3 +311,78 @@ fn normalize_price_to_protocol_precision(
 
     Ok(final_price)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+
+    #[test]
+    fn spot_converging_on_the_ema_passes() {
+        // $150 EMA with 8 decimals, spot 1% either side under a 2% limit
+        let ema = 15_000_000_000;
+        validate_ema_divergence(15_150_000_000, ema, 200).unwrap();
+        validate_ema_divergence(14_850_000_000, ema, 200).unwrap();
+        // and exactly at the limit
+        validate_ema_divergence(15_300_000_000, ema, 200).unwrap();
+    }
+
+    #[test]
+    fn spot_diverging_from_the_ema_is_rejected() {
+        let ema = 15_000_000_000;
+        for spot in [15_301_500_000, 14_698_500_000] {
+            assert_eq!(
+                validate_ema_divergence(spot, ema, 200).unwrap_err(),
+                ErrorCode::PriceDivergenceTooHigh.into()
+            );
+        }
+        // unless the check is disabled
+        validate_ema_divergence(30_000_000_000, ema, 0).unwrap();
+        assert_eq!(
+            validate_ema_divergence(15_000_000_000, 0, 200).unwrap_err(),
+            ErrorCode::InvalidPrice.into()
+        );
+    }
+
+    #[test]
+    fn confidence_band_shrinks_with_leverage() {
+        // with a 2x reference the full 1% holds up to 2x
+        for leverage_bps in [10_000, 20_000] {
+            assert_eq!(max_confidence_bps_for_leverage(leverage_bps, 20_000), 100);
+        }
+        assert_eq!(max_confidence_bps_for_leverage(40_000, 20_000), 50);
+        assert_eq!(max_confidence_bps_for_leverage(80_000, 20_000), 25);
+        assert_eq!(max_confidence_bps_for_leverage(200_000, 20_000), 10);
+        // no reference, no scaling
+        assert_eq!(
+            max_confidence_bps_for_leverage(200_000, 0),
+            MAX_CONFIDENCE_BPS
+        );
+    }
+
+    #[test]
+    fn exit_price_is_shaded_instead_of_rejected() {
+        let clock = Clock::default();
+        // $100 spot with a 1% confidence band
+        let mut update = price_update(usd(100));
+        update.price_message.conf = usd(1);
+        let exit_price = |update: &PriceUpdateV2, suspect: bool, is_long: bool| {
+            get_exit_price(update, FEED_ID, &clock, 200, suspect, is_long).unwrap()
+        };
+
+        // a healthy feed exits at the spot
+        assert_eq!(exit_price(&update, false, true), usd(100));
+        // a suspect one against the position
+        assert_eq!(exit_price(&update, true, true), usd(99));
+        assert_eq!(exit_price(&update, true, false), usd(101));
+
+        // spot 5% over the EMA is past the 2% bound, shaded rather than rejected
+        update.price_message.ema_price = 95_000_000;
+        assert_eq!(exit_price(&update, false, true), usd(99));
+        assert_eq!(exit_price(&update, false, false), usd(101));
+        assert_eq!(
+            check_spot_ema_divergence(&update, 200).unwrap_err(),
+            ErrorCode::PriceDivergenceTooHigh.into()
+        );
+    }
+}
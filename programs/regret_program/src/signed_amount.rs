@@ -0,0 +1,153 @@
+use anchor_lang::prelude::*;
+
+use crate::ErrorCode;
+
+/// Signed USD amount with 6 decimals, e.g. PnL, funding or equity
+///
+/// Replaces the `(magnitude: u64, is_positive: bool)` pairs in the math so signs are
+/// combined by plain arithmetic instead of branching on flags. Converts back to the
+/// pair at the edges where accounts and events still store unsigned amounts.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct SignedAmount(pub i128);
+
+impl SignedAmount {
+    pub const ZERO: SignedAmount = SignedAmount(0);
+
+    /// Build from a magnitude and whether it's positive
+    pub fn from_parts(magnitude: u64, is_positive: bool) -> Self {
+        if is_positive {
+            SignedAmount(magnitude as i128)
+        } else {
+            SignedAmount(-(magnitude as i128))
+        }
+    }
+
+    pub fn from_u64(amount: u64) -> Self {
+        SignedAmount(amount as i128)
+    }
+
+    pub fn from_i64(amount: i64) -> Self {
+        SignedAmount(amount as i128)
+    }
+
+    /// `(magnitude, is_positive)`, zero counts as not positive
+    pub fn to_parts(self) -> Result<(u64, bool)> {
+        Ok((self.magnitude()?, self.is_positive()))
+    }
+
+    pub fn magnitude(self) -> Result<u64> {
+        u64::try_from(self.0.unsigned_abs()).map_err(|_| ErrorCode::MathOverflow.into())
+    }
+
+    /// The amount when positive, otherwise 0
+    pub fn positive_part(self) -> Result<u64> {
+        u64::try_from(self.0.max(0)).map_err(|_| ErrorCode::MathOverflow.into())
+    }
+
+    /// The magnitude when negative, otherwise 0
+    pub fn negative_part(self) -> Result<u64> {
+        u64::try_from(self.0.min(0).unsigned_abs()).map_err(|_| ErrorCode::MathOverflow.into())
+    }
+
+    pub fn to_i64(self) -> Result<i64> {
+        i64::try_from(self.0).map_err(|_| ErrorCode::MathOverflow.into())
+    }
+
+    pub fn is_positive(self) -> bool {
+        self.0 > 0
+    }
+
+    pub fn is_negative(self) -> bool {
+        self.0 < 0
+    }
+
+    pub fn checked_add(self, other: SignedAmount) -> Result<Self> {
+        self.0
+            .checked_add(other.0)
+            .map(SignedAmount)
+            .ok_or(ErrorCode::MathOverflow.into())
+    }
+
+    pub fn checked_sub(self, other: SignedAmount) -> Result<Self> {
+        self.0
+            .checked_sub(other.0)
+            .map(SignedAmount)
+            .ok_or(ErrorCode::MathOverflow.into())
+    }
+
+    pub fn checked_mul(self, factor: i128) -> Result<Self> {
+        self.0
+            .checked_mul(factor)
+            .map(SignedAmount)
+            .ok_or(ErrorCode::MathOverflow.into())
+    }
+
+    pub fn checked_neg(self) -> Result<Self> {
+        self.0
+            .checked_neg()
+            .map(SignedAmount)
+            .ok_or(ErrorCode::MathOverflow.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parts_round_trip() {
+        assert_eq!(SignedAmount::from_parts(5, true), SignedAmount(5));
+        assert_eq!(SignedAmount::from_parts(5, false), SignedAmount(-5));
+        assert_eq!(SignedAmount(-5).to_parts().unwrap(), (5, false));
+        assert_eq!(SignedAmount(5).to_parts().unwrap(), (5, true));
+        // zero never reports positive, whichever flag built it
+        assert_eq!(
+            SignedAmount::from_parts(0, true).to_parts().unwrap(),
+            (0, false)
+        );
+        assert_eq!(
+            SignedAmount::from_parts(u64::MAX, false)
+                .magnitude()
+                .unwrap(),
+            u64::MAX
+        );
+    }
+
+    #[test]
+    fn positive_and_negative_parts() {
+        assert_eq!(SignedAmount(7).positive_part().unwrap(), 7);
+        assert_eq!(SignedAmount(7).negative_part().unwrap(), 0);
+        assert_eq!(SignedAmount(-7).positive_part().unwrap(), 0);
+        assert_eq!(SignedAmount(-7).negative_part().unwrap(), 7);
+        assert_eq!(SignedAmount::ZERO.positive_part().unwrap(), 0);
+        assert_eq!(SignedAmount::ZERO.negative_part().unwrap(), 0);
+    }
+
+    #[test]
+    fn arithmetic_crosses_zero() {
+        let profit = SignedAmount::from_u64(100);
+        let funding = SignedAmount::from_i64(150);
+        assert_eq!(profit.checked_sub(funding).unwrap(), SignedAmount(-50));
+        assert_eq!(
+            SignedAmount(-50).checked_add(SignedAmount(80)).unwrap(),
+            SignedAmount(30)
+        );
+        assert_eq!(SignedAmount(-3).checked_mul(-4).unwrap(), SignedAmount(12));
+        assert_eq!(SignedAmount(12).checked_neg().unwrap(), SignedAmount(-12));
+    }
+
+    #[test]
+    fn overflow_is_an_error() {
+        assert!(SignedAmount(i128::MAX)
+            .checked_add(SignedAmount(1))
+            .is_err());
+        assert!(SignedAmount(i128::MIN)
+            .checked_sub(SignedAmount(1))
+            .is_err());
+        assert!(SignedAmount(i128::MAX).checked_mul(2).is_err());
+        assert!(SignedAmount(i128::MIN).checked_neg().is_err());
+        assert!(SignedAmount(u64::MAX as i128 + 1).magnitude().is_err());
+        assert!(SignedAmount(i64::MIN as i128 - 1).to_i64().is_err());
+        assert_eq!(SignedAmount(i64::MIN as i128).to_i64().unwrap(), i64::MIN);
+    }
+}
@@ -1,3 +1,4 @@
+use crate::ErrorCode;
 use anchor_lang::prelude::*;
 
 #[account]
@@ -11,7 +12,115 @@ pub struct Config {
     pub maintainance_margin: u16,
     pub opening_fee: u16,
     pub closing_fee: u16,
+    // whole USD charged once by `enable_privacy`
     pub privacy_fee: u16,
     pub protocol_fee_share: u16,
     pub last_updated: u64,
+    // max total position value per trader across all positions, 0 means no cap
+    pub max_trader_exposure: u64,
+    // minimum realized profit (USD, 6 decimals) for a rebalance to proceed
+    pub min_rebalance_profit: u64,
+    // keeper reward (USD, 6 decimals) for executing a take-profit or stop-loss, paid out of
+    // the closing fee
+    pub tp_execution_reward: u64,
+    // slots since the last funding settlement after which a close is flagged, 0 disables
+    pub max_funding_staleness_slots: u64,
+    // extra opening fee bps per 1x of leverage above 1x, 0 disables
+    pub leverage_fee_surcharge: u16,
+    // cap on the leverage-scaled opening fee in bps, 0 means no cap
+    pub max_opening_fee: u16,
+    // leverage (bps) above which the accepted oracle confidence shrinks, 0 disables
+    pub confidence_reference_leverage: u64,
+    // share (bps) of the equity left after the liquidator reward returned to the trader, 0 disables
+    pub liquidation_rebate: u16,
+    // slots that must pass before an at-will funding settlement accrues, 0 disables
+    pub min_funding_interval_slots: u64,
+    // max change of actual_size (bps of the previous size) in a single rebalance, 0 disables
+    pub max_rebalance_size_change_bps: u64,
+    // length of the window over which insurance-covered bad debt is capped, 0 means one unbounded epoch
+    pub bad_debt_epoch_slots: u64,
+    // max bad debt insurance covers per epoch, the excess goes to LPs, 0 means no cap
+    pub max_bad_debt_per_epoch: u64,
+    // max amount a single position may borrow from the pool, 0 disables
+    pub max_borrow_per_position: u64,
+    // max gap (bps of the EMA) between the spot and EMA oracle prices, 0 disables
+    pub max_spot_ema_divergence_bps: u64,
+    // protocol-wide ceiling on open positions across all markets, 0 disables
+    pub max_global_positions: u64,
+    // open positions across all markets, makes `Config` writable on every open and close
+    pub total_open_positions: u64,
+    // health (bps) below which a keeper seizes `warning_penalty_bps` of collateral, 0 disables
+    pub warning_health_bps: u64,
+    pub warning_penalty_bps: u16,
+    // insurance target as bps of the pool's open interest, 0 disables, see `Vault::route_fee`
+    pub insurance_target_bps: u64,
+    // share (bps) of each fee routed to insurance while it's below target
+    pub insurance_fee_share_bps: u16,
+    // base slots a keeper waits after a check that found nothing to do, doubling with
+    // each further miss, 0 disables, see `Position::liquidation_backoff`
+    pub liquidation_backoff_slots: u64,
+    // proposed by the admin, only becomes `admin` once it accepts, see `accept_admin`
+    pub pending_admin: Option<Pubkey>,
+    // floor on the collateral left after the opening fee, USD with 6 decimals, 0 disables
+    pub min_effective_collateral: u64,
+    // cap on `Market::net_delta` either way, USD with 6 decimals, 0 disables
+    pub max_net_delta: u64,
+}
+
+impl Config {
+    /// Count a newly opened position, failing once `max_global_positions` is reached
+    pub fn track_position_opened(&mut self) -> Result<()> {
+        require!(
+            self.max_global_positions == 0 || self.total_open_positions < self.max_global_positions,
+            ErrorCode::GlobalPositionLimitReached
+        );
+        self.total_open_positions = self
+            .total_open_positions
+            .checked_add(1)
+            .ok_or(ErrorCode::MathOverflow)?;
+        Ok(())
+    }
+
+    pub fn track_position_closed(&mut self) -> Result<()> {
+        self.total_open_positions = self
+            .total_open_positions
+            .checked_sub(1)
+            .ok_or(ErrorCode::MathOverflow)?;
+        Ok(())
+    }
+
+    /// Reject a single position borrowing more than `max_borrow_per_position`
+    ///
+    /// Unlike the utilization cap this bounds one position rather than the pool.
+    pub fn check_borrow(&self, borrowing_amount: u64) -> Result<()> {
+        require!(
+            self.max_borrow_per_position == 0 || borrowing_amount <= self.max_borrow_per_position,
+            ErrorCode::BorrowCapExceeded
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_utils::*;
+    use crate::ErrorCode;
+
+    #[test]
+    fn borrow_cap_rejects_only_the_larger_position() {
+        let mut config = config();
+        config.max_borrow_per_position = usd(10_000);
+
+        // the same pool lends $10k to one position but not $10k and a cent
+        config.check_borrow(usd(10_000)).unwrap();
+        config.check_borrow(usd(900)).unwrap();
+        assert_eq!(
+            config.check_borrow(usd(10_000) + 10_000).unwrap_err(),
+            ErrorCode::BorrowCapExceeded.into()
+        );
+
+        // a cap of 0 is disabled
+        config.max_borrow_per_position = 0;
+        config.check_borrow(u64::MAX).unwrap();
+    }
 }
@@ -1,5 +1,8 @@
+use crate::{ErrorCode, MAX_POSITION_VALUE, MIN_POSITION_VALUE, PRECISION};
 use anchor_lang::prelude::*;
 
+use super::calculate_funding_rate;
+
 #[account]
 #[derive(InitSpace)]
 pub struct Market {
@@ -11,4 +14,304 @@ pub struct Market {
     pub feed_id: String,
     pub total_active_positions: u64,
     pub is_paused: bool,
+    // slot window in which new positions can be opened, 0/0 means always open
+    pub open_window_start_slot: u64,
+    pub open_window_end_slot: u64,
+    // realized PnL of all closed positions, negative when traders are net losing to the pool
+    pub cumulative_trader_pnl: i64,
+    // bumped whenever `feed_id` changes, positions record the version they opened on
+    pub feed_id_version: u32,
+    // winding down: no new opens, existing positions can still be managed and closed
+    pub reduce_only: bool,
+    // graduated oracle health, see `FeedStatus`
+    pub feed_status: FeedStatus,
+    // flagged for retirement: reduce-only, and keepers can force-expire open positions
+    pub retired: bool,
+    // admin-set price force-expired positions settle at, 0 until the admin sets one
+    pub settlement_price: u64,
+    // longs minus shorts by `Position::position_value`, USD with 6 decimals, the pool
+    // carries the opposite side
+    pub net_delta: i64,
+    // per `SLOTS_PER_8_HOURS` at a fully one-sided market, scaled by the open interest
+    // skew, see `funding_rate_for`
+    pub funding_rate_bps: i64,
+    // sum of `Position::position_value` of the open longs and shorts
+    pub long_open_interest: u64,
+    pub short_open_interest: u64,
+    // distance of a fresh target from the current price, set at `open_market`
+    pub target_band_bps: u64,
+    // per-market leverage cap in basis points, 0 falls back to `Config::max_leverage`
+    pub max_leverage: u64,
+    // per-market maintenance margin in basis points, 0 falls back to
+    // `Config::maintainance_margin`
+    pub maintainance_margin: u16,
+}
+
+/// Oracle health of a market
+///
+/// `Suspect` can be raised by any keeper with evidence (see `flag_feed_suspect`), only
+/// the admin can confirm `Halted` or restore `Healthy`.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Debug, InitSpace)]
+pub enum FeedStatus {
+    Healthy,
+    /// No new opens, closes and liquidations use the confidence-shaded price
+    Suspect,
+    /// Nothing is priced off the feed until the admin restores it
+    Halted,
+}
+
+impl Market {
+    pub fn is_open_at(&self, slot: u64) -> bool {
+        if self.open_window_start_slot == 0 && self.open_window_end_slot == 0 {
+            return true;
+        }
+        slot >= self.open_window_start_slot && slot < self.open_window_end_slot
+    }
+
+    /// Fail when the feed can't be used to open a position
+    pub fn require_feed_healthy(&self) -> Result<()> {
+        match self.feed_status {
+            FeedStatus::Healthy => Ok(()),
+            FeedStatus::Suspect => err!(ErrorCode::FeedSuspect),
+            FeedStatus::Halted => err!(ErrorCode::FeedHalted),
+        }
+    }
+
+    /// Leverage cap for positions on this market, the tighter of its own and the config's
+    pub fn effective_max_leverage(&self, config_max_leverage: u64) -> u64 {
+        if self.max_leverage == 0 {
+            config_max_leverage
+        } else {
+            self.max_leverage.min(config_max_leverage)
+        }
+    }
+
+    /// Base maintenance margin for positions on this market, its own when set
+    pub fn effective_maintainance_margin(&self, config_maintainance_margin: u16) -> u16 {
+        if self.maintainance_margin == 0 {
+            config_maintainance_margin
+        } else {
+            self.maintainance_margin
+        }
+    }
+
+    /// `net_delta` once a position's notional moves from `previous_value` to `new_value`
+    pub fn net_delta_after(
+        &self,
+        is_long: bool,
+        previous_value: u64,
+        new_value: u64,
+    ) -> Result<i64> {
+        let change = new_value as i128 - previous_value as i128;
+        let change = if is_long { change } else { -change };
+        let net_delta = (self.net_delta as i128)
+            .checked_add(change)
+            .ok_or(ErrorCode::MathOverflow)?;
+        i64::try_from(net_delta).map_err(|_| ErrorCode::MathOverflow.into())
+    }
+
+    /// Whether moving `net_delta` to `new_net_delta` respects `max_net_delta`
+    ///
+    /// Beyond the cap only a move that shrinks the imbalance on the same side passes, so
+    /// a book already over the cap (e.g. after the cap was lowered) can be worked down. A
+    /// flip to the other side has to land within the cap. 0 disables the cap.
+    pub fn net_delta_within_cap(&self, new_net_delta: i64, max_net_delta: u64) -> bool {
+        if max_net_delta == 0 || new_net_delta.unsigned_abs() <= max_net_delta {
+            return true;
+        }
+        let same_side = (new_net_delta > 0) == (self.net_delta > 0) && self.net_delta != 0;
+        same_side && new_net_delta.unsigned_abs() < self.net_delta.unsigned_abs()
+    }
+
+    /// Move the open interest for a position whose notional goes from `previous_value`
+    /// to `new_value`, keeping `net_delta` in step
+    pub fn shift_open_interest(
+        &mut self,
+        is_long: bool,
+        previous_value: u64,
+        new_value: u64,
+    ) -> Result<()> {
+        self.net_delta = self.net_delta_after(is_long, previous_value, new_value)?;
+        let open_interest = if is_long {
+            &mut self.long_open_interest
+        } else {
+            &mut self.short_open_interest
+        };
+        // Saturating so positions opened before the counters existed can still close
+        *open_interest = open_interest
+            .saturating_sub(previous_value)
+            .checked_add(new_value)
+            .ok_or(ErrorCode::MathOverflow)?;
+        Ok(())
+    }
+
+    /// Funding rate a position on this market currently pays, negative when it receives
+    ///
+    /// The dominant side pays the skew-scaled rate and the other side receives it. In
+    /// basis points scaled by `PRECISION`, see `calculate_funding_rate`.
+    pub fn funding_rate_for(&self, is_long: bool) -> Result<i64> {
+        let rate = calculate_funding_rate(
+            self.long_open_interest,
+            self.short_open_interest,
+            self.funding_rate_bps,
+        )?;
+        Ok(if is_long { rate } else { -rate })
+    }
+
+    /// `funding_rate_bps` scaled by `PRECISION`, the rate paid at a fully one-sided market
+    pub fn max_funding_rate_scaled(&self) -> Result<i64> {
+        self.funding_rate_bps
+            .checked_mul(PRECISION as i64)
+            .ok_or(ErrorCode::MathOverflow.into())
+    }
+
+    /// Fail when the feed can't be used at all, `Suspect` is still usable
+    pub fn require_feed_not_halted(&self) -> Result<()> {
+        require!(
+            self.feed_status != FeedStatus::Halted,
+            ErrorCode::FeedHalted
+        );
+        Ok(())
+    }
+}
+
+/// Immutable record of the risk parameters in force for a market during an epoch
+#[account]
+#[derive(InitSpace)]
+pub struct MarketParamSnapshot {
+    pub bump: u8,
+    #[max_len(20)]
+    pub pair: String,
+    pub epoch: u64,
+    pub slot: u64,
+    #[max_len(70)]
+    pub feed_id: String,
+    pub decimals: u8,
+    pub max_leverage: u64,
+    pub liquidation_fee: u64,
+    pub maintainance_margin: u16,
+    pub opening_fee: u16,
+    pub closing_fee: u16,
+    pub privacy_fee: u16,
+    pub protocol_fee_share: u16,
+}
+
+/// Outcome of `check_market_decimals`
+pub struct DecimalsCheck {
+    // one smallest unit is worth more than `MIN_POSITION_VALUE`, decimals likely too low
+    pub unit_too_coarse: bool,
+    // `MAX_POSITION_VALUE` at this price doesn't fit a u64 size, decimals likely too high
+    pub max_size_overflows: bool,
+}
+
+/// Heuristic check of a market's decimals against the feed price
+///
+/// # Arguments
+/// * `price` - USD per whole token with 6 decimals
+/// * `decimals` - Configured token decimals
+pub fn check_market_decimals(price: u64, decimals: u8) -> Result<DecimalsCheck> {
+    let token_divisor = 10_u128
+        .checked_pow(decimals as u32)
+        .ok_or(ErrorCode::MathOverflow)?;
+
+    let unit_value = (price as u128) / token_divisor;
+    let max_size = (MAX_POSITION_VALUE as u128)
+        .checked_mul(token_divisor)
+        .ok_or(ErrorCode::MathOverflow)?
+        / (price as u128);
+
+    Ok(DecimalsCheck {
+        unit_too_coarse: unit_value > MIN_POSITION_VALUE as u128,
+        max_size_overflows: max_size > u64::MAX as u128,
+    })
+}
+
+/// Funding read for clients, see `get_funding_rate`
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct FundingRateInfo {
+    // per `funding_period_slots` in basis points scaled by `PRECISION`, positive when
+    // longs pay shorts, negative when shorts pay
+    pub funding_rate_scaled: i64,
+    // rate at a fully one-sided market, see `Market::funding_rate_bps`
+    pub max_funding_rate_bps: i64,
+    pub long_open_interest: u64,
+    pub short_open_interest: u64,
+    pub funding_period_slots: u64,
+    pub total_active_positions: u64,
+    pub slot: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_utils::market as market_with_net_delta;
+
+    #[test]
+    fn open_window_includes_its_start_but_not_its_end() {
+        let mut market = market_with_net_delta(0);
+        // 0/0 is always open
+        assert!(market.is_open_at(0) && market.is_open_at(u64::MAX));
+
+        market.open_window_start_slot = 100;
+        market.open_window_end_slot = 200;
+        for (slot, open) in [
+            (0, false),
+            (99, false),
+            (100, true),
+            (150, true),
+            (199, true),
+            (200, false),
+            (1_000, false),
+        ] {
+            assert_eq!(market.is_open_at(slot), open, "slot {slot}");
+        }
+    }
+
+    #[test]
+    fn net_delta_after_moves_by_side() {
+        let market = market_with_net_delta(100);
+        assert_eq!(market.net_delta_after(true, 0, 50).unwrap(), 150);
+        assert_eq!(market.net_delta_after(false, 0, 50).unwrap(), 50);
+        // Closing a long gives back its contribution
+        assert_eq!(market.net_delta_after(true, 40, 0).unwrap(), 60);
+        assert_eq!(market.net_delta_after(false, 40, 10).unwrap(), 130);
+    }
+
+    #[test]
+    fn net_delta_cap_disabled_at_zero() {
+        let market = market_with_net_delta(0);
+        assert!(market.net_delta_within_cap(i64::MAX, 0));
+        assert!(market.net_delta_within_cap(-i64::MAX, 0));
+    }
+
+    #[test]
+    fn net_delta_cap_from_the_long_side() {
+        let market = market_with_net_delta(40);
+        assert!(market.net_delta_within_cap(50, 50));
+        assert!(!market.net_delta_within_cap(51, 50));
+        // Flipping short past the cap is rejected
+        assert!(market.net_delta_within_cap(-50, 50));
+        assert!(!market.net_delta_within_cap(-51, 50));
+    }
+
+    #[test]
+    fn net_delta_cap_from_the_short_side() {
+        let market = market_with_net_delta(-40);
+        assert!(market.net_delta_within_cap(-50, 50));
+        assert!(!market.net_delta_within_cap(-51, 50));
+        assert!(market.net_delta_within_cap(50, 50));
+        assert!(!market.net_delta_within_cap(51, 50));
+    }
+
+    #[test]
+    fn net_delta_over_the_cap_can_only_shrink_on_its_side() {
+        let long_heavy = market_with_net_delta(100);
+        assert!(long_heavy.net_delta_within_cap(99, 50));
+        assert!(!long_heavy.net_delta_within_cap(100, 50));
+        assert!(!long_heavy.net_delta_within_cap(-99, 50));
+
+        let short_heavy = market_with_net_delta(-100);
+        assert!(short_heavy.net_delta_within_cap(-99, 50));
+        assert!(!short_heavy.net_delta_within_cap(-101, 50));
+        assert!(!short_heavy.net_delta_within_cap(99, 50));
+    }
 }
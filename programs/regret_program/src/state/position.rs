@@ -1,11 +1,25 @@
 use crate::{
-    ErrorCode, BASIS_POINTS, MAX_COLLATERAL, MAX_POSITION_VALUE, MAX_SAFE_PRICE, MIN_COLLATERAL,
-    MIN_POSITION_VALUE, PRECISION, SLOTS_PER_8_HOURS,
+    events::FundingApplied, signed_amount::SignedAmount, ErrorCode, BASIS_POINTS,
+    MARGIN_TIER_DISCOUNT_BPS, MARGIN_WARNING_INTERVAL_SLOTS, MAX_COLLATERAL,
+    MAX_LIQUIDATION_BACKOFF_SHIFT, MAX_MARGIN_TIER, MAX_POSITION_VALUE, MAX_SAFE_PRICE,
+    MIN_COLLATERAL, MIN_MAINTAINANCE_MARGIN, MIN_POSITION_VALUE, PRECISION, SLOTS_PER_8_HOURS,
 };
 use anchor_lang::prelude::*;
 
 use super::Config;
 
+/// Length prefix written before `pair` in the position PDA seeds
+///
+/// Seeds are hashed as one concatenated byte string, so without it "BTC" followed by an
+/// owner starting with `U` derives the same bytes as "BTCU" followed by the rest of
+/// another owner. Prefixing the length makes the encoding unambiguous.
+///
+/// Positions opened before the prefix was added live at the old address and can't be
+/// loaded by the new seeds, they have to be closed before upgrading.
+pub fn pair_seed_len(pair: &str) -> [u8; 1] {
+    [pair.len() as u8]
+}
+
 #[account]
 #[derive(InitSpace)]
 pub struct Position {
@@ -13,8 +27,12 @@ pub struct Position {
     pub owner: Pubkey,
     pub entered_at: u64,
     pub closed_at: u64, // 0 means position is active
+    // wall-clock counterparts of `entered_at`/`closed_at` for display
+    pub entered_at_unix: i64,
+    pub closed_at_unix: i64, // 0 means position is active
     pub last_funding_slot: u64,
-    pub cumulative_funding_paid: u64,
+    // net funding settled so far, negative when the position has received more than it paid
+    pub cumulative_funding_paid: i64,
     pub position_id: u64,
     pub is_long: bool,
     #[max_len(20)]
@@ -28,51 +46,423 @@ pub struct Position {
     pub collateral: u64,
     pub actual_size: u64,
     pub current_price: u64,
+    // notional at entry, re-marked by `update_position`, the basis for exposure accounting
     pub position_value: u64,
     pub leverage: u64,
     pub last_updated: u64,
+    pub take_profit_price: u64, // 0 means unset
+    pub stop_loss_price: u64,   // 0 means unset
+    // funding rate applied at the last settlement in basis points scaled by `PRECISION`,
+    // see `last_funding_slot`
+    pub last_funding_rate_scaled: i64,
+    // `Market::feed_id_version` when the position was opened
+    pub feed_version_at_open: u32,
+    // idempotency key supplied by the client at open, 0 means none
+    pub client_order_id: u64,
+    // health cached on the last open/rebalance for keeper pre-filtering, only as fresh
+    // as `last_health_slot`, always recompute before acting on it
+    pub last_health_ratio_bps: u64,
+    pub last_health_slot: u64,
+    // total opening fee (base + leverage surcharge) charged at open
+    pub opening_fee_paid: u64,
+    // last slot a margin warning penalty was seized, see `MARGIN_WARNING_INTERVAL_SLOTS`
+    pub last_warning_slot: u64,
+    // exact amount booked in `pool.total_borrowed` for this position, set at open and
+    // moved with every re-strike, released as-is on close so the pool is restored exactly
+    pub booked_borrow: u64,
+    // keeper checks in a row that found nothing to do, and the last one, reset by any
+    // keeper action, see `liquidation_backoff`
+    pub liquidation_attempts: u8,
+    pub last_liquidation_attempt_slot: u64,
 }
 
 impl Position {
+    /// Load an open position from an account the handler checks itself
+    ///
+    /// A keeper losing a race to close the position finds the account emptied, or the
+    /// position marked closed, both fail with `PositionAlreadyClosed`.
+    pub fn load_open(info: &AccountInfo) -> Result<Position> {
+        // The seeds pin the key to a position PDA, closing hands it back to the system
+        // program with no data
+        require!(
+            *info.owner == crate::ID && !info.data_is_empty(),
+            ErrorCode::PositionAlreadyClosed
+        );
+        let position = Position::try_deserialize(&mut &info.try_borrow_data()?[..])?;
+        require!(position.closed_at == 0, ErrorCode::PositionAlreadyClosed);
+        Ok(position)
+    }
+
+    /// Write a position loaded by `load_open` back to its account
+    pub fn store(&self, info: &AccountInfo) -> Result<()> {
+        self.try_serialize(&mut &mut info.try_borrow_mut_data()?[..])
+    }
+
+    /// `update_funding` that doesn't accrue until `min_interval_slots` have passed
+    ///
+    /// Used by the at-will settlement paths so a variable rate can't be gamed by
+    /// settling in tiny steps. Below the interval it's a no-op, the elapsed slots keep
+    /// accruing and are picked up by the next settlement.
+    pub fn update_funding_throttled(
+        &mut self,
+        current_slot: u64,
+        current_price: u64,
+        funding_rate_scaled: i64,
+        token_decimals: u8,
+        min_interval_slots: u64,
+    ) -> Result<Option<AppliedFunding>> {
+        if current_slot.saturating_sub(self.last_funding_slot) < min_interval_slots {
+            return Ok(None);
+        }
+
+        self.update_funding(
+            current_slot,
+            current_price,
+            funding_rate_scaled,
+            token_decimals,
+        )
+        .map(Some)
+    }
+
+    /// Accrue funding since `last_funding_slot` into `cumulative_funding_paid`
+    ///
+    /// Doesn't log anything, handlers that persist the position emit
+    /// `funding_applied` for the result. Read-only paths run it on a copy.
     pub fn update_funding(
         &mut self,
         current_slot: u64,
         current_price: u64,
-        funding_rate_bps: i64,
+        funding_rate_scaled: i64,
         token_decimals: u8,
-    ) -> Result<FundingPayment> {
+    ) -> Result<AppliedFunding> {
         let slots_elapsed = current_slot.saturating_sub(self.last_funding_slot);
 
         let funding = calculate_funding_payment(
             self.actual_size,
             current_price,
-            funding_rate_bps,
+            funding_rate_scaled,
             slots_elapsed,
             token_decimals,
         )?;
 
         // Update position state
         self.last_funding_slot = current_slot;
+        self.last_funding_rate_scaled = funding_rate_scaled;
+
+        // Receipts (negative rate) are kept, they flow into net PnL on close
+        self.cumulative_funding_paid = SignedAmount::from_i64(self.cumulative_funding_paid)
+            .checked_add(funding.payment)?
+            .to_i64()?;
 
-        if funding.is_payment {
-            self.cumulative_funding_paid = self
-                .cumulative_funding_paid
-                .checked_add(funding.funding_amount)
-                .ok_or(ErrorCode::MathOverflow)?;
+        Ok(AppliedFunding {
+            payment: funding.payment,
+            funding_rate_scaled,
+            slots_elapsed,
+            slot: current_slot,
+        })
+    }
+
+    /// `FundingApplied` for an `update_funding` result that is being persisted
+    ///
+    /// `owner` is reported as given, pass the trader's `event_owner`.
+    pub fn funding_applied(
+        &self,
+        funding: &AppliedFunding,
+        owner: Pubkey,
+    ) -> Result<FundingApplied> {
+        let (funding_amount, is_payment) = funding.payment.to_parts()?;
+        Ok(FundingApplied {
+            owner,
+            pair: self.pair.clone(),
+            position_id: self.position_id,
+            funding_rate_scaled: funding.funding_rate_scaled,
+            slots_elapsed: funding.slots_elapsed,
+            funding_amount,
+            is_payment,
+            cumulative_funding_paid: self.cumulative_funding_paid,
+            slot: funding.slot,
+        })
+    }
+
+    /// Shrink the position to what's left after `calculate_reduction`
+    pub fn apply_reduction(&mut self, reduction: &ReductionResult) -> Result<()> {
+        let remaining_size = self
+            .actual_size
+            .checked_sub(reduction.closed_size)
+            .ok_or(ErrorCode::MathOverflow)?;
+
+        // Keep the virtual (desired) position in proportion to the real one
+        self.desired_size = proportional(self.desired_size, remaining_size, self.actual_size)?;
+        self.actual_size = remaining_size;
+        self.collateral = self
+            .collateral
+            .checked_sub(reduction.closed_collateral)
+            .ok_or(ErrorCode::MathOverflow)?;
+        self.position_value = self
+            .position_value
+            .checked_sub(reduction.closed_value)
+            .ok_or(ErrorCode::MathOverflow)?;
+        self.cumulative_funding_paid = self
+            .cumulative_funding_paid
+            .checked_sub(reduction.closed_funding)
+            .ok_or(ErrorCode::MathOverflow)?;
+        self.booked_borrow = self
+            .booked_borrow
+            .checked_sub(reduction.closed_borrowed)
+            .ok_or(ErrorCode::MathOverflow)?;
+
+        self.refresh_leverage()
+    }
+
+    /// Carve `fraction_bps` of the position into a new one
+    ///
+    /// Size, collateral, value, borrow, settled funding and opening fee are split pro-rata, the remainder
+    /// stays on `self` so the two parts always sum to the original. Prices, direction
+    /// and triggers are kept on both.
+    pub fn split_off(&mut self, fraction_bps: u64) -> Result<Position> {
+        require!(
+            fraction_bps > 0 && fraction_bps < BASIS_POINTS as u64,
+            ErrorCode::InvalidInput
+        );
+        let basis_points = BASIS_POINTS as u64;
+
+        let mut carved = self.clone();
+        carved.actual_size = proportional(self.actual_size, fraction_bps, basis_points)?;
+        carved.desired_size = proportional(self.desired_size, fraction_bps, basis_points)?;
+        carved.collateral = proportional(self.collateral, fraction_bps, basis_points)?;
+        carved.position_value = proportional(self.position_value, fraction_bps, basis_points)?;
+        let (funding_paid, is_payment) =
+            SignedAmount::from_i64(self.cumulative_funding_paid).to_parts()?;
+        carved.cumulative_funding_paid = SignedAmount::from_parts(
+            proportional(funding_paid, fraction_bps, basis_points)?,
+            is_payment,
+        )
+        .to_i64()?;
+        carved.opening_fee_paid = proportional(self.opening_fee_paid, fraction_bps, basis_points)?;
+        carved.booked_borrow = proportional(self.booked_borrow, fraction_bps, basis_points)?;
+
+        self.actual_size -= carved.actual_size;
+        self.desired_size -= carved.desired_size;
+        self.collateral -= carved.collateral;
+        self.position_value -= carved.position_value;
+        self.cumulative_funding_paid -= carved.cumulative_funding_paid;
+        self.opening_fee_paid -= carved.opening_fee_paid;
+        self.booked_borrow -= carved.booked_borrow;
+
+        self.refresh_leverage()?;
+        carved.refresh_leverage()?;
+
+        Ok(carved)
+    }
+
+    /// Fold `other` into this position
+    ///
+    /// Size, collateral, value, borrow, settled funding and opening fees are summed. Prices are blended by
+    /// size, i.e. total notional over total size, so the merged PnL at any price equals
+    /// the sum of the parts. Triggers are kept only when both positions agree.
+    pub fn merge(&mut self, other: &Position) -> Result<()> {
+        require!(
+            self.owner == other.owner
+                && self.pair == other.pair
+                && self.token_mint == other.token_mint
+                && self.is_long == other.is_long
+                && self.feed_version_at_open == other.feed_version_at_open,
+            ErrorCode::InvalidInput
+        );
+
+        let actual_size = self
+            .actual_size
+            .checked_add(other.actual_size)
+            .ok_or(ErrorCode::MathOverflow)?;
+        let desired_size = self
+            .desired_size
+            .checked_add(other.desired_size)
+            .ok_or(ErrorCode::MathOverflow)?;
+
+        self.actual_entered_price = blend_price(
+            self.actual_entered_price,
+            self.actual_size,
+            other.actual_entered_price,
+            other.actual_size,
+        )?;
+        self.current_target_price = blend_price(
+            self.current_target_price,
+            self.actual_size,
+            other.current_target_price,
+            other.actual_size,
+        )?;
+        self.desired_entry_price = blend_price(
+            self.desired_entry_price,
+            self.desired_size,
+            other.desired_entry_price,
+            other.desired_size,
+        )?;
+
+        self.actual_size = actual_size;
+        self.desired_size = desired_size;
+        self.collateral = self
+            .collateral
+            .checked_add(other.collateral)
+            .ok_or(ErrorCode::MathOverflow)?;
+        self.position_value = self
+            .position_value
+            .checked_add(other.position_value)
+            .ok_or(ErrorCode::MathOverflow)?;
+        self.cumulative_funding_paid = self
+            .cumulative_funding_paid
+            .checked_add(other.cumulative_funding_paid)
+            .ok_or(ErrorCode::MathOverflow)?;
+        self.opening_fee_paid = self
+            .opening_fee_paid
+            .checked_add(other.opening_fee_paid)
+            .ok_or(ErrorCode::MathOverflow)?;
+        self.booked_borrow = self
+            .booked_borrow
+            .checked_add(other.booked_borrow)
+            .ok_or(ErrorCode::MathOverflow)?;
+        self.entered_at = self.entered_at.min(other.entered_at);
+        self.entered_at_unix = self.entered_at_unix.min(other.entered_at_unix);
+
+        if self.take_profit_price != other.take_profit_price {
+            self.take_profit_price = 0;
+        }
+        if self.stop_loss_price != other.stop_loss_price {
+            self.stop_loss_price = 0;
+        }
+
+        self.refresh_leverage()
+    }
+
+    /// Refresh the cached health at `current_price`
+    pub fn cache_health(
+        &mut self,
+        current_price: u64,
+        maintainance_margin: u16,
+        margin_tier: u8,
+        token_decimals: u8,
+        slot: u64,
+    ) -> Result<()> {
+        self.last_health_ratio_bps = calculate_health_ratio(
+            self,
+            current_price,
+            maintainance_margin,
+            margin_tier,
+            token_decimals,
+        )?;
+        self.last_health_slot = slot;
+        Ok(())
+    }
+
+    /// Slots a keeper has to wait after the last fruitless check before trying again
+    ///
+    /// `base_slots` after the first miss, doubling with each further one up to
+    /// `MAX_LIQUIDATION_BACKOFF_SHIFT` doublings.
+    pub fn liquidation_backoff(&self, base_slots: u64) -> u64 {
+        if self.liquidation_attempts == 0 {
+            return 0;
+        }
+        let shift = (self.liquidation_attempts - 1).min(MAX_LIQUIDATION_BACKOFF_SHIFT);
+        base_slots.saturating_mul(1 << shift)
+    }
+
+    /// Check a stop-loss / take-profit pair against the entry, 0 leaves a trigger unset
+    ///
+    /// Longs need `stop_loss < entry < take_profit`, shorts the reverse.
+    pub fn validate_triggers(&self, stop_loss_price: u64, take_profit_price: u64) -> Result<()> {
+        let entry = self.actual_entered_price;
+        if take_profit_price != 0 {
+            validate_price(take_profit_price)?;
+            let valid = if self.is_long {
+                take_profit_price > entry
+            } else {
+                take_profit_price < entry
+            };
+            require!(valid, ErrorCode::InvalidTriggerPrice);
+        }
+        if stop_loss_price != 0 {
+            validate_price(stop_loss_price)?;
+            let valid = if self.is_long {
+                stop_loss_price < entry
+            } else {
+                stop_loss_price > entry
+            };
+            require!(valid, ErrorCode::InvalidTriggerPrice);
+        }
+        Ok(())
+    }
+
+    /// Whether `current_price` reached the take-profit
+    pub fn take_profit_hit(&self, current_price: u64) -> bool {
+        self.take_profit_price != 0
+            && if self.is_long {
+                current_price >= self.take_profit_price
+            } else {
+                current_price <= self.take_profit_price
+            }
+    }
+
+    /// Whether `current_price` reached the stop-loss
+    pub fn stop_loss_hit(&self, current_price: u64) -> bool {
+        self.stop_loss_price != 0
+            && if self.is_long {
+                current_price <= self.stop_loss_price
+            } else {
+                current_price >= self.stop_loss_price
+            }
+    }
+
+    /// What a keeper call at `current_price` acts on, the first that applies
+    ///
+    /// See `rebalance_or_liquidate_position` for the precedence. `Rebalance` only means
+    /// nothing above applies, the rebalance itself may still find nothing to do.
+    ///
+    /// # Arguments
+    /// * `current_price` - USD per token with 6 decimals
+    /// * `liquidation` - `calculate_liquidation` at the same price
+    /// * `warning_health_bps` - `Config::warning_health_bps`, 0 disables warnings
+    /// * `slot` - Current slot
+    pub fn keeper_action(
+        &self,
+        current_price: u64,
+        liquidation: &LiquidationResult,
+        warning_health_bps: u64,
+        slot: u64,
+    ) -> KeeperAction {
+        if liquidation.is_liquidatable {
+            KeeperAction::Liquidate
+        } else if self.stop_loss_hit(current_price) {
+            KeeperAction::StopLoss
+        } else if self.take_profit_hit(current_price) {
+            KeeperAction::TakeProfit
+        } else if warning_health_bps != 0
+            && liquidation.health_ratio < warning_health_bps
+            && slot.saturating_sub(self.last_warning_slot) >= MARGIN_WARNING_INTERVAL_SLOTS
+        {
+            KeeperAction::MarginWarning
         } else {
-            // User receives funding (negative rate)
-            self.cumulative_funding_paid = self
-                .cumulative_funding_paid
-                .saturating_sub(funding.funding_amount);
+            KeeperAction::Rebalance
         }
+    }
 
-        Ok(funding)
+    /// Recompute `leverage` from `position_value` and `collateral`
+    pub fn refresh_leverage(&mut self) -> Result<()> {
+        self.leverage = calculate_leverage_bps(self.position_value, self.collateral)?;
+        Ok(())
     }
 }
 
 pub struct FundingPayment {
-    pub funding_amount: u64,
-    pub is_payment: bool,
+    // positive when the trader pays, negative when they receive
+    pub payment: SignedAmount,
+}
+
+/// Funding accrued onto a position by `Position::update_funding`
+pub struct AppliedFunding {
+    // positive when the trader pays, negative when they receive
+    pub payment: SignedAmount,
+    pub funding_rate_scaled: i64,
+    pub slots_elapsed: u64,
+    pub slot: u64,
 }
 
 pub struct PositionParams {
@@ -82,19 +472,298 @@ pub struct PositionParams {
     pub target_price: u64,
 }
 
+/// Size and notional of a regret position, see `size_long_position`
+#[derive(Debug)]
+pub struct PositionSizing {
+    pub actual_size: u64,
+    pub position_value: u64,
+    // `position_value` before dropping `PRECISION`, keeps the leverage exact
+    position_value_scaled: u128,
+}
+
+impl PositionSizing {
+    /// Complete the sizing into `PositionParams` for a given collateral
+    pub fn with_collateral(&self, collateral: u64, target_price: u64) -> Result<PositionParams> {
+        if collateral == 0 {
+            return err!(ErrorCode::InvalidInput);
+        }
+
+        // Leverage in basis points (1x = 10000)
+        let leverage_bps = self
+            .position_value_scaled
+            .checked_mul(BASIS_POINTS)
+            .ok_or(ErrorCode::MathOverflow)?
+            .checked_div(collateral as u128)
+            .ok_or(ErrorCode::MathOverflow)?
+            .checked_div(PRECISION)
+            .ok_or(ErrorCode::MathOverflow)?;
+
+        let leverage_bps = u64::try_from(leverage_bps).map_err(|_| ErrorCode::MathOverflow)?;
+
+        Ok(PositionParams {
+            actual_size: self.actual_size,
+            leverage_bps,
+            position_value: self.position_value,
+            target_price,
+        })
+    }
+}
+
+/// Fill details returned by `open_position`, mirrors what is stored on the `Position`
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, PartialEq, Eq, Debug)]
+pub struct OpenResult {
+    pub position_id: u64,
+    pub actual_size: u64,
+    pub actual_entered_price: u64,
+    pub leverage: u64,
+    pub position_value: u64,
+    pub liquidation_price: u64,
+    pub opening_fee: u64,
+}
+
 pub struct RebalanceResult {
     pub should_rebalance: bool,
     pub new_actual_size: u64,
     pub new_target_price: u64,
     pub new_leverage_bps: u64,
+    pub new_position_value: u64,
     pub profit_realized: u64,
     pub excess_to_insurance: u64,
 }
 
+impl RebalanceResult {
+    /// Bound the change of `new_actual_size` to `max_change_bps` of `previous_size`
+    ///
+    /// The position value is recomputed for the capped size at `current_price`, the
+    /// caller re-derives leverage from it. Returns whether the cap applied, a cap of 0
+    /// disables it.
+    pub fn cap_size_change(
+        &mut self,
+        previous_size: u64,
+        max_change_bps: u64,
+        current_price: u64,
+        token_decimals: u8,
+    ) -> Result<bool> {
+        if max_change_bps == 0 {
+            return Ok(false);
+        }
+
+        let max_change = proportional(previous_size, max_change_bps, BASIS_POINTS as u64)?;
+        let capped_size = self.new_actual_size.clamp(
+            previous_size.saturating_sub(max_change),
+            previous_size.saturating_add(max_change),
+        );
+        if capped_size == self.new_actual_size {
+            return Ok(false);
+        }
+
+        self.new_actual_size = capped_size;
+        self.new_position_value = notional_value(capped_size, current_price, token_decimals)?;
+
+        Ok(true)
+    }
+}
+
+/// Branch of `rebalance_or_liquidate_position`, see `Position::keeper_action`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeeperAction {
+    Liquidate,
+    StopLoss,
+    TakeProfit,
+    MarginWarning,
+    Rebalance,
+}
+
+pub struct LiquidationResult {
+    pub health_ratio: u64,
+    pub is_liquidatable: bool,
+    pub equity: i64,
+    pub net_pnl: SignedAmount,
+    pub liquidation_reward: u64,
+    pub bad_debt: u64,
+    pub trader_rebate: u64,
+    // equity left after the reward and the rebate, kept by the pool
+    pub retained_equity: u64,
+}
+
+impl LiquidationResult {
+    /// Breakdown of the owner's payout, see `ClosePayout`
+    pub fn payout(&self, owner_reward: u64) -> ClosePayout {
+        ClosePayout {
+            net_pnl: self.net_pnl,
+            withheld: self.liquidation_reward.saturating_add(self.retained_equity),
+            bad_debt: self.bad_debt,
+            amount_to_return: self.trader_rebate,
+            owner_reward,
+        }
+    }
+}
+
+/// Liquidation read for keeper bots, see `get_liquidation_context`
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct LiquidationContext {
+    pub health_ratio_bps: u64,
+    pub equity: i64,
+    pub is_liquidatable: bool,
+    pub expected_liquidator_reward: u64,
+    pub estimated_bad_debt: u64,
+    pub price: u64,
+}
+
+/// Live view of an open position for clients, see `get_position_status`
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct PositionStatus {
+    pub price: u64,
+    pub position_value: u64,
+    pub current_target_price: u64,
+    pub gross_pnl: i64,
+    pub net_pnl: i64,
+    // `net_pnl` in bps of the collateral, e.g. 3500 is +35% on margin
+    pub pnl_pct_of_collateral_bps: i64,
+    pub health_ratio_bps: u64,
+}
+
+/// Which open check limits the result of `get_max_position`
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum MaxOpenConstraint {
+    /// `max_leverage` applied to the available balance
+    Leverage,
+    /// Pool liquidity available to borrow
+    Liquidity,
+    /// `MAX_POSITION_VALUE`
+    PositionValueCap,
+    /// `max_trader_exposure` minus the trader's current exposure
+    TraderExposure,
+    /// Health after one funding period must stay above maintenance
+    Maintenance,
+    /// `max_borrow_per_position` on top of the collateral
+    BorrowCap,
+}
+
+/// Largest open a trader can currently make, see `get_max_position`
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct MaxOpenResult {
+    pub max_desired_size: u64,
+    pub max_position_value: u64,
+    pub collateral: u64,
+    pub binding_constraint: MaxOpenConstraint,
+}
+
+pub struct CloseResult {
+    // price move alone, before funding and fees
+    pub gross_price_pnl: i64,
+    pub net_pnl: SignedAmount,
+    pub closing_fee: u64,
+    pub amount_to_return: u64,
+    pub bad_debt: u64,
+    // profit not paid out because the collateral was fully eroded, see `calculate_close`
+    pub forfeited_pnl: u64,
+}
+
+impl CloseResult {
+    /// Breakdown of the owner's payout, see `ClosePayout`
+    pub fn payout(&self, owner_reward: u64) -> ClosePayout {
+        ClosePayout {
+            net_pnl: self.net_pnl,
+            withheld: self.closing_fee.saturating_add(self.forfeited_pnl),
+            bad_debt: self.bad_debt,
+            amount_to_return: self.amount_to_return,
+            owner_reward,
+        }
+    }
+}
+
+/// What a closing position pays its owner, and what that payout is made of
+///
+/// `amount_to_return` has to equal `collateral + net_pnl + bad_debt - withheld`, see
+/// `verify`. The parts come from the close math, so a payout that drifts from them,
+/// e.g. a fee that is charged but not deducted, is caught before any balance moves.
+pub struct ClosePayout {
+    // PnL including funding at the close price, before fees
+    pub net_pnl: SignedAmount,
+    // equity not paid out: closing fee, liquidation reward, seized or retained equity
+    pub withheld: u64,
+    // loss beyond the collateral, absorbed by the pool
+    pub bad_debt: u64,
+    pub amount_to_return: u64,
+    // paid on top, a keeper reward the owner earned on their own position
+    pub owner_reward: u64,
+}
+
+impl ClosePayout {
+    /// Nothing realized and nothing paid out, the whole collateral is withheld
+    pub fn seizure(collateral: u64) -> Self {
+        ClosePayout {
+            net_pnl: SignedAmount::ZERO,
+            withheld: collateral,
+            bad_debt: 0,
+            amount_to_return: 0,
+            owner_reward: 0,
+        }
+    }
+
+    /// Check `amount_to_return` against its parts and return what is credited
+    pub fn verify(&self, collateral: u64) -> Result<u64> {
+        let expected = SignedAmount::from_u64(collateral)
+            .checked_add(self.net_pnl)?
+            .checked_add(SignedAmount::from_u64(self.bad_debt))?
+            .checked_sub(SignedAmount::from_u64(self.withheld))?;
+        require!(
+            expected == SignedAmount::from_u64(self.amount_to_return),
+            ErrorCode::BalanceInvariantViolated
+        );
+        self.amount_to_return
+            .checked_add(self.owner_reward)
+            .ok_or(ErrorCode::MathOverflow.into())
+    }
+}
+
+pub struct ReductionResult {
+    pub closed_size: u64,
+    pub closed_collateral: u64,
+    pub closed_value: u64,
+    pub closed_borrowed: u64,
+    pub closed_funding: i64,
+    pub realized_pnl: SignedAmount,
+    pub closing_fee: u64,
+    pub amount_to_return: u64,
+    // loss of the closed part beyond its collateral, see `Vault::absorb_bad_debt`
+    pub bad_debt: u64,
+}
+
 pub struct PnLResult {
-    pub gross_pnl: u64,
-    pub net_pnl: u64,
-    pub is_profit: bool,
+    // price move alone
+    pub gross_pnl: SignedAmount,
+    // price move minus settled funding
+    pub net_pnl: SignedAmount,
+}
+
+/// Funding rate from the long/short open interest skew
+///
+/// Scales `max_rate_bps` by `(long_oi - short_oi) / (long_oi + short_oi)`: positive when
+/// longs dominate, negative when shorts do, 0 for a balanced or empty market. Kept at
+/// `PRECISION` so a small skew doesn't round down to 0 whole basis points.
+///
+/// # Arguments
+/// * `long_oi` - Notional of the open longs, USD with 6 decimals
+/// * `short_oi` - Notional of the open shorts, USD with 6 decimals
+/// * `max_rate_bps` - Rate per funding period when only one side is open
+///
+/// # Returns
+/// * Rate per funding period in basis points scaled by `PRECISION`
+pub fn calculate_funding_rate(long_oi: u64, short_oi: u64, max_rate_bps: i64) -> Result<i64> {
+    let total_oi = long_oi as i128 + short_oi as i128;
+    if total_oi == 0 {
+        return Ok(0);
+    }
+    let skew = long_oi as i128 - short_oi as i128;
+    let rate = skew
+        .checked_mul(max_rate_bps as i128)
+        .ok_or(ErrorCode::MathOverflow)?
+        .checked_mul(PRECISION as i128)
+        .ok_or(ErrorCode::MathOverflow)?
+        / total_oi;
+    i64::try_from(rate).map_err(|_| ErrorCode::MathOverflow.into())
 }
 
 // Calculate funding payment based on position size and slots elapsed
@@ -102,61 +771,47 @@ pub struct PnLResult {
 // # Arguments
 // * `actual_size` - Position size in token's smallest units (e.g., 100_000_000 = 1 BTC with 8 decimals)
 // * `current_price` - Current asset price in USD with 6 decimals (e.g., 50_000_000_000 = $50k)
-// * `funding_rate_bps` - Funding rate in basis points per 8 hours (e.g., 10 = 0.1%)
+// * `funding_rate_scaled` - Funding rate in basis points per 8 hours scaled by `PRECISION`
+//   (e.g., 10_000_000 = 0.1%)
 // * `slots_elapsed` - Number of slots elapsed since last funding update
 // * `token_decimals` - Number of decimals for the token
 //
 // # Returns
-// * `FundingPayment` - Contains funding amount in USD with 6 decimals and whether user pays or receives
+// * `FundingPayment` - Funding in USD with 6 decimals, positive when the user pays
 pub fn calculate_funding_payment(
     actual_size: u64,
     current_price: u64,
-    funding_rate_bps: i64,
+    funding_rate_scaled: i64,
     slots_elapsed: u64,
     token_decimals: u8,
 ) -> Result<FundingPayment> {
     // Handle edge cases
-    if actual_size == 0 || current_price == 0 || funding_rate_bps == 0 || slots_elapsed == 0 {
+    if actual_size == 0 || current_price == 0 || funding_rate_scaled == 0 || slots_elapsed == 0 {
         return Ok(FundingPayment {
-            funding_amount: 0,
-            is_payment: funding_rate_bps > 0,
+            payment: SignedAmount::ZERO,
         });
     }
 
-    // Calculate notional value with increased precision
-    // notional_value = (actual_size * current_price * PRECISION) / 10^token_decimals
-    let notional_value_scaled = (actual_size as u128)
-        .checked_mul(current_price as u128)
-        .ok_or(ErrorCode::MathOverflow)?
-        .checked_mul(PRECISION)
-        .ok_or(ErrorCode::MathOverflow)?
-        .checked_div(10_u128.pow(token_decimals as u32))
-        .ok_or(ErrorCode::MathOverflow)?;
-
-    // Calculate funding periods with precision
-    // funding_periods = (slots_elapsed * PRECISION) / SLOTS_PER_8_HOURS
-    let funding_periods_scaled = (slots_elapsed as u128)
-        .checked_mul(PRECISION)
-        .ok_or(ErrorCode::MathOverflow)?
-        .checked_div(SLOTS_PER_8_HOURS as u128)
-        .ok_or(ErrorCode::MathOverflow)?;
+    // Divide by the token decimals first so high-decimal tokens don't overflow
+    let notional = notional_value(actual_size, current_price, token_decimals)?;
 
     // Get absolute value of funding rate
-    let funding_rate_abs = funding_rate_bps.unsigned_abs();
+    let funding_rate_abs = funding_rate_scaled.unsigned_abs();
 
-    // Calculate funding amount with all precision maintained
-    // Formula: (notional_value_scaled * funding_rate_abs * funding_periods_scaled) / (BASIS_POINTS * PRECISION²)
-    let funding_amount_scaled = notional_value_scaled
+    // Formula: (notional * funding_rate_abs * slots_elapsed)
+    //     / (BASIS_POINTS * PRECISION * SLOTS_PER_8_HOURS)
+    // Dividing once at the end keeps the precision of partial funding periods and of
+    // fractional basis point rates
+    let funding_amount_scaled = (notional as u128)
         .checked_mul(funding_rate_abs as u128)
         .ok_or(ErrorCode::MathOverflow)?
-        .checked_mul(funding_periods_scaled)
+        .checked_mul(slots_elapsed as u128)
         .ok_or(ErrorCode::MathOverflow)?;
 
-    // Calculate divisor: basis_points * precision²
     let divisor = BASIS_POINTS
         .checked_mul(PRECISION)
         .ok_or(ErrorCode::MathOverflow)?
-        .checked_mul(PRECISION)
+        .checked_mul(SLOTS_PER_8_HOURS as u128)
         .ok_or(ErrorCode::MathOverflow)?;
 
     let funding_amount = funding_amount_scaled
@@ -167,11 +822,190 @@ pub fn calculate_funding_payment(
     let funding_amount = u64::try_from(funding_amount).map_err(|_| ErrorCode::MathOverflow)?;
 
     Ok(FundingPayment {
-        funding_amount,
-        is_payment: funding_rate_bps > 0,
+        payment: SignedAmount::from_parts(funding_amount, funding_rate_scaled > 0),
     })
 }
 
+/// Calculate the USD notional of a token amount at the given price
+///
+/// # Arguments
+/// * `size` - Token amount in smallest units
+/// * `price` - USD per token with 6 decimals
+/// * `token_decimals` - Number of decimals for the token
+///
+/// # Returns
+/// * USD with 6 decimals, rounded down
+pub fn notional_value(size: u64, price: u64, token_decimals: u8) -> Result<u64> {
+    // notional = size × price / 10^token_decimals
+    let notional = (size as u128)
+        .checked_mul(price as u128)
+        .ok_or(ErrorCode::MathOverflow)?
+        .checked_div(10_u128.pow(token_decimals as u32))
+        .ok_or(ErrorCode::MathOverflow)?;
+
+    u64::try_from(notional).map_err(|_| ErrorCode::MathOverflow.into())
+}
+
+/// Convert a USD notional into a token size at the given price
+///
+/// # Arguments
+/// * `notional_usd` - USD with 6 decimals (e.g., 1_000_000_000 = $1,000)
+/// * `price` - USD per token with 6 decimals
+/// * `token_decimals` - Number of decimals for the token
+///
+/// # Returns
+/// * Token amount in smallest units, rounded down
+pub fn notional_to_size(notional_usd: u64, price: u64, token_decimals: u8) -> Result<u64> {
+    require!(price > 0, ErrorCode::InvalidPrice);
+
+    // size = notional_usd × 10^token_decimals / price
+    let size = (notional_usd as u128)
+        .checked_mul(10_u128.pow(token_decimals as u32))
+        .ok_or(ErrorCode::MathOverflow)?
+        .checked_div(price as u128)
+        .ok_or(ErrorCode::MathOverflow)?;
+
+    u64::try_from(size).map_err(|_| ErrorCode::MathOverflow.into())
+}
+
+/// Leverage in basis points of a position, derived from the stored `position_value`
+///
+/// Leverage and the borrow (`position_value - collateral`) always come from the same
+/// rounded `position_value`. Sub-1x (over-collateralized) positions are not supported
+/// since they would have a negative borrow, they're rejected with `LeverageBelowOne`.
+///
+/// # Arguments
+/// * `position_value` - USD with 6 decimals
+/// * `collateral` - USD with 6 decimals
+pub fn calculate_leverage_bps(position_value: u64, collateral: u64) -> Result<u64> {
+    require!(collateral > 0, ErrorCode::CollateralTooLow);
+    require!(position_value >= collateral, ErrorCode::LeverageBelowOne);
+
+    proportional(position_value, BASIS_POINTS as u64, collateral)
+}
+
+/// Average two prices weighted by their sizes
+pub fn blend_price(price_a: u64, size_a: u64, price_b: u64, size_b: u64) -> Result<u64> {
+    let total_size = (size_a as u128)
+        .checked_add(size_b as u128)
+        .ok_or(ErrorCode::MathOverflow)?;
+    require!(total_size > 0, ErrorCode::InvalidPositionSize);
+
+    let blended = (price_a as u128)
+        .checked_mul(size_a as u128)
+        .ok_or(ErrorCode::MathOverflow)?
+        .checked_add(
+            (price_b as u128)
+                .checked_mul(size_b as u128)
+                .ok_or(ErrorCode::MathOverflow)?,
+        )
+        .ok_or(ErrorCode::MathOverflow)?
+        .checked_div(total_size)
+        .ok_or(ErrorCode::MathOverflow)?;
+
+    u64::try_from(blended).map_err(|_| ErrorCode::MathOverflow.into())
+}
+
+/// Calculate a target price `target_percentage_bps` above current for longs and below for shorts
+///
+/// Shared by the initial target at open and the rolled target at rebalance so both
+/// round the same way: `current_price ± floor(current_price × bps / BASIS_POINTS)`.
+///
+/// # Arguments
+/// * `current_price` - USD per token with 6 decimals
+/// * `target_percentage_bps` - Distance to the target in basis points (e.g., 1000 = 10%)
+/// * `is_long` - Direction of the position
+pub fn calculate_target_price(
+    current_price: u64,
+    target_percentage_bps: u64,
+    is_long: bool,
+) -> Result<u64> {
+    let price_change = proportional(current_price, target_percentage_bps, BASIS_POINTS as u64)?;
+
+    let target_price = if is_long {
+        current_price.checked_add(price_change)
+    } else {
+        current_price.checked_sub(price_change)
+    };
+
+    target_price.ok_or(ErrorCode::MathOverflow.into())
+}
+
+/// Check that the execution price hasn't moved against the trader beyond their tolerance
+///
+/// Longs are protected on the upside and shorts on the downside, a move in the
+/// trader's favour always passes.
+///
+/// # Arguments
+/// * `current_price` - Oracle price the position would open at, USD per token with 6 decimals
+/// * `desired_entry_price` - Price the trader quoted, USD per token with 6 decimals
+/// * `max_slippage_bps` - Tolerated adverse move in basis points of `desired_entry_price`, 0 disables
+/// * `is_long` - Direction of the position
+pub fn check_slippage(
+    current_price: u64,
+    desired_entry_price: u64,
+    max_slippage_bps: u64,
+    is_long: bool,
+) -> Result<()> {
+    if max_slippage_bps == 0 {
+        return Ok(());
+    }
+    let tolerance = proportional(desired_entry_price, max_slippage_bps, BASIS_POINTS as u64)?;
+    let within = if is_long {
+        current_price <= desired_entry_price.saturating_add(tolerance)
+    } else {
+        current_price >= desired_entry_price.saturating_sub(tolerance)
+    };
+    if !within {
+        msg!(
+            "Price {} moved more than {}bps from {}",
+            current_price,
+            max_slippage_bps,
+            desired_entry_price
+        );
+        return err!(ErrorCode::SlippageExceeded);
+    }
+    Ok(())
+}
+
+/// Check that a fresh target sits the full band beyond the current price
+///
+/// A target at or inside the current price makes the position rebalance-eligible the
+/// moment it opens. That happens when the band rounds to 0 at a tiny price, or when
+/// a caller passes a target that wasn't derived from `current_price`.
+///
+/// # Arguments
+/// * `current_price` - USD per token with 6 decimals
+/// * `target_price` - USD per token with 6 decimals
+/// * `target_percentage_bps` - Band the target must clear, in basis points
+/// * `is_long` - Direction of the position
+pub fn validate_target_band(
+    current_price: u64,
+    target_price: u64,
+    target_percentage_bps: u64,
+    is_long: bool,
+) -> Result<()> {
+    let band = proportional(current_price, target_percentage_bps, BASIS_POINTS as u64)?;
+    let distance = if is_long {
+        target_price.checked_sub(current_price)
+    } else {
+        current_price.checked_sub(target_price)
+    };
+
+    match distance {
+        Some(distance) if distance > 0 && distance >= band => Ok(()),
+        _ => {
+            msg!(
+                "Target {} is not {}bps beyond current price {}",
+                target_price,
+                target_percentage_bps,
+                current_price
+            );
+            err!(ErrorCode::InvalidTargetPrice)
+        }
+    }
+}
+
 /// Calculate long position with dynamic leverage
 ///
 /// The key insight: We need to amplify the position so that when price moves from
@@ -203,33 +1037,174 @@ pub fn calculate_long_position(
     collateral: u64,
     token_decimals: u8,
 ) -> Result<PositionParams> {
+    let sizing = size_long_position(
+        desired_entry_price,
+        desired_size,
+        current_price,
+        target_price,
+        token_decimals,
+    )?;
+    sizing.with_collateral(collateral, target_price)
+}
+
+/// Calculate short position with dynamic leverage
+///
+/// For shorts:
+/// Target profit at target_price = desired_size × (desired_entry_price - target_price)
+/// Actual profit = actual_size × (current_price - target_price)
+///
+/// Setting them equal:
+/// actual_size = desired_size × (desired_entry_price - target_price) / (current_price - target_price)
+///
+/// # Arguments
+/// * `desired_entry_price` - USD per token with 6 decimals
+/// * `desired_size` - Token amount in smallest units
+/// * `current_price` - USD per token with 6 decimals
+/// * `target_price` - USD per token with 6 decimals (10-20% below current)
+/// * `collateral` - USD with 6 decimals
+/// * `token_decimals` - Number of decimals for the token
+pub fn calculate_short_position(
+    desired_entry_price: u64,
+    desired_size: u64,
+    current_price: u64,
+    target_price: u64,
+    collateral: u64,
+    token_decimals: u8,
+) -> Result<PositionParams> {
+    let sizing = size_short_position(
+        desired_entry_price,
+        desired_size,
+        current_price,
+        target_price,
+        token_decimals,
+    )?;
+    sizing.with_collateral(collateral, target_price)
+}
+
+/// Size a long regret position, the collateral independent half of
+/// `calculate_long_position`
+///
+/// # Arguments
+/// * `desired_entry_price` - USD per token with 6 decimals
+/// * `desired_size` - Token amount in smallest units
+/// * `current_price` - USD per token with 6 decimals
+/// * `target_price` - USD per token with 6 decimals
+/// * `token_decimals` - Number of decimals for the token
+pub fn size_long_position(
+    desired_entry_price: u64,
+    desired_size: u64,
+    current_price: u64,
+    target_price: u64,
+    token_decimals: u8,
+) -> Result<PositionSizing> {
+    require!(
+        current_price > desired_entry_price,
+        ErrorCode::InvalidPriceForLong
+    );
+    // Ordering is desired_entry < current < target, a target at or below the current
+    // price would make the price movement underflow
+    if target_price <= current_price {
+        msg!(
+            "Long target {} must be above current price {}",
+            target_price,
+            current_price
+        );
+        return err!(ErrorCode::InvalidTargetPrice);
+    }
+
+    // Calculate the profit target at target_price
+    let target_profit_range = (target_price as u128)
+        .checked_sub(desired_entry_price as u128)
+        .ok_or(ErrorCode::MathOverflow)?;
+
+    // Calculate the price movement from current to target
+    let price_movement = (target_price as u128)
+        .checked_sub(current_price as u128)
+        .ok_or(ErrorCode::MathOverflow)?;
+
+    amplify_position(
+        desired_size,
+        target_profit_range,
+        price_movement,
+        current_price,
+        token_decimals,
+    )
+}
+
+/// Size a short regret position, the collateral independent half of
+/// `calculate_short_position`
+///
+/// # Arguments
+/// * `desired_entry_price` - USD per token with 6 decimals
+/// * `desired_size` - Token amount in smallest units
+/// * `current_price` - USD per token with 6 decimals
+/// * `target_price` - USD per token with 6 decimals
+/// * `token_decimals` - Number of decimals for the token
+pub fn size_short_position(
+    desired_entry_price: u64,
+    desired_size: u64,
+    current_price: u64,
+    target_price: u64,
+    token_decimals: u8,
+) -> Result<PositionSizing> {
+    require!(
+        current_price < desired_entry_price,
+        ErrorCode::InvalidPriceForShort
+    );
+
     require!(
-        current_price > desired_entry_price,
-        ErrorCode::InvalidPriceForLong
+        target_price < desired_entry_price,
+        ErrorCode::InvalidTargetPrice
     );
-    require!(target_price > current_price, ErrorCode::InvalidTargetPrice);
 
-    // Handle edge cases
-    if desired_size == 0 || collateral == 0 {
-        return err!(ErrorCode::InvalidInput);
+    // Ordering is desired_entry > current > target, a target at or above the current
+    // price would make the price movement underflow
+    if target_price >= current_price {
+        msg!(
+            "Short target {} must be below current price {}",
+            target_price,
+            current_price
+        );
+        return err!(ErrorCode::InvalidTargetPrice);
     }
 
     // Calculate the profit target at target_price
-    let target_profit_range = (target_price as u128)
-        .checked_sub(desired_entry_price as u128)
+    let target_profit_range = (desired_entry_price as u128)
+        .checked_sub(target_price as u128)
         .ok_or(ErrorCode::MathOverflow)?;
 
     // Calculate the price movement from current to target
-    let price_movement = (target_price as u128)
-        .checked_sub(current_price as u128)
+    let price_movement = (current_price as u128)
+        .checked_sub(target_price as u128)
         .ok_or(ErrorCode::MathOverflow)?;
 
+    amplify_position(
+        desired_size,
+        target_profit_range,
+        price_movement,
+        current_price,
+        token_decimals,
+    )
+}
+
+/// Amplify `desired_size` so the move to the target pays the regretted profit
+///
+/// actual_size = desired_size × target_profit_range / price_movement
+fn amplify_position(
+    desired_size: u64,
+    target_profit_range: u128,
+    price_movement: u128,
+    current_price: u64,
+    token_decimals: u8,
+) -> Result<PositionSizing> {
+    if desired_size == 0 {
+        return err!(ErrorCode::InvalidInput);
+    }
     if price_movement == 0 {
         return err!(ErrorCode::InvalidTargetPrice);
     }
 
     // Calculate required position size with higher precision
-    // actual_size = desired_size × (target_price - desired_entry) / (target_price - current_price)
     let actual_size_scaled = (desired_size as u128)
         .checked_mul(target_profit_range)
         .ok_or(ErrorCode::MathOverflow)?
@@ -263,98 +1238,65 @@ pub fn calculate_long_position(
 
     let position_value = u64::try_from(position_value).map_err(|_| ErrorCode::MathOverflow)?;
 
-    // Leverage in basis points (1x = 10000)
-    let leverage_bps = position_value_scaled
-        .checked_mul(BASIS_POINTS)
-        .ok_or(ErrorCode::MathOverflow)?
-        .checked_div(collateral as u128)
-        .ok_or(ErrorCode::MathOverflow)?
-        .checked_div(PRECISION)
-        .ok_or(ErrorCode::MathOverflow)?;
-
-    let leverage_bps = u64::try_from(leverage_bps).map_err(|_| ErrorCode::MathOverflow)?;
-
-    Ok(PositionParams {
+    Ok(PositionSizing {
         actual_size,
-        leverage_bps,
         position_value,
-        target_price,
+        position_value_scaled,
     })
 }
 
-/// Calculate short position with dynamic leverage
+/// Calculate a limit-entry position (no amplification)
 ///
-/// For shorts:
-/// Target profit at target_price = desired_size × (desired_entry_price - target_price)
-/// Actual profit = actual_size × (current_price - target_price)
+/// This is a distinct path from `calculate_long_position`/`calculate_short_position`,
+/// which require the market to have already moved past `desired_entry_price` (the
+/// "regret" case). A limit entry is the opposite: it only fills when the oracle is at
+/// or better than `desired_entry_price` for the trade direction:
+/// - long: `current_price <= desired_entry_price`
+/// - short: `current_price >= desired_entry_price`
 ///
-/// Setting them equal:
-/// actual_size = desired_size × (desired_entry_price - target_price) / (current_price - target_price)
+/// Since the trader gets their price (or better), there is nothing to amplify and the
+/// position is opened 1:1, i.e. `actual_size = desired_size` entered at `current_price`.
 ///
 /// # Arguments
-/// * `desired_entry_price` - USD per token with 6 decimals
+/// * `desired_entry_price` - Limit price, USD per token with 6 decimals
 /// * `desired_size` - Token amount in smallest units
 /// * `current_price` - USD per token with 6 decimals
-/// * `target_price` - USD per token with 6 decimals (10-20% below current)
+/// * `target_price` - USD per token with 6 decimals
 /// * `collateral` - USD with 6 decimals
 /// * `token_decimals` - Number of decimals for the token
-pub fn calculate_short_position(
+/// * `is_long` - Direction of the position
+pub fn calculate_limit_entry_position(
     desired_entry_price: u64,
     desired_size: u64,
     current_price: u64,
     target_price: u64,
     collateral: u64,
     token_decimals: u8,
+    is_long: bool,
 ) -> Result<PositionParams> {
-    require!(
-        current_price < desired_entry_price,
-        ErrorCode::InvalidPriceForShort
-    );
-
-    require!(
-        target_price < desired_entry_price,
-        ErrorCode::InvalidTargetPrice
-    );
+    if is_long {
+        require!(
+            current_price <= desired_entry_price,
+            ErrorCode::LimitEntryNotMet
+        );
+        require!(target_price > current_price, ErrorCode::InvalidTargetPrice);
+    } else {
+        require!(
+            current_price >= desired_entry_price,
+            ErrorCode::LimitEntryNotMet
+        );
+        require!(target_price < current_price, ErrorCode::InvalidTargetPrice);
+    }
 
     // Handle edge cases
     if desired_size == 0 || collateral == 0 {
-        return Err(ErrorCode::InvalidInput.into());
-    }
-
-    // Calculate the profit target at target_price
-    let target_profit_range = (desired_entry_price as u128)
-        .checked_sub(target_price as u128)
-        .ok_or(ErrorCode::MathOverflow)?;
-
-    // Calculate the price movement from current to target
-    let price_movement = (current_price as u128)
-        .checked_sub(target_price as u128)
-        .ok_or(ErrorCode::MathOverflow)?;
-
-    if price_movement == 0 {
-        return err!(ErrorCode::InvalidTargetPrice);
+        return err!(ErrorCode::InvalidInput);
     }
 
-    // Calculate required position size with higher precision
-    let actual_size_scaled = (desired_size as u128)
-        .checked_mul(target_profit_range)
-        .ok_or(ErrorCode::MathOverflow)?
-        .checked_mul(PRECISION)
-        .ok_or(ErrorCode::MathOverflow)?
-        .checked_div(price_movement)
-        .ok_or(ErrorCode::MathOverflow)?;
-
-    // Remove precision
-    let actual_size = actual_size_scaled
-        .checked_div(PRECISION)
-        .ok_or(ErrorCode::MathOverflow)?;
-
-    let actual_size = u64::try_from(actual_size).map_err(|_| ErrorCode::MathOverflow)?;
-
     let token_divisor = 10_u128.pow(token_decimals as u32);
 
     // Calculate position value in USD with 6 decimals using precision
-    let position_value_scaled = (actual_size as u128)
+    let position_value_scaled = (desired_size as u128)
         .checked_mul(current_price as u128)
         .ok_or(ErrorCode::MathOverflow)?
         .checked_mul(PRECISION)
@@ -380,7 +1322,7 @@ pub fn calculate_short_position(
     let leverage_bps = u64::try_from(leverage_bps).map_err(|_| ErrorCode::MathOverflow)?;
 
     Ok(PositionParams {
-        actual_size,
+        actual_size: desired_size,
         leverage_bps,
         position_value,
         target_price,
@@ -394,11 +1336,13 @@ pub fn calculate_short_position(
 /// * `current_price` - USD per token with 6 decimals
 /// * `target_percentage_bps` - Target percentage in basis points (e.g., 1000 = 10%)
 /// * `token_decimals` - Number of decimals for the token
+/// * `min_rebalance_profit` - USD with 6 decimals, below this the position holds its target
 pub fn calculate_rebalance_with_new_target(
     position: Position,
     current_price: u64,
     target_percentage_bps: u64,
     token_decimals: u8,
+    min_rebalance_profit: u64,
 ) -> Result<RebalanceResult> {
     let is_long = position.is_long;
 
@@ -415,6 +1359,7 @@ pub fn calculate_rebalance_with_new_target(
             new_actual_size: position.actual_size,
             new_target_price: position.current_target_price,
             new_leverage_bps: 0,
+            new_position_value: position.position_value,
             profit_realized: 0,
             excess_to_insurance: 0,
         });
@@ -427,6 +1372,7 @@ pub fn calculate_rebalance_with_new_target(
             new_actual_size: position.actual_size,
             new_target_price: position.current_target_price,
             new_leverage_bps: 0,
+            new_position_value: position.position_value,
             profit_realized: 0,
             excess_to_insurance: 0,
         });
@@ -467,6 +1413,19 @@ pub fn calculate_rebalance_with_new_target(
         u64::try_from(profit_u128).map_err(|_| ErrorCode::MathOverflow)?
     };
 
+    // Marginal target touches aren't worth the rebalance overhead
+    if actual_profit < min_rebalance_profit {
+        return Ok(RebalanceResult {
+            should_rebalance: false,
+            new_actual_size: position.actual_size,
+            new_target_price: position.current_target_price,
+            new_leverage_bps: 0,
+            new_position_value: position.position_value,
+            profit_realized: 0,
+            excess_to_insurance: 0,
+        });
+    }
+
     // Calculate virtual profit (what user expects to see) in USD with 6 decimals
     let virtual_profit = if is_long {
         let price_diff = current_price.saturating_sub(position.desired_entry_price);
@@ -489,59 +1448,480 @@ pub fn calculate_rebalance_with_new_target(
     // Excess profit goes to insurance fund
     let excess_to_insurance = actual_profit.saturating_sub(virtual_profit);
 
-    // Calculate new target price (10-20% from current) with precision
-    let price_change_scaled = (current_price as u128)
-        .checked_mul(target_percentage_bps as u128)
-        .ok_or(ErrorCode::MathOverflow)?
-        .checked_mul(PRECISION)
-        .ok_or(ErrorCode::MathOverflow)?
-        .checked_div(BASIS_POINTS)
-        .ok_or(ErrorCode::MathOverflow)?;
+    // Calculate new target price, the same way as the initial target at open
+    let new_target_price = calculate_target_price(current_price, target_percentage_bps, is_long)?;
+
+    // Calculate new position size for the next cycle
+    let new_params = if is_long {
+        calculate_long_position(
+            position.desired_entry_price,
+            position.desired_size,
+            current_price,
+            new_target_price,
+            position.collateral,
+            token_decimals,
+        )?
+    } else {
+        calculate_short_position(
+            position.desired_entry_price,
+            position.desired_size,
+            current_price,
+            new_target_price,
+            position.collateral,
+            token_decimals,
+        )?
+    };
+
+    Ok(RebalanceResult {
+        should_rebalance: true,
+        new_actual_size: new_params.actual_size,
+        new_target_price,
+        new_leverage_bps: new_params.leverage_bps,
+        new_position_value: new_params.position_value,
+        profit_realized: actual_profit,
+        excess_to_insurance,
+    })
+}
+
+/// Scale `amount` by `numerator / denominator`, rounding down
+pub fn proportional(amount: u64, numerator: u64, denominator: u64) -> Result<u64> {
+    require!(denominator > 0, ErrorCode::InvalidInput);
+
+    let scaled = (amount as u128)
+        .checked_mul(numerator as u128)
+        .ok_or(ErrorCode::MathOverflow)?
+        .checked_div(denominator as u128)
+        .ok_or(ErrorCode::MathOverflow)?;
+
+    u64::try_from(scaled).map_err(|_| ErrorCode::MathOverflow.into())
+}
+
+/// Calculate a fee in basis points of `amount`
+///
+/// # Arguments
+/// * `amount` - USD with 6 decimals
+/// * `fee_bps` - Fee in basis points (e.g., 50 = 0.5%)
+pub fn calculate_fee(amount: u64, fee_bps: u16) -> Result<u64> {
+    let fee_scaled = (amount as u128)
+        .checked_mul(fee_bps as u128)
+        .ok_or(ErrorCode::MathOverflow)?
+        .checked_mul(PRECISION)
+        .ok_or(ErrorCode::MathOverflow)?
+        .checked_div(BASIS_POINTS)
+        .ok_or(ErrorCode::MathOverflow)?;
+
+    let fee = fee_scaled
+        .checked_div(PRECISION)
+        .ok_or(ErrorCode::MathOverflow)?;
+
+    u64::try_from(fee).map_err(|_| ErrorCode::MathOverflow.into())
+}
+
+/// Calculate the largest position value the open checks accept and which check binds
+///
+/// Mirrors the leverage, liquidity, value cap, exposure and funding checks of
+/// `open_position`, inverted for the position value.
+///
+/// # Arguments
+/// * `effective_collateral` - Collateral after the opening fee, USD with 6 decimals
+/// * `config` - Protocol configuration
+/// * `max_leverage` - Leverage cap in basis points, see `Market::effective_max_leverage`
+/// * `maintainance_margin` - Base maintenance margin, see `Market::effective_maintainance_margin`
+/// * `available_liquidity` - Pool liquidity available to borrow
+/// * `current_exposure` - Trader's current total notional exposure
+/// * `funding_rate_bps` - Funding rate per period in basis points
+#[allow(clippy::too_many_arguments)]
+pub fn calculate_max_position_value(
+    effective_collateral: u64,
+    config: &Config,
+    max_leverage: u64,
+    maintainance_margin: u16,
+    margin_tier: u8,
+    available_liquidity: u64,
+    current_exposure: u64,
+    funding_rate_bps: i64,
+) -> Result<(u64, MaxOpenConstraint)> {
+    let basis_points = BASIS_POINTS as u64;
+
+    let by_leverage = proportional(effective_collateral, max_leverage, basis_points)?;
+    let by_liquidity = available_liquidity.saturating_add(effective_collateral);
+
+    // One funding period costs `funding_rate_bps` of the notional, the remaining equity
+    // has to stay strictly above `maintainance_margin` of it
+    let funding_bps = funding_rate_bps.max(0) as u64;
+    let by_maintenance = proportional(
+        effective_collateral,
+        basis_points,
+        (maintenance_margin_for_tier(maintainance_margin, margin_tier)? as u64)
+            .checked_add(funding_bps)
+            .ok_or(ErrorCode::MathOverflow)?,
+    )?
+    .saturating_sub(1);
+
+    let mut limits = vec![
+        (by_leverage, MaxOpenConstraint::Leverage),
+        (by_liquidity, MaxOpenConstraint::Liquidity),
+        (MAX_POSITION_VALUE, MaxOpenConstraint::PositionValueCap),
+        (by_maintenance, MaxOpenConstraint::Maintenance),
+    ];
+    if config.max_trader_exposure > 0 {
+        limits.push((
+            config.max_trader_exposure.saturating_sub(current_exposure),
+            MaxOpenConstraint::TraderExposure,
+        ));
+    }
+    if config.max_borrow_per_position > 0 {
+        limits.push((
+            config
+                .max_borrow_per_position
+                .saturating_add(effective_collateral),
+            MaxOpenConstraint::BorrowCap,
+        ));
+    }
+
+    limits
+        .into_iter()
+        .min_by_key(|(value, _)| *value)
+        .ok_or(ErrorCode::MathOverflow.into())
+}
+
+/// Calculate the collateral that gives a regret position a target leverage
+///
+/// The inverse of the open flow: the amplified `position_value` only depends on the
+/// size and prices, so size it first and divide by the leverage. The direction follows
+/// from the prices, a long when the market has moved above `desired_entry_price`.
+/// The result is the effective collateral, the opening fee comes on top.
+///
+/// # Arguments
+/// * `desired_size` - Token amount in smallest units
+/// * `desired_entry_price` - USD per token with 6 decimals
+/// * `current_price` - USD per token with 6 decimals
+/// * `target_price` - USD per token with 6 decimals
+/// * `target_leverage_bps` - Leverage in basis points (10000 = 1x)
+/// * `token_decimals` - Number of decimals for the token
+pub fn collateral_for_leverage(
+    desired_size: u64,
+    desired_entry_price: u64,
+    current_price: u64,
+    target_price: u64,
+    target_leverage_bps: u64,
+    token_decimals: u8,
+) -> Result<u64> {
+    require!(
+        target_leverage_bps >= BASIS_POINTS as u64,
+        ErrorCode::LeverageBelowOne
+    );
+
+    let sizing = if current_price > desired_entry_price {
+        size_long_position(
+            desired_entry_price,
+            desired_size,
+            current_price,
+            target_price,
+            token_decimals,
+        )?
+    } else {
+        size_short_position(
+            desired_entry_price,
+            desired_size,
+            current_price,
+            target_price,
+            token_decimals,
+        )?
+    };
+
+    proportional(
+        sizing.position_value,
+        BASIS_POINTS as u64,
+        target_leverage_bps,
+    )
+}
+
+/// Calculate the opening fee in basis points for a given leverage
+///
+/// `effective_fee = base_fee + surcharge * (leverage - 1x)`, capped at `max_fee` when set.
+/// The cap never lowers the fee below `base_fee`.
+///
+/// # Arguments
+/// * `base_fee_bps` - Flat opening fee in basis points
+/// * `surcharge_bps` - Extra basis points per 1x of leverage above 1x
+/// * `max_fee_bps` - Cap on the total fee, 0 means no cap
+/// * `leverage_bps` - Position leverage in basis points (10000 = 1x)
+pub fn calculate_opening_fee_bps(
+    base_fee_bps: u16,
+    surcharge_bps: u16,
+    max_fee_bps: u16,
+    leverage_bps: u64,
+) -> Result<u16> {
+    let basis_points = BASIS_POINTS as u64;
+    let surcharge = proportional(
+        surcharge_bps as u64,
+        leverage_bps.saturating_sub(basis_points),
+        basis_points,
+    )?;
+
+    let mut fee_bps = (base_fee_bps as u64)
+        .checked_add(surcharge)
+        .ok_or(ErrorCode::MathOverflow)?;
+    if max_fee_bps > 0 {
+        fee_bps = fee_bps.min(max_fee_bps.max(base_fee_bps) as u64);
+    }
+
+    u16::try_from(fee_bps).map_err(|_| ErrorCode::MathOverflow.into())
+}
+
+/// Split `collateral` into the opening fee and the effective collateral left after it
+///
+/// Fails before anything is charged when the fee rounds to 0, consumes the whole
+/// collateral, or leaves less than `min_effective_collateral`.
+///
+/// # Arguments
+/// * `collateral` - Collateral posted, USD with 6 decimals
+/// * `opening_fee_bps` - `Config::opening_fee`, charged on the collateral
+/// * `min_effective_collateral` - `Config::min_effective_collateral`
+///
+/// # Returns
+/// * `(opening_fee, effective_collateral)`
+pub fn split_opening_collateral(
+    collateral: u64,
+    opening_fee_bps: u16,
+    min_effective_collateral: u64,
+) -> Result<(u64, u64)> {
+    let opening_fee = calculate_fee(collateral, opening_fee_bps)?;
+    require!(opening_fee > 0, ErrorCode::FeeTooLow);
+
+    if opening_fee >= collateral {
+        msg!(
+            "Opening fee {} consumes the whole collateral {}",
+            opening_fee,
+            collateral
+        );
+        return err!(ErrorCode::InsufficientCollateralForFees);
+    }
+    let effective_collateral = collateral - opening_fee;
+    require!(
+        effective_collateral >= min_effective_collateral,
+        ErrorCode::EffectiveCollateralTooLow
+    );
+    Ok((opening_fee, effective_collateral))
+}
+
+/// Split a fee between the protocol and LPs
+///
+/// The LP share is the remainder, so the two parts always sum exactly to `fee`:
+/// a share of 0 routes everything to LPs and `BASIS_POINTS` everything to the protocol.
+///
+/// # Returns
+/// * `(protocol_fee, lp_fee)`
+pub fn split_fee(fee: u64, protocol_fee_share: u16) -> Result<(u64, u64)> {
+    require!(
+        protocol_fee_share as u128 <= BASIS_POINTS,
+        ErrorCode::InvalidInput
+    );
+
+    let protocol_fee = calculate_fee(fee, protocol_fee_share)?;
+    let lp_fee = fee
+        .checked_sub(protocol_fee)
+        .ok_or(ErrorCode::MathOverflow)?;
+
+    Ok((protocol_fee, lp_fee))
+}
+
+/// Calculate whether a position is liquidatable and what a liquidation pays out
+///
+/// A position is liquidatable when its health ratio is below 100%. The liquidator
+/// reward is `liquidation_fee` bps of `position_value`, capped by the remaining
+/// equity, and any loss beyond the collateral is reported as bad debt. When
+/// `liquidation_rebate` is set, that share of the equity left after the reward goes
+/// back to the trader.
+///
+/// # Arguments
+/// * `position` - The position to check, funding should already be settled
+/// * `current_price` - USD per token with 6 decimals
+/// * `config` - Protocol configuration
+/// * `maintainance_margin` - Base margin, see `Market::effective_maintainance_margin`
+/// * `margin_tier` - Owner's `Trader::margin_tier`
+/// * `token_decimals` - Number of decimals for the token
+pub fn calculate_liquidation(
+    position: &Position,
+    current_price: u64,
+    config: &Config,
+    maintainance_margin: u16,
+    margin_tier: u8,
+    token_decimals: u8,
+) -> Result<LiquidationResult> {
+    let health_ratio = calculate_health_ratio(
+        position,
+        current_price,
+        maintainance_margin,
+        margin_tier,
+        token_decimals,
+    )?;
+    let pnl_result = calculate_pnl(position, current_price, token_decimals)?;
+
+    let equity = SignedAmount::from_u64(position.collateral).checked_add(pnl_result.net_pnl)?;
+    let bad_debt = equity.negative_part()?;
+    let positive_equity = equity.positive_part()?;
+    let equity = equity.to_i64()?;
+
+    // Liquidator reward can't exceed what's left of the position
+    let liquidation_reward = (position.position_value as u128)
+        .checked_mul(config.liquidation_fee as u128)
+        .ok_or(ErrorCode::MathOverflow)?
+        .checked_div(BASIS_POINTS)
+        .ok_or(ErrorCode::MathOverflow)?;
+    let liquidation_reward =
+        u64::try_from(liquidation_reward).map_err(|_| ErrorCode::MathOverflow)?;
+    let liquidation_reward = liquidation_reward.min(positive_equity);
+
+    // Only genuine equity left after the liquidator is paid is rebated, never bad debt
+    let remaining_equity = positive_equity.saturating_sub(liquidation_reward);
+    let trader_rebate = calculate_fee(remaining_equity, config.liquidation_rebate)?;
+
+    Ok(LiquidationResult {
+        health_ratio,
+        is_liquidatable: health_ratio < BASIS_POINTS as u64,
+        equity,
+        net_pnl: pnl_result.net_pnl,
+        liquidation_reward,
+        bad_debt,
+        trader_rebate,
+        retained_equity: remaining_equity.saturating_sub(trader_rebate),
+    })
+}
+
+/// Price a close on a stale oracle settles at, see `close_position`
+///
+/// The entry price, so no profit is paid out on a price nobody can verify, unless the
+/// last known price already shows a loss, which is still realized.
+pub fn stale_close_price(position: &Position, last_known_price: u64) -> u64 {
+    if position.is_long {
+        last_known_price.min(position.actual_entered_price)
+    } else {
+        last_known_price.max(position.actual_entered_price)
+    }
+}
+
+/// Calculate the outcome of fully closing a position at `current_price`
+///
+/// The trader gets back `collateral ± net_pnl - closing_fee`, floored at 0 when the
+/// loss and fee exceed the collateral. The fee is capped at what the collateral left
+/// after the loss can pay, and a loss beyond the collateral is reported as bad debt.
+/// A position with no collateral left pays out nothing and is charged no fee.
+///
+/// The closing fee is charged on the notional being closed at `current_price`, not on
+/// the stored `position_value`, so it reflects what is actually taken off the book.
+///
+/// # Arguments
+/// * `position` - The position to close, funding should already be settled
+/// * `current_price` - USD per token with 6 decimals
+/// * `closing_fee_bps` - Closing fee in basis points, applied to the current notional
+/// * `token_decimals` - Number of decimals for the token
+pub fn calculate_close(
+    position: &Position,
+    current_price: u64,
+    closing_fee_bps: u16,
+    token_decimals: u8,
+) -> Result<CloseResult> {
+    let pnl_result = calculate_pnl(position, current_price, token_decimals)?;
+    let gross_price_pnl = pnl_result.gross_pnl.to_i64()?;
+
+    // Collateral fully eroded: nothing to pay out or charge, any loss is bad debt
+    if position.collateral == 0 {
+        return Ok(CloseResult {
+            gross_price_pnl,
+            net_pnl: pnl_result.net_pnl,
+            closing_fee: 0,
+            amount_to_return: 0,
+            bad_debt: pnl_result.net_pnl.negative_part()?,
+            forfeited_pnl: pnl_result.net_pnl.positive_part()?,
+        });
+    }
+
+    let current_notional = notional_value(position.actual_size, current_price, token_decimals)?;
+    let closing_fee = calculate_fee(current_notional, closing_fee_bps)?;
+
+    // Collateral ± PnL, the trader gets nothing and the shortfall is bad debt when the
+    // loss exceeds the collateral. The fee is capped at what is left.
+    let remaining = SignedAmount::from_u64(position.collateral).checked_add(pnl_result.net_pnl)?;
+    let bad_debt = remaining.negative_part()?;
+    let remaining = remaining.positive_part()?;
+    let closing_fee = closing_fee.min(remaining);
+
+    Ok(CloseResult {
+        gross_price_pnl,
+        net_pnl: pnl_result.net_pnl,
+        closing_fee,
+        amount_to_return: remaining - closing_fee,
+        bad_debt,
+        forfeited_pnl: 0,
+    })
+}
 
-    let price_change = price_change_scaled
-        .checked_div(PRECISION)
-        .ok_or(ErrorCode::MathOverflow)?;
+/// Calculate the outcome of reducing a position down to `remaining_size`
+///
+/// The closed part takes its pro-rata share of collateral, position value, borrowed
+/// amount and settled funding, and realizes the same share of the position's net PnL.
+/// The closing fee only applies to the closed notional at `current_price` and, as in
+/// `calculate_close`, is capped at what the closed collateral left after the loss can
+/// pay. A loss beyond the closed collateral is reported as bad debt.
+///
+/// # Arguments
+/// * `position` - The position to reduce, funding should already be settled
+/// * `current_price` - USD per token with 6 decimals
+/// * `remaining_size` - Size left open, in token's smallest units
+/// * `closing_fee_bps` - Closing fee in basis points
+/// * `token_decimals` - Number of decimals for the token
+pub fn calculate_reduction(
+    position: &Position,
+    current_price: u64,
+    remaining_size: u64,
+    closing_fee_bps: u16,
+    token_decimals: u8,
+) -> Result<ReductionResult> {
+    require!(
+        remaining_size > 0 && remaining_size < position.actual_size,
+        ErrorCode::InvalidPositionSize
+    );
 
-    let price_change = u64::try_from(price_change).map_err(|_| ErrorCode::MathOverflow)?;
+    let closed_size = position.actual_size - remaining_size;
 
-    let new_target_price = if is_long {
-        current_price
-            .checked_add(price_change)
-            .ok_or(ErrorCode::MathOverflow)?
-    } else {
-        current_price
-            .checked_sub(price_change)
-            .ok_or(ErrorCode::MathOverflow)?
-    };
+    let closed_collateral = proportional(position.collateral, closed_size, position.actual_size)?;
+    let closed_value = proportional(position.position_value, closed_size, position.actual_size)?;
+    let (funding_paid, is_payment) =
+        SignedAmount::from_i64(position.cumulative_funding_paid).to_parts()?;
+    let closed_funding = SignedAmount::from_parts(
+        proportional(funding_paid, closed_size, position.actual_size)?,
+        is_payment,
+    )
+    .to_i64()?;
+    let closed_borrowed = proportional(position.booked_borrow, closed_size, position.actual_size)?;
 
-    // Calculate new position size for the next cycle
-    let new_params = if is_long {
-        calculate_long_position(
-            position.desired_entry_price,
-            position.desired_size,
-            current_price,
-            new_target_price,
-            position.collateral,
-            token_decimals,
-        )?
-    } else {
-        calculate_short_position(
-            position.desired_entry_price,
-            position.desired_size,
-            current_price,
-            new_target_price,
-            position.collateral,
-            token_decimals,
-        )?
-    };
+    let pnl_result = calculate_pnl(position, current_price, token_decimals)?;
+    let (net_pnl, is_profit) = pnl_result.net_pnl.to_parts()?;
+    let realized_pnl = SignedAmount::from_parts(
+        proportional(net_pnl, closed_size, position.actual_size)?,
+        is_profit,
+    );
 
-    Ok(RebalanceResult {
-        should_rebalance: true,
-        new_actual_size: new_params.actual_size,
-        new_target_price,
-        new_leverage_bps: new_params.leverage_bps,
-        profit_realized: actual_profit,
-        excess_to_insurance,
+    let closed_notional = notional_value(closed_size, current_price, token_decimals)?;
+    let closing_fee = calculate_fee(closed_notional, closing_fee_bps)?;
+
+    let remaining = SignedAmount::from_u64(closed_collateral).checked_add(realized_pnl)?;
+    let bad_debt = remaining.negative_part()?;
+    let remaining = remaining.positive_part()?;
+    let closing_fee = closing_fee.min(remaining);
+
+    Ok(ReductionResult {
+        closed_size,
+        closed_collateral,
+        closed_value,
+        closed_borrowed,
+        closed_funding,
+        realized_pnl,
+        closing_fee,
+        amount_to_return: remaining - closing_fee,
+        bad_debt,
     })
 }
 
@@ -553,7 +1933,7 @@ pub fn calculate_rebalance_with_new_target(
 /// * `token_decimals` - Number of decimals for the token
 ///
 /// # Returns
-/// * `PnLResult` - Contains gross PnL, net PnL (after funding), and profit flag
+/// * `PnLResult` - Signed gross PnL and net PnL (after funding)
 pub fn calculate_pnl(
     position: &Position,
     current_price: u64,
@@ -562,57 +1942,72 @@ pub fn calculate_pnl(
     // Handle edge cases
     if position.actual_size == 0 {
         return Ok(PnLResult {
-            gross_pnl: 0,
-            net_pnl: 0,
-            is_profit: false,
+            gross_pnl: SignedAmount::ZERO,
+            net_pnl: SignedAmount::ZERO,
         });
     }
 
-    let token_divisor = 10_u128.pow(token_decimals as u32);
-
-    // Calculate position values in USD with 6 decimals
-    let current_value = (position.actual_size as u128)
-        .checked_mul(current_price as u128)
-        .ok_or(ErrorCode::MathOverflow)?
-        .checked_div(token_divisor)
-        .ok_or(ErrorCode::MathOverflow)?;
-
-    let entry_value = (position.actual_size as u128)
-        .checked_mul(position.actual_entered_price as u128)
-        .ok_or(ErrorCode::MathOverflow)?
-        .checked_div(token_divisor)
-        .ok_or(ErrorCode::MathOverflow)?;
-
-    let (gross_pnl_u128, is_profit) = if position.is_long {
-        if current_value >= entry_value {
-            (current_value - entry_value, true)
-        } else {
-            (entry_value - current_value, false)
-        }
+    let current_value = notional_value(position.actual_size, current_price, token_decimals)?;
+    let entry_value = notional_value(
+        position.actual_size,
+        position.actual_entered_price,
+        token_decimals,
+    )?;
+
+    // Longs gain when the value rises, shorts when it falls
+    let price_move =
+        SignedAmount::from_u64(current_value).checked_sub(SignedAmount::from_u64(entry_value))?;
+    let gross_pnl = if position.is_long {
+        price_move
     } else {
-        if entry_value >= current_value {
-            (entry_value - current_value, true)
-        } else {
-            (current_value - entry_value, false)
-        }
+        price_move.checked_neg()?
     };
 
-    // Convert to u64 safely
-    let gross_pnl = u64::try_from(gross_pnl_u128).map_err(|_| ErrorCode::MathOverflow)?;
+    let net_pnl =
+        gross_pnl.checked_sub(SignedAmount::from_i64(position.cumulative_funding_paid))?;
 
-    let net_pnl = if is_profit {
-        gross_pnl.saturating_sub(position.cumulative_funding_paid)
-    } else {
-        gross_pnl
-            .checked_add(position.cumulative_funding_paid)
-            .ok_or(ErrorCode::MathOverflow)?
-    };
+    Ok(PnLResult { gross_pnl, net_pnl })
+}
 
-    Ok(PnLResult {
-        gross_pnl,
-        net_pnl,
-        is_profit: if is_profit { net_pnl > 0 } else { false },
-    })
+/// Express a signed PnL in basis points of the collateral, 0 without collateral
+///
+/// # Arguments
+/// * `pnl` - USD with 6 decimals
+/// * `collateral` - USD with 6 decimals
+pub fn calculate_pnl_bps_of_collateral(pnl: SignedAmount, collateral: u64) -> Result<i64> {
+    if collateral == 0 {
+        return Ok(0);
+    }
+    // Truncates towards zero for both signs, so a loss never reads larger than it is
+    SignedAmount(
+        pnl.checked_mul(BASIS_POINTS as i128)?
+            .0
+            .checked_div(collateral as i128)
+            .ok_or(ErrorCode::MathOverflow)?,
+    )
+    .to_i64()
+}
+
+/// Maintenance margin in basis points for a trader's `margin_tier`
+///
+/// Every tier above 0 cuts `MARGIN_TIER_DISCOUNT_BPS` of the base margin, never going
+/// below `MIN_MAINTAINANCE_MARGIN` so a tiered position stays liquidatable.
+///
+/// # Arguments
+/// * `maintainance_margin` - Base maintenance margin in basis points
+/// * `margin_tier` - Trader's tier, 0 up to `MAX_MARGIN_TIER`
+pub fn maintenance_margin_for_tier(maintainance_margin: u16, margin_tier: u8) -> Result<u16> {
+    let discount_bps = MARGIN_TIER_DISCOUNT_BPS
+        .checked_mul(margin_tier.min(MAX_MARGIN_TIER) as u64)
+        .ok_or(ErrorCode::MathOverflow)?;
+    let tiered = proportional(
+        maintainance_margin as u64,
+        (BASIS_POINTS as u64).saturating_sub(discount_bps),
+        BASIS_POINTS as u64,
+    )?;
+    Ok((tiered as u16)
+        .max(MIN_MAINTAINANCE_MARGIN)
+        .min(maintainance_margin))
 }
 
 /// Calculate health ratio for a position
@@ -623,7 +2018,8 @@ pub fn calculate_pnl(
 /// # Arguments
 /// * `position` - The position to check
 /// * `current_price` - USD per token with 6 decimals
-/// * `config` - Protocol configuration
+/// * `maintainance_margin` - Base margin, see `Market::effective_maintainance_margin`
+/// * `margin_tier` - Owner's `Trader::margin_tier`
 /// * `token_decimals` - Number of decimals for the token
 ///
 /// # Returns
@@ -631,7 +2027,8 @@ pub fn calculate_pnl(
 pub fn calculate_health_ratio(
     position: &Position,
     current_price: u64,
-    config: &Config,
+    maintainance_margin: u16,
+    margin_tier: u8,
     token_decimals: u8,
 ) -> Result<u64> {
     // Handle edge cases
@@ -642,13 +2039,9 @@ pub fn calculate_health_ratio(
     let pnl_result = calculate_pnl(position, current_price, token_decimals)?;
 
     // Calculate equity in USD with 6 decimals
-    let equity = if pnl_result.is_profit {
-        (position.collateral as u128)
-            .checked_add(pnl_result.net_pnl as u128)
-            .ok_or(ErrorCode::MathOverflow)?
-    } else {
-        (position.collateral as u128).saturating_sub(pnl_result.net_pnl as u128)
-    };
+    let equity = SignedAmount::from_u64(position.collateral)
+        .checked_add(pnl_result.net_pnl)?
+        .positive_part()? as u128;
 
     // If equity is 0 or negative, position is already liquidatable
     if equity == 0 {
@@ -667,8 +2060,9 @@ pub fn calculate_health_ratio(
         .ok_or(ErrorCode::MathOverflow)?;
 
     // Calculate required margin with precision maintained
+    let maintainance_margin = maintenance_margin_for_tier(maintainance_margin, margin_tier)?;
     let required_margin_scaled = position_value_scaled
-        .checked_mul(config.maintainance_margin as u128)
+        .checked_mul(maintainance_margin as u128)
         .ok_or(ErrorCode::MathOverflow)?
         .checked_div(BASIS_POINTS)
         .ok_or(ErrorCode::MathOverflow)?;
@@ -682,28 +2076,93 @@ pub fn calculate_health_ratio(
         return Ok(u64::MAX);
     }
 
-    // Health ratio in basis points with precision
-    // Scale equity up to match precision level
+    // Health ratio in basis points
+    // Scale equity up to the precision of the required margin, the two cancel out
     let equity_scaled = equity
         .checked_mul(PRECISION)
         .ok_or(ErrorCode::MathOverflow)?;
 
-    let health_ratio_scaled = equity_scaled
+    let health_ratio = equity_scaled
         .checked_mul(BASIS_POINTS)
         .ok_or(ErrorCode::MathOverflow)?
         .checked_div(required_margin_scaled)
         .ok_or(ErrorCode::MathOverflow)?;
 
-    // Remove precision
-    let health_ratio = health_ratio_scaled
-        .checked_div(PRECISION)
-        .ok_or(ErrorCode::MathOverflow)?;
-
     let health_ratio = u64::try_from(health_ratio).map_err(|_| ErrorCode::MathOverflow)?;
 
     Ok(health_ratio)
 }
 
+/// Calculate the price at which a position's health ratio reaches 100%
+///
+/// Solves `equity(P) = required_margin(P)` with the settled funding taken into account:
+/// - long: `P = (entry_value - equity) × 10^decimals × 10000 / (size × (10000 - mm))`
+/// - short: `P = (entry_value + equity) × 10^decimals × 10000 / (size × (10000 + mm))`
+///
+/// where `equity = collateral - cumulative_funding_paid` and
+/// `entry_value = size × actual_entered_price / 10^decimals`.
+///
+/// # Arguments
+/// * `position` - The position to compute the liquidation price for
+/// * `maintainance_margin` - Maintenance margin in basis points
+/// * `token_decimals` - Number of decimals for the token
+///
+/// # Returns
+/// * USD per token with 6 decimals, 0 if a long can't be liquidated by price alone
+pub fn calculate_liquidation_price(
+    position: &Position,
+    maintainance_margin: u16,
+    token_decimals: u8,
+) -> Result<u64> {
+    if position.actual_size == 0 {
+        return Ok(0);
+    }
+
+    let token_divisor = 10_u128.pow(token_decimals as u32);
+
+    let entry_value = (position.actual_size as u128)
+        .checked_mul(position.actual_entered_price as u128)
+        .ok_or(ErrorCode::MathOverflow)?
+        .checked_div(token_divisor)
+        .ok_or(ErrorCode::MathOverflow)?;
+
+    // Received funding adds to the equity
+    let equity =
+        (position.collateral as i128 - position.cumulative_funding_paid as i128).max(0) as u128;
+
+    let (numerator, margin_factor) = if position.is_long {
+        let numerator = entry_value.saturating_sub(equity);
+        let margin_factor = BASIS_POINTS.saturating_sub(maintainance_margin as u128);
+        (numerator, margin_factor)
+    } else {
+        let numerator = entry_value
+            .checked_add(equity)
+            .ok_or(ErrorCode::MathOverflow)?;
+        let margin_factor = BASIS_POINTS
+            .checked_add(maintainance_margin as u128)
+            .ok_or(ErrorCode::MathOverflow)?;
+        (numerator, margin_factor)
+    };
+
+    if numerator == 0 || margin_factor == 0 {
+        return Ok(0);
+    }
+
+    let liquidation_price = numerator
+        .checked_mul(token_divisor)
+        .ok_or(ErrorCode::MathOverflow)?
+        .checked_mul(BASIS_POINTS)
+        .ok_or(ErrorCode::MathOverflow)?
+        .checked_div(
+            (position.actual_size as u128)
+                .checked_mul(margin_factor)
+                .ok_or(ErrorCode::MathOverflow)?,
+        )
+        .ok_or(ErrorCode::MathOverflow)?;
+
+    u64::try_from(liquidation_price).map_err(|_| ErrorCode::MathOverflow.into())
+}
+
 // Validate if position value is within acceptable range
 //
 // # Arguments
@@ -755,6 +2214,24 @@ pub fn validate_price(price: u64) -> Result<()> {
     Ok(())
 }
 
+/// Validate a maintenance margin, at least `MIN_MAINTAINANCE_MARGIN` and at most 100%
+///
+/// A zero margin would make every position unliquidatable, see `calculate_health_ratio`.
+///
+/// # Arguments
+/// * `maintainance_margin` - Maintenance margin in basis points
+pub fn validate_maintainance_margin(maintainance_margin: u16) -> Result<()> {
+    require!(
+        maintainance_margin >= MIN_MAINTAINANCE_MARGIN,
+        ErrorCode::MaintainanceMarginTooLow
+    );
+    require!(
+        maintainance_margin as u128 <= BASIS_POINTS,
+        ErrorCode::InvalidInput
+    );
+    Ok(())
+}
+
 /// Validate position size is non-zero
 ///
 /// # Arguments
@@ -767,3 +2244,1075 @@ pub fn validate_position_size(size: u64) -> Result<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+
+    #[test]
+    fn close_payout_matches_its_parts() {
+        // 10 tokens long at $100 with $200 collateral, closed at $110
+        let position = position(true, usd(100), 10, usd(200));
+        let close = calculate_close(&position, usd(110), 10, DECIMALS).unwrap();
+        assert_eq!(close.net_pnl, SignedAmount::from_u64(usd(100)));
+        // 0.1% of the $1100 notional
+        assert_eq!(close.closing_fee, 1_100_000);
+        assert_eq!(close.amount_to_return, usd(300) - 1_100_000);
+        assert_eq!(
+            close.payout(0).verify(position.collateral).unwrap(),
+            close.amount_to_return
+        );
+        assert_eq!(
+            close.payout(5).verify(position.collateral).unwrap(),
+            close.amount_to_return + 5
+        );
+    }
+
+    #[test]
+    fn closing_fee_is_charged_on_the_current_notional() {
+        // the stored $1,000 entry notional doesn't set the fee
+        let long = position(true, usd(100), 10, usd(500));
+        assert_eq!(long.position_value, usd(1_000));
+        let up = calculate_close(&long, usd(150), 10, DECIMALS).unwrap();
+        assert_eq!(up.closing_fee, 1_500_000);
+        let down = calculate_close(&long, usd(60), 10, DECIMALS).unwrap();
+        assert_eq!(down.closing_fee, 600_000);
+
+        // the same for a short, whichever way the price moved
+        let short = position(false, usd(100), 10, usd(500));
+        let down = calculate_close(&short, usd(80), 10, DECIMALS).unwrap();
+        assert_eq!(down.closing_fee, 800_000);
+        assert_eq!(down.amount_to_return, usd(700) - 800_000);
+    }
+
+    #[test]
+    fn fully_eroded_collateral_closes_to_nothing() {
+        let mut long = position(true, usd(100), 10, usd(200));
+        long.collateral = 0;
+
+        // a further loss is all bad debt, no fee is charged on nothing
+        let loss = calculate_close(&long, usd(90), 10, DECIMALS).unwrap();
+        assert_eq!(loss.net_pnl, SignedAmount::from_parts(usd(100), false));
+        assert_eq!((loss.closing_fee, loss.amount_to_return), (0, 0));
+        assert_eq!(loss.bad_debt, usd(100));
+        assert_eq!(loss.payout(0).verify(0).unwrap(), 0);
+
+        // a recovery isn't paid out either
+        let recovered = calculate_close(&long, usd(110), 10, DECIMALS).unwrap();
+        assert_eq!((recovered.closing_fee, recovered.amount_to_return), (0, 0));
+        assert_eq!((recovered.bad_debt, recovered.forfeited_pnl), (0, usd(100)));
+        assert_eq!(recovered.payout(0).verify(0).unwrap(), 0);
+
+        let flat = calculate_close(&long, usd(100), 10, DECIMALS).unwrap();
+        assert_eq!(
+            (flat.amount_to_return, flat.bad_debt, flat.forfeited_pnl),
+            (0, 0, 0)
+        );
+    }
+
+    #[test]
+    fn close_payout_with_bad_debt_matches_its_parts() {
+        let position = position(true, usd(100), 10, usd(200));
+        let close = calculate_close(&position, usd(70), 10, DECIMALS).unwrap();
+        assert_eq!(close.amount_to_return, 0);
+        assert_eq!(close.closing_fee, 0);
+        assert_eq!(close.bad_debt, usd(100));
+        assert_eq!(close.payout(0).verify(position.collateral).unwrap(), 0);
+    }
+
+    #[test]
+    fn deliberately_broken_payout_is_rejected() {
+        let position = position(false, usd(100), 10, usd(200));
+        let close = calculate_close(&position, usd(95), 10, DECIMALS).unwrap();
+
+        // One unit too much paid out
+        let mut payout = close.payout(0);
+        payout.amount_to_return += 1;
+        assert_eq!(
+            payout.verify(position.collateral).unwrap_err(),
+            ErrorCode::BalanceInvariantViolated.into()
+        );
+
+        // A fee that is charged but not deducted
+        let mut payout = close.payout(0);
+        payout.withheld = 0;
+        assert_eq!(
+            payout.verify(position.collateral).unwrap_err(),
+            ErrorCode::BalanceInvariantViolated.into()
+        );
+
+        // Bad debt reported on a solvent close
+        let mut payout = close.payout(0);
+        payout.bad_debt = 1;
+        assert!(payout.verify(position.collateral).is_err());
+    }
+
+    #[test]
+    fn liquidation_and_seizure_payouts_match_their_parts() {
+        let mut config = config();
+        config.liquidation_rebate = 5_000;
+        let position = position(true, usd(100), 10, usd(100));
+        // At $94 the $40 equity is below 5% of the $940 notional
+        let liquidation =
+            calculate_liquidation(&position, 94_000_000, &config, 500, 0, DECIMALS).unwrap();
+        assert!(liquidation.is_liquidatable);
+        assert!(liquidation.trader_rebate > 0 && liquidation.retained_equity > 0);
+        assert_eq!(
+            liquidation.payout(0).verify(position.collateral).unwrap(),
+            liquidation.trader_rebate
+        );
+
+        assert_eq!(
+            ClosePayout::seizure(position.collateral)
+                .verify(position.collateral)
+                .unwrap(),
+            0
+        );
+    }
+
+    #[test]
+    fn liquidation_rebate_only_returns_genuine_equity() {
+        let mut config = config();
+        config.liquidation_rebate = 5_000;
+        let position = position(true, usd(100), 10, usd(100));
+
+        // solvent at $94: $40 equity, $5 to the liquidator, half the rest rebated
+        let solvent = calculate_liquidation(&position, usd(94), &config, 500, 0, DECIMALS).unwrap();
+        assert!(solvent.is_liquidatable);
+        assert_eq!(solvent.liquidation_reward, usd(5));
+        assert_eq!(solvent.trader_rebate, 17_500_000);
+        assert_eq!(solvent.retained_equity, 17_500_000);
+        assert_eq!(solvent.bad_debt, 0);
+
+        // the equity at $90.50 only just pays the liquidator
+        let drained =
+            calculate_liquidation(&position, 90_500_000, &config, 500, 0, DECIMALS).unwrap();
+        assert_eq!(drained.liquidation_reward, usd(5));
+        assert_eq!((drained.trader_rebate, drained.retained_equity), (0, 0));
+
+        // insolvent at $85: $50 of bad debt and nothing to rebate
+        let insolvent =
+            calculate_liquidation(&position, usd(85), &config, 500, 0, DECIMALS).unwrap();
+        assert_eq!(insolvent.bad_debt, usd(50));
+        assert_eq!(insolvent.liquidation_reward, 0);
+        assert_eq!((insolvent.trader_rebate, insolvent.retained_equity), (0, 0));
+        assert_eq!(insolvent.payout(0).verify(position.collateral).unwrap(), 0);
+
+        // disabled, the pool keeps all of it
+        config.liquidation_rebate = 0;
+        let kept = calculate_liquidation(&position, usd(94), &config, 500, 0, DECIMALS).unwrap();
+        assert_eq!((kept.trader_rebate, kept.retained_equity), (0, usd(35)));
+    }
+
+    #[test]
+    fn balanced_market_pays_no_funding() {
+        assert_eq!(calculate_funding_rate(usd(500), usd(500), 10).unwrap(), 0);
+        assert_eq!(calculate_funding_rate(0, 0, 10).unwrap(), 0);
+    }
+
+    #[test]
+    fn one_sided_market_pays_the_max_rate() {
+        let max_scaled = 10 * PRECISION as i64;
+        assert_eq!(calculate_funding_rate(usd(500), 0, 10).unwrap(), max_scaled);
+        assert_eq!(
+            calculate_funding_rate(0, usd(500), 10).unwrap(),
+            -max_scaled
+        );
+    }
+
+    #[test]
+    fn small_skew_still_pays_funding() {
+        // 5% skew at a 10 bps max is 0.5 bps, which whole basis points would round to 0
+        let rate = calculate_funding_rate(usd(525), usd(475), 10).unwrap();
+        assert_eq!(rate, PRECISION as i64 / 2);
+
+        // $100k notional over one full period pays 0.005%
+        let funding = calculate_funding_payment(
+            1_000 * ONE_TOKEN,
+            usd(100),
+            rate,
+            SLOTS_PER_8_HOURS,
+            DECIMALS,
+        )
+        .unwrap();
+        assert_eq!(funding.payment, SignedAmount::from_u64(usd(5)));
+
+        let received = calculate_funding_payment(
+            1_000 * ONE_TOKEN,
+            usd(100),
+            -rate,
+            SLOTS_PER_8_HOURS,
+            DECIMALS,
+        )
+        .unwrap();
+        assert_eq!(received.payment, SignedAmount::from_parts(usd(5), false));
+    }
+
+    #[test]
+    fn pnl_follows_the_side() {
+        // 10 tokens at $100, moved $10 either way
+        for (is_long, price, expected) in [
+            (true, usd(110), 100),
+            (true, usd(90), -100),
+            (false, usd(90), 100),
+            (false, usd(110), -100),
+        ] {
+            let pnl =
+                calculate_pnl(&position(is_long, usd(100), 10, usd(200)), price, DECIMALS).unwrap();
+            let expected = SignedAmount(expected * 1_000_000);
+            assert_eq!(pnl.gross_pnl, expected);
+            assert_eq!(pnl.net_pnl, expected);
+        }
+    }
+
+    #[test]
+    fn pnl_nets_out_settled_funding() {
+        let mut long = position(true, usd(100), 10, usd(200));
+        long.cumulative_funding_paid = usd(30) as i64;
+        let pnl = calculate_pnl(&long, usd(90), DECIMALS).unwrap();
+        assert_eq!(pnl.gross_pnl, SignedAmount::from_parts(usd(100), false));
+        assert_eq!(pnl.net_pnl, SignedAmount::from_parts(usd(130), false));
+
+        // nothing open, nothing to report
+        long.actual_size = 0;
+        let pnl = calculate_pnl(&long, usd(90), DECIMALS).unwrap();
+        assert_eq!(pnl.net_pnl, SignedAmount::ZERO);
+    }
+
+    #[test]
+    fn short_receiving_funding_adds_to_pnl() {
+        // longs dominate 3:1, so shorts receive half the 10 bps max each period
+        let mut market = market(0);
+        market.long_open_interest = usd(3_000);
+        market.short_open_interest = usd(1_000);
+        let rate = market.funding_rate_for(false).unwrap();
+        assert_eq!(rate, -5 * PRECISION as i64);
+
+        let mut short = position(false, usd(100), 10, usd(200));
+        for period in 1..=3 {
+            let slot = short.last_funding_slot + SLOTS_PER_8_HOURS;
+            let funding = short
+                .update_funding(slot, usd(100), rate, DECIMALS)
+                .unwrap();
+            // 0.05% of the $1000 notional
+            assert_eq!(funding.payment, SignedAmount::from_parts(500_000, false));
+            assert_eq!(short.cumulative_funding_paid, -500_000 * period);
+        }
+
+        let pnl = calculate_pnl(&short, usd(100), DECIMALS).unwrap();
+        assert_eq!(pnl.gross_pnl, SignedAmount::ZERO);
+        assert_eq!(pnl.net_pnl, SignedAmount::from_u64(1_500_000));
+    }
+
+    #[test]
+    fn funding_larger_than_profit_is_a_loss() {
+        // $100 price profit, $130 funding paid
+        let mut long = position(true, usd(100), 10, usd(200));
+        long.cumulative_funding_paid = usd(130) as i64;
+        let pnl = calculate_pnl(&long, usd(110), DECIMALS).unwrap();
+        assert_eq!(pnl.gross_pnl, SignedAmount::from_u64(usd(100)));
+        assert_eq!(pnl.net_pnl, SignedAmount::from_parts(usd(30), false));
+    }
+
+    #[test]
+    fn close_breakdown_sums_to_the_payout() {
+        let mut long = position(true, usd(100), 10, usd(200));
+        long.cumulative_funding_paid = usd(130) as i64;
+        let close = calculate_close(&long, usd(110), 10, DECIMALS).unwrap();
+
+        // gross_price_pnl - total_funding_paid = net_pnl, as `PositionClosed` reports it
+        let net = close.gross_price_pnl - long.cumulative_funding_paid;
+        assert_eq!(SignedAmount::from_i64(net), close.net_pnl);
+        let expected = SignedAmount::from_u64(long.collateral)
+            .checked_add(close.net_pnl)
+            .unwrap()
+            .checked_sub(SignedAmount::from_u64(close.closing_fee))
+            .unwrap();
+        assert_eq!(SignedAmount::from_u64(close.amount_to_return), expected);
+    }
+
+    #[test]
+    fn stale_close_gives_up_profit_but_keeps_losses() {
+        for is_long in [true, false] {
+            let position = position(is_long, usd(100), 10, usd(200));
+            let (better, worse) = if is_long {
+                (usd(120), usd(90))
+            } else {
+                (usd(80), usd(110))
+            };
+            assert_eq!(stale_close_price(&position, better), usd(100));
+            assert_eq!(stale_close_price(&position, worse), worse);
+
+            // at entry only the closing fee comes off the collateral
+            let close = calculate_close(&position, usd(100), 10, DECIMALS).unwrap();
+            assert_eq!(close.net_pnl, SignedAmount::ZERO);
+            assert_eq!(close.amount_to_return, usd(200) - close.closing_fee);
+        }
+    }
+
+    #[test]
+    fn opening_fee_that_eats_the_collateral_is_rejected() {
+        let min = usd(1);
+        assert_eq!(
+            split_opening_collateral(usd(100), 10, min).unwrap(),
+            (100_000, usd(100) - 100_000)
+        );
+        // the fee leaves a cent, below the minimum effective collateral
+        assert_eq!(
+            split_opening_collateral(usd(100), 9_999, min).unwrap_err(),
+            ErrorCode::EffectiveCollateralTooLow.into()
+        );
+        assert_eq!(
+            split_opening_collateral(usd(100), 10_000, min).unwrap_err(),
+            ErrorCode::InsufficientCollateralForFees.into()
+        );
+        // too small to charge anything
+        assert_eq!(
+            split_opening_collateral(999, 10, 0).unwrap_err(),
+            ErrorCode::FeeTooLow.into()
+        );
+    }
+
+    #[test]
+    fn maintenance_margin_has_a_floor() {
+        for margin in [0, MIN_MAINTAINANCE_MARGIN - 1] {
+            assert_eq!(
+                validate_maintainance_margin(margin).unwrap_err(),
+                ErrorCode::MaintainanceMarginTooLow.into()
+            );
+        }
+        assert!(validate_maintainance_margin(MIN_MAINTAINANCE_MARGIN).is_ok());
+        assert!(validate_maintainance_margin(BASIS_POINTS as u16).is_ok());
+        assert_eq!(
+            validate_maintainance_margin(BASIS_POINTS as u16 + 1).unwrap_err(),
+            ErrorCode::InvalidInput.into()
+        );
+    }
+
+    #[test]
+    fn health_ratio_is_equity_over_maintenance() {
+        // 10 tokens long at $100 on $200, at $90: $100 equity against 5% of $900
+        let long = position(true, usd(100), 10, usd(200));
+        assert_eq!(
+            calculate_health_ratio(&long, usd(90), 500, 0, DECIMALS).unwrap(),
+            22_222
+        );
+        // wiped out
+        assert_eq!(
+            calculate_health_ratio(&long, usd(80), 500, 0, DECIMALS).unwrap(),
+            0
+        );
+        // nothing open
+        let mut closed = long.clone();
+        closed.actual_size = 0;
+        assert_eq!(
+            calculate_health_ratio(&closed, usd(90), 500, 0, DECIMALS).unwrap(),
+            u64::MAX
+        );
+    }
+
+    #[test]
+    fn vip_tier_survives_a_move_that_liquidates_standard() {
+        // at $83: $30 equity against 10% of $830 ($83), or 2.5% ($20.75) at the top tier
+        let long = position(true, usd(100), 10, usd(200));
+        let standard = calculate_health_ratio(&long, usd(83), 1_000, 0, DECIMALS).unwrap();
+        let vip = calculate_health_ratio(&long, usd(83), 1_000, MAX_MARGIN_TIER, DECIMALS).unwrap();
+        assert!(standard < BASIS_POINTS as u64);
+        assert!(vip >= BASIS_POINTS as u64);
+    }
+
+    #[test]
+    fn maintenance_margin_by_tier() {
+        // 25% off the base per tier
+        assert_eq!(maintenance_margin_for_tier(1_000, 0).unwrap(), 1_000);
+        assert_eq!(maintenance_margin_for_tier(1_000, 1).unwrap(), 750);
+        assert_eq!(
+            maintenance_margin_for_tier(1_000, MAX_MARGIN_TIER).unwrap(),
+            250
+        );
+        // tiers past the top are clamped to it
+        assert_eq!(maintenance_margin_for_tier(1_000, u8::MAX).unwrap(), 250);
+        // never below the floor, and never above the base when the base is below it
+        assert_eq!(
+            maintenance_margin_for_tier(100, MAX_MARGIN_TIER).unwrap(),
+            MIN_MAINTAINANCE_MARGIN
+        );
+        assert_eq!(maintenance_margin_for_tier(40, 1).unwrap(), 40);
+    }
+
+    #[test]
+    fn keeper_action_precedence() {
+        let config = config();
+        let slot = MARGIN_WARNING_INTERVAL_SLOTS * 10;
+        // 10 tokens long at $100 on $200, stop-loss at $95 and take-profit at $110
+        let mut long = position(true, usd(100), 10, usd(200));
+        long.stop_loss_price = usd(95);
+        long.take_profit_price = usd(110);
+        let action = |position: &Position, price: u64, warning_health_bps: u64| {
+            let liquidation =
+                calculate_liquidation(position, price, &config, 500, 0, DECIMALS).unwrap();
+            position.keeper_action(price, &liquidation, warning_health_bps, slot)
+        };
+
+        // liquidation beats a stop-loss that is hit too
+        assert_eq!(action(&long, usd(82), u64::MAX), KeeperAction::Liquidate);
+        // the stop-loss beats a due margin warning
+        assert_eq!(action(&long, usd(90), u64::MAX), KeeperAction::StopLoss);
+        // so does the take-profit
+        assert_eq!(action(&long, usd(111), u64::MAX), KeeperAction::TakeProfit);
+        // liquidation beats a take-profit that is hit too
+        let mut inverted = long.clone();
+        inverted.take_profit_price = usd(80);
+        inverted.stop_loss_price = 0;
+        assert_eq!(
+            action(&inverted, usd(82), u64::MAX),
+            KeeperAction::Liquidate
+        );
+
+        // without triggers the warning applies, unless disabled or given recently
+        long.stop_loss_price = 0;
+        long.take_profit_price = 0;
+        assert_eq!(
+            action(&long, usd(90), u64::MAX),
+            KeeperAction::MarginWarning
+        );
+        assert_eq!(action(&long, usd(90), 0), KeeperAction::Rebalance);
+        long.last_warning_slot = slot - 1;
+        assert_eq!(action(&long, usd(90), u64::MAX), KeeperAction::Rebalance);
+        // and with nothing else left, the rebalance check
+        assert_eq!(action(&long, usd(120), u64::MAX), KeeperAction::Rebalance);
+    }
+
+    #[test]
+    fn opening_fee_scales_with_leverage() {
+        // 10 bps base plus 5 bps per 1x above 1x, capped at 40 bps
+        assert_eq!(calculate_opening_fee_bps(10, 5, 40, 10_000).unwrap(), 10);
+        assert_eq!(calculate_opening_fee_bps(10, 5, 40, 20_000).unwrap(), 15);
+        assert_eq!(calculate_opening_fee_bps(10, 5, 40, 25_000).unwrap(), 17);
+        assert_eq!(calculate_opening_fee_bps(10, 5, 40, 50_000).unwrap(), 30);
+        // the cap binds from 7x
+        assert_eq!(calculate_opening_fee_bps(10, 5, 40, 70_000).unwrap(), 40);
+        assert_eq!(calculate_opening_fee_bps(10, 5, 40, 200_000).unwrap(), 40);
+        // uncapped, and a cap below the base never undercuts it
+        assert_eq!(calculate_opening_fee_bps(10, 5, 0, 200_000).unwrap(), 105);
+        assert_eq!(calculate_opening_fee_bps(10, 5, 5, 200_000).unwrap(), 10);
+        // without a surcharge the fee is flat
+        assert_eq!(calculate_opening_fee_bps(10, 0, 40, 200_000).unwrap(), 10);
+    }
+
+    #[test]
+    fn each_open_check_binds_the_max_position_in_turn() {
+        let max = |config: &Config, max_leverage, liquidity, collateral, funding_rate_bps| {
+            calculate_max_position_value(
+                collateral,
+                config,
+                max_leverage,
+                500,
+                0,
+                liquidity,
+                usd(2_000),
+                funding_rate_bps,
+            )
+            .unwrap()
+        };
+        let mut config = config();
+        let plenty = usd(100_000_000);
+
+        // 10x of $1,000, with 5% maintenance allowing up to 20x
+        assert_eq!(
+            max(&config, 100_000, plenty, usd(1_000), 0),
+            (usd(10_000), MaxOpenConstraint::Leverage)
+        );
+        // the pool lends only $2,000 on top of the collateral
+        assert_eq!(
+            max(&config, 100_000, usd(2_000), usd(1_000), 0),
+            (usd(3_000), MaxOpenConstraint::Liquidity)
+        );
+        assert_eq!(
+            max(&config, 100_000, plenty, usd(2_000_000), 0),
+            (MAX_POSITION_VALUE, MaxOpenConstraint::PositionValueCap)
+        );
+        // 30x is beyond what maintenance allows, more so with a 1% funding period
+        assert_eq!(
+            max(&config, 300_000, plenty, usd(1_000), 0),
+            (usd(20_000) - 1, MaxOpenConstraint::Maintenance)
+        );
+        assert_eq!(
+            max(&config, 300_000, plenty, usd(1_000), 100),
+            (16_666_666_665, MaxOpenConstraint::Maintenance)
+        );
+
+        // $5,000 of exposure allowed, $2,000 already open
+        config.max_trader_exposure = usd(5_000);
+        assert_eq!(
+            max(&config, 100_000, plenty, usd(1_000), 0),
+            (usd(3_000), MaxOpenConstraint::TraderExposure)
+        );
+        config.max_borrow_per_position = usd(500);
+        assert_eq!(
+            max(&config, 100_000, plenty, usd(1_000), 0),
+            (usd(1_500), MaxOpenConstraint::BorrowCap)
+        );
+    }
+
+    #[test]
+    fn fee_split_always_sums_to_the_fee() {
+        let fee = 1_000_003;
+        // nothing to the protocol
+        assert_eq!(split_fee(fee, 0).unwrap(), (0, fee));
+        // everything to the protocol
+        assert_eq!(split_fee(fee, BASIS_POINTS as u16).unwrap(), (fee, 0));
+        // a share that doesn't divide evenly leaves the dust with LPs
+        let (protocol_fee, lp_fee) = split_fee(fee, 3_333).unwrap();
+        assert_eq!(protocol_fee, 333_300);
+        assert_eq!(protocol_fee + lp_fee, fee);
+        assert_eq!(
+            split_fee(fee, BASIS_POINTS as u16 + 1).unwrap_err(),
+            ErrorCode::InvalidInput.into()
+        );
+    }
+
+    #[test]
+    fn funding_on_an_18_decimal_token() {
+        // 10 tokens at $100k is $1m notional, paying 10 bps over a full period
+        let size = 10 * 10u64.pow(18);
+        let rate = 10 * PRECISION as i64;
+        let funding =
+            calculate_funding_payment(size, usd(100_000), rate, SLOTS_PER_8_HOURS, 18).unwrap();
+        assert_eq!(funding.payment, SignedAmount::from_u64(usd(1_000)));
+
+        // half a period pays half
+        let funding =
+            calculate_funding_payment(size, usd(100_000), -rate, SLOTS_PER_8_HOURS / 2, 18)
+                .unwrap();
+        assert_eq!(funding.payment, SignedAmount::from_parts(usd(500), false));
+    }
+
+    #[test]
+    fn rebalance_size_change_at_and_beyond_the_cap() {
+        let rebalance = |new_actual_size| RebalanceResult {
+            should_rebalance: true,
+            new_actual_size,
+            new_target_price: 0,
+            new_leverage_bps: 0,
+            new_position_value: 0,
+            profit_realized: 0,
+            excess_to_insurance: 0,
+        };
+        let previous = 10 * ONE_TOKEN;
+
+        // a 10% change under a 10% cap is left alone
+        let mut at_cap = rebalance(11 * ONE_TOKEN);
+        assert!(!at_cap
+            .cap_size_change(previous, 1_000, usd(100), DECIMALS)
+            .unwrap());
+        assert_eq!(at_cap.new_actual_size, 11 * ONE_TOKEN);
+
+        // growing past it is clamped, and the value follows the capped size
+        let mut grown = rebalance(15 * ONE_TOKEN);
+        assert!(grown
+            .cap_size_change(previous, 1_000, usd(100), DECIMALS)
+            .unwrap());
+        assert_eq!(grown.new_actual_size, 11 * ONE_TOKEN);
+        assert_eq!(grown.new_position_value, usd(1_100));
+
+        // and so is shrinking past it
+        let mut shrunk = rebalance(5 * ONE_TOKEN);
+        assert!(shrunk
+            .cap_size_change(previous, 1_000, usd(100), DECIMALS)
+            .unwrap());
+        assert_eq!(shrunk.new_actual_size, 9 * ONE_TOKEN);
+        assert_eq!(shrunk.new_position_value, usd(900));
+
+        // a cap of 0 is disabled
+        let mut uncapped = rebalance(15 * ONE_TOKEN);
+        assert!(!uncapped
+            .cap_size_change(previous, 0, usd(100), DECIMALS)
+            .unwrap());
+        assert_eq!(uncapped.new_actual_size, 15 * ONE_TOKEN);
+    }
+
+    #[test]
+    fn leverage_boundary_at_one_x() {
+        // exactly 1x is allowed, with nothing to borrow
+        assert_eq!(
+            calculate_leverage_bps(usd(100), usd(100)).unwrap(),
+            BASIS_POINTS as u64
+        );
+        // a value that rounded one unit below the collateral is sub-1x
+        assert_eq!(
+            calculate_leverage_bps(usd(100) - 1, usd(100)).unwrap_err(),
+            ErrorCode::LeverageBelowOne.into()
+        );
+        assert_eq!(
+            calculate_leverage_bps(usd(100), 0).unwrap_err(),
+            ErrorCode::CollateralTooLow.into()
+        );
+    }
+
+    #[test]
+    fn inverted_target_is_rejected_on_each_side() {
+        // long regretting a $90 entry at $100, with the target at or below current
+        for target in [usd(100), usd(95)] {
+            assert_eq!(
+                size_long_position(usd(90), ONE_TOKEN, usd(100), target, DECIMALS).unwrap_err(),
+                ErrorCode::InvalidTargetPrice.into()
+            );
+        }
+        size_long_position(usd(90), ONE_TOKEN, usd(100), usd(110), DECIMALS).unwrap();
+
+        // short regretting a $110 entry at $100, with the target at or above current
+        for target in [usd(100), usd(105)] {
+            assert_eq!(
+                size_short_position(usd(110), ONE_TOKEN, usd(100), target, DECIMALS).unwrap_err(),
+                ErrorCode::InvalidTargetPrice.into()
+            );
+        }
+        size_short_position(usd(110), ONE_TOKEN, usd(100), usd(90), DECIMALS).unwrap();
+    }
+
+    #[test]
+    fn target_short_of_the_band_is_rejected() {
+        // 10% band at $100
+        validate_target_band(usd(100), usd(110), 1_000, true).unwrap();
+        validate_target_band(usd(100), usd(90), 1_000, false).unwrap();
+        // a cent short of it, already at the current price, or on the wrong side
+        for (target, is_long) in [
+            (usd(110) - 10_000, true),
+            (usd(100), true),
+            (usd(95), true),
+            (usd(90) + 10_000, false),
+            (usd(100), false),
+            (usd(105), false),
+        ] {
+            assert_eq!(
+                validate_target_band(usd(100), target, 1_000, is_long).unwrap_err(),
+                ErrorCode::InvalidTargetPrice.into()
+            );
+        }
+        // a zero band still needs the target strictly beyond the current price
+        assert_eq!(
+            validate_target_band(usd(100), usd(100), 0, true).unwrap_err(),
+            ErrorCode::InvalidTargetPrice.into()
+        );
+    }
+
+    #[test]
+    fn margin_warning_across_health_bands() {
+        let config = config();
+        let slot = MARGIN_WARNING_INTERVAL_SLOTS * 10;
+        // warn under 1.5x the maintenance margin
+        let warning_health_bps = 15_000;
+        let long = position(true, usd(100), 10, usd(200));
+        let action = |price: u64| {
+            let liquidation =
+                calculate_liquidation(&long, price, &config, 500, 0, DECIMALS).unwrap();
+            long.keeper_action(price, &liquidation, warning_health_bps, slot)
+        };
+
+        // healthy at $90 (2.2x)
+        assert_eq!(action(usd(90)), KeeperAction::Rebalance);
+        // inside the band at $86 (1.4x) and $85 (1.2x)
+        assert_eq!(action(usd(86)), KeeperAction::MarginWarning);
+        assert_eq!(action(usd(85)), KeeperAction::MarginWarning);
+        // under maintenance at $84 (0.95x)
+        assert_eq!(action(usd(84)), KeeperAction::Liquidate);
+    }
+
+    #[test]
+    fn collateral_for_leverage_opens_at_that_leverage() {
+        // (is_long, desired_entry, target, leverage) around a $100 market
+        for (is_long, entry, target, leverage_bps) in [
+            (true, usd(90), usd(110), 50_000),
+            (true, usd(95), usd(112), 30_000),
+            (false, usd(110), usd(90), 30_000),
+            (false, usd(107), usd(85), 75_000),
+        ] {
+            let collateral =
+                collateral_for_leverage(ONE_TOKEN, entry, usd(100), target, leverage_bps, DECIMALS)
+                    .unwrap();
+            let params = if is_long {
+                calculate_long_position(entry, ONE_TOKEN, usd(100), target, collateral, DECIMALS)
+            } else {
+                calculate_short_position(entry, ONE_TOKEN, usd(100), target, collateral, DECIMALS)
+            }
+            .unwrap();
+            // rounding the collateral down can only add a hair of leverage
+            assert!(params.leverage_bps >= leverage_bps);
+            assert!(params.leverage_bps - leverage_bps <= 1);
+        }
+
+        assert_eq!(
+            collateral_for_leverage(ONE_TOKEN, usd(90), usd(100), usd(110), 9_999, DECIMALS)
+                .unwrap_err(),
+            ErrorCode::LeverageBelowOne.into()
+        );
+    }
+
+    #[test]
+    fn slippage_only_guards_the_adverse_side() {
+        // 1% tolerance on a $100 quote
+        check_slippage(usd(101), usd(100), 100, true).unwrap();
+        check_slippage(usd(99), usd(100), 100, false).unwrap();
+        // a move in the trader's favour always passes
+        check_slippage(usd(50), usd(100), 100, true).unwrap();
+        check_slippage(usd(150), usd(100), 100, false).unwrap();
+        for (price, is_long) in [(usd(101) + 1, true), (usd(99) - 1, false)] {
+            assert_eq!(
+                check_slippage(price, usd(100), 100, is_long).unwrap_err(),
+                ErrorCode::SlippageExceeded.into()
+            );
+        }
+        // 0 disables the check
+        check_slippage(usd(200), usd(100), 0, true).unwrap();
+    }
+
+    #[test]
+    fn triggers_must_bracket_the_entry() {
+        let long = position(true, usd(100), 10, usd(200));
+        let short = position(false, usd(100), 10, usd(200));
+        long.validate_triggers(usd(95), usd(110)).unwrap();
+        short.validate_triggers(usd(105), usd(90)).unwrap();
+        // either trigger can be left unset
+        long.validate_triggers(0, usd(110)).unwrap();
+        short.validate_triggers(usd(105), 0).unwrap();
+
+        for (position, stop_loss, take_profit) in [
+            (&long, usd(105), 0),
+            (&long, 0, usd(95)),
+            (&long, usd(100), 0),
+            (&short, usd(95), 0),
+            (&short, 0, usd(105)),
+            (&short, 0, usd(100)),
+        ] {
+            assert_eq!(
+                position
+                    .validate_triggers(stop_loss, take_profit)
+                    .unwrap_err(),
+                ErrorCode::InvalidTriggerPrice.into()
+            );
+        }
+        assert_eq!(
+            long.validate_triggers(usd(95), MAX_SAFE_PRICE + 1)
+                .unwrap_err(),
+            ErrorCode::PriceTooHigh.into()
+        );
+    }
+
+    #[test]
+    fn reduction_realizes_its_share() {
+        // half of 10 tokens long at $100 on $200, closed at $110
+        let long = position(true, usd(100), 10, usd(200));
+        let reduction = calculate_reduction(&long, usd(110), 5 * ONE_TOKEN, 10, DECIMALS).unwrap();
+        assert_eq!(reduction.closed_size, 5 * ONE_TOKEN);
+        assert_eq!(reduction.closed_collateral, usd(100));
+        assert_eq!(reduction.closed_value, usd(500));
+        assert_eq!(reduction.closed_borrowed, usd(400));
+        assert_eq!(reduction.realized_pnl, SignedAmount::from_u64(usd(50)));
+        // 0.1% of the $550 closed at the current price
+        assert_eq!(reduction.closing_fee, 550_000);
+        assert_eq!(reduction.amount_to_return, usd(150) - 550_000);
+        assert_eq!(reduction.bad_debt, 0);
+
+        assert_eq!(
+            calculate_reduction(&long, usd(110), 10 * ONE_TOKEN, 10, DECIMALS)
+                .map(|_| ())
+                .unwrap_err(),
+            ErrorCode::InvalidPositionSize.into()
+        );
+    }
+
+    #[test]
+    fn merge_blends_prices_by_size() {
+        for is_long in [true, false] {
+            // 10 tokens at $100 targeting ±10% and 30 tokens at $120 targeting ±10%
+            let mut merged = position(is_long, usd(100), 10, usd(200));
+            merged.current_target_price = if is_long { usd(110) } else { usd(90) };
+            let mut other = position(is_long, usd(120), 30, usd(600));
+            other.current_target_price = if is_long { usd(132) } else { usd(108) };
+            let parts = [merged.clone(), other.clone()];
+
+            merged.merge(&other).unwrap();
+            // (10 × 100 + 30 × 120) / 40
+            assert_eq!(merged.actual_entered_price, usd(115));
+            assert_eq!(merged.desired_entry_price, usd(115));
+            let target = if is_long { 126_500_000 } else { 103_500_000 };
+            assert_eq!(merged.current_target_price, target);
+            assert_eq!(merged.actual_size, 40 * ONE_TOKEN);
+            assert_eq!(merged.collateral, usd(800));
+            assert_eq!(merged.position_value, usd(4_600));
+            assert_eq!(merged.booked_borrow, usd(3_800));
+            assert_eq!(merged.leverage, 57_500);
+
+            // the merged PnL at any price is the sum of the parts
+            for price in [usd(90), usd(115), usd(140)] {
+                let sum = parts.iter().fold(SignedAmount::ZERO, |sum, part| {
+                    let pnl = calculate_pnl(part, price, DECIMALS).unwrap().net_pnl;
+                    sum.checked_add(pnl).unwrap()
+                });
+                assert_eq!(
+                    calculate_pnl(&merged, price, DECIMALS).unwrap().net_pnl,
+                    sum
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn merge_sums_the_settled_funding() {
+        // longs only, each part settles its own 8 hours of funding first
+        let rate = calculate_funding_rate(1, 0, 10).unwrap();
+        let mut merged = position(true, usd(100), 10, usd(200));
+        let mut other = position(true, usd(120), 30, usd(600));
+        other.last_funding_slot = 36_001;
+        let first = merged
+            .update_funding(72_001, usd(110), rate, DECIMALS)
+            .unwrap();
+        let second = other
+            .update_funding(72_001, usd(110), rate, DECIMALS)
+            .unwrap();
+        assert!(first.payment.is_positive() && second.payment.is_positive());
+
+        merged.merge(&other).unwrap();
+        assert_eq!(
+            merged.cumulative_funding_paid,
+            first
+                .payment
+                .checked_add(second.payment)
+                .unwrap()
+                .to_i64()
+                .unwrap()
+        );
+        assert_eq!(merged.last_funding_slot, 72_001);
+    }
+
+    #[test]
+    fn settlements_inside_the_interval_do_not_accrue() {
+        let rate = calculate_funding_rate(1, 0, 10).unwrap();
+        let mut long = position(true, usd(100), 10, usd(200));
+        let untouched = long.clone();
+
+        // one slot short of the interval, a no-op rather than an error
+        let skipped = long
+            .update_funding_throttled(1_000, usd(100), rate, DECIMALS, 1_000)
+            .unwrap();
+        assert!(skipped.is_none());
+        assert_eq!(long.cumulative_funding_paid, 0);
+        assert_eq!(long.last_funding_slot, untouched.last_funding_slot);
+
+        // the skipped slots are picked up by the next settlement past it
+        let applied = long
+            .update_funding_throttled(1_001, usd(100), rate, DECIMALS, 1_000)
+            .unwrap()
+            .unwrap();
+        assert_eq!(applied.slots_elapsed, 1_000);
+        assert!(applied.payment.is_positive());
+        let unthrottled = untouched
+            .clone()
+            .update_funding(1_001, usd(100), rate, DECIMALS)
+            .unwrap();
+        assert_eq!(applied.payment, unthrottled.payment);
+        assert_eq!(
+            long.cumulative_funding_paid,
+            unthrottled.payment.to_i64().unwrap()
+        );
+
+        // and the interval restarts from there
+        let again = long
+            .update_funding_throttled(1_500, usd(100), rate, DECIMALS, 1_000)
+            .unwrap();
+        assert!(again.is_none());
+        assert_eq!(long.last_funding_slot, 1_001);
+    }
+
+    #[test]
+    fn split_carves_its_share() {
+        // 30% of 10 tokens long at $100 on $200, with $9 of funding settled
+        let mut kept = position(true, usd(100), 10, usd(200));
+        kept.current_target_price = usd(110);
+        kept.cumulative_funding_paid = usd(9) as i64;
+        let original = kept.clone();
+
+        let carved = kept.split_off(3_000).unwrap();
+        assert_eq!(carved.actual_size, 3 * ONE_TOKEN);
+        assert_eq!(carved.collateral, usd(60));
+        assert_eq!(carved.position_value, usd(300));
+        assert_eq!(carved.booked_borrow, usd(240));
+        assert_eq!(carved.cumulative_funding_paid, 2_700_000);
+        assert_eq!(kept.actual_size, 7 * ONE_TOKEN);
+        assert_eq!(kept.collateral, usd(140));
+        assert_eq!(kept.position_value, usd(700));
+        assert_eq!(kept.booked_borrow, usd(560));
+        assert_eq!(kept.cumulative_funding_paid, 6_300_000);
+
+        // prices and leverage are unchanged on both parts
+        for part in [&kept, &carved] {
+            assert_eq!(part.actual_entered_price, usd(100));
+            assert_eq!(part.current_target_price, usd(110));
+            assert_eq!(part.leverage, original.leverage);
+        }
+    }
+
+    #[test]
+    fn split_parts_sum_to_the_original() {
+        // odd amounts and a received funding leave a rounding remainder on `self`
+        let mut kept = position(false, 123_456_789, 7, 333_333_333);
+        kept.cumulative_funding_paid = -1_000_001;
+        kept.opening_fee_paid = 999_999;
+        let original = kept.clone();
+
+        let carved = kept.split_off(3_333).unwrap();
+        assert_eq!(kept.actual_size + carved.actual_size, original.actual_size);
+        assert_eq!(
+            kept.desired_size + carved.desired_size,
+            original.desired_size
+        );
+        assert_eq!(kept.collateral + carved.collateral, original.collateral);
+        assert_eq!(
+            kept.position_value + carved.position_value,
+            original.position_value
+        );
+        assert_eq!(
+            kept.booked_borrow + carved.booked_borrow,
+            original.booked_borrow
+        );
+        assert_eq!(
+            kept.cumulative_funding_paid + carved.cumulative_funding_paid,
+            original.cumulative_funding_paid
+        );
+        assert!(carved.cumulative_funding_paid < 0);
+        assert_eq!(
+            kept.opening_fee_paid + carved.opening_fee_paid,
+            original.opening_fee_paid
+        );
+
+        for fraction_bps in [0, BASIS_POINTS as u64] {
+            assert_eq!(
+                kept.split_off(fraction_bps).map(|_| ()).unwrap_err(),
+                ErrorCode::InvalidInput.into()
+            );
+        }
+    }
+
+    #[test]
+    fn merge_across_sides_is_rejected() {
+        let mut long = position(true, usd(100), 10, usd(200));
+        let short = position(false, usd(100), 10, usd(200));
+        assert_eq!(
+            long.merge(&short).unwrap_err(),
+            ErrorCode::InvalidInput.into()
+        );
+    }
+
+    #[test]
+    fn reduction_beyond_the_collateral_reports_bad_debt() {
+        // at $75 the closed half lost $125 on $100 of collateral
+        let long = position(true, usd(100), 10, usd(200));
+        let reduction = calculate_reduction(&long, usd(75), 5 * ONE_TOKEN, 10, DECIMALS).unwrap();
+        assert_eq!(
+            reduction.realized_pnl,
+            SignedAmount::from_parts(usd(125), false)
+        );
+        assert_eq!(reduction.amount_to_return, 0);
+        assert_eq!(reduction.closing_fee, 0);
+        assert_eq!(reduction.bad_debt, usd(25));
+    }
+
+    #[test]
+    fn targets_are_the_bps_band_rounded_down() {
+        // $123.456789 with a 10% band moves by floor(12.3456789) = $12.345678
+        let price = 123_456_789;
+        assert_eq!(
+            calculate_target_price(price, 1000, true).unwrap(),
+            135_802_467
+        );
+        assert_eq!(
+            calculate_target_price(price, 1000, false).unwrap(),
+            111_111_111
+        );
+        assert_eq!(
+            calculate_target_price(price, 250, true).unwrap(),
+            126_543_208
+        );
+        // the rolled target at rebalance goes through the same formula
+        let position = position(true, usd(100), 10, usd(200));
+        let rebalance =
+            calculate_rebalance_with_new_target(position, price, 1000, DECIMALS, 0).unwrap();
+        assert!(rebalance.should_rebalance);
+        assert_eq!(rebalance.new_target_price, 135_802_467);
+    }
+
+    #[test]
+    fn pair_length_prefix_separates_seed_streams() {
+        // an owner starting with `U` after "BTC" reads the same as "BTCU" and a shifted owner
+        let mut a = [7u8; 32];
+        a[0] = b'U';
+        let mut b = [0u8; 32];
+        b[..31].copy_from_slice(&a[1..]);
+        let (owner_a, owner_b) = (Pubkey::new_from_array(a), Pubkey::new_from_array(b));
+        let (id_a, id_b) = (5u64 << 8, 5u64);
+
+        let old_a = [
+            &b"position"[..],
+            b"BTC",
+            owner_a.as_ref(),
+            &id_a.to_le_bytes(),
+        ]
+        .concat();
+        let old_b = [
+            &b"position"[..],
+            b"BTCU",
+            owner_b.as_ref(),
+            &id_b.to_le_bytes(),
+        ]
+        .concat();
+        assert!(old_b.starts_with(&old_a));
+
+        let new_a = [
+            &b"position"[..],
+            &pair_seed_len("BTC"),
+            b"BTC",
+            owner_a.as_ref(),
+            &id_a.to_le_bytes(),
+        ]
+        .concat();
+        let new_b = [
+            &b"position"[..],
+            &pair_seed_len("BTCU"),
+            b"BTCU",
+            owner_b.as_ref(),
+            &id_b.to_le_bytes(),
+        ]
+        .concat();
+        // the streams now part at the length byte, before the pair is read
+        assert_ne!(new_a[8], new_b[8]);
+        assert!(!new_b.starts_with(&new_a));
+    }
+
+    #[test]
+    fn pnl_in_bps_of_collateral_keeps_the_sign() {
+        let position = position(true, usd(100), 10, usd(200));
+        let bps = |price| {
+            let pnl = calculate_pnl(&position, price, DECIMALS).unwrap();
+            calculate_pnl_bps_of_collateral(pnl.net_pnl, position.collateral).unwrap()
+        };
+        // +$100 and -$70 on $200 of margin
+        assert_eq!(bps(usd(110)), 5_000);
+        assert_eq!(bps(usd(93)), -3_500);
+        assert_eq!(bps(usd(100)), 0);
+        // rounds towards zero on both sides
+        assert_eq!(
+            calculate_pnl_bps_of_collateral(SignedAmount(1), 3).unwrap(),
+            3_333
+        );
+        assert_eq!(
+            calculate_pnl_bps_of_collateral(SignedAmount(-1), 3).unwrap(),
+            -3_333
+        );
+        assert_eq!(
+            calculate_pnl_bps_of_collateral(SignedAmount(-1), 0).unwrap(),
+            0
+        );
+    }
+}
@@ -1,3 +1,4 @@
+use crate::{ErrorCode, CLIENT_ORDER_WINDOW_SLOTS, RECENT_CLIENT_ORDERS};
 use anchor_lang::prelude::*;
 
 #[account]
@@ -5,10 +6,88 @@ use anchor_lang::prelude::*;
 pub struct Trader {
     pub owner: Pubkey,
     pub bump: u8,
-    // defaults to false
+    // set by `enable_privacy`, hides the owner in position open/close events
     pub privacy: bool,
     pub position_count: u64,
     pub active_position: u64,
+    // sum of position_value across open positions
+    pub total_notional_exposure: u64,
+    // last non-zero client_order_ids used to open a position, newest first
+    pub recent_client_orders: [ClientOrder; RECENT_CLIENT_ORDERS],
+    // admin-granted maintenance tier, 0 is standard, see `maintenance_margin_for_tier`
+    pub margin_tier: u8,
+    // may manage (take-profit, close) positions on the owner's behalf, never withdraw
+    pub delegate: Option<Pubkey>,
+    // `TraderPoolDetail` accounts open, one per mint, see `close_trader_account`
+    pub balance_accounts: u8,
+    // seized positions kept as a record, their ids stay taken
+    pub retained_positions: u64,
+}
+
+impl Trader {
+    /// Whether `signer` may manage this trader's positions, the owner or their delegate
+    pub fn can_manage(&self, signer: Pubkey) -> bool {
+        signer == self.owner || self.delegate == Some(signer)
+    }
+
+    /// Owner as reported in position events, `Pubkey::default()` once `privacy` is set
+    pub fn event_owner(&self) -> Pubkey {
+        if self.privacy {
+            Pubkey::default()
+        } else {
+            self.owner
+        }
+    }
+
+    /// `total_notional_exposure` once a position worth `position_value` is opened
+    ///
+    /// Fails with `TraderExposureExceeded` past `max_trader_exposure`, 0 means no cap.
+    pub fn exposure_after_open(
+        &self,
+        position_value: u64,
+        max_trader_exposure: u64,
+    ) -> Result<u64> {
+        let exposure = self
+            .total_notional_exposure
+            .checked_add(position_value)
+            .ok_or(ErrorCode::MathOverflow)?;
+        require!(
+            max_trader_exposure == 0 || exposure <= max_trader_exposure,
+            ErrorCode::TraderExposureExceeded
+        );
+        Ok(exposure)
+    }
+
+    /// Whether `client_order_id` was already used for an open within `CLIENT_ORDER_WINDOW_SLOTS`
+    ///
+    /// Only the last `RECENT_CLIENT_ORDERS` ids are remembered, an id older than that
+    /// is accepted again even inside the window.
+    pub fn is_duplicate_order(&self, client_order_id: u64, slot: u64) -> bool {
+        client_order_id != 0
+            && self.recent_client_orders.iter().any(|order| {
+                order.client_order_id == client_order_id
+                    && slot.saturating_sub(order.slot) < CLIENT_ORDER_WINDOW_SLOTS
+            })
+    }
+
+    /// Remember `client_order_id` for `is_duplicate_order`, evicting the oldest
+    pub fn record_client_order(&mut self, client_order_id: u64, slot: u64) {
+        if client_order_id == 0 {
+            return;
+        }
+        self.recent_client_orders.rotate_right(1);
+        self.recent_client_orders[0] = ClientOrder {
+            client_order_id,
+            slot,
+        };
+    }
+}
+
+/// A client order id used to open a position, see `Trader::is_duplicate_order`
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default, InitSpace)]
+pub struct ClientOrder {
+    pub client_order_id: u64,
+    pub slot: u64,
 }
 
 #[account]
@@ -20,10 +99,147 @@ pub struct TraderPoolDetail {
     pub token_mint: String,
     pub balance: u64,
     pub locked_balance: u64,
+    // last `claim_virtual_balance`, see `CLAIM_COOLDOWN_SLOTS`
+    pub last_claim_slot: u64,
+    // real tokens put in through `deposit` and not yet taken out through `withdraw`,
+    // `balance` also carries virtual balance and PnL which never leave the program
+    pub deposited_balance: u64,
 }
 
 impl TraderPoolDetail {
     pub fn available_balance(&self) -> u64 {
         self.balance.saturating_sub(self.locked_balance)
     }
+
+    /// Available balance beyond the real deposits, what `burn_virtual_balance` may burn
+    pub fn virtual_balance(&self) -> u64 {
+        self.available_balance()
+            .saturating_sub(self.deposited_balance)
+    }
+
+    /// What `withdraw` may pay out, the available balance up to the real deposits
+    pub fn withdrawable_balance(&self) -> u64 {
+        self.available_balance().min(self.deposited_balance)
+    }
+
+    /// Reserve `amount` of the balance as position collateral
+    pub fn lock(&mut self, amount: u64) -> Result<()> {
+        self.locked_balance = self
+            .locked_balance
+            .checked_add(amount)
+            .ok_or(ErrorCode::MathOverflow)?;
+        Ok(())
+    }
+
+    /// Release `amount` of locked collateral back to the available balance
+    pub fn unlock(&mut self, amount: u64) -> Result<()> {
+        self.locked_balance = self
+            .locked_balance
+            .checked_sub(amount)
+            .ok_or(ErrorCode::MathOverflow)?;
+        Ok(())
+    }
+
+    /// Unlock `collateral` and replace it in the balance with `amount_to_return`
+    ///
+    /// The collateral never left `balance` when it was locked, so it has to be
+    /// taken out before the payout (collateral ± PnL - fees) is credited.
+    pub fn settle(&mut self, collateral: u64, amount_to_return: u64) -> Result<()> {
+        self.locked_balance = self
+            .locked_balance
+            .checked_sub(collateral)
+            .ok_or(ErrorCode::MathOverflow)?;
+
+        self.balance = self
+            .balance
+            .checked_sub(collateral)
+            .ok_or(ErrorCode::MathOverflow)?
+            .checked_add(amount_to_return)
+            .ok_or(ErrorCode::MathOverflow)?;
+
+        Ok(())
+    }
+}
+
+/// Trader standing for clients, see `get_trader_info`
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Default)]
+pub struct TraderInfo {
+    pub is_registered: bool,
+    pub position_count: u64,
+    pub active_position: u64,
+    pub privacy: bool,
+    pub balance: u64,
+    pub locked_balance: u64,
+    pub available_balance: u64,
+    pub withdrawable_balance: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_utils::*;
+    use crate::ErrorCode;
+
+    #[test]
+    fn exposure_cap_across_opens() {
+        let cap = usd(10_000);
+        let mut trader = trader();
+        for _ in 0..3 {
+            trader.total_notional_exposure = trader.exposure_after_open(usd(3_000), cap).unwrap();
+        }
+        // $9k open, exactly at the cap is allowed, a cent over isn't
+        assert_eq!(trader.exposure_after_open(usd(1_000), cap).unwrap(), cap);
+        assert_eq!(
+            trader.exposure_after_open(usd(1_000) + 1, cap).unwrap_err(),
+            ErrorCode::TraderExposureExceeded.into()
+        );
+        // no cap
+        assert_eq!(
+            trader.exposure_after_open(usd(1_000_000), 0).unwrap(),
+            usd(1_009_000)
+        );
+    }
+
+    #[test]
+    fn settle_replaces_the_collateral_with_the_payout() {
+        let mut trader_balance = trader_balance(usd(1_000));
+        trader_balance.lock(usd(200)).unwrap();
+        // locking leaves the collateral in the balance
+        assert_eq!(trader_balance.balance, usd(1_000));
+        assert_eq!(trader_balance.available_balance(), usd(800));
+
+        // $200 back with a $50 profit is $250, not the $200 counted a second time
+        trader_balance.settle(usd(200), usd(250)).unwrap();
+        assert_eq!(trader_balance.balance, usd(1_050));
+        assert_eq!(trader_balance.locked_balance, 0);
+
+        // a total loss takes the collateral with it
+        trader_balance.lock(usd(200)).unwrap();
+        trader_balance.settle(usd(200), 0).unwrap();
+        assert_eq!(trader_balance.balance, usd(850));
+    }
+
+    #[test]
+    fn replays_are_caught_among_the_recent_orders() {
+        let mut trader = trader();
+        trader.record_client_order(1, 10);
+        trader.record_client_order(2, 11);
+        // A, B, A
+        assert!(trader.is_duplicate_order(1, 12));
+        assert!(!trader.is_duplicate_order(3, 12));
+        // no id isn't an order id
+        trader.record_client_order(0, 12);
+        assert!(!trader.is_duplicate_order(0, 12));
+
+        // out of the window
+        assert!(!trader.is_duplicate_order(1, 10 + crate::CLIENT_ORDER_WINDOW_SLOTS));
+
+        // evicted by newer orders
+        for id in 3..=crate::RECENT_CLIENT_ORDERS as u64 {
+            trader.record_client_order(id, 13);
+        }
+        assert!(trader.is_duplicate_order(1, 13));
+        trader.record_client_order(99, 14);
+        assert!(!trader.is_duplicate_order(1, 14));
+        assert!(trader.is_duplicate_order(2, 14));
+    }
 }
@@ -1,5 +1,8 @@
+use crate::{events::BadDebtAbsorbed, ErrorCode, BASIS_POINTS};
 use anchor_lang::prelude::*;
 
+use super::{calculate_fee, proportional, split_fee, Config, TraderPoolDetail};
+
 #[account]
 #[derive(InitSpace)]
 pub struct Vault {
@@ -8,23 +11,523 @@ pub struct Vault {
     // address of the token
     #[max_len(10)]
     pub token_mint: String,
+    // SPL mint backing real deposits, default until `set_pool_mint`
+    pub mint: Pubkey,
     // total liquidity deposits
     pub lp_deposit: u64,
+    // shares minted by `add_liquidity`, all backed by `lp_token_deposit`
     pub total_lp_shares: u64,
+    // real tokens provided through `add_liquidity` and not yet removed, plus LP fees
+    // compounded out of `forfeited_trader_deposit`, the part of `lp_deposit` LP shares
+    // are redeemed against. The rest of `lp_deposit` is the virtual seed and compounded
+    // virtual fees, lendable but never paid out in tokens, and trader deposits are
+    // tracked apart in `trader_deposit`
+    pub lp_token_deposit: u64,
     pub accumulated_lp_fees: u64,
     pub trader_deposit: u64,
+    // real tokens traders lost to fees and losses, written off their deposits. Backs the
+    // LP fees `compound_lp_fees` adds to `lp_token_deposit`
+    pub forfeited_trader_deposit: u64,
+    // sum of the `collateral` (principal) of open positions, PnL and funding never
+    // move it, only opening, reducing and closing positions do
     pub trader_collateral: u64,
     // total borrowed by traders
     pub total_borrowed: u64,
     // fees earned by the protol
     // only @admin will be able to withdraw
     pub accumulated_fees: u64,
-    //  Liquidation rewards (liquidator withdrawable)
-    pub accumulated_liquidation_rewards: u64,
+    // lifetime liquidation and take-profit rewards, for reporting only. The rewards are
+    // paid into the keepers' balances, nothing booked here is withdrawable
+    pub lifetime_keeper_rewards: u64,
+    // insurance from before `InsuranceFund`, moved into it by `create_insurance_fund`
+    pub accumulated_insurance: u64,
+    // lifetime fee revenue, never decremented on withdrawal
+    pub lifetime_protocol_fees: u64,
+    pub lifetime_lp_fees: u64,
+    // losses beyond the collateral of closed and liquidated positions
+    pub accumulated_bad_debt: u64,
+    // bad debt covered by `InsuranceFund` in the current epoch, see `Config::bad_debt_epoch_slots`
+    pub bad_debt_this_epoch: u64,
+    pub bad_debt_epoch_start_slot: u64,
 }
 
 impl Vault {
     pub fn available_liquidity(&self) -> u64 {
         self.lp_deposit.saturating_sub(self.total_borrowed)
     }
+
+    /// Add a fee split to the protocol and LP accumulators
+    pub fn collect_fees(&mut self, protocol_fee: u64, lp_fee: u64) -> Result<()> {
+        self.accumulated_fees = self
+            .accumulated_fees
+            .checked_add(protocol_fee)
+            .ok_or(ErrorCode::MathOverflow)?;
+
+        self.accumulated_lp_fees = self
+            .accumulated_lp_fees
+            .checked_add(lp_fee)
+            .ok_or(ErrorCode::MathOverflow)?;
+
+        self.lifetime_protocol_fees = self
+            .lifetime_protocol_fees
+            .checked_add(protocol_fee)
+            .ok_or(ErrorCode::MathOverflow)?;
+
+        self.lifetime_lp_fees = self
+            .lifetime_lp_fees
+            .checked_add(lp_fee)
+            .ok_or(ErrorCode::MathOverflow)?;
+
+        Ok(())
+    }
+
+    /// What the LP shares are worth together in tokens, the real deposits and the
+    /// compounded fees real tokens back
+    ///
+    /// The virtual seed and fees charged on virtual balances aren't backed by tokens in
+    /// the vault, so they never price or pay out shares.
+    pub fn lp_value(&self) -> u64 {
+        self.lp_token_deposit
+    }
+
+    /// Add `amount` of real tokens as liquidity and return the shares it is worth
+    ///
+    /// Shares are priced at `lp_value / total_lp_shares`, 1:1 for the first LP. The
+    /// tokens are also lendable, they're added to `lp_deposit` as well.
+    pub fn add_liquidity(&mut self, amount: u64) -> Result<u64> {
+        let shares = if self.total_lp_shares == 0 || self.lp_value() == 0 {
+            amount
+        } else {
+            proportional(amount, self.total_lp_shares, self.lp_value())?
+        };
+        require!(shares > 0, ErrorCode::InvalidInput);
+
+        self.lp_deposit = self
+            .lp_deposit
+            .checked_add(amount)
+            .ok_or(ErrorCode::MathOverflow)?;
+        self.lp_token_deposit = self
+            .lp_token_deposit
+            .checked_add(amount)
+            .ok_or(ErrorCode::MathOverflow)?;
+        self.total_lp_shares = self
+            .total_lp_shares
+            .checked_add(shares)
+            .ok_or(ErrorCode::MathOverflow)?;
+        Ok(shares)
+    }
+
+    /// Redeem `shares` for their cut of `lp_value`, returning the token amount owed
+    ///
+    /// Can only come out of liquidity that isn't lent to traders.
+    pub fn remove_liquidity(&mut self, shares: u64) -> Result<u64> {
+        require!(
+            shares > 0 && shares <= self.total_lp_shares,
+            ErrorCode::InvalidInput
+        );
+        let amount = proportional(self.lp_value(), shares, self.total_lp_shares)?;
+        require!(
+            amount <= self.available_liquidity(),
+            ErrorCode::InsufficientLiquidity
+        );
+
+        self.lp_deposit -= amount;
+        self.lp_token_deposit -= amount;
+        self.total_lp_shares -= shares;
+        Ok(amount)
+    }
+
+    /// Fold `accumulated_lp_fees` into `lp_deposit`, returning the amount moved
+    ///
+    /// The part `forfeited_trader_deposit` backs with real tokens also goes to
+    /// `lp_token_deposit`, raising what every share redeems for. The rest is virtual
+    /// and only becomes lendable.
+    pub fn compound_lp_fees(&mut self) -> Result<u64> {
+        let amount = self.accumulated_lp_fees;
+        let backed = amount.min(self.forfeited_trader_deposit);
+        self.lp_deposit = self
+            .lp_deposit
+            .checked_add(amount)
+            .ok_or(ErrorCode::MathOverflow)?;
+        self.lp_token_deposit = self
+            .lp_token_deposit
+            .checked_add(backed)
+            .ok_or(ErrorCode::MathOverflow)?;
+        self.forfeited_trader_deposit -= backed;
+        self.accumulated_lp_fees = 0;
+        Ok(amount)
+    }
+
+    /// Write a trader's real deposit down to what is left of their balance
+    ///
+    /// Fees and losses come out of `balance`, once it falls below `deposited_balance`
+    /// the difference is real tokens the trader lost. They stay in the vault as
+    /// `forfeited_trader_deposit` instead of turning withdrawable again with later
+    /// virtual profit. Called wherever a trader's balance shrinks.
+    pub fn forfeit_trader_deposit(&mut self, trader_balance: &mut TraderPoolDetail) -> Result<()> {
+        let forfeited = trader_balance
+            .deposited_balance
+            .saturating_sub(trader_balance.balance);
+        trader_balance.deposited_balance -= forfeited;
+        self.trader_deposit = self.trader_deposit.saturating_sub(forfeited);
+        self.forfeited_trader_deposit = self
+            .forfeited_trader_deposit
+            .checked_add(forfeited)
+            .ok_or(ErrorCode::MathOverflow)?;
+        Ok(())
+    }
+
+    /// Notional of the open positions, the booked collateral plus the borrow against it
+    pub fn open_interest(&self) -> u64 {
+        self.trader_collateral.saturating_add(self.total_borrowed)
+    }
+
+    /// Route a collected fee between insurance, the protocol and LPs
+    ///
+    /// While the insurance fund is below `insurance_target_bps` of the open interest,
+    /// `insurance_fee_share_bps` of the fee tops it up, never past the target. The
+    /// rest, or the whole fee at or above target, is split as `split_fee` does.
+    pub fn route_fee(
+        &mut self,
+        fee: u64,
+        config: &Config,
+        insurance: &mut InsuranceFund,
+    ) -> Result<()> {
+        let to_insurance = if config.insurance_target_bps == 0 {
+            0
+        } else {
+            let target = proportional(
+                self.open_interest(),
+                config.insurance_target_bps,
+                BASIS_POINTS as u64,
+            )?;
+            calculate_fee(fee, config.insurance_fee_share_bps)?
+                .min(target.saturating_sub(insurance.balance))
+        };
+
+        let (protocol_fee, lp_fee) = split_fee(fee - to_insurance, config.protocol_fee_share)?;
+        self.collect_fees(protocol_fee, lp_fee)?;
+        insurance.credit(to_insurance)
+    }
+
+    /// Record a loss the trader's collateral couldn't cover and decide who pays for it
+    ///
+    /// Insurance covers it up to `max_bad_debt_per_epoch` per `bad_debt_epoch_slots`
+    /// window so a cascade can't drain it in one bad day. Whatever insurance can't or
+    /// may not cover is socialized to LPs by writing down `lp_deposit`.
+    pub fn absorb_bad_debt(
+        &mut self,
+        amount: u64,
+        slot: u64,
+        config: &Config,
+        insurance: &mut InsuranceFund,
+    ) -> Result<()> {
+        if amount == 0 {
+            return Ok(());
+        }
+        self.accumulated_bad_debt = self
+            .accumulated_bad_debt
+            .checked_add(amount)
+            .ok_or(ErrorCode::MathOverflow)?;
+
+        if config.bad_debt_epoch_slots > 0
+            && slot.saturating_sub(self.bad_debt_epoch_start_slot) >= config.bad_debt_epoch_slots
+        {
+            self.bad_debt_this_epoch = 0;
+            self.bad_debt_epoch_start_slot = slot;
+        }
+
+        let epoch_headroom = if config.max_bad_debt_per_epoch == 0 {
+            u64::MAX
+        } else {
+            config
+                .max_bad_debt_per_epoch
+                .saturating_sub(self.bad_debt_this_epoch)
+        };
+        let from_insurance = amount.min(insurance.balance).min(epoch_headroom);
+        let socialized = amount - from_insurance;
+
+        insurance.balance -= from_insurance;
+        self.bad_debt_this_epoch = self
+            .bad_debt_this_epoch
+            .checked_add(from_insurance)
+            .ok_or(ErrorCode::MathOverflow)?;
+        self.lp_deposit = self.lp_deposit.saturating_sub(socialized);
+
+        msg!(
+            "Bad debt of {}: {} from insurance, {} socialized to LPs",
+            amount,
+            from_insurance,
+            socialized
+        );
+        emit!(BadDebtAbsorbed {
+            token_mint: self.token_mint.clone(),
+            bad_debt: amount,
+            from_insurance,
+            socialized_to_lps: socialized,
+            bad_debt_this_epoch: self.bad_debt_this_epoch,
+            slot,
+        });
+        Ok(())
+    }
+
+    /// Release borrowed liquidity back to the pool
+    ///
+    /// Truncation in the fee/notional math can make `total_borrowed` drift a unit
+    /// below the sum of the open positions, so this saturates instead of failing
+    /// the close and trapping the trader's funds.
+    pub fn release_borrowed(&mut self, amount: u64) {
+        if amount > self.total_borrowed {
+            msg!(
+                "Warning: total_borrowed rounding drift of {}",
+                amount - self.total_borrowed
+            );
+        }
+        self.total_borrowed = self.total_borrowed.saturating_sub(amount);
+    }
+
+    /// Add the collateral of a newly opened or topped up position
+    pub fn lock_trader_collateral(&mut self, amount: u64) -> Result<()> {
+        self.trader_collateral = self
+            .trader_collateral
+            .checked_add(amount)
+            .ok_or(ErrorCode::MathOverflow)?;
+        Ok(())
+    }
+
+    /// Check that `trader_collateral` still covers an open position's `collateral`
+    ///
+    /// Cheap runtime guard for the invariant above in paths that leave a position open,
+    /// the full sum is checked off-band by `verify_collateral_invariant`.
+    pub fn check_covers_collateral(&self, collateral: u64) -> Result<()> {
+        require!(
+            self.trader_collateral >= collateral,
+            ErrorCode::CollateralInvariantViolated
+        );
+        Ok(())
+    }
+
+    /// Release trader collateral from the pool, saturating on rounding drift
+    pub fn release_trader_collateral(&mut self, amount: u64) {
+        if amount > self.trader_collateral {
+            msg!(
+                "Warning: trader_collateral rounding drift of {}",
+                amount - self.trader_collateral
+            );
+        }
+        self.trader_collateral = self.trader_collateral.saturating_sub(amount);
+    }
+}
+
+#[account]
+#[derive(InitSpace)]
+pub struct LpProvider {
+    pub bump: u8,
+    pub pool: Pubkey,
+    pub provider: Pubkey,
+    // claim on `Vault::lp_value`, pro-rata to `Vault::total_lp_shares`
+    pub shares: u64,
+}
+
+/// Per-pool backstop for losses beyond a position's collateral
+///
+/// Funded by rebalance excess, warning penalties, seized collateral and a share of fees
+/// (see `Vault::route_fee`), drawn down by `Vault::absorb_bad_debt`.
+#[account]
+#[derive(InitSpace)]
+pub struct InsuranceFund {
+    pub bump: u8,
+    #[max_len(10)]
+    pub token_mint: String,
+    pub balance: u64,
+}
+
+impl InsuranceFund {
+    pub fn credit(&mut self, amount: u64) -> Result<()> {
+        self.balance = self
+            .balance
+            .checked_add(amount)
+            .ok_or(ErrorCode::MathOverflow)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_utils::*;
+
+    #[test]
+    fn virtual_seed_does_not_back_shares() {
+        let mut pool = pool(usd(100_000_000));
+        let shares = pool.add_liquidity(usd(1_000)).unwrap();
+        assert_eq!(shares, usd(1_000));
+        assert_eq!(pool.remove_liquidity(shares).unwrap(), usd(1_000));
+        assert_eq!(pool.lp_deposit, usd(100_000_000));
+    }
+
+    #[test]
+    fn virtual_fees_do_not_change_the_share_price() {
+        let mut pool = pool(usd(100_000_000));
+        let first = pool.add_liquidity(usd(1_000)).unwrap();
+        pool.collect_fees(0, usd(500)).unwrap();
+        pool.compound_lp_fees().unwrap();
+
+        let second = pool.add_liquidity(usd(1_000)).unwrap();
+        assert_eq!(second, first);
+        assert_eq!(pool.remove_liquidity(first).unwrap(), usd(1_000));
+        assert_eq!(pool.remove_liquidity(second).unwrap(), usd(1_000));
+    }
+
+    #[test]
+    fn backed_fees_raise_the_share_price() {
+        let mut pool = pool(usd(100_000_000));
+        let shares = pool.add_liquidity(usd(1_000)).unwrap();
+        let mut trader = trader_balance(usd(500));
+        trader.deposited_balance = usd(500);
+        pool.trader_deposit = usd(500);
+
+        // a $10 LP fee paid out of a real deposit
+        trader.balance -= usd(10);
+        pool.forfeit_trader_deposit(&mut trader).unwrap();
+        assert_eq!(trader.withdrawable_balance(), usd(490));
+        assert_eq!(pool.trader_deposit, usd(490));
+        pool.collect_fees(0, usd(10)).unwrap();
+        // plus $5 of LP fees charged on virtual balances
+        pool.collect_fees(0, usd(5)).unwrap();
+
+        assert_eq!(pool.compound_lp_fees().unwrap(), usd(15));
+        assert_eq!(pool.lp_deposit, usd(100_001_015));
+        // only the backed $10 reaches the shares
+        assert_eq!(pool.lp_value(), usd(1_010));
+        assert_eq!(pool.forfeited_trader_deposit, 0);
+        assert_eq!(pool.remove_liquidity(shares).unwrap(), usd(1_010));
+    }
+
+    #[test]
+    fn forfeited_deposits_stay_forfeited_after_a_profit() {
+        let mut pool = pool(0);
+        let mut trader = trader_balance(usd(500));
+        trader.deposited_balance = usd(500);
+        pool.trader_deposit = usd(500);
+
+        trader.balance -= usd(100);
+        pool.forfeit_trader_deposit(&mut trader).unwrap();
+        // a virtual win doesn't make the lost tokens withdrawable again
+        trader.balance += usd(300);
+        pool.forfeit_trader_deposit(&mut trader).unwrap();
+        assert_eq!(trader.withdrawable_balance(), usd(400));
+        assert_eq!(pool.trader_deposit, usd(400));
+        assert_eq!(pool.forfeited_trader_deposit, usd(100));
+    }
+
+    #[test]
+    fn withdrawals_leave_the_lifetime_counters() {
+        let mut pool = pool(usd(100_000_000));
+        let shares = pool.add_liquidity(usd(1_000)).unwrap();
+        pool.collect_fees(usd(4), usd(6)).unwrap();
+        pool.collect_fees(usd(1), usd(2)).unwrap();
+        assert_eq!(
+            (pool.lifetime_protocol_fees, pool.lifetime_lp_fees),
+            (usd(5), usd(8))
+        );
+
+        // the LP fees leave their accumulator and the LP takes everything out
+        pool.compound_lp_fees().unwrap();
+        pool.remove_liquidity(shares).unwrap();
+        assert_eq!(pool.accumulated_lp_fees, 0);
+        assert_eq!(
+            (pool.lifetime_protocol_fees, pool.lifetime_lp_fees),
+            (usd(5), usd(8))
+        );
+    }
+
+    #[test]
+    fn fee_routing_shifts_as_insurance_crosses_its_target() {
+        let mut config = config();
+        config.protocol_fee_share = 5_000;
+        config.insurance_target_bps = 1_000;
+        config.insurance_fee_share_bps = 2_000;
+        // $10,000 of open interest, a $1,000 target
+        let mut pool = pool(usd(100_000));
+        pool.trader_collateral = usd(2_000);
+        pool.total_borrowed = usd(8_000);
+
+        // well below target a fifth of the fee goes to insurance
+        let mut insurance = insurance_fund(0);
+        pool.route_fee(usd(100), &config, &mut insurance).unwrap();
+        assert_eq!(insurance.balance, usd(20));
+        assert_eq!(
+            (pool.accumulated_fees, pool.accumulated_lp_fees),
+            (usd(40), usd(40))
+        );
+
+        // just below, only what reaches the target
+        insurance.balance = usd(990);
+        pool.route_fee(usd(100), &config, &mut insurance).unwrap();
+        assert_eq!(insurance.balance, usd(1_000));
+        assert_eq!(
+            (pool.accumulated_fees, pool.accumulated_lp_fees),
+            (usd(85), usd(85))
+        );
+
+        // at target the normal split takes all of it
+        pool.route_fee(usd(100), &config, &mut insurance).unwrap();
+        assert_eq!(insurance.balance, usd(1_000));
+        assert_eq!(
+            (pool.accumulated_fees, pool.accumulated_lp_fees),
+            (usd(135), usd(135))
+        );
+    }
+
+    #[test]
+    fn lent_out_deposits_cannot_be_removed() {
+        let mut pool = pool(0);
+        let shares = pool.add_liquidity(usd(1_000)).unwrap();
+        pool.total_borrowed = usd(600);
+        assert!(pool.remove_liquidity(shares).is_err());
+        assert_eq!(pool.remove_liquidity(shares / 4).unwrap(), usd(250));
+    }
+
+    #[test]
+    fn insurance_covers_bad_debt_before_lps() {
+        let config = config();
+        let mut pool = pool(usd(10_000));
+        let mut insurance = insurance_fund(usd(100));
+
+        pool.absorb_bad_debt(usd(40), 10, &config, &mut insurance)
+            .unwrap();
+        assert_eq!(insurance.balance, usd(60));
+        assert_eq!(pool.lp_deposit, usd(10_000));
+
+        // once insurance runs dry the rest is written off the LP deposit
+        pool.absorb_bad_debt(usd(100), 20, &config, &mut insurance)
+            .unwrap();
+        assert_eq!(insurance.balance, 0);
+        assert_eq!(pool.lp_deposit, usd(9_960));
+        assert_eq!(pool.accumulated_bad_debt, usd(140));
+    }
+
+    #[test]
+    fn insurance_payouts_are_capped_per_epoch() {
+        let mut config = config();
+        config.bad_debt_epoch_slots = 100;
+        config.max_bad_debt_per_epoch = usd(50);
+        let mut pool = pool(usd(10_000));
+        let mut insurance = insurance_fund(usd(1_000));
+
+        pool.absorb_bad_debt(usd(40), 10, &config, &mut insurance)
+            .unwrap();
+        // only $10 of headroom left this epoch
+        pool.absorb_bad_debt(usd(40), 20, &config, &mut insurance)
+            .unwrap();
+        assert_eq!(insurance.balance, usd(950));
+        assert_eq!(pool.lp_deposit, usd(9_970));
+
+        // a new epoch starts over
+        pool.absorb_bad_debt(usd(40), 120, &config, &mut insurance)
+            .unwrap();
+        assert_eq!(insurance.balance, usd(910));
+        assert_eq!(pool.lp_deposit, usd(9_970));
+        assert_eq!(pool.bad_debt_this_epoch, usd(40));
+    }
 }
@@ -0,0 +1,360 @@
+//! Fixtures shared by the unit tests
+
+use crate::state::*;
+use crate::DEFAULT_MIN_EFFECTIVE_COLLATERAL;
+use anchor_lang::prelude::*;
+use anchor_lang::solana_program::program_pack::Pack;
+use anchor_lang::solana_program::program_stubs::{set_syscall_stubs, SyscallStubs};
+use anchor_lang::Event;
+use pyth_solana_receiver_sdk::price_update::{
+    get_feed_id_from_hex, PriceFeedMessage, PriceUpdateV2, VerificationLevel,
+};
+use std::cell::RefCell;
+use std::sync::Once;
+
+pub const DECIMALS: u8 = 9;
+// Pyth SOL/USD
+pub const FEED_ID: &str = "ef0d8b6fda2ceba41da15d4095d1da392a0d2f8ed0c6c7bc0f4cfac8c280b56d";
+pub const ONE_TOKEN: u64 = 1_000_000_000;
+
+/// Whole dollars as USD with 6 decimals
+pub fn usd(dollars: u64) -> u64 {
+    dollars * 1_000_000
+}
+
+/// 10x max leverage, 0.5% liquidation fee, 5% maintenance, 0.1% open and close fees
+pub fn config() -> Config {
+    Config {
+        bump: 0,
+        is_paused: false,
+        admin: Pubkey::default(),
+        max_leverage: 100_000,
+        liquidation_fee: 50,
+        maintainance_margin: 500,
+        opening_fee: 10,
+        closing_fee: 10,
+        privacy_fee: 0,
+        protocol_fee_share: 0,
+        last_updated: 0,
+        max_trader_exposure: 0,
+        min_rebalance_profit: 0,
+        tp_execution_reward: 0,
+        max_funding_staleness_slots: 0,
+        leverage_fee_surcharge: 0,
+        max_opening_fee: 0,
+        confidence_reference_leverage: 0,
+        liquidation_rebate: 0,
+        min_funding_interval_slots: 0,
+        max_rebalance_size_change_bps: 0,
+        bad_debt_epoch_slots: 0,
+        max_bad_debt_per_epoch: 0,
+        max_borrow_per_position: 0,
+        max_spot_ema_divergence_bps: 0,
+        max_global_positions: 0,
+        total_open_positions: 0,
+        warning_health_bps: 0,
+        warning_penalty_bps: 0,
+        insurance_target_bps: 0,
+        insurance_fee_share_bps: 0,
+        liquidation_backoff_slots: 0,
+        pending_admin: None,
+        min_effective_collateral: DEFAULT_MIN_EFFECTIVE_COLLATERAL,
+        max_net_delta: 0,
+    }
+}
+
+pub fn market(net_delta: i64) -> Market {
+    Market {
+        bump: 0,
+        pair: "SOL/USD".to_string(),
+        decimals: DECIMALS,
+        feed_id: FEED_ID.to_string(),
+        total_active_positions: 0,
+        is_paused: false,
+        open_window_start_slot: 0,
+        open_window_end_slot: 0,
+        cumulative_trader_pnl: 0,
+        feed_id_version: 0,
+        reduce_only: false,
+        feed_status: FeedStatus::Healthy,
+        retired: false,
+        settlement_price: 0,
+        net_delta,
+        funding_rate_bps: 10,
+        long_open_interest: 0,
+        short_open_interest: 0,
+        target_band_bps: 1000,
+        max_leverage: 0,
+        maintainance_margin: 0,
+    }
+}
+
+/// Pool with `lp_deposit` of virtual seed and nothing else booked
+pub fn pool(lp_deposit: u64) -> Vault {
+    Vault {
+        bump: 0,
+        is_paused: false,
+        token_mint: String::from("USDC"),
+        mint: Pubkey::default(),
+        lp_deposit,
+        total_lp_shares: 0,
+        lp_token_deposit: 0,
+        accumulated_lp_fees: 0,
+        trader_deposit: 0,
+        forfeited_trader_deposit: 0,
+        trader_collateral: 0,
+        total_borrowed: 0,
+        accumulated_fees: 0,
+        lifetime_keeper_rewards: 0,
+        lifetime_protocol_fees: 0,
+        lifetime_lp_fees: 0,
+        accumulated_insurance: 0,
+        accumulated_bad_debt: 0,
+        bad_debt_this_epoch: 0,
+        bad_debt_epoch_start_slot: 0,
+    }
+}
+
+/// Registered trader with nothing open
+pub fn trader() -> Trader {
+    Trader {
+        owner: Pubkey::default(),
+        bump: 0,
+        privacy: false,
+        position_count: 0,
+        active_position: 0,
+        total_notional_exposure: 0,
+        recent_client_orders: Default::default(),
+        margin_tier: 0,
+        delegate: None,
+        balance_accounts: 1,
+        retained_positions: 0,
+    }
+}
+
+/// Balance account holding `balance`, none of it locked or deposited
+pub fn trader_balance(balance: u64) -> TraderPoolDetail {
+    TraderPoolDetail {
+        owner: Pubkey::default(),
+        bump: 0,
+        token_mint: String::from("USDC"),
+        balance,
+        locked_balance: 0,
+        last_claim_slot: 0,
+        deposited_balance: 0,
+    }
+}
+
+pub fn insurance_fund(balance: u64) -> InsuranceFund {
+    InsuranceFund {
+        bump: 0,
+        token_mint: String::from("USDC"),
+        balance,
+    }
+}
+
+/// An open position of `tokens` whole tokens entered at `entry_price`
+pub fn position(is_long: bool, entry_price: u64, tokens: u64, collateral: u64) -> Position {
+    let actual_size = tokens * ONE_TOKEN;
+    let position_value = notional_value(actual_size, entry_price, DECIMALS).unwrap();
+    Position {
+        bump: 0,
+        owner: Pubkey::default(),
+        entered_at: 1,
+        closed_at: 0,
+        entered_at_unix: 0,
+        closed_at_unix: 0,
+        last_funding_slot: 1,
+        cumulative_funding_paid: 0,
+        position_id: 0,
+        is_long,
+        pair: "SOL/USD".to_string(),
+        token_mint: "USDC".to_string(),
+        current_target_price: 0,
+        desired_size: actual_size,
+        desired_entry_price: entry_price,
+        actual_entered_price: entry_price,
+        collateral,
+        actual_size,
+        current_price: entry_price,
+        position_value,
+        leverage: calculate_leverage_bps(position_value, collateral).unwrap(),
+        last_updated: 1,
+        take_profit_price: 0,
+        stop_loss_price: 0,
+        last_funding_rate_scaled: 0,
+        feed_version_at_open: 0,
+        client_order_id: 0,
+        last_health_ratio_bps: 0,
+        last_health_slot: 0,
+        opening_fee_paid: 0,
+        last_warning_slot: 0,
+        booked_borrow: position_value.saturating_sub(collateral),
+        liquidation_attempts: 0,
+        last_liquidation_attempt_slot: 0,
+    }
+}
+
+/// Fully verified Pyth update for `FEED_ID` at `price`, fresh against the default (and
+/// the stubbed) clock, with no confidence band and the EMA on the spot price
+pub fn price_update(price: u64) -> PriceUpdateV2 {
+    PriceUpdateV2 {
+        write_authority: Pubkey::default(),
+        verification_level: VerificationLevel::Full,
+        price_message: PriceFeedMessage {
+            feed_id: get_feed_id_from_hex(FEED_ID).unwrap(),
+            price: price as i64,
+            conf: 0,
+            exponent: -6,
+            publish_time: 0,
+            prev_publish_time: 0,
+            ema_price: price as i64,
+            ema_conf: 0,
+        },
+        posted_slot: 0,
+    }
+}
+
+/// Slot reported by `Clock::get` once `reset_syscalls` ran, past the first margin warning
+pub const TEST_SLOT: u64 = crate::MARGIN_WARNING_INTERVAL_SLOTS + 1_000;
+
+thread_local! {
+    // `sol_log_data` payloads, i.e. emitted events, of the current test
+    static LOGGED_DATA: RefCell<Vec<Vec<u8>>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Syscalls for calling handlers off-chain, capturing events and serving the clock
+struct TestSyscalls;
+
+impl SyscallStubs for TestSyscalls {
+    fn sol_log_data(&self, fields: &[&[u8]]) {
+        LOGGED_DATA.with(|logged| {
+            logged
+                .borrow_mut()
+                .extend(fields.iter().map(|field| field.to_vec()))
+        });
+    }
+
+    fn sol_get_clock_sysvar(&self, var_addr: *mut u8) -> u64 {
+        let clock = Clock {
+            slot: TEST_SLOT,
+            ..Clock::default()
+        };
+        // SAFETY: `Clock::get` passes a pointer to a `Clock`
+        unsafe { *(var_addr as *mut Clock) = clock };
+        0
+    }
+}
+
+/// Install `TestSyscalls` and forget the events this thread emitted so far
+pub fn reset_syscalls() {
+    static INSTALL: Once = Once::new();
+    INSTALL.call_once(|| {
+        set_syscall_stubs(Box::new(TestSyscalls));
+    });
+    LOGGED_DATA.with(|logged| logged.borrow_mut().clear());
+}
+
+/// Events of type `E` emitted on this thread since `reset_syscalls`
+pub fn emitted<E: Event>() -> Vec<E> {
+    LOGGED_DATA.with(|logged| {
+        logged
+            .borrow()
+            .iter()
+            .filter_map(|data| data.strip_prefix(E::DISCRIMINATOR))
+            .map(|mut event| E::deserialize(&mut event).unwrap())
+            .collect()
+    })
+}
+
+/// Storage behind an `AccountInfo`, for passing accounts to a handler
+///
+/// Laid out like the runtime's input: the original data length sits right before the
+/// key and the data length right before the data, where `AccountInfo::realloc` reads
+/// and writes them, so handlers can close accounts.
+#[repr(C)]
+pub struct TestAccount {
+    original_data_len: u32,
+    key: Pubkey,
+    owner: Pubkey,
+    lamports: u64,
+    // length header, then the account data
+    data: Vec<u8>,
+    executable: bool,
+}
+
+impl TestAccount {
+    fn new(key: Pubkey, owner: Pubkey, account: &[u8]) -> Self {
+        let mut data = (account.len() as u64).to_le_bytes().to_vec();
+        data.extend_from_slice(account);
+        Self {
+            original_data_len: account.len() as u32,
+            key,
+            owner,
+            lamports: 1_000_000_000,
+            data,
+            executable: false,
+        }
+    }
+
+    /// Account of this program holding `account`
+    pub fn program<T: AccountSerialize>(key: Pubkey, account: &T) -> Self {
+        let mut data = Vec::new();
+        account.try_serialize(&mut data).unwrap();
+        Self::new(key, crate::ID, &data)
+    }
+
+    /// Pyth price update account holding `price_update`
+    pub fn oracle(price_update: &PriceUpdateV2) -> Self {
+        let mut data = Vec::new();
+        price_update.try_serialize(&mut data).unwrap();
+        Self::new(Pubkey::new_unique(), pyth_solana_receiver_sdk::ID, &data)
+    }
+
+    /// Wallet with no data, e.g. a signer
+    pub fn wallet(key: Pubkey) -> Self {
+        Self::new(key, System::id(), &[])
+    }
+
+    /// SPL token account holding `account`, e.g. a mint or a token account
+    pub fn spl<T: Pack>(key: Pubkey, account: T) -> Self {
+        let mut data = vec![0; T::LEN];
+        account.pack_into_slice(&mut data);
+        Self::new(key, anchor_spl::token::ID, &data)
+    }
+
+    /// The SPL token program, for handlers that transfer tokens
+    pub fn token_program() -> Self {
+        Self {
+            executable: true,
+            ..Self::new(anchor_spl::token::ID, Pubkey::default(), &[])
+        }
+    }
+
+    /// The system program, for handlers that create accounts
+    pub fn system_program() -> Self {
+        Self {
+            executable: true,
+            ..Self::new(System::id(), Pubkey::default(), &[])
+        }
+    }
+
+    pub fn lamports(&self) -> u64 {
+        self.lamports
+    }
+
+    pub fn info(&mut self, is_signer: bool) -> AccountInfo<'_> {
+        // a handler that closed the account shrank the length in the header
+        let len = u64::from_le_bytes(self.data[..8].try_into().unwrap()) as usize;
+        AccountInfo::new(
+            &self.key,
+            is_signer,
+            true,
+            &mut self.lamports,
+            &mut self.data[8..8 + len],
+            &self.owner,
+            self.executable,
+            0,
+        )
+    }
+}